@@ -0,0 +1,182 @@
+// Lookups, inserts and range scans of the cache oblivious map against `std::collections::BTreeMap`
+// and a sorted `Vec`, over a few sizes and key types. Run with `cargo bench`, or
+// `cargo bench -- lookup/u64` for one group.
+
+use cache_oblivious_btree_map::BTreeMap;
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use std::hint::black_box;
+
+const SIZES: [usize; 3] = [1 << 10, 1 << 14, 1 << 18];
+// Inserts into a sorted `Vec` move half of it each, so it stops at this size.
+const VEC_INSERT_MAX: usize = 1 << 14;
+const SCAN_LEN: u64 = 100;
+
+trait Key: Ord + Clone + Send + Sync + 'static {
+    const NAME: &'static str;
+    fn from_u64(i: u64) -> Self;
+}
+
+impl Key for u64 {
+    const NAME: &'static str = "u64";
+    fn from_u64(i: u64) -> Self {
+        i
+    }
+}
+
+impl Key for String {
+    const NAME: &'static str = "string";
+    fn from_u64(i: u64) -> Self {
+        format!("key-{:016}", i)
+    }
+}
+
+// The even numbers below 2n in random order, so odd numbers make misses.
+fn shuffled(n: usize) -> Vec<u64> {
+    let mut keys = (0..n as u64).map(|i| i * 2).collect::<Vec<_>>();
+    keys.shuffle(&mut StdRng::seed_from_u64(n as u64));
+    keys
+}
+
+fn lookup<K: Key>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("lookup/{}", K::NAME));
+    for n in SIZES {
+        let keys = shuffled(n);
+        let queries = keys.iter().map(|&i| K::from_u64(i)).collect::<Vec<_>>();
+        let co = keys
+            .iter()
+            .map(|&i| (K::from_u64(i), i))
+            .collect::<BTreeMap<_, _>>();
+        let std = keys
+            .iter()
+            .map(|&i| (K::from_u64(i), i))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        let vec = std.iter().map(|(k, &v)| (k.clone(), v)).collect::<Vec<_>>();
+        let mut next = queries.iter().cycle();
+        group.bench_function(BenchmarkId::new("co_btree", n), |b| {
+            b.iter(|| black_box(co.get(next.next().unwrap())))
+        });
+        group.bench_function(BenchmarkId::new("std_btree", n), |b| {
+            b.iter(|| black_box(std.get(next.next().unwrap())))
+        });
+        group.bench_function(BenchmarkId::new("sorted_vec", n), |b| {
+            b.iter(|| {
+                let key = next.next().unwrap();
+                black_box(vec.binary_search_by(|(k, _)| k.cmp(key)).ok())
+            })
+        });
+    }
+    group.finish();
+}
+
+fn insert<K: Key>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("insert/{}", K::NAME));
+    group.sample_size(10);
+    for n in SIZES {
+        let keys = shuffled(n).into_iter().map(K::from_u64).collect::<Vec<_>>();
+        group.bench_function(BenchmarkId::new("co_btree", n), |b| {
+            b.iter_batched(
+                || keys.clone(),
+                |keys| {
+                    let mut map = BTreeMap::new();
+                    for (i, key) in keys.into_iter().enumerate() {
+                        map.insert(key, i);
+                    }
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("std_btree", n), |b| {
+            b.iter_batched(
+                || keys.clone(),
+                |keys| {
+                    let mut map = std::collections::BTreeMap::new();
+                    for (i, key) in keys.into_iter().enumerate() {
+                        map.insert(key, i);
+                    }
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        if n > VEC_INSERT_MAX {
+            continue;
+        }
+        group.bench_function(BenchmarkId::new("sorted_vec", n), |b| {
+            b.iter_batched(
+                || keys.clone(),
+                |keys| {
+                    let mut vec: Vec<(K, usize)> = Vec::new();
+                    for (i, key) in keys.into_iter().enumerate() {
+                        match vec.binary_search_by(|(k, _)| k.cmp(&key)) {
+                            Ok(at) => vec[at] = (key, i),
+                            Err(at) => vec.insert(at, (key, i)),
+                        }
+                    }
+                    vec
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// Sums the values of the entries in a range of `SCAN_LEN` keys.
+fn range_scan<K: Key>(c: &mut Criterion) {
+    let mut group = c.benchmark_group(format!("range_scan/{}", K::NAME));
+    for n in SIZES {
+        let keys = shuffled(n);
+        let bounds = keys
+            .iter()
+            .map(|&i| (K::from_u64(i), K::from_u64(i + SCAN_LEN * 2)))
+            .collect::<Vec<_>>();
+        let co = keys
+            .iter()
+            .map(|&i| (K::from_u64(i), i))
+            .collect::<BTreeMap<_, _>>();
+        let std = keys
+            .iter()
+            .map(|&i| (K::from_u64(i), i))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        let vec = std.iter().map(|(k, &v)| (k.clone(), v)).collect::<Vec<_>>();
+        let mut next = bounds.iter().cycle();
+        group.bench_function(BenchmarkId::new("co_btree", n), |b| {
+            b.iter(|| {
+                let (from, to) = next.next().unwrap();
+                black_box(
+                    co.range(from.clone()..to.clone())
+                        .map(|(_, v)| v)
+                        .sum::<u64>(),
+                )
+            })
+        });
+        group.bench_function(BenchmarkId::new("std_btree", n), |b| {
+            b.iter(|| {
+                let (from, to) = next.next().unwrap();
+                black_box(std.range(from..to).map(|(_, v)| v).sum::<u64>())
+            })
+        });
+        group.bench_function(BenchmarkId::new("sorted_vec", n), |b| {
+            b.iter(|| {
+                let (from, to) = next.next().unwrap();
+                let start = vec.partition_point(|(k, _)| k < from);
+                let end = vec.partition_point(|(k, _)| k < to);
+                black_box(vec[start..end].iter().map(|(_, v)| v).sum::<u64>())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    lookup::<u64>,
+    lookup::<String>,
+    insert::<u64>,
+    insert::<String>,
+    range_scan::<u64>,
+    range_scan::<String>
+);
+criterion_main!(benches);
@@ -0,0 +1,43 @@
+// Reads the input as operations of three bytes, an opcode and two operands, runs them on a map
+// and on `std::collections::BTreeMap` and checks that both answer the same, with the map
+// invariants checked after every step. Keys are single bytes, so operations collide often.
+// Run with `cargo fuzz run operations` from the crate root.
+
+#![no_main]
+
+use cache_oblivious_btree_map::BTreeMap;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut map = BTreeMap::<u8, u8>::new();
+    let mut model = std::collections::BTreeMap::<u8, u8>::new();
+    for op in data.chunks_exact(3) {
+        let (a, b) = (op[1], op[2]);
+        let (lo, hi) = (a.min(b), a.max(b));
+        match op[0] % 8 {
+            0 | 1 => assert_eq!(map.insert(a, b), model.insert(a, b)),
+            2 => assert_eq!(map.remove(&a), model.remove(&a)),
+            3 => {
+                assert_eq!(map.get(&a), model.get(&a));
+                assert_eq!(map.contains_key(&a), model.contains_key(&a));
+            }
+            4 => assert!(map.range(lo..=hi).eq(model.range(lo..=hi))),
+            5 => assert!(map.range(lo..hi).rev().eq(model.range(lo..hi).rev())),
+            6 => {
+                let removed = model.range(lo..hi).count();
+                model.retain(|k, _| !(lo..hi).contains(k));
+                assert_eq!(map.remove_range(lo..hi), removed);
+            }
+            _ => {
+                // Hidden keys must stay invisible until purged, like removed ones.
+                assert_eq!(map.mark_removed(&a), model.remove(&a).is_some());
+                if b % 4 == 0 {
+                    map.purge_marked();
+                }
+            }
+        }
+        map.check_invariants();
+        assert_eq!(map.len(), model.len());
+    }
+    assert!(map.iter().eq(model.iter()));
+});
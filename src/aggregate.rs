@@ -0,0 +1,398 @@
+use crate::{layout::compute_node_id, packed_memory_array::PackedMemoryArray};
+use std::{any::Any, ops::Add, sync::Mutex};
+
+// An associative operation with an identity, folded over the values of a key range by
+// `BTreeMap::range_fold`. `combine` need not be commutative: its left operand always covers
+// the smaller keys.
+pub trait Monoid<V> {
+    type Output: Clone;
+
+    fn identity(&self) -> Self::Output;
+    fn lift(&self, value: &V) -> Self::Output;
+    fn combine(&self, left: &Self::Output, right: &Self::Output) -> Self::Output;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sum;
+
+impl<V: Clone + Default + Add<Output = V>> Monoid<V> for Sum {
+    type Output = V;
+
+    fn identity(&self) -> V {
+        V::default()
+    }
+
+    fn lift(&self, value: &V) -> V {
+        value.clone()
+    }
+
+    fn combine(&self, left: &V, right: &V) -> V {
+        left.clone() + right.clone()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Min;
+
+impl<V: Clone + Ord> Monoid<V> for Min {
+    type Output = Option<V>;
+
+    fn identity(&self) -> Option<V> {
+        None
+    }
+
+    fn lift(&self, value: &V) -> Option<V> {
+        Some(value.clone())
+    }
+
+    fn combine(&self, left: &Option<V>, right: &Option<V>) -> Option<V> {
+        match (left, right) {
+            (Some(l), Some(r)) => Some(l.min(r).clone()),
+            _ => left.clone().or(right.clone()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Max;
+
+impl<V: Clone + Ord> Monoid<V> for Max {
+    type Output = Option<V>;
+
+    fn identity(&self) -> Option<V> {
+        None
+    }
+
+    fn lift(&self, value: &V) -> Option<V> {
+        Some(value.clone())
+    }
+
+    fn combine(&self, left: &Option<V>, right: &Option<V>) -> Option<V> {
+        match (left, right) {
+            (Some(l), Some(r)) => Some(l.max(r).clone()),
+            _ => left.clone().or(right.clone()),
+        }
+    }
+}
+
+// The type erased aggregate layer a map keeps next to its index, see `Aggregates`.
+pub(crate) trait AggregateLayer<K: Ord, V>: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+
+    // Recomputes every node for an index of `height` over the slots of `pma`.
+    fn refresh(
+        &mut self,
+        pma: &PackedMemoryArray<K, V>,
+        height: usize,
+        hidden: &dyn Fn(&K) -> bool,
+    );
+
+    // Recomputes the leaves of the slots in `from..to` and their ancestors.
+    fn update(
+        &mut self,
+        pma: &PackedMemoryArray<K, V>,
+        height: usize,
+        from: usize,
+        to: usize,
+        hidden: &dyn Fn(&K) -> bool,
+    );
+
+    // Records that the values in `from..to` were handed out mutably, they are recomputed on the
+    // next fold unless the slots move before.
+    fn touch(&mut self, from: usize, to: usize);
+}
+
+// The aggregate of every index node's subtree, stored in the vEB order of the index itself.
+// Hidden entries aggregate as the identity. The state sits behind a mutex so a fold, which
+// only borrows the map, can first catch up with values updated in place.
+pub(crate) struct Aggregates<M, A> {
+    monoid: M,
+    state: Mutex<State<A>>,
+}
+
+struct State<A> {
+    values: Vec<A>,
+    height: usize,
+    pending: Vec<(usize, usize)>,
+    // Set when pending slots moved, everything is recomputed on the next fold.
+    stale: bool,
+}
+
+impl<M, A> Aggregates<M, A> {
+    pub(crate) fn new(monoid: M) -> Self {
+        Self {
+            monoid,
+            state: Mutex::new(State {
+                values: vec![],
+                height: 0,
+                pending: vec![],
+                stale: true,
+            }),
+        }
+    }
+
+    // Folds the values of the slots in `from..to` of an index of `height`, combining the largest
+    // subtrees that fit.
+    pub(crate) fn fold<K: Ord, V>(
+        &self,
+        pma: &PackedMemoryArray<K, V>,
+        height: usize,
+        from: usize,
+        to: usize,
+        hidden: &dyn Fn(&K) -> bool,
+    ) -> A
+    where
+        M: Monoid<V, Output = A>,
+    {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.stale || state.height != height {
+            state.recompute_all(&self.monoid, pma, height, hidden);
+        } else {
+            for (from, to) in std::mem::take(&mut state.pending) {
+                state.recompute_range(&self.monoid, pma, from, to, hidden);
+            }
+        }
+        let mut result = self.monoid.identity();
+        state.fold_node(&self.monoid, 1, 0, pma.data_len(), from, to, &mut result);
+        result
+    }
+}
+
+impl<A> State<A> {
+    // Combines the nodes covering the part of `from..to` below `node_id`, whose subtree holds
+    // the slots `lo..hi`, into `result` from left to right.
+    #[allow(clippy::too_many_arguments)]
+    fn fold_node<V, M: Monoid<V, Output = A>>(
+        &self,
+        monoid: &M,
+        node_id: usize,
+        lo: usize,
+        hi: usize,
+        from: usize,
+        to: usize,
+        result: &mut A,
+    ) {
+        if to <= lo || hi <= from {
+            return;
+        }
+        if from <= lo && hi <= to {
+            let value = &self.values[compute_node_id(node_id, self.height) - 1];
+            *result = monoid.combine(result, value);
+            return;
+        }
+        let mid = (lo + hi) >> 1;
+        self.fold_node(monoid, node_id << 1, lo, mid, from, to, result);
+        self.fold_node(monoid, (node_id << 1) | 1, mid, hi, from, to, result);
+    }
+
+    fn leaf_value<K, V, M: Monoid<V, Output = A>>(
+        monoid: &M,
+        slot: Option<(&K, &V)>,
+        hidden: &dyn Fn(&K) -> bool,
+    ) -> A {
+        match slot {
+            Some((k, v)) if !hidden(k) => monoid.lift(v),
+            _ => monoid.identity(),
+        }
+    }
+
+    fn recompute_all<K: Ord, V, M: Monoid<V, Output = A>>(
+        &mut self,
+        monoid: &M,
+        pma: &PackedMemoryArray<K, V>,
+        height: usize,
+        hidden: &dyn Fn(&K) -> bool,
+    ) {
+        let first_leaf_id = 1usize << (height - 1);
+        self.height = height;
+        self.values.clear();
+        self.values
+            .resize_with(first_leaf_id << 1, || monoid.identity());
+        for i in 0..pma.data_len() {
+            self.values[compute_node_id(first_leaf_id + i, height) - 1] =
+                Self::leaf_value(monoid, pma.key_value(i), hidden);
+        }
+        for id in (1..first_leaf_id).rev() {
+            self.recompute_node(monoid, id);
+        }
+        self.pending.clear();
+        self.stale = false;
+    }
+
+    fn recompute_range<K: Ord, V, M: Monoid<V, Output = A>>(
+        &mut self,
+        monoid: &M,
+        pma: &PackedMemoryArray<K, V>,
+        from: usize,
+        to: usize,
+        hidden: &dyn Fn(&K) -> bool,
+    ) {
+        if from >= to {
+            return;
+        }
+        let first_leaf_id = 1usize << (self.height - 1);
+        for i in from..to.min(pma.data_len()) {
+            self.values[compute_node_id(first_leaf_id + i, self.height) - 1] =
+                Self::leaf_value(monoid, pma.key_value(i), hidden);
+        }
+        let (mut lo, mut hi) = (first_leaf_id + from, first_leaf_id + to - 1);
+        while lo > 1 {
+            lo >>= 1;
+            hi >>= 1;
+            for id in lo..=hi {
+                self.recompute_node(monoid, id);
+            }
+        }
+    }
+
+    fn recompute_node<V, M: Monoid<V, Output = A>>(&mut self, monoid: &M, id: usize) {
+        let value = monoid.combine(
+            &self.values[compute_node_id(id << 1, self.height) - 1],
+            &self.values[compute_node_id((id << 1) | 1, self.height) - 1],
+        );
+        self.values[compute_node_id(id, self.height) - 1] = value;
+    }
+}
+
+impl<K: Ord, V, M> AggregateLayer<K, V> for Aggregates<M, M::Output>
+where
+    M: Monoid<V> + Send + Sync + 'static,
+    M::Output: Send + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn refresh(
+        &mut self,
+        pma: &PackedMemoryArray<K, V>,
+        height: usize,
+        hidden: &dyn Fn(&K) -> bool,
+    ) {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        state.recompute_all(&self.monoid, pma, height, hidden);
+    }
+
+    fn update(
+        &mut self,
+        pma: &PackedMemoryArray<K, V>,
+        height: usize,
+        from: usize,
+        to: usize,
+        hidden: &dyn Fn(&K) -> bool,
+    ) {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        // Values touched before the slots moved cannot be located any more.
+        if !state.pending.is_empty() || state.height != height {
+            state.stale = true;
+        }
+        if !state.stale {
+            state.recompute_range(&self.monoid, pma, from, to, hidden);
+        }
+    }
+
+    fn touch(&mut self, from: usize, to: usize) {
+        let state = self.state.get_mut().unwrap_or_else(|e| e.into_inner());
+        if !state.stale {
+            state.pending.push((from, to));
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod aggregate {
+    use crate::{aggregate::Monoid, BTreeMap, Max, Min, Sum};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // Not commutative, so a fold combining out of order shows.
+    struct Concat;
+
+    impl Monoid<String> for Concat {
+        type Output = String;
+
+        fn identity(&self) -> String {
+            String::new()
+        }
+
+        fn lift(&self, value: &String) -> String {
+            value.clone()
+        }
+
+        fn combine(&self, left: &String, right: &String) -> String {
+            format!("{}{}", left, right)
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_range_fold() {
+        let mut map = BTreeMap::<usize, u64>::new();
+        map.enable_aggregate(Sum);
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..2000 {
+            let key = rng.gen_range(0..1000);
+            match rng.gen_range(0..4) {
+                0 => {
+                    map.remove(&key);
+                }
+                1 => {
+                    if let Some(v) = map.get_mut(&key) {
+                        *v += 1;
+                    }
+                }
+                _ => {
+                    map.insert(key, rng.gen_range(0..100));
+                }
+            }
+            if rng.gen_range(0..50) == 0 {
+                map.mark_removed(&rng.gen_range(0..1000));
+            }
+            let (from, to) = (rng.gen_range(0..1000), rng.gen_range(0..1000));
+            let expected = map.range(from..to.max(from)).map(|(_, v)| v).sum::<u64>();
+            assert_eq!(map.range_fold(from..to.max(from), &Sum), expected);
+        }
+        for v in map.range_mut(100..200) {
+            *v.1 = 1000;
+        }
+        let expected = map.values().sum::<u64>();
+        assert_eq!(map.range_fold(.., &Sum), expected);
+        assert_eq!(map.range_fold(.., &Max), map.values().max().copied());
+        map.purge_marked();
+        assert_eq!(map.range_fold(.., &Sum), expected);
+        map.disable_aggregate();
+        assert_eq!(map.range_fold(.., &Sum), expected);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_fold_order() {
+        let mut map = BTreeMap::new();
+        for i in (0..500).rev() {
+            map.insert(i, format!("{},", i));
+        }
+        map.enable_aggregate(Concat);
+        let expected = (100..300).map(|i| format!("{},", i)).collect::<String>();
+        assert_eq!(map.range_fold(100..300, &Concat), expected);
+        map.insert(150, String::from("x,"));
+        *map.get_mut(&151).unwrap() = String::from("y,");
+        let expected = expected.replace(",150,151,", ",x,y,");
+        assert_eq!(map.range_fold(100..300, &Concat), expected);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_large_rebuild() {
+        let mut map = BTreeMap::from_sorted_iter((0..10000i64).map(|i| (i, i % 97)));
+        map.enable_aggregate(Min);
+        map.insert(5000, -1);
+        map.extend((10000..20000i64).map(|i| (i, i % 89)));
+        assert_eq!(map.range_fold(..5000, &Min), Some(0));
+        assert_eq!(map.range_fold(4000..6000, &Min), Some(-1));
+        assert_eq!(map.range_fold(30000.., &Min), None);
+        assert_eq!(
+            map.range_fold(12345..17890, &Min),
+            map.range(12345..17890).map(|(_, v)| *v).min()
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use allocator_api2::alloc::{AllocError, Allocator, Layout};
+use bumpalo::Bump;
+use std::{
+    ptr::NonNull,
+    sync::{Arc, Mutex},
+};
+
+// A bump arena for maps that are built, queried and thrown away as a unit, like per-request
+// temporary indexes. Maps created by `BTreeMap::new_in` take their key, value and index
+// buffers from it, so an allocation is a pointer bump and nothing is freed on its own, not
+// even the buffers a resize leaves behind. The memory goes all at once when the arena and the
+// last map in it are dropped, each map holding a handle that keeps the arena alive. Handles
+// are cheap to clone and can be sent to other threads, allocations take a lock.
+#[derive(Clone, Default)]
+pub struct Arena {
+    bump: Arc<Mutex<Bump>>,
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Creates an arena whose first chunk holds `bytes`.
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self {
+            bump: Arc::new(Mutex::new(Bump::with_capacity(bytes))),
+        }
+    }
+
+    // Bytes of the chunks the arena holds, in use or not.
+    pub fn allocated_bytes(&self) -> usize {
+        self.bump
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .allocated_bytes()
+    }
+}
+
+// SAFETY: blocks come from chunks the bump frees only when it is dropped, and every handle,
+// including the one inside each vector allocated through it, keeps the bump alive, so a block
+// stays valid for as long as any handle does. Clones share the bump, so a block may be given
+// back through any of them.
+unsafe impl Allocator for Arena {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self
+            .bump
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .try_alloc_layout(layout)
+            .map_err(|_| AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    // Blocks are never freed on their own, they go with the arena.
+    unsafe fn deallocate(&self, _ptr: NonNull<u8>, _layout: Layout) {}
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod arena {
+    use crate::{Arena, BTreeMap};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_new_in() {
+        let arena = Arena::new();
+        let mut map = BTreeMap::new_in(&arena);
+        let empty = arena.allocated_bytes();
+        for i in 0..1000usize {
+            map.insert(i, i.to_string());
+        }
+        assert!(arena.allocated_bytes() > empty);
+        for i in (0..1000).step_by(2) {
+            assert_eq!(map.remove(&i), Some(i.to_string()));
+        }
+        assert_eq!(map.len(), 500);
+        assert!(map.iter().map(|(k, _)| *k).eq((1..1000).step_by(2)));
+        map.clear();
+        assert!(map.is_empty());
+        map.try_reserve(2000).unwrap();
+        map.shrink_to_fit();
+        assert_eq!(map.insert(7, "7".to_string()), None);
+        // The map keeps the arena alive after the caller lets go of it.
+        drop(arena);
+        for i in 0..100usize {
+            map.insert(i, i.to_string());
+        }
+        assert_eq!(map.get(&50), Some(&"50".to_string()));
+    }
+
+    #[test]
+    fn test_shared() {
+        let arena = Arena::with_capacity(1 << 16);
+        let count = if cfg!(miri) { 50 } else { 500 };
+        let maps = (0..4)
+            .map(|t| {
+                let arena = arena.clone();
+                std::thread::spawn(move || {
+                    let mut map = BTreeMap::new_in(&arena);
+                    for i in 0..count {
+                        map.insert(i, i * t);
+                    }
+                    map
+                })
+            })
+            .collect::<Vec<_>>();
+        for (t, map) in maps.into_iter().enumerate() {
+            let map = map.join().unwrap();
+            assert!(map
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .eq((0..count).map(|i| (i, i * t))));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+// Bit sets over slot indices, one bit per slot packed in `u64` words, scanned a word at a time.
+
+const BITS: usize = u64::BITS as usize;
+
+#[inline]
+pub(crate) fn words_for(len: usize) -> usize {
+    len.div_ceil(BITS)
+}
+
+#[inline]
+pub(crate) fn get(bits: &[u64], i: usize) -> bool {
+    bits[i / BITS] & (1 << (i % BITS)) != 0
+}
+
+#[inline]
+pub(crate) fn set(bits: &mut [u64], i: usize) {
+    bits[i / BITS] |= 1 << (i % BITS);
+}
+
+#[inline]
+pub(crate) fn unset(bits: &mut [u64], i: usize) {
+    bits[i / BITS] &= !(1 << (i % BITS));
+}
+
+// The bits of the word `w` that fall in [from, to).
+#[inline]
+fn mask(w: usize, from: usize, to: usize) -> u64 {
+    let start = from.saturating_sub(w * BITS).min(BITS);
+    let end = to.saturating_sub(w * BITS).min(BITS);
+    if start >= end {
+        return 0;
+    }
+    let high = if end == BITS { !0 } else { (1 << end) - 1 };
+    high & !((1u64 << start) - 1)
+}
+
+// Sets the bits in [from, to) to `value`.
+pub(crate) fn fill(bits: &mut [u64], from: usize, to: usize, value: bool) {
+    if from >= to {
+        return;
+    }
+    let words = bits[from / BITS..words_for(to)].iter_mut();
+    for (w, word) in (from / BITS..).zip(words) {
+        let mask = mask(w, from, to);
+        if value {
+            *word |= mask;
+        } else {
+            *word &= !mask;
+        }
+    }
+}
+
+pub(crate) fn count(bits: &[u64], from: usize, to: usize) -> usize {
+    if from >= to {
+        return 0;
+    }
+    (from / BITS..words_for(to))
+        .map(|w| (bits[w] & mask(w, from, to)).count_ones() as usize)
+        .sum()
+}
+
+// The first index in [from, to) whose bit is `value`.
+pub(crate) fn next(bits: &[u64], from: usize, to: usize, value: bool) -> Option<usize> {
+    if from >= to {
+        return None;
+    }
+    let words = bits[from / BITS..words_for(to)].iter();
+    for (w, &word) in (from / BITS..).zip(words) {
+        let word = if value { word } else { !word } & mask(w, from, to);
+        if word != 0 {
+            return Some(w * BITS + word.trailing_zeros() as usize);
+        }
+    }
+    None
+}
+
+// The last index in [from, to) whose bit is `value`.
+pub(crate) fn prev(bits: &[u64], from: usize, to: usize, value: bool) -> Option<usize> {
+    if from >= to {
+        return None;
+    }
+    let words = bits[from / BITS..words_for(to)].iter();
+    for (w, &word) in (from / BITS..words_for(to)).zip(words).rev() {
+        let word = if value { word } else { !word } & mask(w, from, to);
+        if word != 0 {
+            return Some(w * BITS + (BITS - 1 - word.leading_zeros() as usize));
+        }
+    }
+    None
+}
+
+// Resizes to `len` bits, the bits past the end reading as unset.
+pub(crate) fn resize(bits: &mut Vec<u64>, len: usize) {
+    bits.resize(words_for(len), 0);
+    if !len.is_multiple_of(BITS) {
+        let last = bits.len() - 1;
+        bits[last] &= (1 << (len % BITS)) - 1;
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod bitmap {
+    use super::{count, fill, get, next, prev, resize, set, unset};
+
+    #[test]
+    fn test_operations() {
+        let mut bits = vec![];
+        resize(&mut bits, 200);
+        assert_eq!(bits.len(), 4);
+        for i in [0, 5, 63, 64, 130, 199] {
+            set(&mut bits, i);
+        }
+        assert!(get(&bits, 63) && !get(&bits, 62));
+        assert_eq!(count(&bits, 0, 200), 6);
+        assert_eq!(count(&bits, 5, 64), 2);
+        assert_eq!(next(&bits, 6, 200, true), Some(63));
+        assert_eq!(next(&bits, 65, 130, true), None);
+        assert_eq!(next(&bits, 63, 70, false), Some(65));
+        assert_eq!(prev(&bits, 0, 130, true), Some(64));
+        assert_eq!(prev(&bits, 0, 65, false), Some(62));
+        assert_eq!(prev(&bits, 1, 5, true), None);
+        fill(&mut bits, 60, 140, true);
+        assert_eq!(count(&bits, 0, 200), 83);
+        fill(&mut bits, 0, 200, false);
+        assert_eq!(count(&bits, 0, 200), 0);
+        set(&mut bits, 199);
+        unset(&mut bits, 199);
+        set(&mut bits, 150);
+        resize(&mut bits, 150);
+        resize(&mut bits, 200);
+        assert_eq!(count(&bits, 0, 200), 0);
+    }
+}
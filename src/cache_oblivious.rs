@@ -1,84 +1,234 @@
 #![allow(dead_code)]
-use crate::packed_memory_array::PackedMemoryArray;
+#[cfg(feature = "cache-sim")]
+use crate::cache_sim::{CacheOperation, CacheSimulator};
+#[cfg(feature = "rayon")]
+use crate::layout::compute_node_id;
+#[cfg(all(target_os = "linux", feature = "numa"))]
+use crate::numa::{self, NumaPlacement, NumaPolicy};
+#[cfg(all(unix, feature = "mlock"))]
+use crate::pinning::{self, PinnedRanges};
+#[cfg(feature = "async")]
+use crate::stream::AsyncIter;
+use crate::{
+    aggregate::{AggregateLayer, Aggregates, Monoid},
+    bitmap,
+    comparable::Comparable,
+    cow::PageCache,
+    entry::{Entry, OccupiedEntry, OccupiedError, VacantEntry},
+    error::CoBTreeError,
+    layout::IndexLayout,
+    packed_memory_array::{IntoKeyValues, PackedMemoryArray, PmaIter, PmaIterMut},
+    slots::Slots,
+    sorting,
+    stats::Stats,
+    transaction::Transaction,
+    view::{FilterView, MapView},
+};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+#[cfg(feature = "cache-sim")]
+use std::sync::Mutex;
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, BinaryHeap, TryReserveError},
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
 
-#[derive(Clone, Eq, PartialEq)]
-enum Node<K: Clone + Ord> {
-    Branch(BranchType<K>),
-    Leaf(LeafType<K>),
+// Nodes hold the PMA index of the largest key below them instead of a copy of the key, so keys
+// are stored once, in the PMA, and need not be `Clone`.
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum Node {
+    Branch(BranchType),
+    Leaf(LeafType),
 }
 
-#[derive(Clone, Eq, PartialEq)]
-struct LeafType<K: Clone + Ord> {
-    key: Option<K>,
+#[derive(Clone, Copy, Eq, PartialEq)]
+struct LeafType {
+    slot: Option<NodeIndex>,
 }
 
-#[derive(Clone, Eq, PartialEq)]
-struct BranchType<K: Clone + Ord> {
-    key: Option<K>,
+#[derive(Clone, Copy, Eq, PartialEq)]
+struct BranchType {
+    slot: Option<NodeIndex>,
+    // Number of occupied slots below, hidden entries included.
+    count: NodeIndex,
 }
 
-fn compute_node_id_internal(n: usize, d: usize, height: usize) -> usize {
-    if height < 3 {
-        n
-    } else {
-        let h2 = ((height + 1) >> 1).next_power_of_two();
-        let h1 = height - h2;
-        if d <= h1 {
-            compute_node_id_internal(n, d, h1)
-        } else {
-            let d2 = d - h1;
-            let d1 = d2 - 1;
-            (1 << h1) - 1
-                + ((1 << h2) - 1) * ((n >> d1) - (1 << h1))
-                + compute_node_id_internal((1 << d1) | (n & ((1 << d1) - 1)), d2, h2)
+// The integer type the index nodes store slots and counts in. The `index32` feature picks
+// `u32`, which halves the nodes on 64 bit targets and caps the PMA at 2^32 slots.
+#[cfg(feature = "index32")]
+type NodeIndex = u32;
+#[cfg(not(feature = "index32"))]
+type NodeIndex = usize;
+
+#[cfg(feature = "index32")]
+#[inline]
+fn to_node_index(i: usize) -> NodeIndex {
+    NodeIndex::try_from(i).expect("The index32 feature caps the PMA at 2^32 slots.")
+}
+
+#[cfg(feature = "index32")]
+#[inline]
+fn from_node_index(i: NodeIndex) -> usize {
+    i as usize
+}
+
+#[cfg(not(feature = "index32"))]
+#[inline]
+fn to_node_index(i: usize) -> NodeIndex {
+    i
+}
+
+#[cfg(not(feature = "index32"))]
+#[inline]
+fn from_node_index(i: NodeIndex) -> usize {
+    i
+}
+
+// Bounds the key and value types need for rebuilding the index: `Send + Sync` with the `rayon`
+// feature, which rebuilds on the rayon thread pool, and nothing otherwise.
+#[cfg(feature = "rayon")]
+pub trait ParallelBounds: Send + Sync {}
+#[cfg(feature = "rayon")]
+impl<T: Send + Sync> ParallelBounds for T {}
+#[cfg(not(feature = "rayon"))]
+pub trait ParallelBounds {}
+#[cfg(not(feature = "rayon"))]
+impl<T> ParallelBounds for T {}
+
+// `extend` and `remove_many` handle a batch entry by entry while the map holds more than this
+// many entries per batch entry, and in one pass over the PMA otherwise.
+const BATCH_MERGE_RATIO: usize = 8;
+
+// Trees at most this high are filled by a single thread.
+#[cfg(feature = "rayon")]
+const PARALLEL_FILL_MIN_HEIGHT: usize = 12;
+
+// Fills a complete tree of `height` laid out in vEB order. With `leaf_level`, `bottom` holds
+// the tree's 2^(height - 1) leaves; otherwise the tree is the top part of a larger one and
+// `bottom` holds the 2^height subtree roots hanging below its last level.
+// The bottom subtrees of the vEB split occupy disjoint chunks, so they are filled in parallel.
+#[cfg(feature = "rayon")]
+fn fill_veb_tree(nodes: &mut [Node], height: usize, bottom: &[Node], leaf_level: bool) {
+    if height <= PARALLEL_FILL_MIN_HEIGHT {
+        let first_bottom_id = 1usize << (height - 1);
+        for id in (1usize..(1 << height)).rev() {
+            nodes[compute_node_id(id, height) - 1] = if id < first_bottom_id {
+                Node::parent_of(
+                    &nodes[compute_node_id(id << 1, height) - 1],
+                    &nodes[compute_node_id((id << 1) | 1, height) - 1],
+                )
+            } else if leaf_level {
+                bottom[id - first_bottom_id]
+            } else {
+                let child = (id - first_bottom_id) << 1;
+                Node::parent_of(&bottom[child], &bottom[child + 1])
+            };
         }
+        return;
     }
+    // Same split as the vEB layout of `layout::compute_node_id`.
+    let bottom_height = ((height + 1) >> 1).next_power_of_two();
+    let top_height = height - bottom_height;
+    let bottom_tree_size = (1usize << bottom_height) - 1;
+    let bottom_per_tree = if leaf_level {
+        1 << (bottom_height - 1)
+    } else {
+        1 << bottom_height
+    };
+    let (top, bottom_trees) = nodes.split_at_mut((1 << top_height) - 1);
+    bottom_trees
+        .par_chunks_mut(bottom_tree_size)
+        .zip(bottom.par_chunks(bottom_per_tree))
+        .for_each(|(tree, bottom)| fill_veb_tree(tree, bottom_height, bottom, leaf_level));
+    let roots: Vec<Node> = bottom_trees
+        .chunks(bottom_tree_size)
+        .map(|tree| tree[0])
+        .collect();
+    fill_veb_tree(top, top_height, &roots, false);
 }
 
-fn compute_node_id(n: usize, height: usize) -> usize {
-    if height < 3 {
-        n
-    } else {
-        let mut log2 = 1usize;
-        while (n >> log2) > 0 {
-            log2 += 1;
-        }
-        compute_node_id_internal(n, log2, height)
+// Fills a complete tree of `height` laid out in BFS order from its leaves, a level at a time
+// from the bottom up, the nodes of a level in parallel.
+#[cfg(feature = "rayon")]
+fn fill_bfs_tree(nodes: &mut [Node], height: usize, leaves: &[Node]) {
+    nodes[(1 << (height - 1)) - 1..].copy_from_slice(leaves);
+    for level in (0..height - 1).rev() {
+        let (upper, lower) = nodes.split_at_mut((1 << (level + 1)) - 1);
+        upper[(1 << level) - 1..]
+            .par_iter_mut()
+            .zip(lower[..1 << (level + 1)].par_chunks(2))
+            .for_each(|(node, children)| *node = Node::parent_of(&children[0], &children[1]));
     }
 }
 
-impl<K> Node<K>
-where
-    K: Clone + Ord,
-{
+// A hint to bring the cache line of `item` closer, ignored where no hint instruction exists.
+#[inline(always)]
+fn prefetch_read<T>(item: &T) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::x86_64::_mm_prefetch::<{ std::arch::x86_64::_MM_HINT_T0 }>(
+            item as *const T as *const i8,
+        );
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = item;
+}
+
+impl Node {
+    #[inline]
+    fn leaf(slot: Option<usize>) -> Node {
+        Node::Leaf(LeafType {
+            slot: slot.map(to_node_index),
+        })
+    }
+
+    #[inline]
+    fn branch(slot: Option<usize>, count: usize) -> Node {
+        Node::Branch(BranchType {
+            slot: slot.map(to_node_index),
+            count: to_node_index(count),
+        })
+    }
+
+    #[inline]
+    fn slot(&self) -> Option<usize> {
+        let slot = match self {
+            Node::Branch(branch) => branch.slot,
+            Node::Leaf(leaf) => leaf.slot,
+        };
+        slot.map(from_node_index)
+    }
+
     #[inline]
-    fn get_key(&self) -> Option<&K> {
+    fn count(&self) -> usize {
         match self {
-            Node::Branch(branch) => branch.key.as_ref(),
-            Node::Leaf(leaf) => leaf.key.as_ref(),
+            Node::Branch(branch) => from_node_index(branch.count),
+            Node::Leaf(leaf) => leaf.slot.is_some() as usize,
         }
     }
 
+    // The branch above two children: the slot of the maximum key and the summed counts.
+    #[inline]
+    fn parent_of(left: &Node, right: &Node) -> Node {
+        Node::branch(right.slot().or(left.slot()), left.count() + right.count())
+    }
+
     #[inline]
-    // Set the key for the leaf node.
-    // Returns whether the key changed.
-    fn set_leaf_key(&mut self, input_key: Option<K>) -> bool {
-        let mut key_changed = true;
+    // Set the slot for the leaf node, `None` when the PMA slot is empty.
+    // Returns whether the slot changed. A leaf always points at its own slot, so only the
+    // occupancy matters: a different key moving into an occupied slot changes nothing above.
+    fn set_leaf_slot(&mut self, input_slot: Option<usize>) -> Result<bool, CoBTreeError> {
         match self {
-            Node::Branch(_) => panic!("Should only call this for leaf nodes."),
+            Node::Branch(_) => Err(CoBTreeError::NodeKind),
             Node::Leaf(leaf) => {
-                if let Some(ref leaf_key) = leaf.key {
-                    if let Some(ref key) = input_key {
-                        key_changed = !key.eq(leaf_key);
-                    }
-                } else if input_key.is_none() {
-                    // Both are None.
-                    return false;
-                }
-                *self = Node::Leaf(LeafType { key: input_key });
+                let input_slot = input_slot.map(to_node_index);
+                let changed = leaf.slot != input_slot;
+                leaf.slot = input_slot;
+                Ok(changed)
             }
         }
-        key_changed
     }
 }
 
@@ -86,540 +236,4372 @@ where
 // https://erikdemaine.org/papers/CacheObliviousBTrees_SICOMP/paper.pdf
 // This is the cache oblivious version since by using this logic and if we put the tree nodes
 // into an array using the specific order, we may reduce the number of memory loading.
-pub struct BTreeMap<K: Ord + Clone, V: Clone> {
+pub struct BTreeMap<K: Ord, V> {
+    // Declared first so the index regions are unlocked before the nodes are freed.
+    #[cfg(all(unix, feature = "mlock"))]
+    pinned: PinnedRanges<K>,
     height: usize,
-    nodes: Vec<Node<K>>,
+    nodes: Slots<Node>,
+    // The order of `nodes`.
+    layout: IndexLayout,
     pma: PackedMemoryArray<K, V>,
     size: usize,
+    // Bumped by every mutation, see `version`.
+    version: u64,
+    // Keys hidden by `mark_removed` but still stored in the PMA.
+    marked: BTreeSet<K>,
+    // Work list of `populate_changes`, kept to avoid an allocation per update.
+    changed_nodes: Vec<usize>,
+    // Values waiting for `drain_deferred`, `None` while drops are not deferred.
+    deferred: Option<Vec<V>>,
+    // Subtree aggregates kept by `enable_aggregate`.
+    aggregates: Option<Box<dyn AggregateLayer<K, V>>>,
+    // The PMA segments written since the last snapshot, one bit each.
+    changed_segments: Vec<u64>,
+    // The pages of the last snapshot, shared with the next one where nothing changed.
+    page_cache: PageCache<K, V>,
+    #[cfg(feature = "cache-sim")]
+    // Behind a mutex rather than a `RefCell` so a simulated map stays `Sync`.
+    cache_sim: Mutex<Option<CacheSimulator>>,
+    #[cfg(all(target_os = "linux", feature = "numa"))]
+    numa_policy: Option<NumaPolicy>,
 }
 
 impl<K, V> Default for BTreeMap<K, V>
 where
-    K: Ord + Clone,
-    V: Clone,
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
 {
     fn default() -> Self {
         Self::new()
     }
 }
 
+// Prints the visible entries in key order, like the std maps.
+impl<K, V> std::fmt::Debug for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds + std::fmt::Debug,
+    V: ParallelBounds + std::fmt::Debug,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// Equality and hashing look at the visible entries in key order only, not at the layout, so
+// maps with the same content compare equal whatever their densities.
+impl<K, V> PartialEq for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds + PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<K, V> Eq for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds + Eq,
+{
+}
+
+// Lexicographic order of the entry sequences, as for the std maps.
+impl<K, V> PartialOrd for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds + PartialOrd,
+{
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.iter().partial_cmp(other.iter())
+    }
+}
+
+impl<K, V> Ord for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds + Ord,
+{
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl<K, V> std::hash::Hash for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds + std::hash::Hash,
+    V: ParallelBounds + std::hash::Hash,
+{
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        for entry in self.iter() {
+            entry.hash(state);
+        }
+    }
+}
+
 impl<K, V> BTreeMap<K, V>
 where
-    K: Ord + Clone,
-    V: Clone,
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
 {
     pub fn new() -> Self {
         Self {
             height: 1,
-            nodes: vec![Node::Leaf(LeafType { key: None })],
+            nodes: Slots::Heap(vec![Node::leaf(None)]),
+            layout: IndexLayout::default(),
             pma: PackedMemoryArray::new(),
             size: 0,
+            version: 0,
+            marked: BTreeSet::new(),
+            changed_nodes: vec![],
+            deferred: None,
+            aggregates: None,
+            changed_segments: vec![],
+            page_cache: PageCache::default(),
+            #[cfg(feature = "cache-sim")]
+            cache_sim: Mutex::new(None),
+            #[cfg(all(unix, feature = "mlock"))]
+            pinned: PinnedRanges::default(),
+            #[cfg(all(target_os = "linux", feature = "numa"))]
+            numa_policy: None,
         }
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.size == 0
+    // Creates an empty map storing its index nodes in the given order.
+    pub fn with_layout(layout: IndexLayout) -> Self {
+        let mut map = Self::new();
+        map.layout = layout;
+        map
     }
 
-    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        let (old_value, changed_range) = self.pma.insert(self.find_index(&key), (key, value));
-        if old_value.is_none() {
-            self.size += 1;
-        }
-        match changed_range {
-            Some((from, to)) => self.populate_changes(from, to),
-            None => self.rebuild(),
-        }
-        old_value
+    pub fn layout(&self) -> IndexLayout {
+        self.layout
     }
 
-    pub fn remove(&mut self, key: &K) -> Option<V> {
-        let index = self.find_index(key);
-        if index >= self.pma.data_len() {
-            None
-        } else {
-            let first_leaf_id = 1usize << (self.height - 1);
-            match self.nodes[self.compute_node_index(first_leaf_id + index)].get_key() {
-                Some(k) => {
-                    if !k.eq(key) {
-                        return None;
-                    }
-                }
-                None => return None,
-            }
-            let (old_value, changed_range) = self.pma.remove(index);
-            if old_value.is_some() {
-                self.size -= 1;
-                match changed_range {
-                    Some((from, to)) => self.populate_changes(from, to),
-                    None => self.rebuild(),
-                }
-            }
-            old_value
+    // Stores the index nodes in another order, rebuilding the index once.
+    pub fn set_layout(&mut self, layout: IndexLayout) {
+        if self.layout != layout {
+            self.layout = layout;
+            self.rebuild();
         }
     }
 
-    pub fn get_top_k_key_values(&self, k: usize) -> Vec<(&K, &V)> {
-        self.pma
-            .get_key_values()
-            .iter()
-            .filter_map(|kv| kv.as_ref())
-            .map(|kv| (&kv.0, &kv.1))
-            .take(k)
-            .collect()
+    // Switches the adaptive PMA layout on or off, see `PackedMemoryArray::set_adaptive`. Maps
+    // fed runs of increasing or decreasing keys, like log timestamps, move fewer entries with
+    // it on.
+    pub fn set_adaptive(&mut self, adaptive: bool) {
+        self.pma.set_adaptive(adaptive);
     }
 
-    pub fn len(&self) -> usize {
-        self.size
+    pub fn is_adaptive(&self) -> bool {
+        self.pma.is_adaptive()
     }
 
-    pub fn clear(&mut self) {
-        *self = Self::new();
+    // Starts counting the work behind updates from zero, see `Stats`. Counting costs a branch
+    // per moved entry and per written index node.
+    pub fn enable_stats(&mut self) {
+        self.pma.set_stats(Some(Stats::default()));
     }
 
-    pub fn key_vec(&self) -> Vec<&K> {
-        self.pma
-            .get_key_values()
-            .iter()
-            .filter_map(|kv| kv.as_ref())
-            .map(|kv| &kv.0)
-            .collect::<Vec<&K>>()
+    // Stops counting, returning the final counts.
+    pub fn disable_stats(&mut self) -> Option<Stats> {
+        let stats = self.pma.stats();
+        self.pma.set_stats(None);
+        stats
     }
 
-    pub fn value_vec(&self) -> Vec<&V> {
-        self.pma
-            .get_key_values()
-            .iter()
-            .filter_map(|kv| kv.as_ref())
-            .map(|kv| &kv.1)
-            .collect::<Vec<&V>>()
+    // The counts since `enable_stats` or the last `reset_stats`, `None` while not counting.
+    pub fn stats(&self) -> Option<Stats> {
+        self.pma.stats()
     }
 
-    pub fn get_first_key(&self) -> Option<&K> {
-        self.pma
-            .get_key_values()
-            .iter()
-            .filter_map(|kv| kv.as_ref())
-            .map(|kv| &kv.0)
-            .next()
+    pub fn reset_stats(&mut self) {
+        if self.pma.stats().is_some() {
+            self.pma.set_stats(Some(Stats::default()));
+        }
     }
 
-    pub fn get(&self, key: &K) -> Option<&V> {
-        let index = self.find_index(key);
-        if index >= self.pma.data_len() {
-            return None;
+    // Creates an empty map laid out for `capacity` entries, so filling it up to there never
+    // doubles the PMA nor rebuilds the index.
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut map = Self::new();
+        map.reserve(capacity);
+        map
+    }
+
+    // Grows the layout in one step so `additional` more entries fit without doubling. The
+    // stored entries are spread over the larger PMA and the index is rebuilt once. Removals
+    // may shrink the layout again, like they do after growth by inserts.
+    pub fn reserve(&mut self, additional: usize) {
+        if self.pma.reserve(self.size + self.marked.len() + additional) {
+            self.rebuild();
         }
-        match &self.pma.get_key_values()[index] {
-            None => None,
-            Some((k, v)) => {
-                if key.eq(k) {
-                    Some(v)
-                } else {
-                    None
-                }
-            }
+    }
+
+    // Same as `reserve`, reporting a failed allocation instead of aborting, as the doubled
+    // buffers of a large map can be big. The map is left unchanged on error.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        let count = (self.size + self.marked.len())
+            .checked_add(additional)
+            .filter(|count| *count <= usize::MAX >> 3)
+            .ok_or_else(|| Vec::<u8>::new().try_reserve(usize::MAX).unwrap_err())?;
+        let len = PackedMemoryArray::<K, V>::layout_len(count);
+        if len <= self.pma.data_len() {
+            return Ok(());
+        }
+        if len - 1 > from_node_index(NodeIndex::MAX) {
+            return Err(Vec::<u8>::new().try_reserve(usize::MAX).unwrap_err());
         }
+        self.release_pins();
+        let reserved = self
+            .nodes
+            .try_reserve_exact((len << 1) - self.nodes.len())
+            .and_then(|_| self.pma.try_reserve(count));
+        // A failure may still have moved the buffers it did reserve.
+        match reserved {
+            Ok(true) => self.rebuild(),
+            _ => self.repin(),
+        }
+        reserved.map(|_| ())
     }
 
-    pub fn get_all_key_values(&self) -> Vec<(&K, &V)> {
-        self.pma
-            .get_key_values()
-            .iter()
-            .filter_map(|kv| kv.as_ref())
-            .map(|kv| (&kv.0, &kv.1))
-            .collect()
+    // Creates an empty map keeping its PMA key slots in a shared mapping of the file at `path`
+    // and its value slots in one of `path` with `.values` appended, both created or truncated.
+    // The files grow and shrink with the PMA, so maps larger than RAM keep the same layout with
+    // the OS paging in the blocks a search touches. The index stays on the heap. Only the
+    // storage is file backed, the content is not meant to be opened again. Zero sized values,
+    // as in a set, take no storage and get no file.
+    #[cfg(all(unix, feature = "mmap"))]
+    pub fn with_mmap<P: AsRef<std::path::Path>>(path: P) -> std::io::Result<Self> {
+        let open = |path: &std::path::Path| {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)
+        };
+        let values_file = if std::mem::size_of::<V>() > 0 {
+            let mut values_path = path.as_ref().as_os_str().to_owned();
+            values_path.push(".values");
+            Some(open(values_path.as_ref())?)
+        } else {
+            None
+        };
+        let mut map = Self::new();
+        map.pma.map_slots(open(path.as_ref())?, values_file)?;
+        Ok(map)
     }
 
-    fn rebuild(&mut self) {
-        self.nodes.resize(
-            self.pma.data_len() << 1,
-            Node::Branch(BranchType { key: None }),
-        );
-        self.height = (self.pma.data_len().trailing_zeros() + 1) as usize;
-        let first_leaf_id = 1usize << (self.height - 1);
-        for i in 1usize..(1 << self.height) {
-            let index = self.compute_node_index(i);
-            self.nodes[index] = if i < first_leaf_id {
-                Node::Branch(BranchType { key: None })
-            } else {
-                Node::Leaf(LeafType { key: None })
-            };
-        }
-        self.populate_changes(0, self.pma.data_len());
+    // Creates an empty map taking its PMA slots and index nodes from the arena, for short lived
+    // maps built, queried and dropped as a unit. Growing leaves the old buffers in the arena
+    // and shrinking gives nothing back, so the arena holds up to about twice the largest size
+    // the map reached until it is dropped together with every map in it. The occupancy and
+    // bookkeeping vectors, a small fraction of the slots, stay on the heap, as do the maps
+    // made from this one, like a `compact_clone`.
+    #[cfg(feature = "arena")]
+    pub fn new_in(arena: &crate::Arena) -> Self {
+        let mut map = Self::new();
+        map.pma.move_to_arena(arena);
+        map.nodes = std::mem::replace(&mut map.nodes, Slots::Heap(vec![])).into_arena(arena);
+        map
     }
 
-    fn find_index(&self, key: &K) -> usize {
-        let mut node_id = 1usize;
-        let mut node_index = self.compute_node_index(node_id);
-        let mut leaf_index = 0usize;
-        while let Node::Branch(_) = &self.nodes[node_index] {
-            leaf_index <<= 1;
-            node_id <<= 1;
-            node_index = self.compute_node_index(node_id);
-            match self.nodes[node_index].get_key() {
-                Some(k) => {
-                    if k.lt(key) {
-                        leaf_index |= 1;
-                        node_id |= 1;
-                    }
-                }
-                None => {
-                    leaf_index |= 1;
-                    node_id |= 1;
+    // Creates an empty map whose slots and index nodes are placed by the policy. The policy is
+    // applied again to the new buffers every time the map resizes.
+    #[cfg(all(target_os = "linux", feature = "numa"))]
+    pub fn with_numa_policy(policy: NumaPolicy) -> std::io::Result<Self> {
+        let mut map = Self::new();
+        map.numa_policy = Some(policy);
+        map.try_place_buffers()?;
+        Ok(map)
+    }
+
+    #[cfg(all(target_os = "linux", feature = "numa"))]
+    pub fn numa_policy(&self) -> Option<&NumaPolicy> {
+        self.numa_policy.as_ref()
+    }
+
+    // Reports on which nodes the pages of the slots and of the index currently are.
+    #[cfg(all(target_os = "linux", feature = "numa"))]
+    pub fn numa_placement(&self) -> std::io::Result<NumaPlacement> {
+        let mut slot_pages = vec![];
+        for (address, len) in self.pma.buffers(0, self.pma.data_len()) {
+            for (node, pages) in numa::placement(address, len)?.into_iter().enumerate() {
+                if slot_pages.len() <= node {
+                    slot_pages.resize(node + 1, 0);
                 }
-            };
-        }
-        node_index = self.compute_node_index(node_id);
-        if let Some(k) = self.nodes[node_index].get_key() {
-            if k.lt(key) {
-                leaf_index += 1;
+                slot_pages[node] += pages;
             }
         }
-        leaf_index
+        Ok(NumaPlacement {
+            slot_pages,
+            index_pages: numa::placement(
+                self.nodes.as_ptr() as usize,
+                std::mem::size_of_val(&self.nodes[..]),
+            )?,
+        })
     }
 
-    // Populated the changed leaves to root.
-    fn populate_changes(&mut self, from: usize, to: usize) {
-        let first_leaf_id = 1usize << (self.height - 1);
-        let key_values = self.pma.get_key_values();
-        let mut changed_nodes = Vec::with_capacity(self.nodes.len());
-        for (i, key_value) in key_values.iter().enumerate().take(to).skip(from) {
-            let leaf_id = first_leaf_id + i;
-            let leaf_index = self.compute_node_index(leaf_id);
-            let leaf = &mut self.nodes[leaf_index];
-            if leaf.set_leaf_key(key_value.as_ref().map(|kv| kv.0.to_owned()))
-                && leaf_id > 1
-                && (changed_nodes.is_empty() || changed_nodes.last().unwrap() != &(leaf_id >> 1))
-            {
-                changed_nodes.push(leaf_id >> 1);
-            }
+    #[cfg(all(target_os = "linux", feature = "numa"))]
+    fn try_place_buffers(&self) -> std::io::Result<()> {
+        let Some(policy) = &self.numa_policy else {
+            return Ok(());
+        };
+        for (address, len) in self.pma.buffers(0, self.pma.data_len()) {
+            numa::bind(address, len, policy)?;
         }
+        numa::bind(
+            self.nodes.as_ptr() as usize,
+            std::mem::size_of_val(&self.nodes[..]),
+            policy,
+        )
+    }
 
-        let mut i = 0;
-        while i < changed_nodes.len() {
-            let changed_node_id = changed_nodes[i];
-            let changed_node_index = self.compute_node_index(changed_node_id);
-            let changed_node = &mut self.nodes[changed_node_index];
-            match changed_node {
-                Node::Branch(_) => {
-                    if self.set_branch_key(
-                        changed_node_index,
-                        self.compute_node_index(changed_node_id << 1),
-                        self.compute_node_index((changed_node_id << 1) | 1),
-                    ) && changed_node_id > 1
-                        && changed_nodes.last().unwrap() != &(changed_node_id >> 1)
-                    {
-                        changed_nodes.push(changed_node_id >> 1);
-                    }
-                }
-                Node::Leaf(_) => panic!("Should not reach here"),
-            }
-            i += 1;
-        }
+    // Placement after a resize is best effort, `numa_placement` shows where the pages ended up.
+    #[cfg(all(target_os = "linux", feature = "numa"))]
+    fn place_buffers(&self) {
+        let _ = self.try_place_buffers();
     }
 
-    fn compute_node_index(&self, x: usize) -> usize {
-        compute_node_id(x, self.height) - 1
+    #[cfg(not(all(target_os = "linux", feature = "numa")))]
+    #[inline(always)]
+    fn place_buffers(&self) {}
+
+    // Starts feeding the memory accessed by index descents, slot reads and index updates into
+    // the simulator, replacing any simulator already running.
+    #[cfg(feature = "cache-sim")]
+    pub fn start_cache_simulation(&mut self, simulator: CacheSimulator) {
+        *self.cache_sim.get_mut().unwrap_or_else(|e| e.into_inner()) = Some(simulator);
     }
 
-    #[inline]
-    // Set the key for this node as the maximum key of the left and right children.
-    // Return whether the key is changed or not.
-    fn set_branch_key(&mut self, node_index: usize, left_index: usize, right_index: usize) -> bool {
-        if let Node::Branch(branch) = &self.nodes[node_index] {
-            let right_key = self.nodes[right_index].get_key();
-            let input_key = if right_key.is_none() {
-                self.nodes[left_index].get_key()
-            } else {
-                right_key
-            };
+    // Stops recording and hands back the simulator with the collected statistics.
+    #[cfg(feature = "cache-sim")]
+    pub fn stop_cache_simulation(&mut self) -> Option<CacheSimulator> {
+        self.cache_sim
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take()
+    }
 
-            match &branch.key {
-                Some(k) => match input_key {
-                    Some(ik) => {
-                        let changed = !k.eq(ik);
-                        self.nodes[node_index] = Node::Branch(BranchType {
-                            key: Some(ik.to_owned()),
-                        });
-                        changed
-                    }
-                    None => {
-                        self.nodes[node_index] = Node::Branch(BranchType { key: None });
-                        true
-                    }
-                },
-
-                None => match input_key {
-                    Some(ik) => {
-                        self.nodes[node_index] = Node::Branch(BranchType {
-                            key: Some(ik.to_owned()),
-                        });
-                        true
-                    }
-                    None => false,
-                },
-            }
-        } else {
-            panic!("Should only set key for branch node.");
+    #[cfg(feature = "cache-sim")]
+    #[inline]
+    fn record_access<T>(&self, item: &T) {
+        let mut simulator = self.cache_sim.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(simulator) = simulator.as_mut() {
+            simulator.access(item as *const T as usize, std::mem::size_of::<T>());
         }
     }
-}
 
-#[cfg(test)]
-mod btree_map {
-    use crate::cache_oblivious::{compute_node_id_internal, BTreeMap};
-    use float_ord::FloatOrd;
-    use rand::{seq::SliceRandom, thread_rng};
+    #[cfg(not(feature = "cache-sim"))]
+    #[inline(always)]
+    fn record_access<T>(&self, _item: &T) {}
 
-    // The excatly tree was shown by the paper.
-    // https://ibb.co/BtmrpDz
-    #[test]
-    fn test_node_ids() {
-        let answer = vec![
-            1usize, 2, 17, 3, 4, 18, 19, 5, 8, 11, 14, 20, 23, 26, 29, 6, 7, 9, 10, 12, 13, 15, 16,
-            21, 22, 24, 25, 27, 28, 30, 31,
-        ];
-        for (n, &id) in answer.iter().enumerate() {
-            let mut log2 = 1usize;
-            let d = n + 1;
-            while (d >> log2) > 0 {
-                log2 += 1;
-            }
-            assert_eq!(compute_node_id_internal(d, log2, 5), id);
+    // Brackets a lookup or an insert for the line counts of the simulator, see
+    // `CacheSimulator::count_lines`.
+    #[cfg(feature = "cache-sim")]
+    fn begin_operation(&self) {
+        let mut simulator = self.cache_sim.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(simulator) = simulator.as_mut() {
+            simulator.begin_operation();
         }
     }
 
-    #[test]
-    fn test_other_keys() {
-        let mut map = BTreeMap::<FloatOrd<f32>, usize>::new();
-        for i in 1000..1128 {
-            assert_eq!(map.insert(FloatOrd(i as f32), i), None);
-            assert_eq!(map.len(), i - 999);
+    #[cfg(feature = "cache-sim")]
+    fn end_operation(&self, operation: CacheOperation) {
+        let mut simulator = self.cache_sim.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(simulator) = simulator.as_mut() {
+            simulator.end_operation(operation);
         }
+    }
 
-        for i in 1000..1128 {
-            assert_eq!(map.remove(&FloatOrd(i as f32)), Some(i));
-            assert_eq!(map.len(), 127);
-            assert_eq!(map.insert(FloatOrd((i + 128) as f32), i + 128), None);
-            assert_eq!(map.len(), 128);
-        }
+    // Locks in memory the PMA slots covering the key range and the upper levels of the index
+    // (the top tree of the vEB split, a prefix of the nodes in either layout), so lookups in
+    // the range do not page fault after memory pressure. The range stays pinned through
+    // resizes: they unlock the old buffers before moving them and lock the range in the new
+    // ones. Between resizes the pinned slots stay put, so rebalances may move entries at the
+    // edges of the range next to them. Pins are released by `unpin_all` or when the map drops.
+    #[cfg(all(unix, feature = "mlock"))]
+    pub fn pin_range<R: RangeBounds<K>>(&mut self, range: R) -> std::io::Result<()>
+    where
+        K: Clone,
+    {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.pin_key_range(&range)?;
+        self.pinned.ranges.push(range);
+        Ok(())
     }
 
-    #[test]
-    fn test_opertions() {
-        let mut map = BTreeMap::<usize, usize>::new();
-        assert_eq!(map.len(), 0);
-        assert_eq!(map.get_all_key_values(), []);
+    #[cfg(all(unix, feature = "mlock"))]
+    fn pin_key_range(&mut self, (start, end): &(Bound<K>, Bound<K>)) -> std::io::Result<()> {
+        let from = self.lower_bound_index(start.as_ref());
+        let to = self.upper_bound_index(end.as_ref()).max(from);
+        let top_height = if self.height < 3 {
+            self.height
+        } else {
+            self.height - ((self.height + 1) >> 1).next_power_of_two()
+        };
+        let top = &self.nodes[..(1 << top_height) - 1];
+        let region = pinning::pin(top.as_ptr() as usize, std::mem::size_of_val(top))?;
+        self.pinned.index.0.push(region);
+        self.pma.pin_slots(from, to)
+    }
 
-        assert_eq!(map.insert(1, 11), None);
-        assert_eq!(map.len(), 1);
-        assert_eq!(map.get_all_key_values(), [(&1, &11)]);
+    // Whether the slots of the key range lie in the pinned regions.
+    #[cfg(all(test, unix, feature = "mlock"))]
+    pub(crate) fn is_range_pinned<R: RangeBounds<K>>(&self, range: R) -> bool {
+        let from = self.lower_bound_index(range.start_bound());
+        let to = self.upper_bound_index(range.end_bound()).max(from);
+        self.pma.are_slots_pinned(from, to)
+    }
 
-        assert_eq!(map.insert(3, 33), None);
-        assert_eq!(map.len(), 2);
-        assert_eq!(map.get_all_key_values(), [(&1, &11), (&3, &33)]);
+    // Number of memory regions currently pinned by `pin_range`.
+    #[cfg(all(unix, feature = "mlock"))]
+    pub fn pinned_regions(&self) -> usize {
+        self.pinned.index.0.len() + self.pma.pinned_slot_regions()
+    }
 
-        assert_eq!(map.insert(0, 0), None);
-        assert_eq!(map.len(), 3);
-        assert_eq!(map.get_all_key_values(), [(&0, &0), (&1, &11), (&3, &33)]);
+    #[cfg(all(unix, feature = "mlock"))]
+    pub fn unpin_all(&mut self) -> std::io::Result<()> {
+        self.pinned.ranges.clear();
+        for region in self.pinned.index.0.drain(..) {
+            pinning::unpin(region)?;
+        }
+        self.pma.unpin_slots()
+    }
 
-        assert_eq!(map.insert(5, 555), None);
-        assert_eq!(map.len(), 4);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&0, &0), (&1, &11), (&3, &33), (&5, &555)]
-        );
+    // Unlocks the pinned regions ahead of a resize of the index or the slots, while the old
+    // buffers are still allocated, keeping the ranges for `repin`.
+    #[cfg(all(unix, feature = "mlock"))]
+    fn release_pins(&mut self) {
+        for region in self.pinned.index.0.drain(..) {
+            let _ = pinning::unpin(region);
+        }
+        let _ = self.pma.unpin_slots();
+    }
 
-        assert_eq!(map.insert(2, 2222), None);
-        assert_eq!(map.len(), 5);
+    #[cfg(not(all(unix, feature = "mlock")))]
+    #[inline(always)]
+    fn release_pins(&mut self) {}
+
+    // Pins the ranges of `pin_range` again in the buffers a resize moved them to. Like the
+    // placement, this is best effort: a range that can no longer be locked, past the memlock
+    // limit say, stays unpinned until the next resize tries again.
+    #[cfg(all(unix, feature = "mlock"))]
+    fn repin(&mut self) {
+        if self.pinned.ranges.is_empty() {
+            return;
+        }
+        self.release_pins();
+        let ranges = std::mem::take(&mut self.pinned.ranges);
+        for range in &ranges {
+            let _ = self.pin_key_range(range);
+        }
+        self.pinned.ranges = ranges;
+    }
+
+    #[cfg(not(all(unix, feature = "mlock")))]
+    #[inline(always)]
+    fn repin(&mut self) {}
+
+    // Moves every entry of `other` into `self`, leaving `other` empty. Both PMAs are merge
+    // walked in one pass and the index is rebuilt once, instead of paying the rebalances of
+    // one insert per entry. On equal keys the value from `other` wins.
+    pub fn append(&mut self, other: &mut Self) {
+        let incoming_meta = other.pma.meta_enabled();
+        let mut incoming = Vec::with_capacity(other.size);
+        for index in 0..other.pma.data_len() {
+            if let Some(kv) = other.pma.take(index) {
+                if !other.is_marked(&kv.0) {
+                    incoming.push((kv, other.pma.get_meta(index)));
+                }
+            }
+        }
+        other.clear();
+        self.merge_entries(incoming, incoming_meta);
+    }
+
+    // Inserts a batch in one merge pass: the batch is sorted, then merged into the smallest
+    // PMA window around the slots it falls between that stays within its density bound, and
+    // the index is updated once over that window. Only a batch too large for even the whole
+    // array grows the layout. When a key shows up several times, the last value wins.
+    pub fn insert_many<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let incoming = sorted_unique(iter.into_iter().collect());
+        let (Some(first), Some(last)) = (incoming.first(), incoming.last()) else {
+            return;
+        };
+        let (lo, hi) = (self.find_index(&first.0), self.find_index(&last.0));
+        let marked = &mut self.marked;
+        let mut revived = 0;
+        let merged = self.pma.merge_window(lo, hi, incoming, |k| {
+            if !marked.is_empty() && marked.remove(k) {
+                revived += 1;
+            }
+        });
+        match merged {
+            Ok((from, to, added)) => {
+                self.size += added + revived;
+                self.version += 1;
+                self.populate_changes(from, to);
+            }
+            Err(incoming) => {
+                self.merge_entries(incoming.into_iter().map(|kv| (kv, 0)).collect(), false)
+            }
+        }
+    }
+
+    // Merges entries sorted by unique keys, with their metadata, in one pass over the PMA.
+    fn merge_entries(&mut self, incoming: Vec<((K, V), u64)>, incoming_meta: bool) {
+        if incoming.is_empty() {
+            return;
+        }
+        let marked = &mut self.marked;
+        let mut revived = 0;
+        let added = self.pma.merge_sorted(incoming, incoming_meta, |k| {
+            if !marked.is_empty() && marked.remove(k) {
+                revived += 1;
+            }
+        });
+        self.size += added + revived;
+        self.version += 1;
+        self.rebuild();
+    }
+
+    // Builds the map from several iterators, each sorted by key, with a heap based k-way merge
+    // that lays the result straight into a packed layout. When the same key shows up in more
+    // than one source, the value from the latest source wins.
+    pub fn merge_build<I>(sources: Vec<I>) -> Self
+    where
+        I: Iterator<Item = (K, V)>,
+    {
+        let mut sources = sources;
+        let mut heap = BinaryHeap::with_capacity(sources.len());
+        for (source, iter) in sources.iter_mut().enumerate() {
+            if let Some((key, value)) = iter.next() {
+                heap.push(MergeHead { key, source, value });
+            }
+        }
+        let mut key_values: Vec<(K, V)> = Vec::new();
+        while let Some(MergeHead { key, source, value }) = heap.pop() {
+            if let Some((next_key, next_value)) = sources[source].next() {
+                assert!(next_key >= key, "Merge sources must be sorted by key.");
+                heap.push(MergeHead {
+                    key: next_key,
+                    source,
+                    value: next_value,
+                });
+            }
+            match key_values.last_mut() {
+                Some(last) if last.0 == key => last.1 = value,
+                _ => key_values.push((key, value)),
+            }
+        }
+        Self::from_sorted_vec(key_values)
+    }
+
+    // Clones the map into a freshly packed layout: the entries are spread evenly over the smallest
+    // PMA that fits them and the index is sized for that PMA, regardless of how sparse the
+    // layout of `self` became after removals.
+    pub fn compact_clone(&self) -> Self
+    where
+        K: Clone,
+        V: Clone,
+    {
+        if !self.pma.meta_enabled() {
+            return Self::from_sorted_vec(
+                self.live_key_values()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+            );
+        }
+        let (key_values, meta) = (0..self.pma.data_len())
+            .filter_map(|i| self.pma.key_value(i).map(|kv| (kv, self.pma.get_meta(i))))
+            .filter(|((k, _), _)| !self.is_marked(k))
+            .map(|((k, v), meta)| ((k.clone(), v.clone()), meta))
+            .unzip::<_, _, Vec<(K, V)>, Vec<u64>>();
+        let mut map = Self::new();
+        map.size = key_values.len();
+        map.pma = PackedMemoryArray::from_sorted_with_meta(key_values, meta);
+        map.pma.enable_meta();
+        map.rebuild();
+        map
+    }
+
+    // Builds the map in O(n) from entries sorted by strictly increasing keys: they are spread
+    // over a right-sized PMA at the target density and the index is filled in one pass.
+    // Panics when the keys are not strictly increasing.
+    pub fn from_sorted_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let key_values = iter.into_iter().collect::<Vec<_>>();
+        assert!(
+            key_values.windows(2).all(|w| w[0].0 < w[1].0),
+            "Keys must be sorted and unique."
+        );
+        Self::from_sorted_vec(key_values)
+    }
+
+    // Key values must be sorted by unique keys.
+    fn from_sorted_vec(key_values: Vec<(K, V)>) -> Self {
+        let mut map = Self::new();
+        map.size = key_values.len();
+        map.pma = PackedMemoryArray::from_sorted(key_values);
+        map.rebuild();
+        map
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.size == 0
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        #[cfg(feature = "cache-sim")]
+        self.begin_operation();
+        let index = self.find_index(&key);
+        let old_value = self.insert_at(index, key, value);
+        #[cfg(feature = "cache-sim")]
+        self.end_operation(CacheOperation::Insert);
+        old_value
+    }
+
+    // Looks the key up once and hands out its slot for reading and updating in place, or for
+    // inserting without a second descent.
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V> {
+        let index = self.find_index(&key);
+        match self.pma.key(index) {
+            Some(k) if *k == key && !self.is_marked(k) => {
+                Entry::Occupied(OccupiedEntry::new(self, index))
+            }
+            _ => Entry::Vacant(VacantEntry::new(self, key, index)),
+        }
+    }
+
+    // Inserts only when the key is absent, handing back the occupied entry and the value
+    // otherwise. One descent either way, and the key is never cloned.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<&mut V, OccupiedError<'_, K, V>> {
+        match self.entry(key) {
+            Entry::Occupied(entry) => Err(OccupiedError { entry, value }),
+            Entry::Vacant(entry) => Ok(entry.insert(value)),
+        }
+    }
+
+    // Inserts like `insert`, reporting a failed allocation or a broken layout instead of
+    // panicking. The room for the entry is reserved up front, so a failed allocation leaves the
+    // map unchanged. The other errors mean the map is corrupt and should be dropped.
+    pub fn try_insert_raw(&mut self, key: K, value: V) -> Result<Option<V>, CoBTreeError> {
+        self.try_reserve(1)?;
+        let index = self.find_index(&key);
+        self.try_insert_at(index, key, value)
+    }
+
+    // Removes like `remove`, reporting a broken layout instead of panicking.
+    pub fn try_remove_raw<Q: Comparable<K> + ?Sized>(
+        &mut self,
+        key: &Q,
+    ) -> Result<Option<V>, CoBTreeError> {
+        let index = self.find_index(key);
+        let unmarked = match self.pma.key(index) {
+            Some(k) if key.equivalent(k) => !self.marked.is_empty() && self.marked.remove(k),
+            _ => return Ok(None),
+        };
+        self.try_remove_at(index, unmarked)
+    }
+
+    // Inserts at `index`, the slot `find_index` picked for the key.
+    fn insert_at(&mut self, index: usize, key: K, value: V) -> Option<V> {
+        self.try_insert_at(index, key, value)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn try_insert_at(&mut self, index: usize, key: K, value: V) -> Result<Option<V>, CoBTreeError> {
+        self.version += 1;
+        let unmarked = self.is_marked(&key) && self.marked.remove(&key);
+        let (mut old_value, changed_range) = self.pma.try_insert_at(index, (key, value))?;
+        // The value got replaced in place, which moves no slot.
+        let replaced = old_value.is_some();
+        if unmarked {
+            old_value = None;
+        }
+        if old_value.is_none() {
+            self.size += 1;
+        }
+        match changed_range {
+            Some((from, to)) => self.try_populate_changes(from, to)?,
+            None => self.rebuild(),
+        }
+        if replaced {
+            self.slots_changed(index, index + 1);
+        }
+        Ok(old_value)
+    }
+
+    pub fn remove<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> Option<V> {
+        let index = self.find_index(key);
+        if index >= self.pma.data_len() {
+            None
+        } else {
+            // A hidden entry is dropped for good but was already gone for readers.
+            let unmarked = match self.pma.key(index) {
+                Some(k) if key.equivalent(k) => !self.marked.is_empty() && self.marked.remove(k),
+                _ => return None,
+            };
+            self.remove_at(index, unmarked)
+        }
+    }
+
+    // Removes the entry stored at `index`, `hidden` when it was hidden by `mark_removed` and
+    // so no longer counted nor visible.
+    fn remove_at(&mut self, index: usize, hidden: bool) -> Option<V> {
+        self.try_remove_at(index, hidden)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    fn try_remove_at(&mut self, index: usize, hidden: bool) -> Result<Option<V>, CoBTreeError> {
+        let (old_value, changed_range) = self.pma.remove_at(index);
+        if old_value.is_some() {
+            if !hidden {
+                self.size -= 1;
+            }
+            self.version += 1;
+            match changed_range {
+                Some((from, to)) => self.try_populate_changes(from, to)?,
+                None => self.rebuild(),
+            }
+        }
+        Ok(old_value.filter(|_| !hidden))
+    }
+
+    // Inserts a key that is not visible yet at `index` and returns where the entry ended up
+    // after the rebalance. A cursor placed in front of the new entry follows it.
+    pub(crate) fn insert_vacant(&mut self, index: usize, key: K, value: V) -> usize {
+        let owner = Arc::new(());
+        let cursor = self.pma.register_cursor(index, &owner);
+        self.insert_at(index, key, value);
+        let position = self.pma.cursor_position(cursor);
+        self.pma.unregister_cursor(cursor);
+        self.pma
+            .next_occupied(position, self.pma.data_len())
+            .unwrap()
+    }
+
+    pub(crate) fn remove_entry_at(&mut self, index: usize) -> V {
+        self.remove_at(index, false).unwrap()
+    }
+
+    pub(crate) fn entry_at(&self, index: usize) -> (&K, &V) {
+        self.pma.key_value(index).unwrap()
+    }
+
+    pub(crate) fn value_at_mut(&mut self, index: usize) -> &mut V {
+        self.version += 1;
+        self.touch_slots(index, index + 1);
+        self.pma.value_mut(index).unwrap()
+    }
+
+    // Runs `f` against a transaction that buffers its inserts and removes. The buffered writes
+    // are applied only when `f` returns `Ok`, as one `remove_many` and one `insert_many` batch;
+    // on `Err` or a panic the map is left exactly as it was.
+    pub fn transaction<T, E, F>(&mut self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut Transaction<'_, K, V>) -> Result<T, E>,
+    {
+        let mut txn = Transaction::new(self);
+        let result = f(&mut txn)?;
+        let (mut inserts, mut removes) = (vec![], vec![]);
+        for (key, value) in txn.into_staged() {
+            match value {
+                Some(value) => inserts.push((key, value)),
+                None => removes.push(key),
+            }
+        }
+        // A key has one staged write, so the two batches touch different keys.
+        self.remove_many(&removes);
+        self.insert_many(inserts);
+        Ok(result)
+    }
+
+    // With `enabled`, the values dropped by `discard`, `clear` and `purge_marked` are queued
+    // instead of dropped in place, so values with an expensive `Drop` do not weigh on the
+    // latency of those calls. The queue is emptied by `drain_deferred`, or handed to another
+    // thread with `take_deferred`. Disabling drains the queue.
+    pub fn defer_drops(&mut self, enabled: bool) {
+        match (enabled, self.deferred.is_some()) {
+            (true, false) => self.deferred = Some(vec![]),
+            (false, true) => self.deferred = None,
+            _ => {}
+        }
+    }
+
+    // Removes the entry like `remove` but disposes of the value instead of returning it,
+    // queueing it when drops are deferred. Returns whether a visible entry got removed.
+    pub fn discard<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> bool {
+        match self.remove(key) {
+            Some(value) => {
+                if let Some(deferred) = self.deferred.as_mut() {
+                    deferred.push(value);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Number of values waiting to be dropped.
+    pub fn deferred_len(&self) -> usize {
+        self.deferred.as_ref().map_or(0, |deferred| deferred.len())
+    }
+
+    // Drops the queued values now.
+    pub fn drain_deferred(&mut self) {
+        if let Some(deferred) = self.deferred.as_mut() {
+            deferred.clear();
+        }
+    }
+
+    // Hands the queued values over, typically to be dropped on a background thread.
+    pub fn take_deferred(&mut self) -> Vec<V> {
+        self.deferred
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn defer_drop(&mut self, dropped: Vec<(K, V)>) {
+        if let Some(deferred) = self.deferred.as_mut() {
+            deferred.extend(dropped.into_iter().map(|(_, v)| v));
+        }
+    }
+
+    // Removes and yields, in key order, the entries for which `pred` returns true. Extracted
+    // slots are just emptied while iterating; the PMA is laid out again and the index rebuilt
+    // once when the iterator is dropped, so the whole pass costs O(n) whatever the number of
+    // extracted entries. Entries not reached before the drop stay in the map.
+    pub fn extract_if<F>(&mut self, pred: F) -> ExtractIf<'_, K, V, F>
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        ExtractIf {
+            map: self,
+            index: 0,
+            pred,
+            extracted: false,
+        }
+    }
+
+    // Moves every entry out in key order and leaves the map empty, back to its single slot
+    // layout with the buffers kept for reuse. The slots are emptied in one pass, there is no
+    // rebalance per entry.
+    pub fn drain(&mut self) -> Drain<K, V> {
+        let mut entries = self.pma.retain(|_, _| false);
+        if !self.marked.is_empty() {
+            let marked = std::mem::take(&mut self.marked);
+            entries.retain(|(k, _)| !marked.contains(k));
+        }
+        self.size = 0;
+        self.version += 1;
+        self.rebuild();
+        Drain {
+            entries: entries.into_iter(),
+        }
+    }
+
+    // Hides the entry from every read while keeping it stored, until `purge_marked` drops it.
+    // Returns whether a visible entry got hidden. The hidden keys are tracked by copy, hence
+    // the `Clone` bound.
+    pub fn mark_removed<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> bool
+    where
+        K: Clone,
+    {
+        let Some(index) = self.find_entry_index(key) else {
+            return false;
+        };
+        let (key, _) = self.entry_at(index);
+        self.marked.insert(key.clone());
+        self.slots_changed(index, index + 1);
+        self.size -= 1;
+        self.version += 1;
+        true
+    }
+
+    pub fn marked_len(&self) -> usize {
+        self.marked.len()
+    }
+
+    // Renders the index and the PMA as a Graphviz graph, for looking at the layout and at
+    // what rebalances do on small maps. Branches show their position in the node array and
+    // the largest key below them, and point at their children. The PMA is one record per
+    // segment with a field per slot, and the leaves of the index are edges into those fields.
+    // Entries hidden by `mark_removed` are shown in parentheses.
+    pub fn to_dot(&self) -> String
+    where
+        K: std::fmt::Debug,
+    {
+        use std::fmt::Write as _;
+        // Record labels give a meaning to these characters.
+        let escape = |s: String| {
+            s.chars().fold(String::new(), |mut escaped, c| {
+                if "\\\"{}|<>".contains(c) {
+                    escaped.push('\\');
+                }
+                escaped.push(c);
+                escaped
+            })
+        };
+        let label = |slot: Option<usize>| match slot.and_then(|slot| self.pma.key(slot)) {
+            Some(k) if self.is_marked(k) => escape(format!("({:?})", k)),
+            Some(k) => escape(format!("{:?}", k)),
+            None => "-".to_string(),
+        };
+        let leaves = self.pma.data_len();
+        let segment_size = self.pma.segment_size();
+        let mut dot = String::from("digraph BTreeMap {\n    node [shape=record];\n");
+        for id in 1..leaves {
+            let index = self.compute_node_index(id);
+            let node = &self.nodes[index];
+            writeln!(
+                dot,
+                "    n{} [label=\"#{}|{}\"];",
+                id,
+                index,
+                label(node.slot())
+            )
+            .unwrap();
+            for child in [id << 1, (id << 1) | 1] {
+                if child < leaves {
+                    writeln!(dot, "    n{} -> n{};", id, child).unwrap();
+                } else {
+                    let slot = child - leaves;
+                    let segment = slot / segment_size;
+                    writeln!(dot, "    n{} -> s{}:p{};", id, segment, slot).unwrap();
+                }
+            }
+        }
+        dot.push_str("    subgraph cluster_pma {\n        label=\"PMA\";\n");
+        for segment in 0..leaves / segment_size {
+            let fields = (segment * segment_size..(segment + 1) * segment_size)
+                .map(|slot| format!("<p{}> {}", slot, label(Some(slot))))
+                .collect::<Vec<_>>();
+            writeln!(
+                dot,
+                "        s{} [label=\"{}\"];",
+                segment,
+                fields.join("|")
+            )
+            .unwrap();
+        }
+        dot.push_str("    }\n}\n");
+        dot
+    }
+
+    // Checks the map against what every operation keeps true and panics at the first
+    // violation: the layout of the PMA, see `PackedMemoryArray::check_invariants`, every leaf
+    // of the index pointing at its slot exactly when the slot is occupied, every branch holding
+    // the slot of the largest key below it and the number of entries below it, and the length
+    // counting the stored entries not hidden by `mark_removed`. Takes O(n) time. Debug builds
+    // check the entry count at the root after every update.
+    pub fn check_invariants(&self) {
+        self.pma.check_invariants();
+        let leaves = self.pma.data_len();
+        assert_eq!(1 << (self.height - 1), leaves, "The index height is stale.");
+        for id in (1..leaves << 1).rev() {
+            let expected = if id >= leaves {
+                let slot = id - leaves;
+                Node::leaf(self.pma.is_occupied(slot).then_some(slot))
+            } else {
+                Node::parent_of(
+                    &self.nodes[self.compute_node_index(id << 1)],
+                    &self.nodes[self.compute_node_index((id << 1) | 1)],
+                )
+            };
+            assert!(
+                self.nodes[self.compute_node_index(id)] == expected,
+                "Index node {} is stale.",
+                id
+            );
+        }
+        assert!(
+            self.marked.iter().all(|k| self.pma.get(k).is_some()),
+            "A hidden key is not stored."
+        );
+        assert_eq!(
+            self.size + self.marked.len(),
+            self.pma.len(),
+            "The length does not count the visible entries."
+        );
+    }
+
+    // Physically drops every entry hidden by `mark_removed` in a single compaction pass over the
+    // PMA followed by one index rebuild. Returns the number of dropped entries.
+    pub fn purge_marked(&mut self) -> usize {
+        if self.marked.is_empty() {
+            return 0;
+        }
+        let marked = std::mem::take(&mut self.marked);
+        let purged = self.pma.retain(|k, _| !marked.contains(k));
+        let purged_len = purged.len();
+        self.defer_drop(purged);
+        self.version += 1;
+        self.rebuild();
+        purged_len
+    }
+
+    // Removes the entries of all the keys, hidden ones included, and returns how many visible
+    // entries went away. A large batch is dropped in a single compaction pass that lays the
+    // PMA out again at the density of the survivors, followed by one index rebuild, instead of
+    // the cascade of window rebalances and shrinks of one remove per key. The values are
+    // disposed of as `discard` does.
+    pub fn remove_many<'a, Q, I>(&mut self, keys: I) -> usize
+    where
+        I: IntoIterator<Item = &'a Q>,
+        Q: Ord + Comparable<K> + ?Sized + 'a,
+    {
+        let mut victims = keys.into_iter().collect::<Vec<_>>();
+        victims.sort_unstable();
+        victims.dedup();
+        if victims.len() * BATCH_MERGE_RATIO < self.size {
+            return victims.into_iter().filter(|key| self.discard(*key)).count();
+        }
+        // The batch pass moves every entry, so it is only worth it when a victim is there.
+        let present =
+            |key: &Q| matches!(self.pma.key(self.find_index(key)), Some(k) if key.equivalent(k));
+        if !victims.iter().any(|key| present(key)) {
+            return 0;
+        }
+        let marked = &mut self.marked;
+        let mut victims = victims.into_iter().peekable();
+        let mut removed = 0;
+        let dropped = self.pma.retain(|k, _| {
+            while victims
+                .next_if(|key| (*key).compare(k) == Ordering::Less)
+                .is_some()
+            {}
+            if victims.next_if(|key| (*key).equivalent(k)).is_none() {
+                return true;
+            }
+            if marked.is_empty() || !marked.remove(k) {
+                removed += 1;
+            }
+            false
+        });
+        if !dropped.is_empty() {
+            self.size -= removed;
+            self.version += 1;
+        }
+        self.defer_drop(dropped);
+        self.rebuild();
+        removed
+    }
+
+    // Removes every entry in the range, hidden ones included, and returns how many visible
+    // entries went away. The covered slots are contiguous, so they are blanked in one go and
+    // the PMA is rebalanced and the index updated once, whatever the size of the range. The
+    // values are disposed of as `discard` does.
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: R) -> usize {
+        let from = self.lower_bound_index(range.start_bound());
+        let to = self.upper_bound_index(range.end_bound()).max(from);
+        let (taken, changed_range) = self.pma.remove_span(from, to);
+        if taken.is_empty() {
+            return 0;
+        }
+        let mut removed = taken.len();
+        if !self.marked.is_empty() {
+            removed -= taken.iter().filter(|(k, _)| self.marked.remove(k)).count();
+        }
+        self.size -= removed;
+        self.version += 1;
+        self.defer_drop(taken);
+        match changed_range {
+            Some((from, to)) => self.populate_changes(from, to),
+            None => self.rebuild(),
+        }
+        removed
+    }
+
+    #[inline]
+    pub(crate) fn is_marked(&self, key: &K) -> bool {
+        !self.marked.is_empty() && self.marked.contains(key)
+    }
+
+    // The stored key values visible to reads, in key order.
+    fn live_key_values(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.pma
+            .range(0, self.pma.data_len())
+            .filter(|(k, _)| !self.is_marked(k))
+    }
+
+    pub fn get_top_k_key_values(&self, k: usize) -> Vec<(&K, &V)> {
+        self.live_key_values().take(k).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    // Releases the memory kept around for reuse after the map shrank or got cleared. Without
+    // it the slot, index and work buffers only ever grow, so a map that is filled and drained
+    // in cycles does not go back to the allocator.
+    pub fn shrink_to_fit(&mut self) {
+        self.release_pins();
+        self.pma.shrink_to_fit();
+        self.nodes.shrink_to_fit();
+        self.changed_nodes = vec![];
+        self.repin();
+    }
+
+    pub(crate) fn pma(&self) -> &PackedMemoryArray<K, V> {
+        &self.pma
+    }
+
+    // A map over a PMA restored as is, holding `len` entries.
+    pub(crate) fn from_layout(pma: PackedMemoryArray<K, V>, len: usize) -> Self {
+        let mut map = Self::new();
+        map.pma = pma;
+        map.size = len;
+        map.rebuild();
+        map
+    }
+
+    // Number of slots the PMA buffer holds without reallocating.
+    pub(crate) fn slot_capacity(&self) -> usize {
+        self.pma.capacity()
+    }
+
+    pub fn clear(&mut self) {
+        if self.deferred.is_some() {
+            let dropped = self.pma.retain(|_, _| false);
+            self.defer_drop(dropped);
+        }
+        self.pma.clear();
+        self.marked.clear();
+        self.size = 0;
+        self.version += 1;
+        self.rebuild();
+    }
+
+    // The mutation stamp of the map. It grows with every insert, update, removal or clear, so a
+    // value cached together with the stamp it was read at is known to be current as long as
+    // the stamp has not moved.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // Like `get`, also returning the mutation stamp the value was read at.
+    pub fn get_versioned<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(&V, u64)> {
+        self.get(key).map(|v| (v, self.version))
+    }
+
+    // Iterates over the entries in key order, walking the PMA slots lazily and skipping gaps.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            range: self.range(..),
+            remaining: self.size,
+        }
+    }
+
+    // Iterates over the entries in key order with mutable access to the values. Keys are handed
+    // out shared, so the order the index relies on cannot be broken.
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        let remaining = self.size;
+        IterMut {
+            range: self.range_mut(..),
+            remaining,
+        }
+    }
+
+    pub fn values_mut(&mut self) -> impl DoubleEndedIterator<Item = &mut V> + ExactSizeIterator {
+        self.iter_mut().map(|(_, v)| v)
+    }
+
+    pub fn keys(&self) -> Keys<'_, K, V> {
+        Keys { iter: self.iter() }
+    }
+
+    pub fn values(&self) -> Values<'_, K, V> {
+        Values { iter: self.iter() }
+    }
+
+    pub fn key_vec(&self) -> Vec<&K> {
+        self.live_key_values().map(|(k, _)| k).collect::<Vec<&K>>()
+    }
+
+    pub fn value_vec(&self) -> Vec<&V> {
+        self.live_key_values().map(|(_, v)| v).collect::<Vec<&V>>()
+    }
+
+    pub fn get_first_key(&self) -> Option<&K> {
+        self.first_key_value().map(|(k, _)| k)
+    }
+
+    // The entry with the smallest key. The density bounds keep the gap in front of the first
+    // occupied slot short, so this reads only a few slots.
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        self.iter().next()
+    }
+
+    // The entry with the largest key, read from the end of the PMA like `first_key_value`.
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        self.iter().next_back()
+    }
+
+    // The entry with the smallest key, to update its value in place or remove it, as a
+    // priority queue takes its minimum.
+    pub fn first_entry(&mut self) -> Option<OccupiedEntry<'_, K, V>> {
+        let mut index = self.pma.next_occupied(0, self.pma.data_len())?;
+        while self.is_marked(self.entry_at(index).0) {
+            index = self.pma.next_occupied(index + 1, self.pma.data_len())?;
+        }
+        Some(OccupiedEntry::new(self, index))
+    }
+
+    // The entry with the largest key, like `first_entry`.
+    pub fn last_entry(&mut self) -> Option<OccupiedEntry<'_, K, V>> {
+        let mut index = self.pma.prev_occupied(0, self.pma.data_len())?;
+        while self.is_marked(self.entry_at(index).0) {
+            index = self.pma.prev_occupied(0, index)?;
+        }
+        Some(OccupiedEntry::new(self, index))
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&V> {
+        self.get_key_value(key).map(|(_, v)| v)
+    }
+
+    // Like `get`, also returning the stored key, which may differ from a borrowed or derived
+    // query key.
+    pub fn get_key_value<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(&K, &V)> {
+        #[cfg(feature = "cache-sim")]
+        self.begin_operation();
+        let found = self.lookup(key);
+        #[cfg(feature = "cache-sim")]
+        self.end_operation(CacheOperation::Lookup);
+        found
+    }
+
+    // `get_key_value` without the bracket of the line counts.
+    fn lookup<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(&K, &V)> {
+        let index = self.find_index(key);
+        if index >= self.pma.data_len() {
+            return None;
+        }
+        self.record_access(self.pma.key_slot(index));
+        match self.pma.key_value(index) {
+            None => None,
+            Some((k, v)) => {
+                if key.equivalent(k) && !self.is_marked(k) {
+                    Some((k, v))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    pub fn contains_key<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.find_entry_index(key).is_some()
+    }
+
+    pub fn get_mut<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> Option<&mut V> {
+        let index = self.find_entry_index(key)?;
+        Some(self.value_at_mut(index))
+    }
+
+    // The first entry with a key not less than `key`, the successor query when the key itself
+    // may be missing.
+    pub fn lower_bound<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(&K, &V)> {
+        self.range_entries(Bound::Included(key), Bound::Unbounded)
+            .next()
+    }
+
+    // The first entry with a key greater than `key`.
+    pub fn upper_bound<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(&K, &V)> {
+        self.range_entries(Bound::Excluded(key), Bound::Unbounded)
+            .next()
+    }
+
+    // The number of entries with a key less than `key`, from the subtree counts kept in the
+    // branches: O(log n) plus a walk of the keys hidden by `mark_removed` below `key`.
+    pub fn rank<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> usize {
+        let hidden = self
+            .marked
+            .iter()
+            .take_while(|k| key.compare(k) == Ordering::Greater)
+            .count();
+        self.occupied_before(self.find_index(key)) - hidden
+    }
+
+    // The entry with the `i`-th smallest key, counting from 0, the inverse of `rank`.
+    pub fn select(&self, i: usize) -> Option<(&K, &V)> {
+        if i >= self.size {
+            return None;
+        }
+        // Every hidden entry at or before the position shifts the target one occupied slot on.
+        let mut target = i;
+        for k in &self.marked {
+            if self.occupied_before(self.find_index(k)) > target {
+                break;
+            }
+            target += 1;
+        }
+        self.pma.key_value(self.select_slot(target))
+    }
+
+    // The number of entries with keys in the range, from two descents of the index without
+    // scanning the PMA between them.
+    pub fn range_count<R: RangeBounds<K>>(&self, range: R) -> usize {
+        let from = self.lower_bound_index(range.start_bound());
+        let to = self.upper_bound_index(range.end_bound()).max(from);
+        // An empty slot range also covers inverted bounds, which `BTreeSet::range` rejects.
+        let hidden = match self.marked.is_empty() || from == to {
+            true => 0,
+            false => self.marked.range(range).count(),
+        };
+        self.occupied_before(to) - self.occupied_before(from) - hidden
+    }
+
+    // Keeps the aggregate of `monoid` over the values below every index node from now on, so
+    // `range_fold` with a monoid of the same type folds any range in O(log n) combines. The
+    // aggregates are updated with the index; values handed out mutably are folded in again
+    // on the next `range_fold`. Replaces the aggregate kept before, if any.
+    pub fn enable_aggregate<M>(&mut self, monoid: M)
+    where
+        M: Monoid<V> + Send + Sync + 'static,
+        M::Output: Send + 'static,
+    {
+        let mut aggregates = Box::new(Aggregates::new(monoid));
+        let marked = &self.marked;
+        let hidden = |k: &K| !marked.is_empty() && marked.contains(k);
+        AggregateLayer::<K, V>::refresh(aggregates.as_mut(), &self.pma, self.height, &hidden);
+        self.aggregates = Some(aggregates);
+    }
+
+    pub fn disable_aggregate(&mut self) {
+        self.aggregates = None;
+    }
+
+    // Folds the values in the range, in key order. Uses the aggregate kept by `enable_aggregate`
+    // when it is of the type of `monoid`, and scans the range with `monoid` otherwise.
+    pub fn range_fold<R, M>(&self, range: R, monoid: &M) -> M::Output
+    where
+        R: RangeBounds<K>,
+        M: Monoid<V> + 'static,
+        M::Output: 'static,
+    {
+        let from = self.lower_bound_index(range.start_bound());
+        let to = self.upper_bound_index(range.end_bound()).max(from);
+        let kept = self
+            .aggregates
+            .as_ref()
+            .and_then(|a| a.as_any().downcast_ref::<Aggregates<M, M::Output>>());
+        match kept {
+            Some(aggregates) => {
+                let marked = &self.marked;
+                let hidden = |k: &K| !marked.is_empty() && marked.contains(k);
+                aggregates.fold(&self.pma, self.height, from, to, &hidden)
+            }
+            None => self.range(range).fold(monoid.identity(), |acc, (_, v)| {
+                monoid.combine(&acc, &monoid.lift(v))
+            }),
+        }
+    }
+
+    // The number of occupied slots before `index`, summing the counts of the left siblings on
+    // the path to the leaf.
+    fn occupied_before(&self, index: usize) -> usize {
+        if index >= self.pma.data_len() {
+            return self.nodes[self.compute_node_index(1)].count();
+        }
+        let mut count = 0;
+        let mut node_id = 1usize;
+        for level in (0..self.height - 1).rev() {
+            node_id <<= 1;
+            if (index >> level) & 1 == 1 {
+                count += self.nodes[self.compute_node_index(node_id)].count();
+                node_id |= 1;
+            }
+        }
+        count
+    }
+
+    // The index of the occupied slot preceded by `n` others, which must exist.
+    fn select_slot(&self, mut n: usize) -> usize {
+        let mut node_id = 1usize;
+        let mut leaf_index = 0usize;
+        while let Node::Branch(_) = &self.nodes[self.compute_node_index(node_id)] {
+            leaf_index <<= 1;
+            node_id <<= 1;
+            let left_count = self.nodes[self.compute_node_index(node_id)].count();
+            if n >= left_count {
+                n -= left_count;
+                leaf_index |= 1;
+                node_id |= 1;
+            }
+        }
+        leaf_index
+    }
+
+    // Up to `n` entries before and `n` entries after `key`, plus the entry with the key itself
+    // if it exists, found with one descent and a walk of the neighbouring slots.
+    pub fn get_surrounding<Q: Comparable<K> + ?Sized>(
+        &self,
+        key: &Q,
+        n: usize,
+    ) -> Surrounding<'_, K, V> {
+        let index = self.find_index(key);
+        let split = index.min(self.pma.data_len());
+        let mut tail = self
+            .pma
+            .range(split, self.pma.data_len())
+            .filter(|(k, _)| !self.is_marked(k))
+            .peekable();
+        let exact = tail.next_if(|(k, _)| key.equivalent(*k));
+        let mut before = self
+            .pma
+            .range(0, split)
+            .rev()
+            .filter(|(k, _)| !self.is_marked(k))
+            .take(n)
+            .collect::<Vec<_>>();
+        before.reverse();
+        Surrounding {
+            before,
+            exact,
+            after: tail.take(n).collect(),
+        }
+    }
+
+    // Warms the cache for a later lookup of `key`: walks the descent path, hinting both children
+    // of every branch before the comparison picks one, and finally hints the slot. Nothing is
+    // returned, the point is to overlap the memory latency of the next lookup with other work.
+    pub fn prefetch<Q: Comparable<K> + ?Sized>(&self, key: &Q) {
+        let mut node_id = 1usize;
+        let mut leaf_index = 0usize;
+        while let Node::Branch(_) = &self.nodes[self.compute_node_index(node_id)] {
+            let left_index = self.compute_node_index(node_id << 1);
+            let right_index = self.compute_node_index((node_id << 1) | 1);
+            prefetch_read(&self.nodes[left_index]);
+            prefetch_read(&self.nodes[right_index]);
+            leaf_index <<= 1;
+            node_id <<= 1;
+            if self
+                .node_key(left_index)
+                .is_none_or(|k| key.compare(k) == Ordering::Greater)
+            {
+                leaf_index |= 1;
+                node_id |= 1;
+            }
+        }
+        if leaf_index < self.pma.data_len() {
+            prefetch_read(self.pma.key_slot(leaf_index));
+        }
+    }
+
+    // Reads the 8 byte metadata slot of an entry, 0 unless set by `set_meta`.
+    pub fn get_meta<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<u64> {
+        self.find_entry_index(key)
+            .map(|index| self.pma.get_meta(index))
+    }
+
+    // Sets the metadata slot of an entry and returns whether the entry exists. The slots live
+    // in an array parallel to the PMA, allocated on first use and moved along with the entries
+    // by every rebalance. A new entry starts with 0, updating a value keeps its metadata.
+    pub fn set_meta<Q: Comparable<K> + ?Sized>(&mut self, key: &Q, meta: u64) -> bool {
+        match self.find_entry_index(key) {
+            Some(index) => {
+                self.pma.enable_meta();
+                self.pma.set_meta(index, meta);
+                self.version += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The PMA index of the visible entry with the key.
+    fn find_entry_index<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<usize> {
+        let index = self.find_index(key);
+        match self.pma.key(index) {
+            Some(k) if key.equivalent(k) && !self.is_marked(k) => Some(index),
+            _ => None,
+        }
+    }
+
+    pub fn get_all_key_values(&self) -> Vec<(&K, &V)> {
+        self.live_key_values().collect()
+    }
+
+    // Walks the map and an iterator of `(key, item)` pairs sorted by key in lockstep, calling
+    // `f` with the entry and the item for every item whose key is in the map. Both sides are
+    // scanned once, so a bulk reconciliation costs O(n + m) instead of one descent per item.
+    // Items sharing a key all meet the same entry. Returns the number of matched items.
+    pub fn join_sorted<I, Q, T, F>(&self, items: I, mut f: F) -> usize
+    where
+        I: IntoIterator<Item = (Q, T)>,
+        Q: Comparable<K>,
+        F: FnMut(&K, &V, T),
+    {
+        let mut entries = self.live_key_values().peekable();
+        let mut matched = 0;
+        for (key, item) in items {
+            while entries
+                .next_if(|(k, _)| key.compare(k) == Ordering::Greater)
+                .is_some()
+            {}
+            match entries.peek() {
+                Some((k, v)) if key.equivalent(k) => {
+                    f(k, v, item);
+                    matched += 1;
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        matched
+    }
+
+    // Iterates over the entries with keys in the range, in key order. One descent of the index
+    // finds the first slot, the scan then walks the PMA slots up to the end bound.
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Range<'_, K, V> {
+        self.range_entries(range.start_bound(), range.end_bound())
+    }
+
+    // Like `range`, with mutable access to the values, for updating a band of entries in place.
+    pub fn range_mut<R: RangeBounds<K>>(&mut self, range: R) -> RangeMut<'_, K, V> {
+        let from = self.lower_bound_index(range.start_bound());
+        let to = self.upper_bound_index(range.end_bound()).max(from);
+        self.version += 1;
+        self.touch_slots(from, to);
+        RangeMut {
+            slots: self.pma.range_mut(from, to),
+            marked: &self.marked,
+        }
+    }
+
+    // The visible entries between the bounds, in key order.
+    pub(crate) fn range_entries<Q: Comparable<K> + ?Sized>(
+        &self,
+        start: Bound<&Q>,
+        end: Bound<&Q>,
+    ) -> Range<'_, K, V> {
+        let from = self.lower_bound_index(start);
+        let to = self.upper_bound_index(end).max(from);
+        Range {
+            slots: self.pma.range(from, to),
+            marked: &self.marked,
+        }
+    }
+
+    // Occupancy of the PMA region covering the key range, to spot over-dense regions that make
+    // inserts rebalance large windows, or sparse ones left behind by removals.
+    pub fn range_stats<R: RangeBounds<K>>(&self, range: R) -> RangeStats {
+        let from = self.lower_bound_index(range.start_bound());
+        let to = self.upper_bound_index(range.end_bound()).max(from);
+        let occupied = self.pma.count_occupied(from, to);
+        let hidden = match self.marked.is_empty() {
+            true => 0,
+            false => self
+                .pma
+                .range(from, to)
+                .filter(|(k, _)| self.is_marked(k))
+                .count(),
+        };
+        RangeStats {
+            entries: occupied - hidden,
+            occupied,
+            slots: to - from,
+            density: match to - from {
+                0 => 0.0,
+                slots => occupied as f64 / slots as f64,
+            },
+        }
+    }
+
+    // The entries in key order for async consumers, yielding to the executor every
+    // `yield_every` entries.
+    #[cfg(feature = "async")]
+    pub fn async_iter(&self, yield_every: usize) -> AsyncIter<impl Iterator<Item = (&K, &V)> + '_> {
+        AsyncIter::new(self.live_key_values(), yield_every)
+    }
+
+    // A read-only view of the entries accepted by `pred`, for instance the keys of one tenant
+    // in a shared index.
+    pub fn view_filter<P: Fn(&K, &V) -> bool>(&self, pred: P) -> FilterView<'_, K, V, P> {
+        FilterView::new(self, pred)
+    }
+
+    // A read-only view of the map with every value passed through `f` on read.
+    pub fn view_map<U, F: Fn(&K, &V) -> U>(&self, f: F) -> MapView<'_, K, V, F> {
+        MapView::new(self, f)
+    }
+
+    // Returns the maximal runs of occupied PMA slots whose keys fall in the range, as the keys
+    // and the values of the run, with entries hidden by `mark_removed` breaking runs like gaps
+    // do. A dense region comes back as one pair of contiguous slices, while a sparse region
+    // degrades to one single-entry run per entry.
+    pub fn range_slices<R: RangeBounds<K>>(&self, range: R) -> RangeSlices<'_, K, V> {
+        let from = self.lower_bound_index(range.start_bound());
+        let to = self.upper_bound_index(range.end_bound()).max(from);
+        RangeSlices {
+            pma: &self.pma,
+            from,
+            to,
+            marked: &self.marked,
+        }
+    }
+
+    // Registers a cursor in front of the first entry. Cursors do not borrow the map: they stay
+    // valid across inserts and removes, which remap them during rebalances so a paused scan
+    // resumes right after the last entry it returned. Dropping the cursor unregisters it.
+    pub fn cursor(&mut self) -> Cursor {
+        self.register_cursor(0)
+    }
+
+    // Registers a cursor in front of the first entry with a key not less than `key`.
+    pub fn cursor_at<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> Cursor {
+        let position = self.lower_bound_index(Bound::Included(key));
+        self.register_cursor(position)
+    }
+
+    fn register_cursor(&mut self, position: usize) -> Cursor {
+        let owner = Arc::new(());
+        Cursor {
+            id: self.pma.register_cursor(position, &owner),
+            owner,
+        }
+    }
+
+    // The id of a cursor of this map. Panics for a cursor of another map.
+    fn cursor_id(&self, cursor: &Cursor) -> usize {
+        assert!(
+            self.pma.owns_cursor(cursor.id, &cursor.owner),
+            "The cursor belongs to another map."
+        );
+        cursor.id
+    }
+
+    // Returns the entry in front of the cursor without moving it.
+    pub fn cursor_peek(&self, cursor: &Cursor) -> Option<(&K, &V)> {
+        let position = self.pma.cursor_position(self.cursor_id(cursor));
+        self.pma
+            .range(position, self.pma.data_len())
+            .find(|(k, _)| !self.is_marked(k))
+    }
+
+    // Returns the entry in front of the cursor and moves the cursor past it.
+    pub fn cursor_next(&mut self, cursor: &Cursor) -> Option<(&K, &V)> {
+        let id = self.cursor_id(cursor);
+        let position = self.pma.cursor_position(id);
+        let len = self.pma.data_len();
+        let mut index = position;
+        while let Some(next) = self.pma.next_occupied(index, len) {
+            if self.pma.key(next).is_some_and(|k| !self.is_marked(k)) {
+                self.pma.set_cursor_position(id, next + 1);
+                return self.pma.key_value(next);
+            }
+            index = next + 1;
+        }
+        self.pma.set_cursor_position(id, len);
+        None
+    }
+
+    // Unregisters the cursor right away. Dropping it does the same, its slot is then freed by
+    // the next cursor registered.
+    pub fn release_cursor(&mut self, cursor: Cursor) {
+        let id = self.cursor_id(&cursor);
+        self.pma.unregister_cursor(id);
+    }
+
+    // Like `get`, searching outward from the slot of the last hinted operation instead of
+    // descending from the root, in O(log d) probes for a key d slots away, and leaving the
+    // slot of this key in the hint for the next one.
+    pub fn get_hint<Q: Comparable<K> + ?Sized>(&self, key: &Q, hint: &mut Hint) -> Option<&V> {
+        let index = self.find_index_from(key, hint.index);
+        hint.index = index;
+        if index >= self.pma.data_len() {
+            return None;
+        }
+        let (k, v) = self.pma.key_value(index)?;
+        (key.equivalent(k) && !self.is_marked(k)).then_some(v)
+    }
+
+    // Like `insert`, searching outward from the hinted slot as `get_hint` does. The hint may
+    // go stale when the insert rebalances, which only makes the next search longer.
+    pub fn insert_hint(&mut self, key: K, value: V, hint: &mut Hint) -> Option<V> {
+        let index = self.find_index_from(&key, hint.index);
+        hint.index = index;
+        self.insert_at(index, key, value)
+    }
+
+    // Recomputes the whole index after the PMA got laid out again. Growing it from the old
+    // index instead would not save anything: a resize spreads every entry evenly over the new
+    // slots, so every slot the nodes point at changes, and the vEB position of a node depends
+    // on the height, so no old subtree keeps its place either. The rebuild stays linear, like
+    // the redistribution and the reallocation it follows.
+    fn rebuild(&mut self) {
+        self.pma.record_stats(|stats| stats.rebuilds += 1);
+        self.release_pins();
+        self.place_buffers();
+        #[cfg(feature = "rayon")]
+        if self.pma.data_len() >= 1 << PARALLEL_FILL_MIN_HEIGHT {
+            self.par_rebuild();
+            return self.repin();
+        }
+        self.rebuild_serial();
+        self.repin();
+    }
+
+    fn rebuild_serial(&mut self) {
+        self.nodes
+            .resize_with(self.pma.data_len() << 1, || Node::branch(None, 0));
+        self.height = (self.pma.data_len().trailing_zeros() + 1) as usize;
+        let first_leaf_id = 1usize << (self.height - 1);
+        for i in 1usize..(1 << self.height) {
+            let index = self.compute_node_index(i);
+            self.nodes[index] = if i < first_leaf_id {
+                Node::branch(None, 0)
+            } else {
+                Node::leaf(None)
+            };
+        }
+        self.populate_changes(0, self.pma.data_len());
+    }
+
+    #[cfg(feature = "rayon")]
+    fn par_rebuild(&mut self) {
+        let leaves = self.pma.data_len();
+        self.nodes
+            .resize_with(leaves << 1, || Node::branch(None, 0));
+        self.height = (leaves.trailing_zeros() + 1) as usize;
+        let pma = &self.pma;
+        let leaf_nodes: Vec<Node> = (0..pma.data_len())
+            .into_par_iter()
+            .map(|i| Node::leaf(pma.is_occupied(i).then_some(i)))
+            .collect();
+        self.pma
+            .record_stats(|stats| stats.nodes_touched += ((leaves << 1) - 1) as u64);
+        let nodes = &mut self.nodes[..(leaves << 1) - 1];
+        match self.layout {
+            IndexLayout::VanEmdeBoas => fill_veb_tree(nodes, self.height, &leaf_nodes, true),
+            IndexLayout::Bfs => fill_bfs_tree(nodes, self.height, &leaf_nodes),
+        }
+        if let Some(aggregates) = &mut self.aggregates {
+            let marked = &self.marked;
+            let hidden = |k: &K| !marked.is_empty() && marked.contains(k);
+            aggregates.refresh(&self.pma, self.height, &hidden);
+        }
+        self.record_changed_slots(0, leaves);
+    }
+
+    fn find_index<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> usize {
+        let mut node_id = 1usize;
+        let mut node_index = self.compute_node_index(node_id);
+        let mut leaf_index = 0usize;
+        self.record_access(&self.nodes[node_index]);
+        while let Node::Branch(_) = &self.nodes[node_index] {
+            leaf_index <<= 1;
+            node_id <<= 1;
+            node_index = self.compute_node_index(node_id);
+            self.record_access(&self.nodes[node_index]);
+            match self.node_key(node_index) {
+                Some(k) => {
+                    if key.compare(k) == Ordering::Greater {
+                        leaf_index |= 1;
+                        node_id |= 1;
+                    }
+                }
+                None => {
+                    leaf_index |= 1;
+                    node_id |= 1;
+                }
+            };
+        }
+        node_index = self.compute_node_index(node_id);
+        if let Some(k) = self.node_key(node_index) {
+            if key.compare(k) == Ordering::Greater {
+                leaf_index += 1;
+            }
+        }
+        leaf_index
+    }
+
+    // The slot of the first key not less than `key`, or the end of the array, found by
+    // galloping from `from` to a range of slots holding it and bisecting the range. A slot
+    // `s` is at or before the answer when the last key in front of it is less than `key`.
+    fn find_index_from<Q: Comparable<K> + ?Sized>(&self, key: &Q, from: usize) -> usize {
+        let len = self.pma.data_len();
+        let before = |s: usize| match self.pma.prev_occupied(0, s) {
+            Some(i) => {
+                self.record_access(self.pma.key_slot(i));
+                self.pma
+                    .key(i)
+                    .is_some_and(|k| key.compare(k) == Ordering::Greater)
+            }
+            None => true,
+        };
+        let from = from.min(len);
+        let (mut lo, mut hi) = (from, from);
+        let mut step = 1;
+        if before(from) {
+            loop {
+                if hi == len {
+                    return len;
+                }
+                hi = (from + step).min(len);
+                if !before(hi) {
+                    break;
+                }
+                lo = hi;
+                step <<= 1;
+            }
+        } else {
+            loop {
+                lo = from.saturating_sub(step);
+                if before(lo) {
+                    break;
+                }
+                hi = lo;
+                step <<= 1;
+            }
+        }
+        // `lo` is at or before the answer and `hi` past it.
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if before(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    // The first PMA index whose slot may hold a key satisfying the lower bound.
+    fn lower_bound_index<Q: Comparable<K> + ?Sized>(&self, bound: Bound<&Q>) -> usize {
+        match bound {
+            Bound::Included(key) => self.find_index(key),
+            Bound::Excluded(key) => self.skip_equal_key(self.find_index(key), key),
+            Bound::Unbounded => 0,
+        }
+    }
+
+    // The PMA index past the last slot whose key satisfies the upper bound.
+    fn upper_bound_index<Q: Comparable<K> + ?Sized>(&self, bound: Bound<&Q>) -> usize {
+        match bound {
+            Bound::Included(key) => self.skip_equal_key(self.find_index(key), key),
+            Bound::Excluded(key) => self.find_index(key),
+            Bound::Unbounded => self.pma.data_len(),
+        }
+    }
+
+    // `find_index` stops on the slot holding the key if it exists, step over it in that case.
+    fn skip_equal_key<Q: Comparable<K> + ?Sized>(&self, index: usize, key: &Q) -> usize {
+        match self.pma.key(index) {
+            Some(k) if key.equivalent(k) => index + 1,
+            _ => index.min(self.pma.data_len()),
+        }
+    }
+
+    // Populated the changed leaves to root.
+    fn populate_changes(&mut self, from: usize, to: usize) {
+        self.try_populate_changes(from, to)
+            .unwrap_or_else(|error| panic!("{}", error));
+    }
+
+    // Same as `populate_changes`, failing when a node on the way up has the wrong kind.
+    fn try_populate_changes(&mut self, from: usize, to: usize) -> Result<(), CoBTreeError> {
+        let first_leaf_id = 1usize << (self.height - 1);
+        let mut changed_nodes = std::mem::take(&mut self.changed_nodes);
+        changed_nodes.clear();
+        for i in from..to.min(self.pma.data_len()) {
+            let leaf_id = first_leaf_id + i;
+            let leaf_index = self.compute_node_index(leaf_id);
+            self.record_access(self.pma.key_slot(i));
+            self.record_access(&self.nodes[leaf_index]);
+            let slot = self.pma.is_occupied(i).then_some(i);
+            let leaf = &mut self.nodes[leaf_index];
+            if leaf.set_leaf_slot(slot)?
+                && leaf_id > 1
+                && (changed_nodes.is_empty() || changed_nodes.last().unwrap() != &(leaf_id >> 1))
+            {
+                changed_nodes.push(leaf_id >> 1);
+            }
+        }
+
+        let mut i = 0;
+        while i < changed_nodes.len() {
+            let changed_node_id = changed_nodes[i];
+            let changed_node_index = self.compute_node_index(changed_node_id);
+            self.record_access(&self.nodes[changed_node_index]);
+            let changed_node = &mut self.nodes[changed_node_index];
+            match changed_node {
+                Node::Branch(_) => {
+                    if self.set_branch_key(
+                        changed_node_index,
+                        self.compute_node_index(changed_node_id << 1),
+                        self.compute_node_index((changed_node_id << 1) | 1),
+                    )? && changed_node_id > 1
+                        && changed_nodes.last().unwrap() != &(changed_node_id >> 1)
+                    {
+                        changed_nodes.push(changed_node_id >> 1);
+                    }
+                }
+                Node::Leaf(_) => return Err(CoBTreeError::NodeKind),
+            }
+            i += 1;
+        }
+        let touched = to.min(self.pma.data_len()).saturating_sub(from) + changed_nodes.len();
+        self.pma
+            .record_stats(|stats| stats.nodes_touched += touched as u64);
+        self.changed_nodes = changed_nodes;
+        debug_assert_eq!(
+            self.nodes[self.compute_node_index(1)].count(),
+            self.pma.len()
+        );
+        self.slots_changed(from, to);
+        Ok(())
+    }
+
+    // Brings what is derived from the slots in `from..to` up to date after they were written.
+    fn slots_changed(&mut self, from: usize, to: usize) {
+        if let Some(aggregates) = &mut self.aggregates {
+            let marked = &self.marked;
+            let hidden = |k: &K| !marked.is_empty() && marked.contains(k);
+            aggregates.update(&self.pma, self.height, from, to, &hidden);
+        }
+        self.record_changed_slots(from, to);
+    }
+
+    // Notes that the values in `from..to` are handed out mutably.
+    fn touch_slots(&mut self, from: usize, to: usize) {
+        if let Some(aggregates) = &mut self.aggregates {
+            aggregates.touch(from, to);
+        }
+        self.record_changed_slots(from, to);
+    }
+
+    #[inline]
+    fn record_changed_slots(&mut self, from: usize, to: usize) {
+        if from >= to {
+            return;
+        }
+        let segment_size = self.pma.segment_size();
+        let (from, to) = (from / segment_size, to.div_ceil(segment_size));
+        if self.changed_segments.len() < bitmap::words_for(to) {
+            bitmap::resize(&mut self.changed_segments, to);
+        }
+        bitmap::fill(&mut self.changed_segments, from, to, true);
+    }
+
+    // The segments written since the last call, as a bit set over segment indices.
+    pub(crate) fn take_changed_segments(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.changed_segments)
+    }
+
+    pub(crate) fn take_page_cache(&mut self) -> PageCache<K, V> {
+        std::mem::take(&mut self.page_cache)
+    }
+
+    pub(crate) fn set_page_cache(&mut self, cache: PageCache<K, V>) {
+        self.page_cache = cache;
+    }
+
+    fn compute_node_index(&self, x: usize) -> usize {
+        self.layout.node_index(x, self.height)
+    }
+
+    // The key stored in the slot the node points at.
+    #[inline]
+    fn node_key(&self, node_index: usize) -> Option<&K> {
+        let slot = self.nodes[node_index].slot()?;
+        self.record_access(self.pma.key_slot(slot));
+        self.pma.key(slot)
+    }
+
+    #[inline]
+    // Set the slot for this node as the one of the maximum key of the left and right children,
+    // and its count as their sum. Return whether the node is changed or not.
+    fn set_branch_key(
+        &mut self,
+        node_index: usize,
+        left_index: usize,
+        right_index: usize,
+    ) -> Result<bool, CoBTreeError> {
+        if let Node::Leaf(_) = self.nodes[node_index] {
+            return Err(CoBTreeError::NodeKind);
+        }
+        let node = Node::parent_of(&self.nodes[left_index], &self.nodes[right_index]);
+        let changed = node != self.nodes[node_index];
+        self.nodes[node_index] = node;
+        Ok(changed)
+    }
+}
+
+// A handle to a cursor registered in a map. Only the map that created it accepts it, and
+// dropping it unregisters the cursor: rebalances stop remapping it and its slot is reused.
+pub struct Cursor {
+    id: usize,
+    // The map holds the other end weakly, which ties the handle to its slot there.
+    owner: Arc<()>,
+}
+
+// A slot position handed from one hinted operation to the next, `Hint::default()` starting
+// at the front. Any hint gives the right answer, a near one just a faster search.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Hint {
+    index: usize,
+}
+
+// A source head of `merge_build`, ordered so `BinaryHeap` pops the smallest key first and,
+// for equal keys, the earliest source first.
+struct MergeHead<K, V> {
+    key: K,
+    source: usize,
+    value: V,
+}
+
+impl<K: Ord, V> Ord for MergeHead<K, V> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .key
+            .cmp(&self.key)
+            .then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+impl<K: Ord, V> PartialOrd for MergeHead<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord, V> PartialEq for MergeHead<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord, V> Eq for MergeHead<K, V> {}
+
+// The neighbourhood of a key returned by `BTreeMap::get_surrounding`.
+#[derive(Debug, PartialEq)]
+pub struct Surrounding<'a, K, V> {
+    // The closest entries before the key, in key order.
+    pub before: Vec<(&'a K, &'a V)>,
+    // The entry with the key, if any.
+    pub exact: Option<(&'a K, &'a V)>,
+    // The closest entries after the key, in key order.
+    pub after: Vec<(&'a K, &'a V)>,
+}
+
+// Iterator over a key range of the map, see `BTreeMap::range`.
+pub struct Range<'a, K, V> {
+    slots: PmaIter<'a, K, V>,
+    marked: &'a BTreeSet<K>,
+}
+
+impl<'a, K: Ord, V> Iterator for Range<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let marked = self.marked;
+        self.slots
+            .find(|(k, _)| marked.is_empty() || !marked.contains(k))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.slots.size_hint()
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Range<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let marked = self.marked;
+        self.slots
+            .rfind(|(k, _)| marked.is_empty() || !marked.contains(k))
+    }
+}
+
+// Iterator over the entries of the map, see `BTreeMap::iter`.
+pub struct Iter<'a, K, V> {
+    range: Range<'a, K, V>,
+    remaining: usize,
+}
+
+impl<'a, K: Ord, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.range.next()?;
+        self.remaining -= 1;
+        Some(next)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Iter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let next = self.range.next_back()?;
+        self.remaining -= 1;
+        Some(next)
+    }
+}
+
+impl<K: Ord, V> ExactSizeIterator for Iter<'_, K, V> {}
+
+// Iterator over a key range of the map with mutable values, see `BTreeMap::range_mut`.
+pub struct RangeMut<'a, K, V> {
+    slots: PmaIterMut<'a, K, V>,
+    marked: &'a BTreeSet<K>,
+}
+
+impl<'a, K: Ord, V> Iterator for RangeMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let marked = self.marked;
+        self.slots
+            .find(|(k, _)| marked.is_empty() || !marked.contains(*k))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.slots.size_hint()
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for RangeMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let marked = self.marked;
+        self.slots
+            .rfind(|(k, _)| marked.is_empty() || !marked.contains(*k))
+    }
+}
+
+// Iterator over the entries of the map with mutable values, see `BTreeMap::iter_mut`.
+pub struct IterMut<'a, K, V> {
+    range: RangeMut<'a, K, V>,
+    remaining: usize,
+}
+
+impl<'a, K: Ord, V> Iterator for IterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.range.next()?;
+        self.remaining -= 1;
+        Some(next)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for IterMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let next = self.range.next_back()?;
+        self.remaining -= 1;
+        Some(next)
+    }
+}
+
+impl<K: Ord, V> ExactSizeIterator for IterMut<'_, K, V> {}
+
+pub struct Keys<'a, K, V> {
+    iter: Iter<'a, K, V>,
+}
+
+impl<'a, K: Ord, V> Iterator for Keys<'a, K, V> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(k, _)| k)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Keys<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|(k, _)| k)
+    }
+}
+
+impl<K: Ord, V> ExactSizeIterator for Keys<'_, K, V> {}
+
+pub struct Values<'a, K, V> {
+    iter: Iter<'a, K, V>,
+}
+
+impl<'a, K: Ord, V> Iterator for Values<'a, K, V> {
+    type Item = &'a V;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next().map(|(_, v)| v)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for Values<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.iter.next_back().map(|(_, v)| v)
+    }
+}
+
+impl<K: Ord, V> ExactSizeIterator for Values<'_, K, V> {}
+
+// Iterator removing the entries accepted by a predicate, see `BTreeMap::extract_if`.
+pub struct ExtractIf<'a, K, V, F>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+    F: FnMut(&K, &mut V) -> bool,
+{
+    map: &'a mut BTreeMap<K, V>,
+    // Next slot to look at.
+    index: usize,
+    pred: F,
+    extracted: bool,
+}
+
+impl<K, V, F> Iterator for ExtractIf<'_, K, V, F>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+    F: FnMut(&K, &mut V) -> bool,
+{
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let map = &mut *self.map;
+        while self.index < map.pma.data_len() {
+            let index = self.index;
+            self.index += 1;
+            let extract = match map.pma.key_value_mut(index) {
+                Some((k, _)) if !map.marked.is_empty() && map.marked.contains(k) => continue,
+                Some((k, v)) => (self.pred)(k, v),
+                None => continue,
+            };
+            // The predicate may have changed the value, kept or not.
+            map.version += 1;
+            map.touch_slots(index, index + 1);
+            if extract {
+                self.extracted = true;
+                map.size -= 1;
+                return map.pma.take(index);
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.map.size))
+    }
+}
+
+impl<K, V, F> Drop for ExtractIf<'_, K, V, F>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+    F: FnMut(&K, &mut V) -> bool,
+{
+    fn drop(&mut self) {
+        if self.extracted {
+            // Compacts the emptied slots away and restores the density bounds.
+            self.map.pma.retain(|_, _| true);
+            self.map.rebuild();
+        }
+    }
+}
+
+// The entries taken out by `BTreeMap::drain`, in key order.
+pub struct Drain<K, V> {
+    entries: std::vec::IntoIter<(K, V)>,
+}
+
+impl<K, V> Iterator for Drain<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for Drain<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.entries.next_back()
+    }
+}
+
+impl<K, V> ExactSizeIterator for Drain<K, V> {}
+
+// Consuming iterator over the entries of the map, see `IntoIterator for BTreeMap`.
+pub struct IntoIter<K, V> {
+    slots: IntoKeyValues<K, V>,
+    marked: BTreeSet<K>,
+    remaining: usize,
+}
+
+impl<K: Ord, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let marked = &self.marked;
+        let next = self
+            .slots
+            .find(|(k, _)| marked.is_empty() || !marked.contains(k))?;
+        self.remaining -= 1;
+        Some(next)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Ord, V> DoubleEndedIterator for IntoIter<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let marked = &self.marked;
+        let next = self
+            .slots
+            .rfind(|(k, _)| marked.is_empty() || !marked.contains(k))?;
+        self.remaining -= 1;
+        Some(next)
+    }
+}
+
+impl<K: Ord, V> ExactSizeIterator for IntoIter<K, V> {}
+
+// Moves the entries out in key order, the slots are taken over as they are and nothing is
+// cloned. Entries hidden by `mark_removed` are dropped on the way.
+impl<K, V> IntoIterator for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            slots: self.pma.into_key_values(),
+            marked: self.marked,
+            remaining: self.size,
+        }
+    }
+}
+
+// Sorts the batch first. A batch that is large next to the map is merged in with a single pass
+// over the PMA, like `append`; a small one is inserted entry by entry, in key order. When the
+// batch holds a key several times, the last value wins.
+impl<K, V> Extend<(K, V)> for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        let incoming = sorted_unique(iter.into_iter().collect());
+        if incoming.len() * BATCH_MERGE_RATIO < self.size {
+            for (k, v) in incoming {
+                self.insert(k, v);
+            }
+        } else {
+            self.merge_entries(incoming.into_iter().map(|kv| (kv, 0)).collect(), false);
+        }
+    }
+}
+
+// Sorts key values by key with funnelsort, keeping the last value of every key. Input already
+// sorted by unique keys is returned as is.
+fn sorted_unique<K: Ord, V>(mut key_values: Vec<(K, V)>) -> Vec<(K, V)> {
+    if key_values.windows(2).all(|w| w[0].0 < w[1].0) {
+        return key_values;
+    }
+    sorting::funnel_sort_by(&mut key_values, |a, b| a.0.cmp(&b.0));
+    let mut deduped: Vec<(K, V)> = Vec::with_capacity(key_values.len());
+    for kv in key_values {
+        match deduped.last_mut() {
+            Some(last) if last.0 == kv.0 => *last = kv,
+            _ => deduped.push(kv),
+        }
+    }
+    deduped
+}
+
+// Input already sorted by unique keys is laid out directly, anything else is sorted first.
+// When a key shows up several times, the last value wins.
+impl<K, V> FromIterator<(K, V)> for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self::from_sorted_vec(sorted_unique(iter.into_iter().collect()))
+    }
+}
+
+impl<'a, K, V> Extend<(&'a K, &'a V)> for BTreeMap<K, V>
+where
+    K: Ord + Copy + ParallelBounds,
+    V: Copy + ParallelBounds,
+{
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(&k, &v)| (k, v)));
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a mut BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    type Item = (&'a K, &'a mut V);
+    type IntoIter = IterMut<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+impl<'a, K, V> IntoIterator for &'a BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RangeStats {
+    // Visible entries in the range.
+    pub entries: usize,
+    // Slots holding an entry, including the ones hidden by `mark_removed`.
+    pub occupied: usize,
+    // Slots of the region, from the first to the last slot the range may use.
+    pub slots: usize,
+    // `occupied / slots`, 0 for an empty region.
+    pub density: f64,
+}
+
+pub struct RangeSlices<'a, K: Ord, V> {
+    pma: &'a PackedMemoryArray<K, V>,
+    // The slots left to scan.
+    from: usize,
+    to: usize,
+    marked: &'a BTreeSet<K>,
+}
+
+impl<'a, K: Ord, V> RangeSlices<'a, K, V> {
+    // Whether the occupied slot `index` holds an entry not hidden by `mark_removed`.
+    #[inline]
+    fn is_live(&self, index: usize) -> bool {
+        self.marked.is_empty()
+            || self
+                .pma
+                .key(index)
+                .is_some_and(|k| !self.marked.contains(k))
+    }
+}
+
+impl<'a, K: Ord, V> Iterator for RangeSlices<'a, K, V> {
+    type Item = (&'a [K], &'a [V]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut start = self.from;
+        let start = loop {
+            start = self.pma.next_occupied(start, self.to)?;
+            if self.is_live(start) {
+                break start;
+            }
+            start += 1;
+        };
+        let mut end = self.pma.next_free(start, self.to).unwrap_or(self.to);
+        if !self.marked.is_empty() {
+            end = (start..end).find(|&i| !self.is_live(i)).unwrap_or(end);
+        }
+        self.from = end;
+        Some(self.pma.run(start, end))
+    }
+}
+
+#[cfg(test)]
+mod btree_map {
+    use crate::{
+        cache_oblivious::{BTreeMap, Hint, Node, RangeSlices},
+        layout::IndexLayout,
+        stats::Stats,
+        CoBTreeError,
+    };
+    use float_ord::FloatOrd;
+    use rand::{seq::SliceRandom, thread_rng, Rng, SeedableRng};
+    use std::ops::Bound;
+
+    #[test]
+    fn test_other_keys() {
+        let mut map = BTreeMap::<FloatOrd<f32>, usize>::new();
+        for i in 1000..1128 {
+            assert_eq!(map.insert(FloatOrd(i as f32), i), None);
+            assert_eq!(map.len(), i - 999);
+        }
+
+        for i in 1000..1128 {
+            assert_eq!(map.remove(&FloatOrd(i as f32)), Some(i));
+            assert_eq!(map.len(), 127);
+            assert_eq!(map.insert(FloatOrd((i + 128) as f32), i + 128), None);
+            assert_eq!(map.len(), 128);
+        }
+    }
+
+    #[test]
+    fn test_opertions() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.get_all_key_values(), []);
+
+        assert_eq!(map.insert(1, 11), None);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get_all_key_values(), [(&1, &11)]);
+
+        assert_eq!(map.insert(3, 33), None);
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get_all_key_values(), [(&1, &11), (&3, &33)]);
+
+        assert_eq!(map.insert(0, 0), None);
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get_all_key_values(), [(&0, &0), (&1, &11), (&3, &33)]);
+
+        assert_eq!(map.insert(5, 555), None);
+        assert_eq!(map.len(), 4);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&0, &0), (&1, &11), (&3, &33), (&5, &555)]
+        );
+
+        assert_eq!(map.insert(2, 2222), None);
+        assert_eq!(map.len(), 5);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&0, &0), (&1, &11), (&2, &2222), (&3, &33), (&5, &555)]
+        );
+
+        assert_eq!(map.insert(1, 1000), Some(11));
+        assert_eq!(map.len(), 5);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&0, &0), (&1, &1000), (&2, &2222), (&3, &33), (&5, &555)]
+        );
+
+        assert_eq!(map.insert(4, 44444), None);
+        assert_eq!(map.len(), 6);
+        assert_eq!(
+            map.get_all_key_values(),
+            [
+                (&0, &0),
+                (&1, &1000),
+                (&2, &2222),
+                (&3, &33),
+                (&4, &44444),
+                (&5, &555)
+            ]
+        );
+
+        assert_eq!(map.insert(0, 44444), Some(0));
+        assert_eq!(map.len(), 6);
+        assert_eq!(
+            map.get_all_key_values(),
+            [
+                (&0, &44444),
+                (&1, &1000),
+                (&2, &2222),
+                (&3, &33),
+                (&4, &44444),
+                (&5, &555)
+            ]
+        );
+
+        assert_eq!(map.remove(&0), Some(44444));
+        assert_eq!(map.len(), 5);
+        assert_eq!(
+            map.get_all_key_values(),
+            [
+                (&1, &1000),
+                (&2, &2222),
+                (&3, &33),
+                (&4, &44444),
+                (&5, &555)
+            ]
+        );
+
+        assert_eq!(map.remove(&0), None);
+        assert_eq!(map.len(), 5);
+        assert_eq!(
+            map.get_all_key_values(),
+            [
+                (&1, &1000),
+                (&2, &2222),
+                (&3, &33),
+                (&4, &44444),
+                (&5, &555)
+            ]
+        );
+
+        assert_eq!(map.remove(&4), Some(44444));
+        assert_eq!(map.len(), 4);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&1, &1000), (&2, &2222), (&3, &33), (&5, &555)]
+        );
+
+        assert_eq!(map.remove(&4), None);
+        assert_eq!(map.len(), 4);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&1, &1000), (&2, &2222), (&3, &33), (&5, &555)]
+        );
+
+        assert_eq!(map.insert(4, 44), None);
+        assert_eq!(map.len(), 5);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&1, &1000), (&2, &2222), (&3, &33), (&4, &44), (&5, &555)]
+        );
+
+        assert_eq!(map.insert(5, 55), Some(555));
+        assert_eq!(map.len(), 5);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&1, &1000), (&2, &2222), (&3, &33), (&4, &44), (&5, &55)]
+        );
+
+        assert_eq!(map.remove(&1), Some(1000));
+        assert_eq!(map.len(), 4);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&2, &2222), (&3, &33), (&4, &44), (&5, &55)]
+        );
+
+        assert_eq!(map.remove(&5), Some(55));
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&2, &2222), (&3, &33), (&4, &44)]
+        );
+
+        assert_eq!(map.remove(&6), None);
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&2, &2222), (&3, &33), (&4, &44)]
+        );
+
+        assert_eq!(map.get(&3), Some(&33));
+        assert_eq!(map.get(&2), Some(&2222));
+        assert_eq!(map.get(&100), None);
+
+        assert_eq!(map.get_top_k_key_values(1), [(&2, &2222)]);
+        assert_eq!(map.get_top_k_key_values(2), [(&2, &2222), (&3, &33)]);
+        assert_eq!(
+            map.get_top_k_key_values(3),
+            [(&2, &2222), (&3, &33), (&4, &44)]
+        );
+        assert_eq!(
+            map.get_top_k_key_values(4),
+            [(&2, &2222), (&3, &33), (&4, &44)]
+        );
+
+        assert_eq!(map.remove(&3), Some(33));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get_all_key_values(), [(&2, &2222), (&4, &44)]);
+
+        assert_eq!(map.insert(3, 33), None);
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&2, &2222), (&3, &33), (&4, &44)]
+        );
+
+        assert_eq!(map.insert(3, 66), Some(33));
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&2, &2222), (&3, &66), (&4, &44)]
+        );
+
+        assert_eq!(map.remove(&5), None);
+        assert_eq!(map.len(), 3);
+        assert_eq!(
+            map.get_all_key_values(),
+            [(&2, &2222), (&3, &66), (&4, &44)]
+        );
+
+        assert_eq!(map.remove(&4), Some(44));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get_all_key_values(), [(&2, &2222), (&3, &66)]);
+        assert_eq!(map.get_top_k_key_values(1), [(&2, &2222)]);
+
+        assert_eq!(map.insert(3, 123), Some(66));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get_all_key_values(), [(&2, &2222), (&3, &123)]);
+
+        assert_eq!(map.insert(2, 321), Some(2222));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get_all_key_values(), [(&2, &321), (&3, &123)]);
+        assert_eq!(map.get_top_k_key_values(1), [(&2, &321)]);
+
+        assert_eq!(map.remove(&3), Some(123));
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get_all_key_values(), [(&2, &321)]);
+
+        assert_eq!(map.remove(&3), None);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.get_all_key_values(), [(&2, &321)]);
+        assert_eq!(map.get_top_k_key_values(1), [(&2, &321)]);
+
+        assert_eq!(map.remove(&2), Some(321));
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.get_all_key_values(), []);
+
+        assert_eq!(map.remove(&2), None);
+        assert_eq!(map.len(), 0);
+        assert_eq!(map.get_all_key_values(), []);
+    }
+
+    #[test]
+    fn test_range_slices() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..200 {
+            map.insert(i, i * 10);
+        }
+        for i in (0..200).step_by(3) {
+            map.remove(&i);
+        }
+        let expected = |from: usize, to: usize| {
+            (from..to)
+                .filter(|i| i % 3 != 0)
+                .map(|i| (i, i * 10))
+                .collect::<Vec<(usize, usize)>>()
+        };
+        let collect = |slices: RangeSlices<usize, usize>| {
+            let mut result = vec![];
+            for (keys, values) in slices {
+                assert!(!keys.is_empty());
+                assert_eq!(keys.len(), values.len());
+                result.extend(keys.iter().copied().zip(values.iter().copied()));
+            }
+            result
+        };
+        assert_eq!(collect(map.range_slices(..)), expected(0, 200));
+        assert_eq!(collect(map.range_slices(10..50)), expected(10, 50));
+        assert_eq!(collect(map.range_slices(10..=50)), expected(10, 51));
+        assert_eq!(
+            collect(map.range_slices((Bound::Excluded(10), Bound::Included(50)))),
+            expected(11, 51)
+        );
+        assert_eq!(collect(map.range_slices(150..)), expected(150, 200));
+        assert_eq!(collect(map.range_slices(300..)), []);
+        assert_eq!(
+            collect(map.range_slices((Bound::Included(50), Bound::Excluded(10)))),
+            []
+        );
+        assert_eq!(
+            collect(BTreeMap::<usize, usize>::new().range_slices(..)),
+            []
+        );
+    }
+
+    #[test]
+    fn test_merge_build() {
+        let map = BTreeMap::<usize, usize>::merge_build(Vec::<std::vec::IntoIter<_>>::new());
+        assert!(map.is_empty());
+        assert_eq!(map.get_all_key_values(), []);
+
+        let sources = vec![
+            (0..300).step_by(3).map(|i| (i, 0)).collect::<Vec<_>>(),
+            vec![],
+            (0..300).step_by(2).map(|i| (i, 2)).collect::<Vec<_>>(),
+            (100..200).map(|i| (i, 3)).collect::<Vec<_>>(),
+        ];
+        let mut expected = std::collections::BTreeMap::new();
+        sources.iter().flatten().for_each(|&(k, v)| {
+            expected.insert(k, v);
+        });
+        let mut map = BTreeMap::merge_build(sources.into_iter().map(|s| s.into_iter()).collect());
+        assert_eq!(map.len(), expected.len());
         assert_eq!(
             map.get_all_key_values(),
-            [(&0, &0), (&1, &11), (&2, &2222), (&3, &33), (&5, &555)]
+            expected.iter().collect::<Vec<(&usize, &usize)>>()
+        );
+        for (k, v) in expected.iter() {
+            assert_eq!(map.get(k), Some(v));
+        }
+        // Make sure the built map keeps working under updates.
+        for k in 0..400 {
+            if k % 5 == 0 {
+                assert_eq!(map.remove(&k), expected.remove(&k));
+            } else {
+                assert_eq!(map.insert(k, k), expected.insert(k, k));
+            }
+        }
+        assert_eq!(
+            map.get_all_key_values(),
+            expected.iter().collect::<Vec<(&usize, &usize)>>()
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_compact_clone() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert_eq!(map.compact_clone().get_all_key_values(), []);
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        for i in 0..1000 {
+            if i % 10 != 0 {
+                map.remove(&i);
+            }
+        }
+        let mut clone = map.compact_clone();
+        assert_eq!(clone.len(), 100);
+        assert_eq!(clone.get_all_key_values(), map.get_all_key_values());
+        assert!(clone.pma.data_len() * 3 >= 100 * 4);
+        assert!(clone.pma.data_len() * 3 < 100 * 8);
+        assert_eq!(clone.nodes.len(), clone.pma.data_len() << 1);
+        for i in 0..1000 {
+            assert_eq!(clone.get(&i), map.get(&i));
+        }
+        assert_eq!(clone.insert(5, 5), None);
+        assert_eq!(clone.remove(&10), Some(10));
+        assert_eq!(map.get(&5), None);
+        assert_eq!(map.get(&10), Some(&10));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_invariants() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        map.check_invariants();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(17);
+        for step in 0..3000 {
+            let key = rng.gen_range(0..1000);
+            match rng.gen_range(0..10) {
+                0..=5 => {
+                    map.insert(key, key);
+                }
+                6 | 7 => {
+                    map.remove(&key);
+                }
+                8 => {
+                    map.mark_removed(&key);
+                }
+                _ => {
+                    map.remove_range(key..key + 20);
+                }
+            }
+            if step % 100 == 0 {
+                map.check_invariants();
+            }
+            if step == 1500 {
+                map.purge_marked();
+                map.set_layout(IndexLayout::Bfs);
+            }
+        }
+        map.check_invariants();
+        map.shrink_to_fit();
+        map.check_invariants();
+        map.clear();
+        map.check_invariants();
+    }
+
+    #[test]
+    fn test_dot() {
+        let mut map = BTreeMap::<&str, usize>::new();
+        assert_eq!(
+            map.to_dot(),
+            "digraph BTreeMap {\n    node [shape=record];\n    subgraph cluster_pma {\n        \
+             label=\"PMA\";\n        s0 [label=\"<p0> -\"];\n    }\n}\n"
+        );
+        for (i, k) in ["a", "b|c", "d"].into_iter().enumerate() {
+            map.insert(k, i);
+        }
+        map.mark_removed("d");
+        let dot = map.to_dot();
+        assert!(dot.contains(r##"n1 [label="#0|(\"d\")"];"##), "{}", dot);
+        assert!(dot.contains(r##"n2 [label="#1|\"a\""];"##), "{}", dot);
+        assert!(dot.contains(r#"\"b\|c\""#), "{}", dot);
+        // Every slot hangs off one branch.
+        assert_eq!(dot.matches(":p").count(), map.pma.data_len());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_stats() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert_eq!(map.stats(), None);
+        map.enable_stats();
+        assert_eq!(map.stats(), Some(Stats::default()));
+        for i in 0..1024 {
+            map.insert(i, i);
+        }
+        let stats = map.stats().unwrap();
+        assert_eq!(stats.operations, 1024);
+        // A rebuild per doubling of the PMA.
+        assert_eq!(stats.rebuilds, 11);
+        assert!(stats.rebalances >= stats.rebuilds);
+        assert!(
+            stats.moved >= 1024 && stats.nodes_touched >= 2047,
+            "{:?}",
+            stats
+        );
+        // Appends find room at the end of every window they rebalance, so they move about as
+        // many entries as inserts at the front do, far fewer than the map holds.
+        let moved = |keys: &mut dyn Iterator<Item = usize>| {
+            let mut map = BTreeMap::<usize, usize>::new();
+            map.enable_stats();
+            keys.for_each(|k| {
+                map.insert(k, k);
+            });
+            map.stats().unwrap().moved / 4096
+        };
+        let (ascending, descending) = (moved(&mut (0..4096)), moved(&mut (0..4096).rev()));
+        assert!(
+            ascending < 256 && ascending < descending * 2,
+            "{}",
+            ascending
+        );
+
+        map.reset_stats();
+        map.insert(0, 1);
+        let stats = map.stats().unwrap();
+        assert_eq!((stats.operations, stats.moved, stats.rebalances), (1, 0, 0));
+        map.remove(&5);
+        assert_eq!(map.disable_stats().unwrap().operations, 2);
+        map.insert(5, 5);
+        assert_eq!(map.stats(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_hints() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let mut hint = Hint::default();
+        assert_eq!(map.get_hint(&5, &mut hint), None);
+        for i in 0..2000 {
+            assert_eq!(map.insert_hint(i * 2, i, &mut hint), None);
+        }
+        assert_eq!(map.insert_hint(10, 0, &mut hint), Some(5));
+        assert_eq!(map.insert_hint(11, 0, &mut hint), None);
+        for (i, (k, v)) in map.iter().enumerate() {
+            match i {
+                5 => assert_eq!((*k, *v), (10, 0)),
+                6 => assert_eq!((*k, *v), (11, 0)),
+                _ => assert_eq!(*k, *v * 2),
+            }
+        }
+        // Stale and far hints still find every key, backwards and forwards.
+        for i in (0..4000).rev().chain(0..4000) {
+            let expected = map.get(&i);
+            assert_eq!(map.get_hint(&i, &mut hint), expected);
+            let mut far = Hint::default();
+            assert_eq!(map.get_hint(&i, &mut far), expected);
+        }
+        let mut values: Vec<usize> = (0..1000).collect();
+        values.shuffle(&mut thread_rng());
+        for &v in &values {
+            map.insert_hint(v * 4 + 1, v, &mut hint);
+        }
+        map.remove(&4);
+        map.mark_removed(&8);
+        assert_eq!(map.get_hint(&4, &mut hint), None);
+        assert_eq!(map.get_hint(&8, &mut hint), None);
+        assert_eq!(map.insert_hint(8, 8, &mut hint), None);
+        assert_eq!(map.get_hint(&8, &mut hint), Some(&8));
+        assert_eq!(map.len(), 3000);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_cursors() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let empty = map.cursor();
+        assert_eq!(map.cursor_peek(&empty), None);
+        assert_eq!(map.cursor_next(&empty), None);
+        map.release_cursor(empty);
+
+        for i in (0..1000).step_by(2) {
+            map.insert(i, i);
+        }
+        let cursor = map.cursor();
+        let middle = map.cursor_at(&501);
+        assert_eq!(map.cursor_peek(&middle), Some((&502, &502)));
+        let mut values: Vec<usize> = (0..1000).collect();
+        values.shuffle(&mut thread_rng());
+        let mut expected = (0..1000)
+            .step_by(2)
+            .collect::<std::collections::BTreeSet<usize>>();
+        // The next entry of a paused cursor is always the smallest key after the last one it
+        // returned, whatever was written in between.
+        let mut last = None;
+        let next_expected =
+            |expected: &std::collections::BTreeSet<usize>, last: Option<usize>| match last {
+                Some(last) => expected.range(last + 1..).next().copied(),
+                None => expected.iter().next().copied(),
+            };
+        for (step, &v) in values.iter().enumerate() {
+            if step % 2 == 0 {
+                let next = map.cursor_next(&cursor).map(|(&k, _)| k);
+                assert_eq!(next, next_expected(&expected, last));
+                last = next.or(last);
+            }
+            if v % 3 == 0 {
+                map.remove(&v);
+                expected.remove(&v);
+            } else {
+                map.insert(v, v);
+                expected.insert(v);
+            }
+        }
+        loop {
+            let next = map.cursor_next(&cursor).map(|(&k, _)| k);
+            assert_eq!(next, next_expected(&expected, last));
+            match next {
+                Some(_) => last = next,
+                None => break,
+            }
+        }
+        assert_eq!(
+            map.cursor_next(&middle).map(|(&k, _)| k),
+            next_expected(&expected, Some(501))
+        );
+        map.release_cursor(cursor);
+        map.release_cursor(middle);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_dropped_cursors() {
+        let mut map = (0..1000usize).map(|i| (i, i)).collect::<BTreeMap<_, _>>();
+        let kept = map.cursor_at(&500);
+        for i in 0..100 {
+            let cursor = map.cursor_at(&i);
+            assert_eq!(map.cursor_next(&cursor), Some((&i, &i)));
+        }
+        // Every dropped cursor left its slot to the next one.
+        let cursor = map.cursor();
+        assert_eq!((kept.id, cursor.id), (0, 1));
+        drop(cursor);
+        for i in 1000..3000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.pma.live_cursor_count(), 1);
+        assert_eq!(map.cursor_next(&kept), Some((&500, &500)));
+        map.check_invariants();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    #[should_panic(expected = "another map")]
+    fn test_foreign_cursor() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let mut other = BTreeMap::<usize, usize>::new();
+        let _own = map.cursor();
+        let cursor = other.cursor();
+        map.cursor_next(&cursor);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_par_rebuild() {
+        let layouts = [IndexLayout::VanEmdeBoas, IndexLayout::Bfs];
+        for (n, layout) in [5000usize, 70000]
+            .into_iter()
+            .flat_map(|n| layouts.map(|l| (n, l)))
+        {
+            let mut map = BTreeMap::<usize, usize>::with_layout(layout);
+            map.pma = crate::packed_memory_array::PackedMemoryArray::from_sorted(
+                (0..n).map(|i| (i * 2, i)).collect(),
+            );
+            map.size = n;
+            map.par_rebuild();
+            let nodes = map.nodes.to_vec();
+            map.rebuild_serial();
+            assert!(nodes[..] == map.nodes[..]);
+            for i in 0..n {
+                assert_eq!(map.get(&(i * 2)), Some(&i));
+                assert_eq!(map.get(&(i * 2 + 1)), None);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_layouts() {
+        let mut map = BTreeMap::<usize, usize>::with_layout(IndexLayout::EYTZINGER);
+        let mut expected = std::collections::BTreeMap::new();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        for _ in 0..5000 {
+            let key = rng.gen_range(0..2000);
+            if rng.gen_bool(0.6) {
+                assert_eq!(map.insert(key, key), expected.insert(key, key));
+            } else {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            }
+        }
+        for layout in [IndexLayout::VanEmdeBoas, IndexLayout::Bfs] {
+            map.set_layout(layout);
+            assert_eq!(map.layout(), layout);
+            for key in 0..2000 {
+                assert_eq!(map.get(&key), expected.get(&key));
+            }
+            assert!(map.range(500..1500).eq(expected.range(500..1500)));
+            assert_eq!(map.rank(&1000), expected.range(..1000).count());
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_adaptive() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        map.set_adaptive(true);
+        assert!(map.is_adaptive());
+        let mut expected = std::collections::BTreeMap::new();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(8);
+        for i in 0..4000 {
+            let key = match (i / 500) % 3 {
+                0 => 100_000 + i,
+                1 => 100_000 - i,
+                _ => rng.gen_range(0..200_000),
+            };
+            assert_eq!(map.insert(key, i), expected.insert(key, i));
+            if i % 4 == 0 {
+                let key = rng.gen_range(0..200_000);
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            }
+        }
+        assert!(map.iter().eq(expected.iter()));
+        for key in expected.keys() {
+            assert_eq!(map.get(key), expected.get(key));
+        }
+    }
+
+    #[test]
+    fn test_versions() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert_eq!(map.get_versioned(&1), None);
+        let mut last = map.version();
+        let mut check_bumped = |map: &BTreeMap<usize, usize>, bumped: bool| {
+            assert_eq!(map.version() > last, bumped);
+            last = map.version();
+        };
+        map.insert(1, 10);
+        check_bumped(&map, true);
+        assert_eq!(map.get_versioned(&1), Some((&10, map.version())));
+        map.insert(1, 11);
+        check_bumped(&map, true);
+        assert_eq!(map.get_versioned(&1), Some((&11, map.version())));
+        map.get(&1);
+        check_bumped(&map, false);
+        map.remove(&2);
+        check_bumped(&map, false);
+        map.remove(&1);
+        check_bumped(&map, true);
+        map.insert(2, 2);
+        let cursor = map.cursor();
+        map.clear();
+        check_bumped(&map, true);
+        assert!(map.is_empty());
+        assert_eq!(map.get_all_key_values(), []);
+        assert_eq!(map.cursor_next(&cursor), None);
+        map.insert(3, 3);
+        assert_eq!(map.cursor_next(&cursor), Some((&3, &3)));
+        check_bumped(&map, true);
+    }
+
+    #[test]
+    fn test_mark_and_purge() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        let cursor = map.cursor_at(&50);
+        for i in (0..100).step_by(2) {
+            assert!(map.mark_removed(&i));
+        }
+        assert!(!map.mark_removed(&0));
+        assert!(!map.mark_removed(&1000));
+        assert_eq!(map.len(), 50);
+        assert_eq!(map.marked_len(), 50);
+        let odd = (1..100).step_by(2).collect::<Vec<usize>>();
+        assert_eq!(map.key_vec(), odd.iter().collect::<Vec<&usize>>());
+        assert_eq!(map.get(&2), None);
+        assert_eq!(map.get(&3), Some(&3));
+        assert_eq!(map.get_first_key(), Some(&1));
+        assert!(map
+            .range_slices(..)
+            .all(|(keys, _)| keys.len() == 1 && keys[0] % 2 == 1));
+        assert_eq!(map.cursor_peek(&cursor), Some((&51, &51)));
+
+        // Inserting a hidden key brings it back as a fresh entry, removing one just drops it.
+        assert_eq!(map.insert(4, 44), None);
+        assert_eq!(map.get(&4), Some(&44));
+        assert_eq!(map.remove(&6), None);
+        assert_eq!(map.len(), 51);
+        assert_eq!(map.marked_len(), 48);
+
+        assert_eq!(map.purge_marked(), 48);
+        assert_eq!(map.purge_marked(), 0);
+        assert_eq!(map.len(), 51);
+        assert_eq!(map.marked_len(), 0);
+        let mut expected = odd.clone();
+        expected.push(4);
+        expected.sort();
+        assert_eq!(map.key_vec(), expected.iter().collect::<Vec<&usize>>());
+        for i in 0..100 {
+            assert_eq!(map.get(&i).is_some(), expected.contains(&i));
+        }
+        assert_eq!(map.cursor_next(&cursor), Some((&51, &51)));
+        assert_eq!(map.insert(2, 2), None);
+        assert_eq!(map.len(), 52);
+        assert!(map.mark_removed(&2));
+        map.clear();
+        assert_eq!(map.marked_len(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_meta() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert!(!map.set_meta(&1, 1));
+        for i in (0..500).step_by(2) {
+            map.insert(i, i);
+        }
+        assert_eq!(map.get_meta(&2), Some(0));
+        assert_eq!(map.get_meta(&3), None);
+        for i in (0..500).step_by(2) {
+            assert!(map.set_meta(&i, i as u64 * 3));
+        }
+        // Interleaved writes shuffle the PMA around the entries.
+        for i in (1..500).step_by(2) {
+            map.insert(i, i);
+        }
+        map.insert(4, 44);
+        for i in (0..500).step_by(6) {
+            map.remove(&i);
+        }
+        assert!(map.mark_removed(&8));
+        assert_eq!(map.get_meta(&8), None);
+        map.purge_marked();
+        for i in 0..500 {
+            let expected = match i {
+                _ if i % 6 == 0 || i == 8 => None,
+                _ if i % 2 == 1 => Some(0),
+                _ => Some(i as u64 * 3),
+            };
+            assert_eq!(map.get_meta(&i), expected);
+        }
+        let clone = map.compact_clone();
+        for i in 0..500 {
+            assert_eq!(clone.get_meta(&i), map.get_meta(&i));
+        }
+        map.clear();
+        map.insert(1, 1);
+        assert_eq!(map.get_meta(&1), Some(0));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_buffer_reuse() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        let nodes = map.nodes.as_ptr();
+        let nodes_capacity = map.nodes.capacity();
+        let pma_capacity = map.pma.capacity();
+        for _ in 0..3 {
+            map.clear();
+            assert!(map.is_empty());
+            assert_eq!(map.get(&1), None);
+            for i in 0..1000 {
+                map.insert(i, i);
+            }
+            assert_eq!(map.nodes.as_ptr(), nodes);
+            assert_eq!(map.nodes.capacity(), nodes_capacity);
+            assert_eq!(map.pma.capacity(), pma_capacity);
+        }
+        for i in 0..990 {
+            map.remove(&i);
+        }
+        map.shrink_to_fit();
+        assert_eq!(map.nodes.capacity(), map.nodes.len());
+        assert_eq!(map.pma.capacity(), map.pma.data_len());
+        assert_eq!(
+            map.key_vec(),
+            (990..1000)
+                .collect::<Vec<usize>>()
+                .iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_join_sorted() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..200 {
+            map.insert(i * 3, i);
+        }
+        map.mark_removed(&30);
+        let items = [0, 1, 3, 3, 30, 299, 597, 600, 603]
+            .into_iter()
+            .map(|k| (k, k * 10));
+        let mut joined = vec![];
+        let matched = map.join_sorted(items, |&k, &v, item| joined.push((k, v, item)));
+        assert_eq!(matched, 4);
+        assert_eq!(
+            joined,
+            vec![(0, 0, 0), (3, 1, 30), (3, 1, 30), (597, 199, 5970)]
+        );
+        assert_eq!(
+            BTreeMap::<usize, usize>::new().join_sorted([(1, ())], |_, _, _| {}),
+            0
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_range_stats() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let stats = map.range_stats(..);
+        assert_eq!((stats.entries, stats.occupied, stats.density), (0, 0, 0.0));
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        map.mark_removed(&500);
+        let all = map.range_stats(..);
+        assert_eq!(all.entries, 999);
+        assert_eq!(all.occupied, 1000);
+        assert_eq!(all.slots, map.pma.data_len());
+        assert_eq!(all.density, 1000.0 / all.slots as f64);
+
+        let part = map.range_stats(400..600);
+        assert_eq!((part.entries, part.occupied), (199, 200));
+        assert!(part.slots >= 200 && part.slots < all.slots);
+        assert!(part.density > 0.0 && part.density <= 1.0);
+        assert_eq!(map.range_stats(2000..).entries, 0);
+    }
+
+    #[test]
+    fn test_deferred_drop() {
+        use std::sync::Arc;
+
+        let tracker = Arc::new(());
+        let mut map = BTreeMap::<usize, Arc<()>>::new();
+        for i in 0..100 {
+            map.insert(i, tracker.clone());
+        }
+        // Without deferring, values are dropped in place.
+        assert!(map.discard(&0));
+        assert!(!map.discard(&0));
+        assert_eq!(Arc::strong_count(&tracker), 100);
+        assert_eq!(map.deferred_len(), 0);
+
+        map.defer_drops(true);
+        assert!(map.discard(&1));
+        map.mark_removed(&2);
+        map.mark_removed(&3);
+        assert_eq!(map.purge_marked(), 2);
+        assert_eq!(map.deferred_len(), 3);
+        assert_eq!(Arc::strong_count(&tracker), 100);
+        map.drain_deferred();
+        assert_eq!(Arc::strong_count(&tracker), 97);
+
+        map.clear();
+        assert!(map.is_empty());
+        assert_eq!(map.deferred_len(), 96);
+        assert_eq!(Arc::strong_count(&tracker), 97);
+        let batch = map.take_deferred();
+        assert_eq!(map.deferred_len(), 0);
+        drop(batch);
+        assert_eq!(Arc::strong_count(&tracker), 1);
+
+        map.insert(1, tracker.clone());
+        map.discard(&1);
+        map.defer_drops(false);
+        assert_eq!(Arc::strong_count(&tracker), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_get_surrounding() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let empty = map.get_surrounding(&1, 3);
+        assert!(empty.before.is_empty() && empty.exact.is_none() && empty.after.is_empty());
+        for i in 0..500 {
+            map.insert(i * 2, i);
+        }
+        map.mark_removed(&96);
+        let keys =
+            |entries: &[(&usize, &usize)]| entries.iter().map(|(&k, _)| k).collect::<Vec<_>>();
+
+        let around = map.get_surrounding(&100, 3);
+        assert_eq!(keys(&around.before), vec![92, 94, 98]);
+        assert_eq!(around.exact, Some((&100, &50)));
+        assert_eq!(keys(&around.after), vec![102, 104, 106]);
+
+        let between = map.get_surrounding(&101, 2);
+        assert_eq!(keys(&between.before), vec![98, 100]);
+        assert_eq!(between.exact, None);
+        assert_eq!(keys(&between.after), vec![102, 104]);
+
+        let edge = map.get_surrounding(&0, 2);
+        assert!(edge.before.is_empty());
+        assert_eq!(edge.exact, Some((&0, &0)));
+        let end = map.get_surrounding(&5000, 2);
+        assert_eq!(keys(&end.before), vec![996, 998]);
+        assert!(end.after.is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_range() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert_eq!(map.range(..).next(), None);
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..600 {
+            let key = (i * 37) % 1000;
+            map.insert(key, i);
+            expected.insert(key, i);
+        }
+        for i in (0..1000).step_by(3) {
+            map.remove(&i);
+            expected.remove(&i);
+        }
+        map.mark_removed(&500);
+        expected.remove(&500);
+        let check = |from: Bound<usize>, to: Bound<usize>| {
+            assert_eq!(
+                map.range((from, to)).collect::<Vec<_>>(),
+                expected.range((from, to)).collect::<Vec<_>>()
+            );
+        };
+        check(Bound::Unbounded, Bound::Unbounded);
+        for (a, b) in [(0, 1000), (100, 101), (499, 501), (500, 500), (998, 2000)] {
+            check(Bound::Included(a), Bound::Excluded(b));
+            check(Bound::Included(a), Bound::Included(b));
+            check(Bound::Excluded(a), Bound::Included(b));
+            check(Bound::Unbounded, Bound::Included(b));
+            check(Bound::Excluded(a), Bound::Unbounded);
+        }
+        assert_eq!(
+            map.range((Bound::Included(200), Bound::Excluded(100)))
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_iter() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert_eq!(map.iter().next(), None);
+        for i in (0..500).rev() {
+            map.insert(i * 2, i);
+        }
+        map.remove(&10);
+        map.mark_removed(&20);
+        let iter = map.iter();
+        assert_eq!(iter.len(), 498);
+        assert_eq!(
+            iter.map(|(&k, &v)| (k, v)).collect::<Vec<_>>(),
+            (0..500)
+                .filter(|&i| i != 5 && i != 10)
+                .map(|i| (i * 2, i))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            map.keys().copied().collect::<Vec<_>>(),
+            map.key_vec().into_iter().copied().collect::<Vec<_>>()
+        );
+        assert_eq!(map.values().len(), 498);
+        assert_eq!(map.values().sum::<usize>(), (0..500).sum::<usize>() - 15);
+        assert_eq!((&map).into_iter().count(), 498);
+    }
+
+    #[test]
+    fn test_iter_mut() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..300 {
+            map.insert(i, i);
+        }
+        map.mark_removed(&7);
+        let version = map.version();
+        for (&k, v) in map.iter_mut() {
+            *v += k;
+        }
+        assert!(map.version() > version);
+        for v in map.values_mut() {
+            *v += 1;
+        }
+        for (_, v) in &mut map {
+            *v *= 10;
+        }
+        assert_eq!(map.iter_mut().len(), 299);
+        assert_eq!(map.get(&3), Some(&70));
+        assert_eq!(map.get(&299), Some(&5990));
+        // The hidden entry was not touched.
+        map.insert(7, 0);
+        assert_eq!(map.get(&7), Some(&0));
+        map.insert(1000, 1);
+        assert_eq!(map.get(&1000), Some(&1));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_into_iter() {
+        // Values that cannot be cloned cheaply are moved, not cloned.
+        #[derive(Debug, PartialEq)]
+        struct NoClone(usize);
+        impl Clone for NoClone {
+            fn clone(&self) -> Self {
+                panic!("Value cloned.");
+            }
+        }
+
+        let mut map = BTreeMap::new();
+        for i in (0..400).rev() {
+            map.insert(i, NoClone(i));
+        }
+        map.mark_removed(&100);
+        map.remove(&200);
+        let iter = map.into_iter();
+        assert_eq!(iter.len(), 398);
+        let entries = iter.collect::<Vec<_>>();
+        assert_eq!(entries.len(), 398);
+        assert!(entries.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(entries
+            .iter()
+            .all(|(k, v)| v.0 == *k && *k != 100 && *k != 200));
+        assert_eq!(BTreeMap::<usize, usize>::new().into_iter().next(), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<BTreeMap<String, Vec<u8>>>();
+
+        let map = std::sync::Arc::new(std::sync::Mutex::new(BTreeMap::new()));
+        std::thread::scope(|scope| {
+            for t in 0..4u32 {
+                let map = map.clone();
+                scope.spawn(move || {
+                    for i in 0..250 {
+                        map.lock().unwrap().insert(i * 4 + t, t);
+                    }
+                });
+            }
+        });
+        let map = map.lock().unwrap();
+        assert_eq!(map.len(), 1000);
+        assert!(map
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .eq((0..1000).map(|i| (i, i % 4))));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_non_clone_keys() {
+        // Keys owning a unique resource, the index refers to them by slot.
+        #[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
+        struct Handle(Box<usize>);
+
+        let mut map = BTreeMap::new();
+        for i in (0..1000).rev() {
+            map.insert(Handle(Box::new(i * 2)), i);
+        }
+        for i in 0..1000 {
+            assert_eq!(map.get(&Handle(Box::new(i * 2))), Some(&i));
+            assert!(!map.contains_key(&Handle(Box::new(i * 2 + 1))));
+        }
+        for i in 0..500 {
+            assert_eq!(map.remove(&Handle(Box::new(i * 4))), Some(i * 2));
+        }
+        assert_eq!(map.len(), 500);
+        assert!(map
+            .range(Handle(Box::new(100))..Handle(Box::new(120)))
+            .map(|(k, _)| *k.0)
+            .eq([102, 106, 110, 114, 118]));
+        assert_eq!(map.first_key_value().map(|(k, _)| *k.0), Some(2));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_move_only_values() {
+        let mut map = BTreeMap::<usize, Box<dyn Fn(usize) -> usize + Send + Sync>>::new();
+        for i in (0..1000).rev() {
+            map.insert(i, Box::new(move |x| x + i));
+        }
+        for i in 0..500 {
+            assert!(map.remove(&(i * 2)).is_some());
+        }
+        assert_eq!(map.len(), 500);
+        assert_eq!(map.get(&7).map(|f| f(1)), Some(8));
+        assert!(map.iter().all(|(k, f)| f(0) == *k && k % 2 == 1));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut channels = BTreeMap::new();
+        channels.insert(1, sender);
+        channels.get(&1).unwrap().send(5).unwrap();
+        assert_eq!(receiver.recv(), Ok(5));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_rank_select() {
+        let mut map = BTreeMap::new();
+        assert_eq!(map.rank(&5), 0);
+        assert_eq!(map.select(0), None);
+        for i in (0..1000).rev() {
+            map.insert(i * 2, i);
+        }
+        for i in 0..500 {
+            map.remove(&(i * 4));
+        }
+        for k in [2, 602, 1998] {
+            map.mark_removed(&k);
+        }
+        let keys = map.keys().copied().collect::<Vec<_>>();
+        assert_eq!(keys.len(), 497);
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(map.rank(k), i);
+            assert_eq!(map.rank(&(k + 1)), i + 1);
+            assert_eq!(map.select(i).map(|(k, _)| *k), Some(*k));
+        }
+        assert_eq!(map.rank(&0), 0);
+        assert_eq!(map.rank(&5000), 497);
+        assert_eq!(map.select(497), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_range_count() {
+        let mut map = BTreeMap::new();
+        assert_eq!(map.range_count(..), 0);
+        for i in (0..1000).rev() {
+            map.insert(i * 2, i);
+        }
+        map.mark_removed(&100);
+        map.remove(&200);
+        assert_eq!(map.range_count(..), 998);
+        for (from, to) in [
+            (0, 2000),
+            (99, 101),
+            (100, 201),
+            (1, 1),
+            (500, 400),
+            (1990, 5000),
+        ] {
+            assert_eq!(map.range_count(from..to), map.range(from..to).count());
+            assert_eq!(map.range_count(from..=to), map.range(from..=to).count());
+        }
+        assert_eq!(
+            map.range_count((Bound::Excluded(98), Bound::Unbounded)),
+            map.range(99..).count()
         );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_with_capacity() {
+        let mut map = BTreeMap::with_capacity(1500);
+        let len = map.pma.data_len();
+        assert!(len >= 2000);
+        for i in (0..1500).rev() {
+            map.insert(i, i);
+        }
+        assert_eq!(map.pma.data_len(), len);
+        map.reserve(0);
+        assert_eq!(map.pma.data_len(), len);
+
+        let cursor = map.cursor_at(&700);
+        map.set_meta(&10, 5);
+        map.mark_removed(&20);
+        map.reserve(20000);
+        assert!(map.pma.data_len() >= 20000);
+        assert_eq!(map.len(), 1499);
+        assert_eq!(map.cursor_next(&cursor), Some((&700, &700)));
+        assert_eq!(map.get_meta(&10), Some(5));
+        assert_eq!(map.get(&20), None);
+        assert!(map
+            .iter()
+            .map(|(k, _)| *k)
+            .eq((0..1500).filter(|k| *k != 20)));
+        map.release_cursor(cursor);
+    }
+
+    #[test]
+    fn test_try_reserve() {
+        let mut map = BTreeMap::new();
+        for i in 0..100 {
+            map.insert(i, i);
+        }
+        let len = map.pma.data_len();
+        assert!(map.try_reserve(usize::MAX).is_err());
+        assert!(map.try_reserve(usize::MAX >> 4).is_err());
+        assert_eq!(map.pma.data_len(), len);
+        assert_eq!(map.len(), 100);
+        assert!(map.try_reserve(5000).is_ok());
+        assert!(map.pma.data_len() >= 6800);
+        assert!(map.iter().map(|(k, _)| *k).eq(0..100));
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_node_size() {
+        if cfg!(feature = "index32") {
+            assert_eq!(std::mem::size_of::<Node>(), 12);
+            // The index cannot address the slots of a map this large.
+            let mut map = BTreeMap::<u8, ()>::new();
+            assert!(map.try_reserve(1 << 32).is_err());
+        } else {
+            assert_eq!(std::mem::size_of::<Node>(), 24);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_try_raw() {
+        let mut map = BTreeMap::new();
+        for i in (0..1000).rev() {
+            assert_eq!(map.try_insert_raw(i, i), Ok(None));
+        }
+        assert_eq!(map.try_insert_raw(5, 50), Ok(Some(5)));
+        assert_eq!(map.try_remove_raw(&5), Ok(Some(50)));
+        assert_eq!(map.try_remove_raw(&5), Ok(None));
+        map.check_invariants();
+        assert!(map.keys().copied().eq((0..1000).filter(|k| *k != 5)));
 
-        assert_eq!(map.insert(1, 1000), Some(11));
-        assert_eq!(map.len(), 5);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&0, &0), (&1, &1000), (&2, &2222), (&3, &33), (&5, &555)]
-        );
+        // A branch in place of the leaf of a slot breaks the update of the index.
+        let index = map.find_index(&7);
+        let leaf = map.compute_node_index((1 << (map.height - 1)) + index);
+        map.nodes[leaf] = Node::branch(Some(index), 1);
+        assert_eq!(map.try_remove_raw(&7), Err(CoBTreeError::NodeKind));
+    }
 
-        assert_eq!(map.insert(4, 44444), None);
-        assert_eq!(map.len(), 6);
+    #[test]
+    fn test_double_ended() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..300 {
+            map.insert(i, i);
+        }
+        map.mark_removed(&299);
+        map.mark_removed(&150);
+        let latest = map
+            .iter()
+            .rev()
+            .take(3)
+            .map(|(&k, _)| k)
+            .collect::<Vec<_>>();
+        assert_eq!(latest, vec![298, 297, 296]);
+        assert_eq!(map.keys().next_back(), Some(&298));
+        assert_eq!(map.values().rev().nth(1), Some(&297));
         assert_eq!(
-            map.get_all_key_values(),
-            [
-                (&0, &0),
-                (&1, &1000),
-                (&2, &2222),
-                (&3, &33),
-                (&4, &44444),
-                (&5, &555)
-            ]
+            map.range(140..160)
+                .rev()
+                .map(|(&k, _)| k)
+                .collect::<Vec<_>>(),
+            (140..160).rev().filter(|&k| k != 150).collect::<Vec<_>>()
         );
 
-        assert_eq!(map.insert(0, 44444), Some(0));
-        assert_eq!(map.len(), 6);
-        assert_eq!(
-            map.get_all_key_values(),
-            [
-                (&0, &44444),
-                (&1, &1000),
-                (&2, &2222),
-                (&3, &33),
-                (&4, &44444),
-                (&5, &555)
-            ]
-        );
+        // Meeting in the middle yields every entry exactly once.
+        let mut iter = map.iter();
+        let mut seen = vec![];
+        while let Some((&front, _)) = iter.next() {
+            seen.push(front);
+            if let Some((&back, _)) = iter.next_back() {
+                seen.push(back);
+            }
+            assert_eq!(iter.len(), 298 - seen.len());
+        }
+        seen.sort_unstable();
+        assert_eq!(seen, map.key_vec().into_iter().copied().collect::<Vec<_>>());
 
-        assert_eq!(map.remove(&0), Some(44444));
-        assert_eq!(map.len(), 5);
-        assert_eq!(
-            map.get_all_key_values(),
-            [
-                (&1, &1000),
-                (&2, &2222),
-                (&3, &33),
-                (&4, &44444),
-                (&5, &555)
-            ]
-        );
+        if let Some((_, v)) = map.iter_mut().next_back() {
+            *v = 0;
+        }
+        assert_eq!(map.get(&298), Some(&0));
+        assert_eq!(map.values_mut().next_back(), Some(&mut 0));
+        assert_eq!(map.into_iter().next_back(), Some((298, 0)));
+    }
 
-        assert_eq!(map.remove(&0), None);
-        assert_eq!(map.len(), 5);
-        assert_eq!(
-            map.get_all_key_values(),
-            [
-                (&1, &1000),
-                (&2, &2222),
-                (&3, &33),
-                (&4, &44444),
-                (&5, &555)
-            ]
-        );
+    #[test]
+    fn test_get_mut() {
+        let mut map = BTreeMap::<String, usize>::new();
+        for i in 0..100 {
+            map.insert(format!("{:03}", i), i);
+        }
+        map.mark_removed(&String::from("042"));
+        let version = map.version();
+        assert_eq!(map.get_mut("100"), None);
+        assert_eq!(map.get_mut("042"), None);
+        assert_eq!(map.version(), version);
+        *map.get_mut("007").unwrap() += 100;
+        assert_eq!(map.get("007"), Some(&107));
+        assert!(map.version() > version);
+    }
 
-        assert_eq!(map.remove(&4), Some(44444));
-        assert_eq!(map.len(), 4);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&1, &1000), (&2, &2222), (&3, &33), (&5, &555)]
-        );
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_contains_key() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert!(!map.contains_key(&0));
+        for i in 0..500 {
+            map.insert(i * 2, i);
+        }
+        map.remove(&10);
+        map.mark_removed(&20);
+        for i in 0..1100 {
+            assert_eq!(map.contains_key(&i), map.get(&i).is_some());
+        }
+    }
 
-        assert_eq!(map.remove(&4), None);
-        assert_eq!(map.len(), 4);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&1, &1000), (&2, &2222), (&3, &33), (&5, &555)]
-        );
+    #[test]
+    fn test_get_key_value() {
+        let mut map = BTreeMap::<String, usize>::new();
+        map.insert(String::from("alpha"), 1);
+        map.insert(String::from("beta"), 2);
+        let (key, value) = map.get_key_value("beta").unwrap();
+        assert_eq!((key.as_str(), *value), ("beta", 2));
+        assert!(std::ptr::eq(key, map.key_vec()[1]));
+        assert_eq!(map.get_key_value("gamma"), None);
+        map.mark_removed(&String::from("alpha"));
+        assert_eq!(map.get_key_value("alpha"), None);
+    }
 
-        assert_eq!(map.insert(4, 44), None);
-        assert_eq!(map.len(), 5);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&1, &1000), (&2, &2222), (&3, &33), (&4, &44), (&5, &555)]
-        );
+    #[test]
+    fn test_first_last_key_value() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert_eq!((map.first_key_value(), map.last_key_value()), (None, None));
+        for i in 10..300 {
+            map.insert(i, i * 2);
+        }
+        assert_eq!(map.first_key_value(), Some((&10, &20)));
+        assert_eq!(map.last_key_value(), Some((&299, &598)));
+        map.remove(&10);
+        map.mark_removed(&299);
+        assert_eq!(map.first_key_value(), Some((&11, &22)));
+        assert_eq!(map.last_key_value(), Some((&298, &596)));
+        assert_eq!(map.get_first_key(), Some(&11));
+    }
 
-        assert_eq!(map.insert(5, 55), Some(555));
-        assert_eq!(map.len(), 5);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&1, &1000), (&2, &2222), (&3, &33), (&4, &44), (&5, &55)]
-        );
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_extract_if() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        map.mark_removed(&30);
+        let cursor = map.cursor_at(&500);
+        let extracted = map
+            .extract_if(|&k, v| {
+                *v += 1;
+                k % 3 == 0
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(extracted.len(), 333);
+        assert!(extracted.iter().all(|&(k, v)| k % 3 == 0 && v == k + 1));
+        assert_eq!(map.len(), 999 - 333);
+        for i in 0..1000 {
+            let expected = (i % 3 != 0).then_some(i + 1);
+            assert_eq!(map.get(&i).copied(), expected);
+        }
+        assert_eq!(map.cursor_next(&cursor), Some((&500, &501)));
+        map.release_cursor(cursor);
 
-        assert_eq!(map.remove(&1), Some(1000));
-        assert_eq!(map.len(), 4);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&2, &2222), (&3, &33), (&4, &44), (&5, &55)]
-        );
+        // Dropping the iterator early keeps the entries it did not reach.
+        let first = map.extract_if(|_, _| true).take(10).count();
+        assert_eq!(first, 10);
+        assert_eq!(map.len(), 999 - 333 - 10);
+        assert_eq!(map.first_key_value(), Some((&16, &17)));
+        map.insert(1, 1);
+        assert_eq!(map.get(&1), Some(&1));
+    }
 
-        assert_eq!(map.remove(&5), Some(55));
-        assert_eq!(map.len(), 3);
+    #[test]
+    fn test_extract_if_updates() {
+        use crate::Sum;
+        let mut map = (0..1000)
+            .map(|i| (i, 1))
+            .collect::<BTreeMap<usize, usize>>();
+        map.enable_aggregate(Sum);
+        let version = map.version();
+        // Values changed by the predicate count as updates even when nothing is extracted.
         assert_eq!(
-            map.get_all_key_values(),
-            [(&2, &2222), (&3, &33), (&4, &44)]
+            map.extract_if(|_, v| {
+                *v = 2;
+                false
+            })
+            .count(),
+            0
         );
+        assert_eq!(map.range_fold(.., &Sum), 2000);
+        assert!(map.version() > version);
+    }
 
-        assert_eq!(map.remove(&6), None);
-        assert_eq!(map.len(), 3);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&2, &2222), (&3, &33), (&4, &44)]
-        );
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_append() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let mut other = BTreeMap::<usize, usize>::new();
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..600 {
+            map.insert(i * 2, i);
+            expected.insert(i * 2, i);
+        }
+        for i in 0..400 {
+            other.insert(i * 3, i + 10000);
+            expected.insert(i * 3, i + 10000);
+        }
+        // A hidden key of `self` comes back with the value from `other`, a hidden key of
+        // `other` is not carried over.
+        map.set_meta(&4, 44);
+        map.set_meta(&6, 66);
+        map.mark_removed(&6);
+        other.mark_removed(&9);
+        expected.remove(&9);
+        other.set_meta(&3, 33);
+        let cursor = map.cursor_at(&100);
+        assert_eq!(map.cursor_next(&cursor), Some((&100, &50)));
 
-        assert_eq!(map.get(&3), Some(&33));
-        assert_eq!(map.get(&2), Some(&2222));
-        assert_eq!(map.get(&100), None);
+        map.append(&mut other);
+        assert!(other.is_empty());
+        assert_eq!(other.get(&3), None);
+        assert_eq!(map.len(), expected.len());
+        assert!(map.iter().eq(expected.iter()));
+        assert_eq!(map.get_meta(&4), Some(44));
+        assert_eq!(map.get_meta(&6), Some(66));
+        assert_eq!(map.get_meta(&3), Some(33));
+        // The cursor resumes after the last entry it returned, new entries included.
+        assert_eq!(map.cursor_next(&cursor), Some((&102, &10034)));
+        map.release_cursor(cursor);
 
-        assert_eq!(map.get_top_k_key_values(1), [(&2, &2222)]);
-        assert_eq!(map.get_top_k_key_values(2), [(&2, &2222), (&3, &33)]);
-        assert_eq!(
-            map.get_top_k_key_values(3),
-            [(&2, &2222), (&3, &33), (&4, &44)]
-        );
-        assert_eq!(
-            map.get_top_k_key_values(4),
-            [(&2, &2222), (&3, &33), (&4, &44)]
-        );
+        map.append(&mut other);
+        assert_eq!(map.len(), expected.len());
+        other.append(&mut map);
+        assert!(other.iter().eq(expected.iter()));
+        other.insert(1, 1);
+        assert_eq!(other.get(&1), Some(&1));
+    }
 
-        assert_eq!(map.remove(&3), Some(33));
-        assert_eq!(map.len(), 2);
-        assert_eq!(map.get_all_key_values(), [(&2, &2222), (&4, &44)]);
+    #[test]
+    fn test_extend() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let mut expected = std::collections::BTreeMap::new();
+        // Merged batches, then small batches inserted one by one.
+        for (round, len) in [(0, 500), (1, 500), (2, 3), (3, 10)] {
+            let batch = (0..len)
+                .map(|i| ((i * 7 + round) % 1000, i + round))
+                .collect::<Vec<_>>();
+            map.extend(batch.iter().cloned());
+            expected.extend(batch);
+            assert!(map.iter().eq(expected.iter()));
+        }
+        // Duplicates inside a batch keep the last value.
+        map.extend([(5000, 1), (5000, 2)]);
+        assert_eq!(map.get(&5000), Some(&2));
+        let copied = [(6000, 6)];
+        map.extend(copied.iter().map(|(k, v)| (k, v)));
+        assert_eq!(map.get(&6000), Some(&6));
+        assert_eq!(map.len(), expected.len() + 2);
+    }
 
-        assert_eq!(map.insert(3, 33), None);
-        assert_eq!(map.len(), 3);
-        assert_eq!(
-            map.get_all_key_values(),
-            [(&2, &2222), (&3, &33), (&4, &44)]
-        );
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_insert_many() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let mut expected = std::collections::BTreeMap::new();
+        map.insert_many((0..2000).step_by(4).map(|i| (i, i)));
+        expected.extend((0..2000).step_by(4).map(|i| (i, i)));
+        map.set_meta(&800, 8);
+        map.mark_removed(&808);
+        let cursor = map.cursor_at(&805);
+        let slots = map.range_stats(..).slots;
+        // A narrow batch of new and existing keys, with a hidden one coming back, merges into a
+        // window without growing the layout.
+        let batch = (790..830).map(|i| (i, i + 1)).collect::<Vec<_>>();
+        map.insert_many(batch.iter().rev().cloned());
+        expected.extend(batch);
+        assert_eq!(map.range_stats(..).slots, slots);
+        assert_eq!(map.len(), expected.len());
+        assert!(map.iter().eq(expected.iter()));
+        assert_eq!(map.get_meta(&800), Some(8));
+        assert_eq!(map.cursor_next(&cursor), Some((&805, &806)));
+        assert_eq!(map.marked_len(), 0);
+        // A batch spread over the whole map, then one too large for the layout.
+        map.insert_many((0..2000).step_by(100).map(|i| (i + 1, 0)));
+        expected.extend((0..2000).step_by(100).map(|i| (i + 1, 0)));
+        map.insert_many((2000..4000).map(|i| (i, i)));
+        expected.extend((2000..4000).map(|i| (i, i)));
+        assert_eq!(map.len(), expected.len());
+        assert!(map.iter().eq(expected.iter()));
+        for key in [1, 801, 2500, 3999] {
+            assert_eq!(map.get(&key), expected.get(&key));
+        }
+        map.insert_many([(5000, 1), (5000, 2)]);
+        assert_eq!(map.get(&5000), Some(&2));
+        expected.insert(5000, 2);
+        // Clustered batches with removals in between.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        for _ in 0..100 {
+            let start = rng.gen_range(0..6000);
+            let batch = (0..rng.gen_range(1..40))
+                .map(|_| (start + rng.gen_range(0..60), rng.gen_range(0..10)))
+                .collect::<Vec<_>>();
+            map.insert_many(batch.iter().cloned());
+            expected.extend(batch);
+            for _ in 0..5 {
+                let key = rng.gen_range(0..6000);
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            }
+        }
+        assert_eq!(map.len(), expected.len());
+        assert!(map.iter().eq(expected.iter()));
+    }
 
-        assert_eq!(map.insert(3, 66), Some(33));
-        assert_eq!(map.len(), 3);
+    #[test]
+    fn test_remove_many() {
+        let mut map = (0..2000usize).map(|i| (i, i)).collect::<BTreeMap<_, _>>();
+        let slots = map.range_stats(..).slots;
+        map.mark_removed(&10);
+        map.defer_drops(true);
+        let cursor = map.cursor_at(&1500);
+        // Every even key, one hidden, plus keys that are not there.
+        let victims = (0..2000).step_by(2).chain([5000, 6000]).collect::<Vec<_>>();
+        assert_eq!(map.remove_many(victims.iter().rev()), 999);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.marked_len(), 0);
+        assert_eq!(map.deferred_len(), 1000);
+        assert!(map.range_stats(..).slots < slots);
+        assert!(map.iter().map(|(k, _)| *k).eq((1..2000).step_by(2)));
+        assert_eq!(map.cursor_next(&cursor), Some((&1501, &1501)));
+        // A batch of absent keys leaves the map alone.
+        let version = map.version;
+        map.take_changed_segments();
+        assert_eq!(map.remove_many((2000..4000).collect::<Vec<_>>().iter()), 0);
+        assert_eq!(map.version, version);
+        assert!(map.take_changed_segments().iter().all(|&word| word == 0));
+        assert_eq!(map.cursor_next(&cursor), Some((&1503, &1503)));
+        // A small batch goes key by key.
+        assert_eq!(map.remove_many(&[1, 3, 4]), 2);
+        assert_eq!(map.len(), 998);
+        assert_eq!(map.first_key_value(), Some((&5, &5)));
         assert_eq!(
-            map.get_all_key_values(),
-            [(&2, &2222), (&3, &66), (&4, &44)]
+            map.remove_many(map.keys().copied().collect::<Vec<_>>().iter()),
+            998
         );
+        assert!(map.is_empty());
+    }
 
-        assert_eq!(map.remove(&5), None);
-        assert_eq!(map.len(), 3);
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_remove_range() {
+        let mut map = (0..2000usize).map(|i| (i, i)).collect::<BTreeMap<_, _>>();
+        let mut expected = (0..2000usize)
+            .map(|i| (i, i))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        let cursor = map.cursor_at(&1000);
+        map.mark_removed(&700);
+        expected.remove(&700);
+        assert_eq!(map.remove_range(500..1500), 999);
+        expected.retain(|k, _| !(500..1500).contains(k));
+        assert_eq!(map.len(), expected.len());
+        assert_eq!(map.marked_len(), 0);
+        assert!(map.iter().eq(expected.iter()));
+        assert_eq!(map.cursor_next(&cursor), Some((&1500, &1500)));
+        // A few keys inside one segment, then bounds matching nothing.
+        assert_eq!(map.remove_range(10..=12), 3);
         assert_eq!(
-            map.get_all_key_values(),
-            [(&2, &2222), (&3, &66), (&4, &44)]
+            map.remove_range((Bound::Excluded(1499), Bound::Excluded(1500))),
+            0
         );
+        assert_eq!(map.remove_range(3000..), 0);
+        expected.retain(|k, _| !(10..=12).contains(k));
+        assert!(map.iter().eq(expected.iter()));
+        for key in [9, 13, 499, 1500, 1999] {
+            assert_eq!(map.get(&key), Some(&key));
+        }
+        assert_eq!(map.remove_range(..), expected.len());
+        assert!(map.is_empty());
+        // Windows of a time series dropped while it keeps growing.
+        let mut expected = std::collections::BTreeMap::new();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+        for round in 0..40 {
+            for i in 0..50 {
+                map.insert(round * 50 + i, i);
+                expected.insert(round * 50 + i, i);
+            }
+            let from = rng.gen_range(0..2000);
+            let to = from + rng.gen_range(0..100);
+            let removed = expected.range(from..to).count();
+            expected.retain(|k, _| !(from..to).contains(k));
+            assert_eq!(map.remove_range(from..to), removed);
+            assert_eq!(map.get(&(round * 50)), expected.get(&(round * 50)));
+        }
+        assert_eq!(map.len(), expected.len());
+        assert!(map.iter().eq(expected.iter()));
+    }
 
-        assert_eq!(map.remove(&4), Some(44));
-        assert_eq!(map.len(), 2);
-        assert_eq!(map.get_all_key_values(), [(&2, &2222), (&3, &66)]);
-        assert_eq!(map.get_top_k_key_values(1), [(&2, &2222)]);
+    #[test]
+    fn test_from_iter() {
+        let sorted = (0..1000).map(|i| (i, i * 2)).collect::<BTreeMap<_, _>>();
+        assert_eq!(sorted.len(), 1000);
+        assert!(sorted
+            .iter()
+            .enumerate()
+            .all(|(i, (&k, &v))| k == i && v == i * 2));
 
-        assert_eq!(map.insert(3, 123), Some(66));
-        assert_eq!(map.len(), 2);
-        assert_eq!(map.get_all_key_values(), [(&2, &2222), (&3, &123)]);
+        let shuffled = (0..1000)
+            .map(|i| ((i * 7919) % 500, i))
+            .collect::<BTreeMap<usize, usize>>();
+        let expected = (0..1000)
+            .map(|i| ((i * 7919) % 500, i))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        assert_eq!(shuffled.len(), 500);
+        assert!(shuffled.iter().eq(expected.iter()));
 
-        assert_eq!(map.insert(2, 321), Some(2222));
-        assert_eq!(map.len(), 2);
-        assert_eq!(map.get_all_key_values(), [(&2, &321), (&3, &123)]);
-        assert_eq!(map.get_top_k_key_values(1), [(&2, &321)]);
+        let mut empty = std::iter::empty().collect::<BTreeMap<usize, usize>>();
+        assert!(empty.is_empty());
+        empty.insert(1, 1);
+        assert_eq!(empty.get(&1), Some(&1));
+    }
 
-        assert_eq!(map.remove(&3), Some(123));
-        assert_eq!(map.len(), 1);
-        assert_eq!(map.get_all_key_values(), [(&2, &321)]);
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_from_sorted_iter() {
+        for n in [0usize, 1, 2, 3, 100, 5000] {
+            let mut map = BTreeMap::from_sorted_iter((0..n).map(|i| (i * 2, i)));
+            assert_eq!(map.len(), n);
+            for i in 0..n * 2 {
+                assert_eq!(map.get(&i), (i % 2 == 0).then_some(&(i / 2)));
+            }
+            let density = n as f64 / map.pma.data_len() as f64;
+            assert!(n < 2 || (density > 0.375 && density <= 0.75));
+            map.insert(1, 1);
+            assert_eq!(map.get(&1), Some(&1));
+        }
+    }
 
-        assert_eq!(map.remove(&3), None);
-        assert_eq!(map.len(), 1);
-        assert_eq!(map.get_all_key_values(), [(&2, &321)]);
-        assert_eq!(map.get_top_k_key_values(1), [(&2, &321)]);
+    #[test]
+    #[should_panic(expected = "Keys must be sorted and unique.")]
+    fn test_from_sorted_iter_unsorted() {
+        BTreeMap::from_sorted_iter([(2, 0), (1, 0)]);
+    }
 
-        assert_eq!(map.remove(&2), Some(321));
-        assert_eq!(map.len(), 0);
-        assert_eq!(map.get_all_key_values(), []);
+    #[test]
+    fn test_debug() {
+        let mut map = BTreeMap::<usize, &str>::new();
+        assert_eq!(format!("{:?}", map), "{}");
+        for (k, v) in [(3, "c"), (1, "a"), (2, "b")] {
+            map.insert(k, v);
+        }
+        map.mark_removed(&2);
+        assert_eq!(format!("{:?}", map), r#"{1: "a", 3: "c"}"#);
+    }
 
-        assert_eq!(map.remove(&2), None);
-        assert_eq!(map.len(), 0);
-        assert_eq!(map.get_all_key_values(), []);
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_eq_and_hash() {
+        use std::hash::{BuildHasher, RandomState};
+
+        let mut sparse = BTreeMap::<usize, usize>::new();
+        for i in 0..1000 {
+            sparse.insert(i, i);
+        }
+        for i in 100..1000 {
+            sparse.remove(&i);
+        }
+        sparse.insert(2000, 0);
+        sparse.mark_removed(&2000);
+        let packed = (0..100).map(|i| (i, i)).collect::<BTreeMap<_, _>>();
+        let slots = |map: &BTreeMap<usize, usize>| {
+            (0..map.pma.data_len())
+                .map(|i| map.pma.key(i).copied())
+                .collect::<Vec<_>>()
+        };
+        assert!(slots(&sparse) != slots(&packed));
+        assert_eq!(sparse, packed);
+        let state = RandomState::new();
+        assert_eq!(state.hash_one(&sparse), state.hash_one(&packed));
+
+        sparse.insert(5, 6);
+        assert_ne!(sparse, packed);
+        assert_ne!(state.hash_one(&sparse), state.hash_one(&packed));
+        sparse.insert(5, 5);
+        sparse.insert(100, 100);
+        assert_ne!(sparse, packed);
+    }
+
+    #[test]
+    fn test_ord() {
+        let map = |entries: &[(usize, usize)]| entries.iter().copied().collect::<BTreeMap<_, _>>();
+        let std_map = |entries: &[(usize, usize)]| {
+            entries
+                .iter()
+                .copied()
+                .collect::<std::collections::BTreeMap<_, _>>()
+        };
+        let cases: [&[(usize, usize)]; 6] = [
+            &[],
+            &[(1, 1)],
+            &[(1, 2)],
+            &[(1, 1), (2, 0)],
+            &[(2, 0)],
+            &[(0, 9), (5, 5)],
+        ];
+        for a in cases {
+            for b in cases {
+                assert_eq!(map(a).cmp(&map(b)), std_map(a).cmp(&std_map(b)));
+                assert_eq!(
+                    map(a).partial_cmp(&map(b)),
+                    std_map(a).partial_cmp(&std_map(b))
+                );
+            }
+        }
+        let mut sorted = cases.iter().map(|c| map(c)).collect::<Vec<_>>();
+        sorted.sort();
+        assert_eq!(sorted[0], map(&[]));
+        assert_eq!(sorted[5], map(&[(2, 0)]));
+    }
+
+    #[test]
+    fn test_borrowed_keys() {
+        let mut map = BTreeMap::<String, usize>::new();
+        for i in 0..200 {
+            map.insert(format!("key{:03}", i), i);
+        }
+        assert_eq!(map.get("key007"), Some(&7));
+        assert!(map.contains_key("key199"));
+        assert!(!map.contains_key("key200"));
+        assert_eq!(map.remove("key008"), Some(8));
+        assert!(map.mark_removed("key009"));
+        assert!(!map.mark_removed("key009"));
+        assert_eq!(map.get("key009"), None);
+        let cursor = map.cursor_at("key008");
+        assert_eq!(
+            map.cursor_next(&cursor),
+            Some((&String::from("key010"), &10))
+        );
+        map.release_cursor(cursor);
+
+        let mut bytes = BTreeMap::<Vec<u8>, usize>::new();
+        bytes.insert(b"abc".to_vec(), 1);
+        assert_eq!(bytes.get(&b"abc"[..]), Some(&1));
+    }
+
+    #[test]
+    fn test_range_mut() {
+        let mut map = (0..500).map(|i| (i, i)).collect::<BTreeMap<usize, usize>>();
+        map.mark_removed(&150);
+        let version = map.version();
+        for (_, v) in map.range_mut(100..200) {
+            *v *= 10;
+        }
+        assert!(map.version() > version);
+        assert_eq!(map.range_mut(100..200).count(), 99);
+        if let Some((&k, v)) = map.range_mut(..=300).next_back() {
+            assert_eq!(k, 300);
+            *v = 0;
+        }
+        for i in 0..500 {
+            let expected = match i {
+                150 => None,
+                100..200 => Some(i * 10),
+                300 => Some(0),
+                _ => Some(i),
+            };
+            assert_eq!(map.get(&i).copied(), expected);
+        }
+    }
+
+    #[test]
+    fn test_lower_upper_bound() {
+        let mut map = (0..300)
+            .map(|i| (i * 3, i))
+            .collect::<BTreeMap<usize, usize>>();
+        map.mark_removed(&30);
+        let expected = map
+            .iter()
+            .map(|(&k, &v)| (k, v))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        for key in 0..1000 {
+            assert_eq!(map.lower_bound(&key), expected.range(key..).next(),);
+            assert_eq!(
+                map.upper_bound(&key),
+                expected
+                    .range((Bound::Excluded(key), Bound::Unbounded))
+                    .next(),
+            );
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_drain() {
+        let mut map = BTreeMap::<usize, String>::new();
+        for i in (0..700).rev() {
+            map.insert(i, i.to_string());
+        }
+        map.mark_removed(&5);
+        let capacity = map.slot_capacity();
+        let drained = map.drain();
+        assert_eq!(drained.len(), 699);
+        assert!(drained
+            .enumerate()
+            .all(|(i, (k, v))| k == if i < 5 { i } else { i + 1 } && v == k.to_string()));
+        assert!(map.is_empty());
+        assert_eq!(map.pma.data_len(), 1);
+        assert_eq!(map.slot_capacity(), capacity);
+        assert_eq!(map.marked_len(), 0);
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.drain().next(), None);
+        map.insert(5, String::from("5"));
+        assert_eq!(map.get(&5).map(String::as_str), Some("5"));
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        map.prefetch(&1);
+        for i in 0..300 {
+            map.insert(i * 2, i);
+        }
+        let version = map.version();
+        for i in 0..700 {
+            map.prefetch(&i);
+            assert_eq!(map.get(&i), (i % 2 == 0 && i < 600).then_some(&(i / 2)));
+        }
+        assert_eq!(map.version(), version);
     }
 
     #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
     fn sanity_test() {
         let mut numbers: Vec<usize> = (0..10000).collect();
         numbers.shuffle(&mut thread_rng());
@@ -0,0 +1,330 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// An ideal cache model in the sense of the cache oblivious literature: the memory is split
+// into blocks of `block_size` bytes, the cache holds `capacity` blocks and evicts the least
+// recently used one. Every access to a block that is not cached counts as one block transfer.
+pub struct CacheSimulator {
+    block_size: usize,
+    capacity: usize,
+    clock: u64,
+    // Block id -> time of the last access.
+    cached: HashMap<usize, u64>,
+    // Time of the last access -> block id, the first entry is the eviction candidate.
+    lru: BTreeMap<u64, usize>,
+    stats: CacheStats,
+    // Set by `count_lines`.
+    lines: Option<LineCounts>,
+}
+
+// The distinct lines touched by the current operation and the histograms of the finished ones.
+struct LineCounts {
+    line_size: usize,
+    touched: HashSet<usize>,
+    lookups: LineHistogram,
+    inserts: LineHistogram,
+}
+
+// The map operations whose touched lines are counted.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheOperation {
+    Lookup,
+    Insert,
+}
+
+// The number of operations that touched each number of distinct lines.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LineHistogram {
+    // Operations by touched lines, the last count not zero.
+    counts: Vec<u64>,
+}
+
+impl LineHistogram {
+    fn record(&mut self, lines: usize) {
+        if self.counts.len() <= lines {
+            self.counts.resize(lines + 1, 0);
+        }
+        self.counts[lines] += 1;
+    }
+
+    pub fn operations(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    // The number of operations that touched exactly `lines` lines.
+    pub fn count(&self, lines: usize) -> u64 {
+        self.counts.get(lines).copied().unwrap_or(0)
+    }
+
+    // The most lines an operation touched, 0 before any operation.
+    pub fn max(&self) -> usize {
+        self.counts.len().saturating_sub(1)
+    }
+
+    // The lines touched per operation on average, 0 before any operation.
+    pub fn mean(&self) -> f64 {
+        let lines = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(lines, &count)| lines as u64 * count)
+            .sum::<u64>();
+        match self.operations() {
+            0 => 0.0,
+            operations => lines as f64 / operations as f64,
+        }
+    }
+
+    // The numbers of touched lines some operation had, with their operation counts, in
+    // increasing order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(lines, &count)| (lines, count))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    // Number of block accesses, an access spanning several blocks counts once per block.
+    pub accesses: u64,
+    // Number of accesses that missed the cache.
+    pub transfers: u64,
+}
+
+impl CacheSimulator {
+    pub fn new(block_size: usize, capacity: usize) -> Self {
+        assert!(block_size > 0, "Block size must be positive.");
+        assert!(capacity > 0, "Cache must hold at least one block.");
+        Self {
+            block_size,
+            capacity,
+            clock: 0,
+            cached: HashMap::new(),
+            lru: BTreeMap::new(),
+            stats: CacheStats::default(),
+            lines: None,
+        }
+    }
+
+    // Also counts the distinct lines of `line_size` bytes every lookup and insert of the map
+    // touches, independently of the cache model, into a histogram per operation. The counts
+    // tell how many cache lines an operation needs at best, which hardware counters would
+    // only tell mixed with the rest of the program.
+    pub fn count_lines(mut self, line_size: usize) -> Self {
+        assert!(line_size > 0, "Line size must be positive.");
+        self.lines = Some(LineCounts {
+            line_size,
+            touched: HashSet::new(),
+            lookups: LineHistogram::default(),
+            inserts: LineHistogram::default(),
+        });
+        self
+    }
+
+    pub fn line_size(&self) -> Option<usize> {
+        self.lines.as_ref().map(|lines| lines.line_size)
+    }
+
+    // The lines touched by the operations of a kind, `None` without `count_lines`.
+    pub fn line_histogram(&self, operation: CacheOperation) -> Option<&LineHistogram> {
+        self.lines.as_ref().map(|lines| match operation {
+            CacheOperation::Lookup => &lines.lookups,
+            CacheOperation::Insert => &lines.inserts,
+        })
+    }
+
+    // Forgets the lines touched so far, at the start of an operation.
+    pub fn begin_operation(&mut self) {
+        if let Some(lines) = &mut self.lines {
+            lines.touched.clear();
+        }
+    }
+
+    // Adds the lines touched since `begin_operation` to the histogram of `operation`.
+    pub fn end_operation(&mut self, operation: CacheOperation) {
+        if let Some(lines) = &mut self.lines {
+            let touched = lines.touched.len();
+            lines.touched.clear();
+            match operation {
+                CacheOperation::Lookup => lines.lookups.record(touched),
+                CacheOperation::Insert => lines.inserts.record(touched),
+            }
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    // Resets the counters and the line histograms, the cache content is kept so a measured
+    // phase can start warm.
+    pub fn reset_stats(&mut self) {
+        self.stats = CacheStats::default();
+        if let Some(lines) = &mut self.lines {
+            lines.lookups = LineHistogram::default();
+            lines.inserts = LineHistogram::default();
+        }
+    }
+
+    // Empties the cache and resets the counters.
+    pub fn flush(&mut self) {
+        self.cached.clear();
+        self.lru.clear();
+        self.reset_stats();
+    }
+
+    // Records an access of `len` bytes starting at `address`.
+    pub fn access(&mut self, address: usize, len: usize) {
+        let first = address / self.block_size;
+        let last = (address + len.max(1) - 1) / self.block_size;
+        for block in first..=last {
+            self.access_block(block);
+        }
+        if let Some(lines) = &mut self.lines {
+            let first = address / lines.line_size;
+            let last = (address + len.max(1) - 1) / lines.line_size;
+            lines.touched.extend(first..=last);
+        }
+    }
+
+    fn access_block(&mut self, block: usize) {
+        self.clock += 1;
+        self.stats.accesses += 1;
+        match self.cached.insert(block, self.clock) {
+            Some(last_used) => {
+                self.lru.remove(&last_used);
+            }
+            None => {
+                self.stats.transfers += 1;
+                if self.cached.len() > self.capacity {
+                    let (_, evicted) = self.lru.pop_first().unwrap();
+                    self.cached.remove(&evicted);
+                }
+            }
+        }
+        self.lru.insert(self.clock, block);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod cache_sim {
+    use crate::{
+        cache_sim::{CacheOperation, CacheSimulator},
+        BTreeMap, IndexLayout,
+    };
+
+    #[test]
+    fn test_lru() {
+        let mut sim = CacheSimulator::new(64, 2);
+        sim.access(0, 8);
+        sim.access(8, 8);
+        assert_eq!(sim.stats().accesses, 2);
+        assert_eq!(sim.stats().transfers, 1);
+
+        // Spans two blocks.
+        sim.access(60, 8);
+        assert_eq!(sim.stats().accesses, 4);
+        assert_eq!(sim.stats().transfers, 2);
+
+        // Block 0 is the least recently used one and gets evicted.
+        sim.access(128, 1);
+        assert_eq!(sim.stats().transfers, 3);
+        sim.access(64, 1);
+        assert_eq!(sim.stats().transfers, 3);
+        sim.access(0, 1);
+        assert_eq!(sim.stats().transfers, 4);
+
+        sim.reset_stats();
+        sim.access(0, 1);
+        assert_eq!(sim.stats().transfers, 0);
+        sim.flush();
+        sim.access(0, 1);
+        assert_eq!(sim.stats().transfers, 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_lookup_transfers() {
+        // The index nodes of a descent span O(log_B n) blocks, but every comparison reads its key
+        // from the PMA slot the node points at. The slots compared in the upper levels are far
+        // apart, so a cold lookup transfers about one block per level on top of the nodes.
+        let n = 1usize << 16;
+        let mut map = BTreeMap::merge_build(vec![(0..n).map(|i| (i, i))]);
+        map.start_cache_simulation(CacheSimulator::new(4096, 16));
+        let lookups = 1000;
+        for i in 0..lookups {
+            assert_eq!(map.get(&(i * 61 % n)), Some(&(i * 61 % n)));
+        }
+        let stats = map.stop_cache_simulation().unwrap().stats();
+        let height = (n.trailing_zeros() + 2) as u64;
+        eprintln!("STATS {:?} h {}", stats, height);
+        assert!(stats.accesses >= height * lookups as u64);
+        assert!(stats.transfers <= (height + 4) * lookups as u64);
+    }
+
+    #[test]
+    fn test_line_counts() {
+        let mut sim = CacheSimulator::new(64, 2).count_lines(16);
+        assert_eq!(sim.line_size(), Some(16));
+        sim.begin_operation();
+        sim.access(0, 8);
+        sim.access(8, 8);
+        sim.access(60, 8);
+        sim.end_operation(CacheOperation::Lookup);
+        sim.begin_operation();
+        sim.end_operation(CacheOperation::Lookup);
+        let lookups = sim.line_histogram(CacheOperation::Lookup).unwrap();
+        assert_eq!(lookups.iter().collect::<Vec<_>>(), [(0, 1), (3, 1)]);
+        assert_eq!(
+            (lookups.operations(), lookups.max(), lookups.mean()),
+            (2, 3, 1.5)
+        );
+        assert_eq!(lookups.count(2), 0);
+        sim.reset_stats();
+        assert_eq!(sim.line_histogram(CacheOperation::Lookup).unwrap().max(), 0);
+        assert!(CacheSimulator::new(64, 2)
+            .line_histogram(CacheOperation::Insert)
+            .is_none());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_map_line_counts() {
+        // A descent reads a node and a key per level, so a lookup touches O(height) lines,
+        // fewer in the vEB layout where the nodes of a subtree share lines.
+        let n = 1usize << 12;
+        let mut lines = vec![];
+        for layout in [IndexLayout::VanEmdeBoas, IndexLayout::Bfs] {
+            let mut map = BTreeMap::with_layout(layout);
+            map.start_cache_simulation(CacheSimulator::new(4096, 16).count_lines(64));
+            for i in 0..n {
+                map.insert(i * 7 % n, i);
+            }
+            for i in 0..1000 {
+                map.get(&(i * 61 % n));
+            }
+            let sim = map.stop_cache_simulation().unwrap();
+            let lookups = sim.line_histogram(CacheOperation::Lookup).unwrap();
+            let inserts = sim.line_histogram(CacheOperation::Insert).unwrap();
+            assert_eq!(
+                (lookups.operations(), inserts.operations()),
+                (1000, n as u64)
+            );
+            let height = n.trailing_zeros() as usize + 2;
+            assert!(lookups.max() <= 3 * height, "{:?}", lookups);
+            lines.push(lookups.mean());
+        }
+        assert!(lines[0] <= lines[1], "{:?}", lines);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::{cache_oblivious::ParallelBounds, comparable::Comparable, BTreeMap};
+use std::borrow::Cow;
+
+// Compresses value payloads. Implementations must round trip: `decode(encode(x)) == x`.
+// LZ4 and zstd come behind the `lz4` and `zstd` features, other compressors plug in by
+// implementing the trait.
+pub trait ValueCodec {
+    fn encode(&self, value: &[u8]) -> Vec<u8>;
+    fn decode(&self, encoded: &[u8]) -> Vec<u8>;
+}
+
+// Stores values as they are.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Identity;
+
+impl ValueCodec for Identity {
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        value.to_vec()
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Vec<u8> {
+        encoded.to_vec()
+    }
+}
+
+// Byte run length encoding as (run length, byte) pairs, cheap and effective on payloads with
+// long runs like zero padded records.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunLength;
+
+impl ValueCodec for RunLength {
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![];
+        for run in value.chunk_by(|a, b| a == b) {
+            for chunk in run.chunks(u8::MAX as usize) {
+                encoded.extend([chunk.len() as u8, chunk[0]]);
+            }
+        }
+        encoded
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Vec<u8> {
+        let mut value = vec![];
+        for pair in encoded.chunks_exact(2) {
+            value.extend(std::iter::repeat_n(pair[1], pair[0] as usize));
+        }
+        value
+    }
+}
+
+// LZ4 block compression with the decoded length up front, fast on both ends.
+#[cfg(feature = "lz4")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl ValueCodec for Lz4 {
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        lz4_flex::compress_prepend_size(value)
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Vec<u8> {
+        lz4_flex::decompress_size_prepended(encoded).expect("corrupt LZ4 payload")
+    }
+}
+
+// zstd frames at the given compression level, 1 to 22, trading encode speed for ratio.
+#[cfg(feature = "zstd")]
+#[derive(Clone, Copy, Debug)]
+pub struct Zstd {
+    pub level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Self {
+        Self {
+            level: zstd::DEFAULT_COMPRESSION_LEVEL,
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl ValueCodec for Zstd {
+    fn encode(&self, value: &[u8]) -> Vec<u8> {
+        zstd::encode_all(value, self.level).expect("zstd compression failed")
+    }
+
+    fn decode(&self, encoded: &[u8]) -> Vec<u8> {
+        zstd::decode_all(encoded).expect("corrupt zstd payload")
+    }
+}
+
+#[derive(Clone)]
+enum Slot {
+    Raw(Box<[u8]>),
+    Encoded(Box<[u8]>),
+}
+
+impl Slot {
+    fn stored_len(&self) -> usize {
+        match self {
+            Slot::Raw(bytes) | Slot::Encoded(bytes) => bytes.len(),
+        }
+    }
+}
+
+// A map of byte payloads that runs every value of at least `threshold` bytes through the
+// codec, one value per slot, and keeps the encoded form only when it is smaller. Small values
+// stay raw since the codec overhead would outweigh the saving. Reads decode on the fly.
+// Payloads live out of line behind the PMA slots: a slot has the fixed size of its value type,
+// so compressing values in place could not make the array any smaller.
+pub struct CompressedMap<K: Ord, C: ValueCodec = Identity> {
+    map: BTreeMap<K, Slot>,
+    codec: C,
+    threshold: usize,
+    // Payload bytes held by the slots.
+    stored_bytes: usize,
+}
+
+impl<K, C> CompressedMap<K, C>
+where
+    K: Ord + ParallelBounds,
+    C: ValueCodec,
+{
+    pub const DEFAULT_THRESHOLD: usize = 64;
+
+    pub fn new(codec: C) -> Self {
+        Self::with_threshold(codec, Self::DEFAULT_THRESHOLD)
+    }
+
+    pub fn with_threshold(codec: C, threshold: usize) -> Self {
+        Self {
+            map: BTreeMap::new(),
+            codec,
+            threshold,
+            stored_bytes: 0,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: &[u8]) -> Option<Vec<u8>> {
+        let slot = self.encode(value);
+        self.stored_bytes += slot.stored_len();
+        self.map.insert(key, slot).map(|old| self.release(old))
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<Cow<'_, [u8]>> {
+        self.map.get(key).map(|slot| match slot {
+            Slot::Raw(bytes) => Cow::Borrowed(&bytes[..]),
+            Slot::Encoded(bytes) => Cow::Owned(self.codec.decode(bytes)),
+        })
+    }
+
+    pub fn remove<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> Option<Vec<u8>> {
+        self.map.remove(key).map(|old| self.release(old))
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // Payload bytes currently held, after compression.
+    pub fn stored_bytes(&self) -> usize {
+        self.stored_bytes
+    }
+
+    fn encode(&self, value: &[u8]) -> Slot {
+        if value.len() >= self.threshold {
+            let encoded = self.codec.encode(value);
+            if encoded.len() < value.len() {
+                return Slot::Encoded(encoded.into_boxed_slice());
+            }
+        }
+        Slot::Raw(value.into())
+    }
+
+    fn release(&mut self, slot: Slot) -> Vec<u8> {
+        self.stored_bytes -= slot.stored_len();
+        match slot {
+            Slot::Raw(bytes) => bytes.into_vec(),
+            Slot::Encoded(bytes) => self.codec.decode(&bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod codec {
+    use crate::codec::{CompressedMap, Identity, RunLength, ValueCodec};
+
+    #[test]
+    fn test_run_length() {
+        let codec = RunLength;
+        for value in [
+            vec![],
+            vec![1, 2, 3],
+            vec![0; 1000],
+            [vec![7; 300], vec![1, 1]].concat(),
+        ] {
+            assert_eq!(codec.decode(&codec.encode(&value)), value);
+        }
+        assert_eq!(codec.encode(&[0; 600]).len(), 6);
+    }
+
+    #[test]
+    fn test_compressed_map() {
+        let mut map = CompressedMap::new(RunLength);
+        for i in 0..100usize {
+            map.insert(i, &vec![i as u8; 1000]);
+        }
+        // Short and incompressible values are kept raw.
+        map.insert(100, &[1, 2, 3]);
+        map.insert(101, &(0..=255).collect::<Vec<u8>>());
+        assert_eq!(map.len(), 102);
+        assert_eq!(map.stored_bytes(), 100 * 8 + 3 + 256);
+        assert_eq!(map.get(&5).unwrap().as_ref(), &[5u8; 1000][..]);
+        assert_eq!(map.get(&100).unwrap().as_ref(), &[1, 2, 3]);
+
+        assert_eq!(map.insert(5, &[9; 10]), Some(vec![5; 1000]));
+        assert_eq!(map.remove(&101).unwrap().len(), 256);
+        assert_eq!(map.remove(&101), None);
+        assert_eq!(map.stored_bytes(), 99 * 8 + 10 + 3);
+
+        let mut plain = CompressedMap::<usize>::new(Identity);
+        plain.insert(1, &[0; 100]);
+        assert_eq!(plain.stored_bytes(), 100);
+        assert_eq!(plain.get(&1).unwrap().len(), 100);
+    }
+
+    // Values with some structure to find and a few random ones that do not compress.
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn payloads() -> Vec<Vec<u8>> {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut payloads = vec![vec![], vec![1, 2, 3], vec![0; 5000]];
+        payloads.push((0..4000).map(|i| (i % 17) as u8).collect());
+        payloads.push(b"key=value;".repeat(300));
+        payloads.push((0..1000).map(|_| rng.gen()).collect());
+        payloads
+    }
+
+    #[cfg(any(feature = "lz4", feature = "zstd"))]
+    fn check_codec<C: ValueCodec>(codec: C) {
+        for value in payloads() {
+            assert_eq!(codec.decode(&codec.encode(&value)), value);
+        }
+        assert!(codec.encode(&[0; 5000]).len() < 100);
+
+        let mut map = CompressedMap::new(codec);
+        for (i, value) in payloads().into_iter().enumerate() {
+            map.insert(i, &value);
+        }
+        assert!(map.stored_bytes() < 1000 + 3 + 500);
+        for (i, value) in payloads().into_iter().enumerate() {
+            assert_eq!(map.get(&i).unwrap().as_ref(), &value[..]);
+        }
+        assert_eq!(map.remove(&2), Some(vec![0; 5000]));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_lz4() {
+        check_codec(crate::codec::Lz4);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_zstd() {
+        use crate::codec::Zstd;
+        check_codec(Zstd::default());
+        check_codec(Zstd { level: 19 });
+    }
+}
@@ -0,0 +1,81 @@
+use std::{borrow::Borrow, cmp::Ordering};
+
+// Key equivalence for lookups with a query type other than `K`. Every type `K` borrows as is
+// covered by the blanket implementation, other query types, like a tuple of references
+// against an owned composite key, implement it by hand.
+pub trait Equivalent<K: ?Sized> {
+    fn equivalent(&self, key: &K) -> bool;
+}
+
+// Key ordering for lookups with a query type other than `K`. It must agree with the ordering
+// of `K`: comparing the query against the keys of the map has to give the same result as
+// comparing the key the query stands for.
+pub trait Comparable<K: ?Sized>: Equivalent<K> {
+    fn compare(&self, key: &K) -> Ordering;
+}
+
+impl<Q, K> Equivalent<K> for Q
+where
+    Q: Eq + ?Sized,
+    K: Borrow<Q> + ?Sized,
+{
+    #[inline]
+    fn equivalent(&self, key: &K) -> bool {
+        self.eq(key.borrow())
+    }
+}
+
+impl<Q, K> Comparable<K> for Q
+where
+    Q: Ord + ?Sized,
+    K: Borrow<Q> + ?Sized,
+{
+    #[inline]
+    fn compare(&self, key: &K) -> Ordering {
+        self.cmp(key.borrow())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod comparable {
+    use crate::{
+        comparable::{Comparable, Equivalent},
+        BTreeMap,
+    };
+    use std::cmp::Ordering;
+
+    struct Query<'a>(u64, &'a str);
+
+    impl Equivalent<(u64, String)> for Query<'_> {
+        fn equivalent(&self, key: &(u64, String)) -> bool {
+            self.0 == key.0 && self.1 == key.1
+        }
+    }
+
+    impl Comparable<(u64, String)> for Query<'_> {
+        fn compare(&self, key: &(u64, String)) -> Ordering {
+            self.0.cmp(&key.0).then_with(|| self.1.cmp(key.1.as_str()))
+        }
+    }
+
+    #[test]
+    fn test_composite_query() {
+        let mut map = BTreeMap::new();
+        for i in 0..100u64 {
+            map.insert((i / 10, format!("k{}", i % 10)), i);
+        }
+        assert_eq!(map.get(&Query(3, "k4")), Some(&34));
+        assert_eq!(map.get(&Query(3, "k")), None);
+        assert_eq!(map.get(&Query(10, "k0")), None);
+        assert_eq!(map.remove(&Query(5, "k5")), Some(55));
+        assert_eq!(map.get(&Query(5, "k5")), None);
+        assert_eq!(map.len(), 99);
+
+        // Plain borrowed lookups keep working through the blanket implementations.
+        let mut names = BTreeMap::new();
+        names.insert(String::from("b"), 2);
+        assert_eq!(names.get("b"), Some(&2));
+        assert_eq!(names.get(&String::from("b")), Some(&2));
+    }
+}
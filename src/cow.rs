@@ -0,0 +1,241 @@
+use crate::{bitmap, cache_oblivious::ParallelBounds, BTreeMap, Comparable};
+use std::{
+    cmp::Ordering,
+    sync::{Arc, Weak},
+};
+
+// The entries of one PMA segment as a snapshot holds them, gaps and hidden keys squeezed out.
+pub(crate) type Page<K, V> = Arc<[(K, V)]>;
+type WeakPage<K, V> = Weak<[(K, V)]>;
+
+// The pages the map handed to its last snapshot, so the next one copies only the segments
+// written since. Held weakly: once every snapshot holding a page is dropped, the memory goes
+// with it and the segment is copied again if needed.
+pub(crate) struct PageCache<K, V> {
+    // Per PMA segment, `None` when it was empty.
+    segments: Vec<Option<WeakPage<K, V>>>,
+    // The slot count and segment size the segments were cut with.
+    layout: (usize, usize),
+}
+
+impl<K, V> Default for PageCache<K, V> {
+    fn default() -> Self {
+        Self {
+            segments: vec![],
+            layout: (0, 0),
+        }
+    }
+}
+
+// A read-only view of a map as it was when `BTreeMap::snapshot` took it. It owns its entries,
+// so the map can keep changing meanwhile and a long scan over the snapshot never holds up a
+// writer. Snapshots are copy on write per PMA segment: a segment no write has touched since
+// the previous snapshot is shared with it, so taking a snapshot copies only the windows the
+// writes and rebalances in between went through. Cloning a snapshot copies no entries.
+pub struct MapSnapshot<K, V> {
+    // The non empty pages in key order.
+    pages: Vec<Page<K, V>>,
+    len: usize,
+}
+
+impl<K, V> Clone for MapSnapshot<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            pages: self.pages.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<K, V> BTreeMap<K, V>
+where
+    K: Ord + Clone + ParallelBounds,
+    V: Clone + ParallelBounds,
+{
+    // Takes a consistent read-only view of the map, see `MapSnapshot`. The first snapshot
+    // copies every entry, later ones only the segments written since the one before while it
+    // is still alive.
+    pub fn snapshot(&mut self) -> MapSnapshot<K, V> {
+        let mut changed = self.take_changed_segments();
+        let mut cache = self.take_page_cache();
+        let pma = self.pma();
+        let slot_count = pma.data_len();
+        let segment_size = pma.segment_size();
+        let segment_count = slot_count.div_ceil(segment_size);
+        if cache.layout != (slot_count, segment_size) {
+            // The layout got doubled, halved or rebuilt, every segment is cut again.
+            cache.layout = (slot_count, segment_size);
+            cache.segments = vec![None; segment_count];
+            changed = vec![!0; bitmap::words_for(segment_count)];
+        } else if changed.len() < bitmap::words_for(segment_count) {
+            bitmap::resize(&mut changed, segment_count);
+        }
+        let mut pages = Vec::with_capacity(segment_count);
+        for (segment, cached) in cache.segments.iter_mut().enumerate() {
+            let live = cached.as_ref().map(Weak::upgrade);
+            if !bitmap::get(&changed, segment) {
+                match live {
+                    None => continue,
+                    Some(Some(page)) => {
+                        pages.push(page);
+                        continue;
+                    }
+                    // Every snapshot holding the page is gone, copy it again.
+                    Some(None) => {}
+                }
+            }
+            let start = segment * segment_size;
+            let page = pma
+                .range(start, (start + segment_size).min(slot_count))
+                .filter(|(k, _)| !self.is_marked(k))
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Page<K, V>>();
+            if page.is_empty() {
+                *cached = None;
+            } else {
+                *cached = Some(Arc::downgrade(&page));
+                pages.push(page);
+            }
+        }
+        self.set_page_cache(cache);
+        MapSnapshot {
+            pages,
+            len: self.len(),
+        }
+    }
+}
+
+impl<K: Ord, V> MapSnapshot<K, V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&V> {
+        self.get_key_value(key).map(|(_, v)| v)
+    }
+
+    pub fn get_key_value<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(&K, &V)> {
+        // The first page whose last key is not below the key is the only one that may hold it.
+        let page = self
+            .pages
+            .partition_point(|page| key.compare(&page[page.len() - 1].0) == Ordering::Greater);
+        let entries = self.pages.get(page)?;
+        let index = entries
+            .binary_search_by(|(k, _)| key.compare(k).reverse())
+            .ok()?;
+        let (k, v) = &entries[index];
+        Some((k, v))
+    }
+
+    pub fn contains_key<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Iterates over the entries of the snapshot in key order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> + '_ {
+        self.pages
+            .iter()
+            .flat_map(|page| page.iter().map(|(k, v)| (k, v)))
+    }
+
+    // The number of pages this snapshot shares with `other` rather than holding its own copy.
+    #[cfg(test)]
+    pub(crate) fn shared_pages(&self, other: &Self) -> usize {
+        self.pages
+            .iter()
+            .filter(|page| other.pages.iter().any(|p| Arc::ptr_eq(page, p)))
+            .count()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod cow {
+    use crate::BTreeMap;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap as StdBTreeMap;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_snapshot() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut map = BTreeMap::new();
+        let mut model = StdBTreeMap::new();
+        let mut snapshots = vec![];
+        for round in 0..40 {
+            for _ in 0..200 {
+                let key = rng.gen_range(0..2000u32);
+                match rng.gen_range(0..4) {
+                    0 | 1 => {
+                        map.insert(key, round);
+                        model.insert(key, round);
+                    }
+                    2 => {
+                        map.remove(&key);
+                        model.remove(&key);
+                    }
+                    _ => {
+                        if let Some(v) = map.get_mut(&key) {
+                            *v += 100;
+                            *model.get_mut(&key).unwrap() += 100;
+                        }
+                    }
+                }
+            }
+            if round % 7 == 3 {
+                let hi = rng.gen_range(0..2000);
+                map.remove_range(hi / 2..hi);
+                model.retain(|k, _| !(hi / 2..hi).contains(k));
+            }
+            if round % 5 == 0 {
+                // Dropping about half the snapshots lets some cached pages die in between.
+                snapshots.retain(|_: &(_, _)| rng.gen_bool(0.5));
+            }
+            snapshots.push((map.snapshot(), model.clone()));
+            for (snapshot, model) in &snapshots {
+                assert_eq!(snapshot.len(), model.len());
+                assert!(snapshot.iter().eq(model.iter()));
+                for key in (0..2000).step_by(37) {
+                    assert_eq!(snapshot.get(&key), model.get(&key));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_shared_pages() {
+        let mut map = (0..1000u32).map(|i| (i, i)).collect::<BTreeMap<_, _>>();
+        let before = map.snapshot();
+        let pages = before.pages.len();
+        assert_eq!(map.snapshot().shared_pages(&before), pages);
+        *map.get_mut(&10).unwrap() = 0;
+        map.mark_removed(&900);
+        let after = map.snapshot();
+        assert_eq!(after.shared_pages(&before), pages - 2);
+        assert_eq!(before.get(&10), Some(&10));
+        assert_eq!(after.get(&10), Some(&0));
+        assert!(before.contains_key(&900) && !after.contains_key(&900));
+        assert_eq!(after.len(), 999);
+    }
+
+    #[test]
+    fn test_extract_if_updates() {
+        let mut map = (0..1000u32).map(|i| (i, 1)).collect::<BTreeMap<_, _>>();
+        let before = map.snapshot();
+        // The predicate updates every value and extracts none.
+        map.extract_if(|_, v| {
+            *v = 2;
+            false
+        })
+        .for_each(drop);
+        let after = map.snapshot();
+        assert_eq!(after.shared_pages(&before), 0);
+        assert_eq!(before.get(&5), Some(&1));
+        assert_eq!(after.get(&5), Some(&2));
+        assert!(after.iter().eq(map.iter()));
+    }
+}
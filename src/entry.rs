@@ -0,0 +1,275 @@
+use crate::{cache_oblivious::ParallelBounds, BTreeMap};
+use std::fmt;
+
+// A view into a single slot of the map, vacant or occupied, from `BTreeMap::entry`.
+pub enum Entry<'a, K: Ord, V> {
+    Vacant(VacantEntry<'a, K, V>),
+    Occupied(OccupiedEntry<'a, K, V>),
+}
+
+// The key is not in the map, `index` is the slot the index descent picked for it.
+pub struct VacantEntry<'a, K: Ord, V> {
+    map: &'a mut BTreeMap<K, V>,
+    key: K,
+    index: usize,
+}
+
+// The key is in the map, stored at `index`.
+pub struct OccupiedEntry<'a, K: Ord, V> {
+    map: &'a mut BTreeMap<K, V>,
+    index: usize,
+}
+
+// The error of `BTreeMap::try_insert` when the key is already in the map: the entry and the
+// value that was not inserted.
+pub struct OccupiedError<'a, K: Ord, V> {
+    pub entry: OccupiedEntry<'a, K, V>,
+    pub value: V,
+}
+
+impl<K, V> fmt::Debug for OccupiedError<'_, K, V>
+where
+    K: Ord + ParallelBounds + fmt::Debug,
+    V: ParallelBounds + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OccupiedError")
+            .field("key", self.entry.key())
+            .field("old_value", self.entry.get())
+            .field("new_value", &self.value)
+            .finish()
+    }
+}
+
+impl<K, V> fmt::Display for OccupiedError<'_, K, V>
+where
+    K: Ord + ParallelBounds + fmt::Debug,
+    V: ParallelBounds + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to insert {:?}, key {:?} already exists with value {:?}",
+            self.value,
+            self.entry.key(),
+            self.entry.get(),
+        )
+    }
+}
+
+impl<K, V> std::error::Error for OccupiedError<'_, K, V>
+where
+    K: Ord + ParallelBounds + fmt::Debug,
+    V: ParallelBounds + fmt::Debug,
+{
+}
+
+impl<'a, K, V> Entry<'a, K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    pub fn key(&self) -> &K {
+        match self {
+            Entry::Vacant(entry) => entry.key(),
+            Entry::Occupied(entry) => entry.key(),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default),
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => entry.insert(default()),
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Entry::Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            }
+            Entry::Occupied(entry) => entry.into_mut(),
+        }
+    }
+
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    pub fn and_modify<F: FnOnce(&mut V)>(self, f: F) -> Self {
+        match self {
+            Entry::Vacant(entry) => Entry::Vacant(entry),
+            Entry::Occupied(mut entry) => {
+                f(entry.get_mut());
+                Entry::Occupied(entry)
+            }
+        }
+    }
+}
+
+impl<'a, K, V> VacantEntry<'a, K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    pub(crate) fn new(map: &'a mut BTreeMap<K, V>, key: K, index: usize) -> Self {
+        Self { map, key, index }
+    }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    pub fn insert(self, value: V) -> &'a mut V {
+        let index = self.map.insert_vacant(self.index, self.key, value);
+        self.map.value_at_mut(index)
+    }
+}
+
+impl<'a, K, V> OccupiedEntry<'a, K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    pub(crate) fn new(map: &'a mut BTreeMap<K, V>, index: usize) -> Self {
+        Self { map, index }
+    }
+
+    pub fn key(&self) -> &K {
+        self.map.entry_at(self.index).0
+    }
+
+    pub fn get(&self) -> &V {
+        self.map.entry_at(self.index).1
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        self.map.value_at_mut(self.index)
+    }
+
+    pub fn into_mut(self) -> &'a mut V {
+        self.map.value_at_mut(self.index)
+    }
+
+    pub fn insert(&mut self, value: V) -> V {
+        std::mem::replace(self.get_mut(), value)
+    }
+
+    pub fn remove(self) -> V {
+        self.map.remove_entry_at(self.index)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod entry {
+    use crate::{BTreeMap, Entry};
+
+    #[test]
+    fn test_try_insert() {
+        let mut map = BTreeMap::<usize, String>::new();
+        for i in 0..100 {
+            *map.try_insert(i, String::new()).unwrap() += "x";
+        }
+        assert_eq!(map.get(&42).map(String::as_str), Some("x"));
+        let error = map.try_insert(42, String::from("y")).unwrap_err();
+        assert_eq!(error.entry.get(), "x");
+        assert_eq!(error.value, "y");
+        assert_eq!(
+            error.to_string(),
+            r#"failed to insert "y", key 42 already exists with value "x""#
+        );
+        map.mark_removed(&42);
+        assert_eq!(map.try_insert(42, String::from("z")).unwrap(), "z");
+        assert_eq!(map.len(), 100);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_entry() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        let mut expected = std::collections::BTreeMap::new();
+        for i in 0..2000 {
+            let key = (i * 7919) % 701;
+            *map.entry(key).or_insert(0) += i;
+            *expected.entry(key).or_insert(0) += i;
+        }
+        assert_eq!(map.len(), expected.len());
+        assert!(map.iter().eq(expected.iter()));
+
+        map.entry(5).and_modify(|v| *v = 1).or_insert(2);
+        map.entry(1000).and_modify(|v| *v = 1).or_insert(2);
+        assert_eq!((map.get(&5), map.get(&1000)), (Some(&1), Some(&2)));
+        assert_eq!(*map.entry(1001).or_insert_with_key(|&k| k + 1), 1002);
+        assert_eq!(*map.entry(1002).or_default(), 0);
+        assert_eq!(map.entry(1003).key(), &1003);
+
+        match map.entry(5) {
+            Entry::Occupied(mut entry) => {
+                assert_eq!(entry.key(), &5);
+                assert_eq!(entry.insert(10), 1);
+                *entry.get_mut() += 1;
+                assert_eq!(entry.get(), &11);
+                assert_eq!(entry.remove(), 11);
+            }
+            Entry::Vacant(_) => panic!("Entry 5 should be occupied."),
+        }
+        assert_eq!(map.get(&5), None);
+
+        // A hidden key is vacant and comes back on insert.
+        map.mark_removed(&6);
+        let len = map.len();
+        match map.entry(6) {
+            Entry::Vacant(entry) => assert_eq!(*entry.insert(60), 60),
+            Entry::Occupied(_) => panic!("Entry 6 should be vacant."),
+        }
+        assert_eq!(map.get(&6), Some(&60));
+        assert_eq!(map.len(), len + 1);
+        assert_eq!(map.marked_len(), 0);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_first_last_entry() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        assert!(map.first_entry().is_none() && map.last_entry().is_none());
+        for i in (0..500).rev() {
+            map.insert(i, i);
+        }
+        map.mark_removed(&0);
+        map.mark_removed(&499);
+        *map.first_entry().unwrap().get_mut() += 100;
+        assert_eq!(map.get(&1), Some(&101));
+        assert_eq!(map.last_entry().unwrap().key(), &498);
+
+        // Draining from both ends, like a double ended priority queue.
+        let mut popped = vec![];
+        while let Some(entry) = map.first_entry() {
+            popped.push(*entry.key());
+            entry.remove();
+            if let Some(entry) = map.last_entry() {
+                popped.push(*entry.key());
+                entry.remove();
+            }
+        }
+        assert_eq!(popped.len(), 498);
+        assert_eq!(popped[..4], [1, 498, 2, 497]);
+        assert!(map.is_empty());
+        map.check_invariants();
+    }
+}
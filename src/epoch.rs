@@ -0,0 +1,297 @@
+use crate::{cache_oblivious::ParallelBounds, BTreeMap, Comparable, MapSnapshot};
+use crossbeam_epoch::{pin, unprotected, Atomic, Guard, Owned};
+use std::{
+    marker::PhantomData,
+    sync::{atomic::Ordering, Arc},
+};
+
+struct Shared<K, V> {
+    current: Atomic<MapSnapshot<K, V>>,
+}
+
+impl<K, V> Drop for Shared<K, V> {
+    fn drop(&mut self) {
+        // Safety: the last handle is gone, so no reader holds the generation any more.
+        unsafe {
+            let current = self.current.load(Ordering::Relaxed, unprotected());
+            if !current.is_null() {
+                drop(current.into_owned());
+            }
+        }
+    }
+}
+
+// The single writer of a map read concurrently by any number of `EpochReader`s. Writes go to a
+// private map and become visible at the next `publish`, which takes a snapshot of it, copying
+// only the PMA segments written since the previous one, and swaps it in atomically. Retired
+// generations are freed once no reader pinned before the swap is left.
+//
+// Optimistic readers validating per window sequence counters, seqlock style, are not an
+// option over the live PMA: the slots hold arbitrary keys and values in plain memory, so a
+// read racing a rebalance is a data race and may follow a torn pointer of a key it then
+// compares, and growing the PMA frees the buffer under the reader, which no counter check
+// can prevent. Readers only ever touch immutable pages here instead.
+pub struct EpochWriter<K: Ord, V> {
+    map: BTreeMap<K, V>,
+    shared: Arc<Shared<K, V>>,
+}
+
+impl<K, V> Default for EpochWriter<K, V>
+where
+    K: Ord + Clone + ParallelBounds + Send + Sync,
+    V: Clone + ParallelBounds + Send + Sync,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> EpochWriter<K, V>
+where
+    K: Ord + Clone + ParallelBounds + Send + Sync,
+    V: Clone + ParallelBounds + Send + Sync,
+{
+    pub fn new() -> Self {
+        let shared = Shared {
+            current: Atomic::new(BTreeMap::new().snapshot()),
+        };
+        Self {
+            map: BTreeMap::new(),
+            shared: Arc::new(shared),
+        }
+    }
+
+    // A reader of the generations this writer publishes.
+    pub fn reader(&self) -> EpochReader<K, V> {
+        EpochReader {
+            shared: self.shared.clone(),
+        }
+    }
+
+    // The writer's own map, including the writes not published yet.
+    pub fn map(&self) -> &BTreeMap<K, V> {
+        &self.map
+    }
+
+    pub fn map_mut(&mut self) -> &mut BTreeMap<K, V> {
+        &mut self.map
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.map.insert(key, value)
+    }
+
+    pub fn remove<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> Option<V> {
+        self.map.remove(key)
+    }
+
+    // Makes every write so far visible to readers pinning after this returns.
+    pub fn publish(&mut self) {
+        let generation = self.map.snapshot();
+        let guard = pin();
+        let retired = self
+            .shared
+            .current
+            .swap(Owned::new(generation), Ordering::AcqRel, &guard);
+        // Safety: the generation is unreachable from now on, the readers that pinned it before
+        // the swap keep it alive until they unpin.
+        unsafe { guard.defer_destroy(retired) };
+    }
+}
+
+// A handle reading the latest generation an `EpochWriter` published. Readers never block nor
+// get blocked by the writer, and can be cloned and sent to other threads.
+pub struct EpochReader<K, V> {
+    shared: Arc<Shared<K, V>>,
+}
+
+impl<K, V> Clone for EpochReader<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<K: Ord, V> EpochReader<K, V> {
+    // Pins the current generation. Every read through the guard sees that same generation,
+    // whatever gets published meanwhile.
+    pub fn read(&self) -> ReadGuard<'_, K, V> {
+        let guard = pin();
+        let generation = self.shared.current.load(Ordering::Acquire, &guard).as_raw();
+        ReadGuard {
+            _guard: guard,
+            generation,
+            _reader: PhantomData,
+        }
+    }
+
+    // Reads the value under one short lived pin.
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.read().get(key).cloned()
+    }
+}
+
+// A pinned generation, see `EpochReader::read`. Holding it delays freeing the generations
+// retired meanwhile, so it is meant to be short lived.
+pub struct ReadGuard<'a, K, V> {
+    _guard: Guard,
+    generation: *const MapSnapshot<K, V>,
+    _reader: PhantomData<&'a EpochReader<K, V>>,
+}
+
+impl<K: Ord, V> ReadGuard<'_, K, V> {
+    fn generation(&self) -> &MapSnapshot<K, V> {
+        // Safety: the generation was loaded under `self._guard`, which stays pinned for as long
+        // as `self` lives, and the reader borrowed keeps the shared state alive.
+        unsafe { &*self.generation }
+    }
+
+    pub fn len(&self) -> usize {
+        self.generation().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&V> {
+        self.generation().get(key)
+    }
+
+    pub fn contains_key<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Iterates over the entries of the pinned generation in key order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> + '_ {
+        self.generation().iter()
+    }
+
+    // The pinned generation as a snapshot that outlives the pin, sharing its pages.
+    pub fn to_snapshot(&self) -> MapSnapshot<K, V> {
+        self.generation().clone()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod epoch {
+    use crate::EpochWriter;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_publish() {
+        let mut writer = EpochWriter::new();
+        let reader = writer.reader();
+        for i in 0..1000u32 {
+            writer.insert(i, i * 2);
+        }
+        assert!(reader.read().is_empty());
+        writer.publish();
+        let before = reader.read();
+        assert_eq!(before.len(), 1000);
+        assert_eq!(reader.get(&500), Some(1000));
+
+        writer.remove(&500);
+        *writer.map_mut().get_mut(&501).unwrap() = 0;
+        writer.map_mut().mark_removed(&502);
+        writer.insert(2000, 1);
+        writer.publish();
+        // The generation pinned before the publish is left as it was.
+        assert_eq!(before.get(&500), Some(&1000));
+        assert_eq!(before.iter().count(), 1000);
+        let after = reader.read();
+        assert_eq!(after.len(), 999);
+        assert_eq!(after.get(&500), None);
+        assert_eq!(after.get(&501), Some(&0));
+        assert!(!after.contains_key(&502));
+        assert_eq!(after.get(&2000), Some(&1));
+        assert!(after
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .eq(writer.map().iter().map(|(k, v)| (*k, *v))));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_publish_extract_if_updates() {
+        let mut writer = EpochWriter::new();
+        let reader = writer.reader();
+        for i in 0..1000u32 {
+            writer.insert(i, 1);
+        }
+        writer.publish();
+        writer
+            .map_mut()
+            .extract_if(|_, v| {
+                *v = 2;
+                false
+            })
+            .for_each(drop);
+        writer.publish();
+        assert_eq!(reader.get(&5), Some(2));
+        assert!(reader.read().iter().all(|(_, v)| *v == 2));
+    }
+
+    #[test]
+    fn test_shared_pages() {
+        let mut writer = EpochWriter::new();
+        let reader = writer.reader();
+        let count = if cfg!(miri) { 100 } else { 1000 };
+        for i in 0..count {
+            writer.insert(i, i);
+        }
+        writer.publish();
+        let before = reader.read().to_snapshot();
+        *writer.map_mut().get_mut(&10).unwrap() = 0;
+        writer.publish();
+        let after = reader.read().to_snapshot();
+        assert_eq!(
+            after.shared_pages(&before),
+            before.shared_pages(&before) - 1
+        );
+    }
+
+    #[test]
+    fn test_concurrent_readers() {
+        let mut writer = EpochWriter::new();
+        let done = AtomicBool::new(false);
+        let count = if cfg!(miri) { 50 } else { 2000u64 };
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let reader = writer.reader();
+                let done = &done;
+                scope.spawn(move || {
+                    while !done.load(Ordering::Acquire) {
+                        // The writer inserts the keys in order, so a generation holding `len`
+                        // entries holds exactly the first `len` keys.
+                        let read = reader.read();
+                        let len = read.len() as u64;
+                        assert_eq!(read.iter().count() as u64, len);
+                        if len > 0 {
+                            assert_eq!(read.get(&(len - 1)), Some(&(len - 1)));
+                            assert_eq!(read.get(&len), None);
+                        }
+                    }
+                });
+            }
+            for i in 0..count {
+                writer.insert(i, i);
+                if i % 10 == 0 {
+                    writer.publish();
+                }
+            }
+            writer.publish();
+            done.store(true, Ordering::Release);
+        });
+        assert_eq!(writer.reader().read().len() as u64, count);
+    }
+}
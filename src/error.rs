@@ -0,0 +1,39 @@
+use std::{collections::TryReserveError, fmt};
+
+// Why one of the fallible `try_*_raw` operations of `BTreeMap` failed. The plain operations
+// panic with the same message instead. Except for `Alloc`, these are broken invariants of the
+// layout, after which the map should be dropped rather than used further.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CoBTreeError {
+    // Growing the slots or the index failed to allocate. The map is left unchanged.
+    Alloc(TryReserveError),
+    // The window picked for an insert had no free slot, so the window counts were off.
+    NoSpace,
+    // The index had a leaf where a branch belongs, or the other way around.
+    NodeKind,
+}
+
+impl fmt::Display for CoBTreeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CoBTreeError::Alloc(error) => write!(f, "failed to grow the map: {}", error),
+            CoBTreeError::NoSpace => write!(f, "no space to insert in the rebalanced window"),
+            CoBTreeError::NodeKind => write!(f, "index node of the wrong kind"),
+        }
+    }
+}
+
+impl std::error::Error for CoBTreeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CoBTreeError::Alloc(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<TryReserveError> for CoBTreeError {
+    fn from(error: TryReserveError) -> Self {
+        CoBTreeError::Alloc(error)
+    }
+}
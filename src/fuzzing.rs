@@ -0,0 +1,64 @@
+use crate::{cache_oblivious::ParallelBounds, BTreeMap, CacheObliviousSet};
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+// Builds the map from arbitrary `(key, value)` pairs, the last value of a repeated key wins,
+// like `std::collections::BTreeMap` does.
+impl<'a, K, V> Arbitrary<'a> for BTreeMap<K, V>
+where
+    K: Arbitrary<'a> + Ord + ParallelBounds,
+    V: Arbitrary<'a> + ParallelBounds,
+{
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        u.arbitrary_iter()?.collect()
+    }
+
+    fn arbitrary_take_rest(u: Unstructured<'a>) -> Result<Self> {
+        u.arbitrary_take_rest_iter()?.collect()
+    }
+
+    fn size_hint(_depth: usize) -> (usize, Option<usize>) {
+        (0, None)
+    }
+}
+
+impl<'a, K> Arbitrary<'a> for CacheObliviousSet<K>
+where
+    K: Arbitrary<'a> + Ord + ParallelBounds,
+{
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        u.arbitrary_iter()?.collect()
+    }
+
+    fn arbitrary_take_rest(u: Unstructured<'a>) -> Result<Self> {
+        u.arbitrary_take_rest_iter()?.collect()
+    }
+
+    fn size_hint(_depth: usize) -> (usize, Option<usize>) {
+        (0, None)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod fuzzing {
+    use crate::{BTreeMap, CacheObliviousSet};
+    use arbitrary::{Arbitrary, Unstructured};
+
+    #[test]
+    fn test_arbitrary() {
+        let data = (1..=255u8).cycle().take(4096).collect::<Vec<_>>();
+        let map = BTreeMap::<u8, u16>::arbitrary(&mut Unstructured::new(&data)).unwrap();
+        assert!(!map.is_empty());
+        map.check_invariants();
+        assert!(map.keys().zip(map.keys().skip(1)).all(|(a, b)| a < b));
+
+        let map = BTreeMap::<u16, u8>::arbitrary_take_rest(Unstructured::new(&data)).unwrap();
+        map.check_invariants();
+        let set = CacheObliviousSet::<u8>::arbitrary_take_rest(Unstructured::new(&data)).unwrap();
+        assert!(set.iter().zip(set.iter().skip(1)).all(|(a, b)| a < b));
+
+        assert!(BTreeMap::<u8, u8>::arbitrary(&mut Unstructured::new(&[]))
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+// The van Emde Boas layout of a complete binary tree, as the index of `BTreeMap` uses it, for
+// static search structures of your own. Nodes are numbered in BFS order from 1 at the root, the
+// children of `n` being `2n` and `2n + 1`, and a tree of `height` levels has 2^height - 1
+// nodes. The layout splits the tree at half its height, stores the top tree first and the
+// bottom trees after it left to right, each laid out the same way recursively, so a root to
+// leaf path touches O(log_B n) blocks of any block size B.
+
+// The split of a tree of `height` levels into the height of the top tree and of the bottom
+// trees. Bottom trees get a power of two height, so every subtree splits evenly again.
+#[inline]
+pub(crate) fn split(height: usize) -> (usize, usize) {
+    let bottom = ((height + 1) >> 1).next_power_of_two();
+    (height - bottom, bottom)
+}
+
+// The BFS number `n` on level `d` (1 at the root) in a tree of `height` levels.
+fn node_id(n: usize, d: usize, height: usize) -> usize {
+    if height < 3 {
+        return n;
+    }
+    let (h1, h2) = split(height);
+    if d <= h1 {
+        node_id(n, d, h1)
+    } else {
+        let d1 = d - h1 - 1;
+        (1 << h1) - 1
+            + ((1 << h2) - 1) * ((n >> d1) - (1 << h1))
+            + node_id((1 << d1) | (n & ((1 << d1) - 1)), d - h1, h2)
+    }
+}
+
+#[inline]
+fn level(n: usize) -> usize {
+    (usize::BITS - n.leading_zeros()) as usize
+}
+
+// The position, from 1, in the vEB layout of the node with BFS number `n` in a tree of
+// `height` levels.
+pub fn compute_node_id(n: usize, height: usize) -> usize {
+    debug_assert!(n > 0 && level(n) <= height);
+    node_id(n, level(n), height)
+}
+
+// The inverse of `compute_node_id`: the BFS number of the node at `position`, from 1.
+pub fn compute_bfs_id(position: usize, height: usize) -> usize {
+    debug_assert!(position > 0 && level(position) <= height);
+    if height < 3 {
+        return position;
+    }
+    let (h1, h2) = split(height);
+    let top_size = (1 << h1) - 1;
+    if position <= top_size {
+        return compute_bfs_id(position, h1);
+    }
+    let bottom_size = (1 << h2) - 1;
+    let tree = (position - top_size - 1) / bottom_size;
+    let local = compute_bfs_id((position - top_size - 1) % bottom_size + 1, h2);
+    let d1 = level(local) - 1;
+    (((1 << h1) + tree) << d1) | (local - (1 << d1))
+}
+
+// The index, from 0, in an array laid out in vEB order of the node with BFS index `i`, from 0.
+#[inline]
+pub fn veb_index(i: usize, height: usize) -> usize {
+    compute_node_id(i + 1, height) - 1
+}
+
+// The inverse of `veb_index`.
+#[inline]
+pub fn bfs_index(i: usize, height: usize) -> usize {
+    compute_bfs_id(i + 1, height) - 1
+}
+
+// The order the index nodes of a map are stored in, picked per map with
+// `BTreeMap::with_layout`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IndexLayout {
+    // Recursive subtrees, so a descent touches O(log_B n) blocks whatever the block size.
+    #[default]
+    VanEmdeBoas,
+    // Level by level, a level after the one above, the children of a node next to each other.
+    // A descent touches a block per level below the first few, but the address of a node is
+    // its BFS index, with no layout math on the way down.
+    Bfs,
+}
+
+impl IndexLayout {
+    // BFS order is the Eytzinger layout of a complete tree.
+    pub const EYTZINGER: IndexLayout = IndexLayout::Bfs;
+
+    // The array index, from 0, of the node with BFS number `n`, from 1.
+    #[inline]
+    pub fn node_index(self, n: usize, height: usize) -> usize {
+        match self {
+            IndexLayout::VanEmdeBoas => compute_node_id(n, height) - 1,
+            IndexLayout::Bfs => n - 1,
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod layout {
+    use super::{bfs_index, compute_bfs_id, compute_node_id, veb_index, IndexLayout};
+
+    // The excatly tree was shown by the paper.
+    // https://ibb.co/BtmrpDz
+    #[test]
+    fn test_node_ids() {
+        let answer = vec![
+            1usize, 2, 17, 3, 4, 18, 19, 5, 8, 11, 14, 20, 23, 26, 29, 6, 7, 9, 10, 12, 13, 15, 16,
+            21, 22, 24, 25, 27, 28, 30, 31,
+        ];
+        for (n, &id) in answer.iter().enumerate() {
+            assert_eq!(compute_node_id(n + 1, 5), id);
+            assert_eq!(compute_bfs_id(id, 5), n + 1);
+        }
+    }
+
+    #[test]
+    fn test_inverse() {
+        for height in 1..12 {
+            let mut seen = vec![false; (1 << height) - 1];
+            for i in 0..seen.len() {
+                let index = veb_index(i, height);
+                assert!(!seen[index]);
+                seen[index] = true;
+                assert_eq!(bfs_index(index, height), i);
+            }
+        }
+    }
+
+    #[test]
+    fn test_index_layouts() {
+        for layout in [IndexLayout::VanEmdeBoas, IndexLayout::EYTZINGER] {
+            let mut seen = [false; 63];
+            for n in 1..64 {
+                seen[layout.node_index(n, 6)] = true;
+            }
+            assert!(seen.iter().all(|&seen| seen));
+            // The top tree of the vEB split comes first either way.
+            assert!((1..4).all(|n| layout.node_index(n, 6) < 3));
+        }
+    }
+}
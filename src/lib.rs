@@ -1,4 +1,86 @@
+mod aggregate;
+pub use aggregate::{Max, Min, Monoid, Sum};
+#[cfg(feature = "arena")]
+mod arena;
+#[cfg(feature = "arena")]
+pub use arena::Arena;
+mod bitmap;
+#[cfg(feature = "cache-sim")]
+mod cache_sim;
+#[cfg(feature = "cache-sim")]
+pub use cache_sim::{CacheOperation, CacheSimulator, CacheStats, LineHistogram};
 mod cache_oblivious;
-pub use cache_oblivious::BTreeMap;
+pub use cache_oblivious::{
+    BTreeMap, Cursor, Drain, ExtractIf, Hint, IntoIter, Iter, IterMut, Keys, ParallelBounds, Range,
+    RangeMut, RangeSlices, RangeStats, Surrounding, Values,
+};
+mod codec;
+#[cfg(feature = "lz4")]
+pub use codec::Lz4;
+#[cfg(feature = "zstd")]
+pub use codec::Zstd;
+pub use codec::{CompressedMap, Identity, RunLength, ValueCodec};
+mod comparable;
+pub use comparable::{Comparable, Equivalent};
+mod cow;
+pub use cow::MapSnapshot;
+mod entry;
+pub use entry::{Entry, OccupiedEntry, OccupiedError, VacantEntry};
+#[cfg(feature = "epoch")]
+mod epoch;
+#[cfg(feature = "epoch")]
+pub use epoch::{EpochReader, EpochWriter, ReadGuard};
+mod error;
+pub use error::CoBTreeError;
+#[cfg(feature = "arbitrary")]
+mod fuzzing;
+pub mod layout;
+pub use layout::IndexLayout;
+#[cfg(all(target_os = "linux", feature = "numa"))]
+mod numa;
+#[cfg(all(target_os = "linux", feature = "numa"))]
+pub use numa::{NumaPlacement, NumaPolicy};
+#[cfg(all(unix, feature = "mmap"))]
+mod mmap;
+mod ordered_map;
+pub use ordered_map::OrderedMap;
+mod merger;
 mod packed_memory_array;
+pub use packed_memory_array::{PackedMemoryArray, PmaIter};
+mod persistent;
+pub use persistent::{PersistentIter, PersistentMap};
+#[cfg(all(unix, feature = "mlock"))]
+mod pinning;
+mod priority_queue;
+pub use priority_queue::FunnelHeap;
+mod range_map;
+pub use range_map::RangeMap;
 mod segment;
+#[cfg(feature = "serde")]
+mod serialization;
+mod set;
+mod sharded;
+pub use sharded::{ShardedBTreeMap, ShardedRange};
+mod slots;
+mod snapshot;
+pub use set::{CacheObliviousSet, Difference, Intersection, SymmetricDifference, Union};
+pub use snapshot::Persist;
+pub mod sorting;
+mod static_map;
+pub use static_map::CoStaticMap;
+mod stats;
+pub use stats::Stats;
+#[cfg(feature = "proptest")]
+pub mod strategy;
+#[cfg(feature = "async")]
+mod stream;
+#[cfg(feature = "async")]
+pub use stream::AsyncIter;
+mod string_map;
+pub use string_map::{StringBTreeMap, StringIter};
+mod transaction;
+pub use transaction::Transaction;
+mod versioned;
+pub use versioned::VersionedMap;
+mod view;
+pub use view::{FilterView, MapView};
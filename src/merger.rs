@@ -0,0 +1,177 @@
+use crate::layout::{split, veb_index};
+use std::collections::VecDeque;
+
+// A k-merger, the merge tree of funnelsort and of the funnel heap: a complete binary tree of
+// k - 1 vertices merging k sorted inputs, each vertex with an output buffer it fills from its
+// children on demand. The buffers are stored in vEB order, and at each split of the tree the
+// roots of the bottom trees get a buffer holding the cube of the inputs below them, so a
+// merge of n elements costs O(n/B log_{M/B} n/B) block transfers whatever the block size.
+// Buffers allocate as they fill. Merges take from the left on ties, which keeps them stable.
+pub(crate) struct Merger<T> {
+    // The number of inputs, a power of two from 2.
+    k: usize,
+    // The vertices have BFS numbers 1..k. Vertex v merges vertices 2v and 2v + 1, or inputs
+    // 2v - k and 2v - k + 1 on the bottom level.
+    height: usize,
+    // The output buffers of the vertices in vEB order, with their capacities and the number of
+    // elements in each subtree, the buffer included.
+    buffers: Vec<VecDeque<T>>,
+    caps: Vec<usize>,
+    totals: Vec<usize>,
+    inputs: Vec<VecDeque<T>>,
+}
+
+// Whether to take `a` over `b`, `None` when both are exhausted.
+pub(crate) fn take_first<T>(
+    a: Option<&T>,
+    b: Option<&T>,
+    le: &impl Fn(&T, &T) -> bool,
+) -> Option<bool> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(le(a, b)),
+        (a, b) => a.or(b).map(|_| a.is_some()),
+    }
+}
+
+impl<T> Merger<T> {
+    // A merger of `k` empty inputs. The root buffer holds k^3 elements.
+    pub(crate) fn new(k: usize) -> Self {
+        Self::with_inputs((0..k).map(|_| VecDeque::new()).collect())
+    }
+
+    // A merger of the sorted `inputs`, as many as a power of two from 2.
+    pub(crate) fn with_inputs(inputs: Vec<VecDeque<T>>) -> Self {
+        let k = inputs.len();
+        debug_assert!(k >= 2 && k.is_power_of_two());
+        let height = k.trailing_zeros() as usize;
+        let mut caps = vec![0; k - 1];
+        caps[veb_index(0, height)] = k.pow(3);
+        Self::set_caps(1, height, height, &mut caps);
+        let mut merger = Self {
+            k,
+            height,
+            buffers: (1..k).map(|_| VecDeque::new()).collect(),
+            caps,
+            totals: vec![0; k - 1],
+            inputs,
+        };
+        merger.recount(&(1..k).collect::<Vec<_>>());
+        merger
+    }
+
+    // Gives the root of each bottom tree of the vEB split of the subtree at `root`, `h` levels
+    // high, a buffer holding the cube of the inputs below it, as the middle buffers of a
+    // k-merger do, and splits the top and the bottom trees the same way.
+    fn set_caps(root: usize, h: usize, height: usize, caps: &mut [usize]) {
+        if h < 2 {
+            return;
+        }
+        let (top, bottom) = split(h);
+        Self::set_caps(root, top, height, caps);
+        for i in 0..1usize << top {
+            let node = (root << top) | i;
+            caps[veb_index(node - 1, height)] = 1 << (3 * bottom);
+            Self::set_caps(node, bottom, height, caps);
+        }
+    }
+
+    pub(crate) fn inputs(&self) -> usize {
+        self.k
+    }
+
+    #[inline]
+    fn index(&self, v: usize) -> usize {
+        veb_index(v - 1, self.height)
+    }
+
+    // The number of elements in the merger, buffers and inputs.
+    pub(crate) fn len(&self) -> usize {
+        self.totals[self.index(1)]
+    }
+
+    pub(crate) fn buffer_mut(&mut self, v: usize) -> &mut VecDeque<T> {
+        let at = self.index(v);
+        &mut self.buffers[at]
+    }
+
+    pub(crate) fn input_mut(&mut self, j: usize) -> &mut VecDeque<T> {
+        &mut self.inputs[j]
+    }
+
+    // Fills the buffer of vertex `v` from its children until it is full or they run dry.
+    fn fill(&mut self, v: usize, le: &impl Fn(&T, &T) -> bool) {
+        let at = self.index(v);
+        while self.buffers[at].len() < self.caps[at] {
+            match self.pop_child(v, le) {
+                Some(item) => self.buffers[at].push_back(item),
+                None => break,
+            }
+        }
+    }
+
+    // The smaller head of the children of vertex `v`, taken out, filling them first if empty.
+    fn pop_child(&mut self, v: usize, le: &impl Fn(&T, &T) -> bool) -> Option<T> {
+        if 2 * v >= self.k {
+            let j = 2 * v - self.k;
+            let left = take_first(self.inputs[j].front(), self.inputs[j + 1].front(), le)?;
+            return self.inputs[j + usize::from(!left)].pop_front();
+        }
+        let (l, r) = (self.index(2 * v), self.index(2 * v + 1));
+        for (child, at) in [(2 * v, l), (2 * v + 1, r)] {
+            if self.buffers[at].is_empty() && self.totals[at] > 0 {
+                self.fill(child, le);
+            }
+        }
+        let left = take_first(self.buffers[l].front(), self.buffers[r].front(), le)?;
+        let at = if left { l } else { r };
+        self.totals[at] -= 1;
+        self.buffers[at].pop_front()
+    }
+
+    // The smallest element left, filling the root buffer first if empty.
+    pub(crate) fn front(&mut self, le: &impl Fn(&T, &T) -> bool) -> Option<&T> {
+        let root = self.index(1);
+        if self.buffers[root].is_empty() && self.totals[root] > 0 {
+            self.fill(1, le);
+        }
+        self.buffers[root].front()
+    }
+
+    // Takes out the element `front` returned.
+    pub(crate) fn pop_front(&mut self) -> Option<T> {
+        let root = self.index(1);
+        let item = self.buffers[root].pop_front()?;
+        self.totals[root] -= 1;
+        Some(item)
+    }
+
+    pub(crate) fn pop(&mut self, le: &impl Fn(&T, &T) -> bool) -> Option<T> {
+        self.front(le)?;
+        self.pop_front()
+    }
+
+    // The vertices from the root down to the one input `j` feeds, as BFS numbers.
+    pub(crate) fn path(&self, j: usize) -> Vec<usize> {
+        let mut path = vec![(self.k + j) >> 1];
+        while *path.last().unwrap() > 1 {
+            path.push(path.last().unwrap() >> 1);
+        }
+        path.reverse();
+        path
+    }
+
+    // Recounts the subtrees of the vertices in `vertices`, sorted by BFS number, after their
+    // buffers or inputs were rewritten.
+    pub(crate) fn recount(&mut self, vertices: &[usize]) {
+        for &v in vertices.iter().rev() {
+            let below = if 2 * v >= self.k {
+                let j = 2 * v - self.k;
+                self.inputs[j].len() + self.inputs[j + 1].len()
+            } else {
+                self.totals[self.index(2 * v)] + self.totals[self.index(2 * v + 1)]
+            };
+            let at = self.index(v);
+            self.totals[at] = self.buffers[at].len() + below;
+        }
+    }
+}
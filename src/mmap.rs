@@ -0,0 +1,264 @@
+use std::{
+    collections::TryReserveError,
+    fs::File,
+    io,
+    mem::size_of,
+    ops::{Deref, DerefMut},
+    os::unix::io::AsRawFd,
+    ptr::{self, NonNull},
+    slice,
+};
+
+// A vector of `T` kept in a shared mapping of `file`. Growing and shrinking resizes the file
+// with `ftruncate` and maps it again, the content carries over through the page cache.
+// Invariants the unsafe code relies on: unless `capacity` is 0, `ptr` is a live mapping of
+// `capacity * size_of::<T>()` bytes and the file is at least that long, so every byte of the
+// mapping is backed; `len <= capacity`; and the items in `[0, len)` are initialized.
+pub(crate) struct MappedSlots<T> {
+    file: File,
+    ptr: NonNull<T>,
+    len: usize,
+    capacity: usize,
+}
+
+// SAFETY: the mapping is owned exclusively, like the buffer of a `Vec`, so sending or sharing
+// the slots is sound exactly when it is for the items.
+unsafe impl<T: Send> Send for MappedSlots<T> {}
+unsafe impl<T: Sync> Sync for MappedSlots<T> {}
+
+impl<T> MappedSlots<T> {
+    pub(crate) fn new(file: File) -> io::Result<Self> {
+        assert!(
+            size_of::<T>() > 0,
+            "Mapped slots cannot hold zero sized types."
+        );
+        file.set_len(0)?;
+        Ok(Self {
+            file,
+            ptr: NonNull::dangling(),
+            len: 0,
+            capacity: 0,
+        })
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Maps the file again with room for `capacity` items, which must not drop below `len`.
+    // On error the old mapping, its capacity and a file long enough for it are kept.
+    fn remap(&mut self, capacity: usize) -> io::Result<()> {
+        debug_assert!(capacity >= self.len);
+        let bytes = capacity
+            .checked_mul(size_of::<T>())
+            .filter(|bytes| *bytes <= isize::MAX as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "Slot file too large."))?;
+        // The file grows before the larger mapping is made and shrinks only once the larger
+        // old mapping is gone, so no mapped page ever lies past the end of the file.
+        let shrinks = capacity < self.capacity;
+        if !shrinks {
+            self.file.set_len(bytes as u64)?;
+        }
+        let ptr = if bytes == 0 {
+            NonNull::dangling()
+        } else {
+            // SAFETY: a fresh shared mapping of the first `bytes` bytes of a file at least that
+            // long, placed by the kernel where it overlaps nothing. The result is checked below.
+            let address = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    bytes,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    self.file.as_raw_fd(),
+                    0,
+                )
+            };
+            if address == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            NonNull::new(address as *mut T).unwrap()
+        };
+        // The old mapping is unmapped only now that the new one exists, the items in
+        // `[0, len)` are shared by both through the page cache.
+        self.unmap();
+        self.ptr = ptr;
+        self.capacity = capacity;
+        if shrinks {
+            // A file left longer than the mapping is harmless, the next remap resizes it.
+            self.file.set_len(bytes as u64)?;
+        }
+        Ok(())
+    }
+
+    // Unmaps the current mapping. `ptr` is stale afterwards: callers map again or drop.
+    fn unmap(&mut self) {
+        if self.capacity > 0 {
+            // SAFETY: `ptr` and `capacity * size_of::<T>()` are exactly the address and length
+            // of the mapping made by `remap`, and no reference into it outlives `&mut self`.
+            unsafe {
+                libc::munmap(
+                    self.ptr.as_ptr() as *mut libc::c_void,
+                    self.capacity * size_of::<T>(),
+                );
+            }
+        }
+    }
+
+    pub(crate) fn grow(&mut self, additional: usize) -> io::Result<()> {
+        let required = self
+            .len
+            .checked_add(additional)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::OutOfMemory, "Slot file too large."))?;
+        if required > self.capacity {
+            self.remap(required.max(self.capacity * 2))?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        match self.len.checked_add(additional) {
+            Some(required) if required <= self.capacity => Ok(()),
+            Some(required) if self.remap(required).is_ok() => Ok(()),
+            // A file that cannot grow reports like an allocation beyond the address space.
+            _ => Err(Vec::<T>::new().try_reserve(usize::MAX).unwrap_err()),
+        }
+    }
+
+    pub(crate) fn push(&mut self, item: T) {
+        self.grow(1).expect("Failed to grow the slot file.");
+        // SAFETY: after `grow`, `len < capacity`, so the slot at `len` lies in the mapping. It
+        // is not initialized, so writing without dropping leaks nothing.
+        unsafe { self.ptr.as_ptr().add(self.len).write(item) };
+        self.len += 1;
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        // SAFETY: `len < self.len <= capacity`, so the offset stays within the mapping.
+        let tail =
+            ptr::slice_from_raw_parts_mut(unsafe { self.ptr.as_ptr().add(len) }, self.len - len);
+        // The length drops first, so a panicking `Drop` of an item leaks the rest instead of
+        // dropping them twice.
+        self.len = len;
+        // SAFETY: the tail `[len, old len)` was initialized and is no longer reachable.
+        unsafe { ptr::drop_in_place(tail) };
+    }
+
+    pub(crate) fn resize_with<F: FnMut() -> T>(&mut self, len: usize, mut f: F) {
+        if len <= self.len {
+            return self.truncate(len);
+        }
+        self.grow(len - self.len)
+            .expect("Failed to grow the slot file.");
+        while self.len < len {
+            self.push(f());
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        if self.capacity > self.len {
+            // Keeping the larger mapping is harmless if the file cannot shrink.
+            let _ = self.remap(self.len);
+        }
+    }
+
+    // Moves the items out to the heap.
+    pub(crate) fn into_vec(mut self) -> Vec<T> {
+        let mut items = Vec::with_capacity(self.len);
+        for i in 0..self.len {
+            // SAFETY: `i < len`, so the item is initialized. Each one is read once, and `len`
+            // is reset below so `drop` does not drop the moved items again.
+            items.push(unsafe { self.ptr.as_ptr().add(i).read() });
+        }
+        self.len = 0;
+        items
+    }
+}
+
+impl<T> Deref for MappedSlots<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        // SAFETY: `[0, len)` lies in the mapping and is initialized, and `ptr` is aligned and
+        // non null even while nothing is mapped. The borrow of `self` keeps the mapping alive.
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for MappedSlots<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        // SAFETY: as for `deref`, and the exclusive borrow of `self` makes the slice unique.
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<T> Drop for MappedSlots<T> {
+    fn drop(&mut self) {
+        self.truncate(0);
+        self.unmap();
+        let _ = self.file.set_len(0);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod mmap {
+    use crate::BTreeMap;
+    use std::fs;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri does not support mmap")]
+    fn test_mapped_map() {
+        let path = std::env::temp_dir().join(format!("co-btree-mmap-{}", std::process::id()));
+        let values_path = path.with_extension("values");
+        let mut map = BTreeMap::<usize, String>::with_mmap(&path).unwrap();
+        for i in (0..3000).rev() {
+            map.insert(i, format!("{}", i));
+        }
+        let files = [
+            (&path, std::mem::size_of::<usize>() as u64),
+            (&values_path, std::mem::size_of::<String>() as u64),
+        ];
+        for (path, slot_size) in files {
+            assert!(fs::metadata(path).unwrap().len() >= map.slot_capacity() as u64 * slot_size);
+        }
+        for i in 0..2900 {
+            assert_eq!(map.remove(&i), Some(format!("{}", i)));
+        }
+        map.shrink_to_fit();
+        for (path, slot_size) in files {
+            assert_eq!(
+                fs::metadata(path).unwrap().len(),
+                map.slot_capacity() as u64 * slot_size
+            );
+        }
+        assert!(map.iter().map(|(k, _)| *k).eq(2900..3000));
+        assert_eq!(map.get(&2950).map(String::as_str), Some("2950"));
+        let entries = map.into_iter().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 100);
+        for (path, _) in files {
+            assert_eq!(fs::metadata(path).unwrap().len(), 0);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri does not support mmap")]
+    fn test_mapped_set() {
+        let path = std::env::temp_dir().join(format!("co-btree-mmap-set-{}", std::process::id()));
+        let mut map = BTreeMap::<u64, ()>::with_mmap(&path).unwrap();
+        map.extend((0..2000).map(|i| (i, ())));
+        assert!(map.keys().copied().eq(0..2000));
+        // The unit values get no file.
+        assert!(!path.with_extension("values").exists());
+        assert_eq!(
+            fs::metadata(&path).unwrap().len(),
+            map.slot_capacity() as u64 * 8
+        );
+        drop(map);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use std::io;
+
+// Memory policy modes and flags of the kernel, see mbind(2).
+const MPOL_BIND: libc::c_int = 2;
+const MPOL_INTERLEAVE: libc::c_int = 3;
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+const MPOL_F_NODE: libc::c_int = 1 << 0;
+const MPOL_F_ADDR: libc::c_int = 1 << 1;
+
+// Nodes fit in a single mask word.
+const MAX_NODES: usize = 64;
+
+// Where the pages of a map should live.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NumaPolicy {
+    // All pages on one node.
+    Bind(usize),
+    // Pages spread round robin over the given nodes.
+    Interleave(Vec<usize>),
+}
+
+// Number of resident pages per NUMA node, indexed by node id.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct NumaPlacement {
+    // Pages of the PMA slots.
+    pub slot_pages: Vec<usize>,
+    // Pages of the index nodes.
+    pub index_pages: Vec<usize>,
+}
+
+impl NumaPolicy {
+    fn mode_and_mask(&self) -> io::Result<(libc::c_int, u64)> {
+        let (mode, nodes) = match self {
+            NumaPolicy::Bind(node) => (MPOL_BIND, std::slice::from_ref(node)),
+            NumaPolicy::Interleave(nodes) => (MPOL_INTERLEAVE, nodes.as_slice()),
+        };
+        if nodes.is_empty() || nodes.iter().any(|&node| node >= MAX_NODES) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        Ok((mode, nodes.iter().fold(0, |mask, &node| mask | 1 << node)))
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// Page aligned start and length of the pages covering `len` bytes from `address`.
+fn page_span(address: usize, len: usize) -> (usize, usize) {
+    let page_size = page_size();
+    let start = address / page_size * page_size;
+    let end = (address + len).div_ceil(page_size) * page_size;
+    (start, end - start)
+}
+
+// Applies the policy to the pages covering `len` bytes from `address` and migrates the pages
+// already faulted in. The pages at both ends may be shared with other allocations, which then
+// follow the same policy.
+pub(crate) fn bind(address: usize, len: usize, policy: &NumaPolicy) -> io::Result<()> {
+    let (mode, mask) = policy.mode_and_mask()?;
+    if len == 0 {
+        return Ok(());
+    }
+    let (start, len) = page_span(address, len);
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            start,
+            len,
+            mode,
+            &mask as *const u64,
+            MAX_NODES + 1,
+            MPOL_MF_MOVE,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Counts the pages covering `len` bytes from `address` per node they live on.
+pub(crate) fn placement(address: usize, len: usize) -> io::Result<Vec<usize>> {
+    let mut pages = vec![];
+    if len == 0 {
+        return Ok(pages);
+    }
+    let (start, len) = page_span(address, len);
+    for page in (start..start + len).step_by(page_size()) {
+        let mut node: libc::c_int = 0;
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_get_mempolicy,
+                &mut node as *mut libc::c_int,
+                std::ptr::null_mut::<u64>(),
+                0usize,
+                page,
+                MPOL_F_NODE | MPOL_F_ADDR,
+            )
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let node = node as usize;
+        if pages.len() <= node {
+            pages.resize(node + 1, 0);
+        }
+        pages[node] += 1;
+    }
+    Ok(pages)
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod numa {
+    use crate::{numa::NumaPolicy, BTreeMap};
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_policy_mask() {
+        assert_eq!(NumaPolicy::Bind(3).mode_and_mask().unwrap().1, 0b1000);
+        assert_eq!(
+            NumaPolicy::Interleave(vec![0, 2])
+                .mode_and_mask()
+                .unwrap()
+                .1,
+            0b101
+        );
+        for policy in [NumaPolicy::Bind(64), NumaPolicy::Interleave(vec![])] {
+            assert_eq!(
+                policy.mode_and_mask().unwrap_err().kind(),
+                ErrorKind::InvalidInput
+            );
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri does not support mbind")]
+    fn test_bind_map() {
+        // Node 0 exists on every machine, NUMA or not.
+        let mut map = BTreeMap::<usize, usize>::with_numa_policy(NumaPolicy::Bind(0)).unwrap();
+        for i in 0..2000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.numa_policy(), Some(&NumaPolicy::Bind(0)));
+        let placement = map.numa_placement().unwrap();
+        assert_eq!(placement.slot_pages.len(), 1);
+        assert_eq!(placement.index_pages.len(), 1);
+        assert!(placement.slot_pages[0] > 0);
+        assert_eq!(map.get(&1234), Some(&1234));
+
+        assert!(BTreeMap::<usize, usize>::with_numa_policy(NumaPolicy::Bind(64)).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{cache_oblivious::ParallelBounds, BTreeMap};
+use std::{borrow::Borrow, ops::Bound};
+
+// An object safe view of an ordered map over byte string keys and values, for crossing plugin
+// or dynamic library boundaries as `dyn OrderedMap` without monomorphizing the caller against
+// concrete key and value types. Keys are ordered bytewise.
+pub trait OrderedMap {
+    fn get(&self, key: &[u8]) -> Option<&[u8]>;
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>>;
+
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    // The entries between the bounds in key order.
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn Iterator<Item = (&'a [u8], &'a [u8])> + 'a>;
+}
+
+// Any map whose keys borrow as byte slices and whose values hold bytes, like
+// `BTreeMap<Vec<u8>, Vec<u8>>` or `BTreeMap<Box<[u8]>, Box<[u8]>>`.
+impl<K, V> OrderedMap for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds + Borrow<[u8]> + for<'a> From<&'a [u8]>,
+    V: ParallelBounds + AsRef<[u8]> + Into<Vec<u8>> + for<'a> From<&'a [u8]>,
+{
+    fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        BTreeMap::get(self, key).map(|v| v.as_ref())
+    }
+
+    fn insert(&mut self, key: &[u8], value: &[u8]) -> Option<Vec<u8>> {
+        BTreeMap::insert(self, K::from(key), V::from(value)).map(Into::into)
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        BTreeMap::remove(self, key).map(Into::into)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+    ) -> Box<dyn Iterator<Item = (&'a [u8], &'a [u8])> + 'a> {
+        Box::new(
+            self.range_entries(start, end)
+                .map(|(k, v)| (k.borrow(), v.as_ref())),
+        )
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod ordered_map {
+    use crate::{ordered_map::OrderedMap, BTreeMap};
+    use std::ops::Bound;
+
+    // Stands in for code on the other side of a plugin boundary.
+    fn fill(map: &mut dyn OrderedMap) {
+        for i in 0..100u8 {
+            map.insert(&[b'k', i], &[i; 3]);
+        }
+    }
+
+    #[test]
+    fn test_dyn_map() {
+        let mut maps: Vec<Box<dyn OrderedMap>> = vec![
+            Box::new(BTreeMap::<Vec<u8>, Vec<u8>>::new()),
+            Box::new(BTreeMap::<Box<[u8]>, Box<[u8]>>::new()),
+        ];
+        for map in maps.iter_mut() {
+            assert!(map.is_empty());
+            fill(map.as_mut());
+            assert_eq!(map.len(), 100);
+            assert_eq!(map.get(&[b'k', 7]), Some(&[7u8, 7, 7][..]));
+            assert!(!map.contains_key(b"x"));
+            assert_eq!(map.insert(&[b'k', 7], b"new"), Some(vec![7, 7, 7]));
+            assert_eq!(map.remove(&[b'k', 8]), Some(vec![8, 8, 8]));
+            assert_eq!(map.remove(&[b'k', 8]), None);
+            let keys = map
+                .range(Bound::Excluded(&[b'k', 5]), Bound::Included(&[b'k', 9]))
+                .map(|(k, _)| k[1])
+                .collect::<Vec<_>>();
+            assert_eq!(keys, vec![6, 7, 9]);
+            assert_eq!(
+                map.range(Bound::Unbounded, Bound::Excluded(b"k")).count(),
+                0
+            );
+        }
+    }
+}
@@ -1,81 +1,1243 @@
 #![allow(dead_code)]
 
-use crate::segment::Segment;
+#[cfg(all(unix, feature = "mmap"))]
+use crate::mmap::MappedSlots;
+#[cfg(all(unix, feature = "mlock"))]
+use crate::pinning::{self, PinnedRegions};
+use crate::{
+    bitmap, comparable::Comparable, segment::Segment, slots::Slots, stats::Stats, CoBTreeError,
+};
 use num_rational::Ratio;
+use std::{
+    cmp::Ordering,
+    collections::TryReserveError,
+    fmt,
+    mem::MaybeUninit,
+    sync::{Arc, Weak},
+};
 
-pub(crate) struct PackedMemoryArray<K: Clone + Ord, V: Clone> {
-    v: Vec<Option<(K, V)>>,
-    data: Vec<*mut Option<(K, V)>>,
+// The value an update replaced or removed, and the range of slots it changed, `None` for the
+// whole array after a resize.
+type Changed<V> = (Option<V>, Option<(usize, usize)>);
+
+// The height of the density tree and the log of the segment size of a layout of `len` slots, a
+// power of two: the segments get about the square root of the slots, the rest go to the levels.
+pub(crate) fn layout_shape(len: usize) -> (usize, usize) {
+    let len_log2 = len.trailing_zeros() as usize;
+    let segment_size_log2 = len_log2 >> 1;
+    (len_log2 - segment_size_log2 + 1, segment_size_log2)
+}
+
+// Whether a window of `size` slots at `depth` of a density tree `height` levels high may hold
+// `count` entries after an insert.
+#[inline]
+pub(crate) fn insert_density_ok(height: usize, depth: usize, count: usize, size: usize) -> bool {
+    // (1 / 4) + 3 * (d / height) * 4 = (height * 3 + d) / (height * 4)
+    Ratio::new_raw(count, size) <= Ratio::new_raw(height * 3 + depth, height << 2)
+}
+
+// Whether such a window may hold `count` entries after a remove.
+#[inline]
+pub(crate) fn remove_density_ok(height: usize, depth: usize, count: usize, size: usize) -> bool {
+    // (1 / 2) - (d / height) / 4 = (height * 2 - d) / (height * 4)
+    Ratio::new_raw(count, size) >= Ratio::new_raw((height << 1) - depth, height << 2)
+}
+
+// A sorted array of key values with gaps spread between them, so an insert only shifts the
+// entries up to the nearest gap and a rebalance only touches a window sized to the density
+// lost. It is the leaf level of `BTreeMap`, and on its own a sorted map without the index
+// tree: lookups binary search the slots, which is enough for small arrays and for rank based
+// access.
+pub struct PackedMemoryArray<K: Ord, V> {
+    // The slot regions locked by `pin_slots`, released before any resize moves the slots.
+    // Declared first so they are unlocked before the slots are freed.
+    #[cfg(all(unix, feature = "mlock"))]
+    pinned: PinnedRegions,
+    // Keys and values in parallel slots, so descents and rebalance scans reading keys do not
+    // pull the values into the cache. A slot is initialized exactly when its `occupied` bit is
+    // set, so gaps cost no tag and no padding.
+    keys: Slots<MaybeUninit<K>>,
+    values: Slots<MaybeUninit<V>>,
     height: usize,
     segment_size_log2: usize,
     segment_size: usize,
+    // Registered cursors, a position means the next entry is the first occupied slot at or
+    // after it. Rebalances remap them so every cursor keeps the same entries behind it.
+    cursors: Vec<Option<CursorSlot>>,
+    // Optional metadata slots parallel to the keys, empty until enabled.
+    meta: Vec<u64>,
+    // Occupied slots of every window of the density tree, the root window at 1 and the leaf
+    // segments from `1 << (height - 1)` on, so density checks read one counter per level
+    // instead of scanning the windows.
+    counts: Vec<usize>,
+    // One bit per slot, set for the occupied ones. Every code path reads or drops a slot only
+    // through its bit.
+    occupied: Vec<u64>,
+    // With `adaptive`, rebalances after a run of sequential inserts leave the free slots they
+    // make near the insertion point rather than spread evenly.
+    adaptive: bool,
+    run: InsertRun,
+    // Counters of the work done by updates, `None` while not counting.
+    stats: Option<Stats>,
 }
 
+struct CursorSlot {
+    position: usize,
+    // Held strongly by the cursor handle. Once the handle is dropped the slot is skipped by
+    // rebalances and taken by the next cursor registered.
+    owner: Weak<()>,
+}
+
+impl CursorSlot {
+    fn is_live(&self) -> bool {
+        self.owner.strong_count() > 0
+    }
+}
+
+// The run of inserts the latest ones belong to, each new entry landing right behind the
+// previous one (ascending keys) or right in front of it (descending keys).
+#[derive(Clone, Copy, Default)]
+struct InsertRun {
+    rank: usize,
+    length: usize,
+    ascending: bool,
+}
+
+// Runs at least this long count as sequential inserts.
+const ADAPTIVE_MIN_RUN: usize = 4;
+
 impl<K, V> PackedMemoryArray<K, V>
 where
-    K: Clone + Ord,
-    V: Clone,
+    K: Ord,
 {
     #[inline]
-    pub(crate) fn new() -> Self {
-        let mut v = vec![None];
+    pub fn new() -> Self {
         Self {
-            data: vec![&mut v[0]],
-            v,
+            #[cfg(all(unix, feature = "mlock"))]
+            pinned: PinnedRegions::default(),
+            keys: Slots::Heap(vec![MaybeUninit::uninit()]),
+            values: Slots::Heap(vec![MaybeUninit::uninit()]),
             height: 1,
             segment_size_log2: 0,
             segment_size: 1,
+            cursors: vec![],
+            meta: vec![],
+            counts: vec![0, 0],
+            occupied: vec![0],
+            adaptive: false,
+            stats: None,
+            run: InsertRun::default(),
+        }
+    }
+
+    // The number of key values.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.counts[1]
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The slot right behind the last key less than `key`, or 0. The binary search skips over
+    // gaps by jumping to the next occupied slot of the half it probes.
+    fn search<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> usize {
+        let (mut lo, mut hi) = (0, self.data_len());
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match self.next_occupied(mid, hi) {
+                Some(i) if key.compare(self.key(i).unwrap()) == Ordering::Greater => lo = i + 1,
+                Some(i) => hi = i,
+                None => hi = mid,
+            }
+        }
+        lo
+    }
+
+    // The first slot holding a key not less than `key`, or the number of slots.
+    fn lower_bound<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> usize {
+        self.next_occupied(self.search(key), self.data_len())
+            .unwrap_or(self.data_len())
+    }
+
+    // Inserts a key value at its place in key order, returning the value it replaces. A new
+    // key takes the free slot right behind the previous key if there is one, or the one right
+    // in front of the next key during a descending run, so runs fill gaps from their far end.
+    pub fn insert_sorted(&mut self, key: K, value: V) -> Option<V> {
+        let slot = self.search(&key);
+        let index = match self.next_occupied(slot, self.data_len()) {
+            Some(next) if self.key(next) == Some(&key) => next,
+            Some(next) if next > slot && self.run.length > 1 && !self.run.ascending => next - 1,
+            _ => slot,
+        };
+        self.insert_at(index, (key, value)).0
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&V> {
+        let (k, v) = self.key_value(self.lower_bound(key))?;
+        key.equivalent(k).then_some(v)
+    }
+
+    pub fn remove<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> Option<V> {
+        let index = self.lower_bound(key);
+        match self.key(index) {
+            Some(k) if key.equivalent(k) => self.remove_at(index).0,
+            _ => None,
+        }
+    }
+
+    // The key value with `rank` smaller keys, found by walking the window counters down to a
+    // leaf segment and scanning it.
+    pub fn get_by_rank(&self, rank: usize) -> Option<(&K, &V)> {
+        if rank >= self.len() {
+            return None;
+        }
+        let first_segment_id = 1 << (self.height - 1);
+        let (mut id, mut rank) = (1, rank);
+        while id < first_segment_id {
+            id <<= 1;
+            if self.counts[id] <= rank {
+                rank -= self.counts[id];
+                id |= 1;
+            }
+        }
+        let mut index = (id - first_segment_id) << self.segment_size_log2;
+        loop {
+            index = self.next_occupied(index, self.data_len())?;
+            if rank == 0 {
+                return self.key_value(index);
+            }
+            rank -= 1;
+            index += 1;
+        }
+    }
+
+    // The key values in key order.
+    pub fn iter(&self) -> PmaIter<'_, K, V> {
+        self.range(0, self.data_len())
+    }
+
+    // Switches the adaptive layout on or off. An adaptive array watches for runs of sequential
+    // inserts, like increasing timestamps or keys inserted over and over in front of the same
+    // entry, and makes the rebalances they trigger leave half the free slots as one gap at the
+    // insertion point, so the next inserts of the run find room without moving entries. Other
+    // insert patterns get the even layout either way.
+    pub fn set_adaptive(&mut self, adaptive: bool) {
+        self.adaptive = adaptive;
+        self.run = InsertRun::default();
+    }
+
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    // Checks the layout against what every operation keeps true and panics at the first
+    // violation. The key and value slots must stay parallel to the occupancy bitmap. Every
+    // window counter must match the occupied slots of its window, and the keys must be strictly
+    // increasing. Updates only enforce the density thresholds on the windows they rebalance,
+    // so a window is held to its size and nothing tighter. Takes O(n) time.
+    pub fn check_invariants(&self) {
+        let len = self.data_len();
+        assert!(len.is_power_of_two(), "The array has {} slots.", len);
+        assert_eq!(self.values.len(), len, "The value slots are not parallel.");
+        assert!(
+            !self.meta_enabled() || self.meta.len() == len,
+            "The metadata slots are not parallel."
+        );
+        assert_eq!(self.occupied.len(), bitmap::words_for(len));
+        assert_eq!(
+            bitmap::count(
+                &self.occupied,
+                len,
+                self.occupied.len() * u64::BITS as usize
+            ),
+            0,
+            "Slots past the end are occupied."
+        );
+        assert_eq!(self.segment_size, 1 << self.segment_size_log2);
+        let first_segment_id = 1 << (self.height - 1);
+        assert_eq!(first_segment_id << self.segment_size_log2, len);
+        assert_eq!(self.counts.len(), first_segment_id << 1);
+        for id in (1..first_segment_id << 1).rev() {
+            let count = if id >= first_segment_id {
+                let start = (id - first_segment_id) << self.segment_size_log2;
+                bitmap::count(&self.occupied, start, start + self.segment_size)
+            } else {
+                self.counts[id << 1] + self.counts[(id << 1) | 1]
+            };
+            assert_eq!(
+                self.counts[id], count,
+                "Window {} counts {} entries, it holds {}.",
+                id, self.counts[id], count
+            );
+        }
+        let mut last = None;
+        for (key, _) in self.iter() {
+            assert!(last < Some(key), "The keys are out of order.");
+            last = Some(key);
+        }
+        for (_, position) in self.live_cursors() {
+            assert!(position <= len, "A cursor is past the end.");
+        }
+    }
+
+    // The number of entries in front of the slot `index`, summed over the window counters
+    // instead of counted.
+    fn rank_of_slot(&self, index: usize) -> usize {
+        if index >= self.data_len() {
+            return self.len();
+        }
+        let segment_start = index & !(self.segment_size - 1);
+        let mut rank = bitmap::count(&self.occupied, segment_start, index);
+        let mut id = (1 << (self.height - 1)) + (index >> self.segment_size_log2);
+        while id > 1 {
+            if id & 1 == 1 {
+                rank += self.counts[id ^ 1];
+            }
+            id >>= 1;
+        }
+        rank
+    }
+
+    // Feeds a new entry about to be inserted in front of the slot `index` to the run tracker.
+    // Returns the direction of the run when it is long enough to adapt the layout to.
+    fn track_insert(&mut self, index: usize) -> Option<bool> {
+        if !self.adaptive {
+            return None;
+        }
+        let rank = self.rank_of_slot(index);
+        let run = self.run;
+        self.run = match (run.length > 0).then(|| rank.checked_sub(run.rank)) {
+            Some(Some(1)) if run.ascending || run.length == 1 => InsertRun {
+                rank,
+                length: run.length + 1,
+                ascending: true,
+            },
+            Some(Some(0)) if !run.ascending || run.length == 1 => InsertRun {
+                rank,
+                length: run.length + 1,
+                ascending: false,
+            },
+            _ => InsertRun {
+                rank,
+                length: 1,
+                ascending: false,
+            },
+        };
+        (self.run.length >= ADAPTIVE_MIN_RUN).then_some(self.run.ascending)
+    }
+
+    // Lays out key values, which must be sorted by unique keys, evenly over the smallest
+    // layout that a sequence of inserts would accept at the root window (density <= 3 / 4).
+    pub(crate) fn from_sorted(key_values: Vec<(K, V)>) -> Self {
+        Self::from_sorted_with_meta(key_values, vec![])
+    }
+
+    // Same as `from_sorted`, with the metadata of every key value when `meta` is not empty.
+    pub(crate) fn from_sorted_with_meta(key_values: Vec<(K, V)>, meta: Vec<u64>) -> Self {
+        let count = key_values.len();
+        let mut pma = Self::new();
+        let (keys, values) = key_values
+            .into_iter()
+            .map(|(k, v)| (MaybeUninit::new(k), MaybeUninit::new(v)))
+            .unzip();
+        pma.keys = Slots::Heap(keys);
+        pma.values = Slots::Heap(values);
+        pma.meta = meta;
+        if pma.meta_enabled() && count == 0 {
+            pma.meta.push(0);
+        }
+        pma.relayout(count);
+        pma
+    }
+
+    // Spreads the `count` key values packed at the front of the slots (and `meta`) evenly over the
+    // smallest layout that a sequence of inserts would accept at the root window, reusing the
+    // buffers already allocated.
+    fn relayout(&mut self, count: usize) {
+        self.relayout_to(count, Self::layout_len(count));
+    }
+
+    // The number of slots of the smallest layout taking `count` key values at the root window.
+    pub(crate) fn layout_len(count: usize) -> usize {
+        match count {
+            0 => 1,
+            _ => (count * 4).div_ceil(3).next_power_of_two().max(2),
+        }
+    }
+
+    // Same as `relayout`, over a layout of `len` slots, a power of two large enough.
+    fn relayout_to(&mut self, count: usize, len: usize) {
+        self.release_pins();
+        self.keys.resize_with(len, MaybeUninit::uninit);
+        self.values.resize_with(len, MaybeUninit::uninit);
+        if self.meta_enabled() {
+            self.meta.resize(len, 0);
+        }
+        bitmap::resize(&mut self.occupied, len);
+        bitmap::fill(&mut self.occupied, 0, len, false);
+        bitmap::fill(&mut self.occupied, 0, count, true);
+        (self.height, self.segment_size_log2) = layout_shape(len);
+        self.segment_size = 1 << self.segment_size_log2;
+        self.segment(0, len, Some(count)).shuffle_key_values(false);
+        self.recount();
+    }
+
+    // Counts the occupied slots of every window again, after the layout changed.
+    fn recount(&mut self) {
+        self.counts.clear();
+        self.counts.resize(2 << (self.height - 1), 0);
+        self.recount_window(0, self.data_len());
+    }
+
+    // Counts the leaf segments covering the slots [from, to) again, and the windows above.
+    fn recount_window(&mut self, from: usize, to: usize) {
+        if from >= to {
+            return;
+        }
+        let first_segment_id = 1 << (self.height - 1);
+        let mut lo = first_segment_id + (from >> self.segment_size_log2);
+        let mut hi = first_segment_id + ((to - 1) >> self.segment_size_log2);
+        for id in lo..=hi {
+            let start = (id - first_segment_id) << self.segment_size_log2;
+            self.counts[id] = bitmap::count(&self.occupied, start, start + self.segment_size);
+        }
+        while lo > 1 {
+            lo >>= 1;
+            hi >>= 1;
+            for id in lo..=hi {
+                self.counts[id] = self.counts[id << 1] + self.counts[(id << 1) | 1];
+            }
+        }
+    }
+
+    // The occupied slots of the window of `size` slots at `from`, which is aligned on `size`.
+    #[inline]
+    fn window_count(&self, from: usize, size: usize) -> usize {
+        let level = (size >> self.segment_size_log2).trailing_zeros();
+        self.counts[((1 << (self.height - 1)) + (from >> self.segment_size_log2)) >> level]
+    }
+
+    // Grows in one step to the layout that takes `count` key values without doubling, keeping
+    // cursors behind the same entries. Returns whether the layout changed, it never shrinks.
+    pub(crate) fn reserve(&mut self, count: usize) -> bool {
+        let len = Self::layout_len(count);
+        let old_len = self.data_len();
+        if len <= old_len {
+            return false;
+        }
+        let stored = bitmap::count(&self.occupied, 0, old_len);
+        let ranks = self.cursor_ranks(0, old_len, true);
+        self.segment(0, old_len, Some(stored))
+            .move_all_key_values_to_front();
+        self.relayout_to(stored, len);
+        self.restore_cursors(0, len, ranks);
+        true
+    }
+
+    // Same as `reserve`, allocating the larger buffers up front so a failed allocation leaves
+    // the layout untouched.
+    pub(crate) fn try_reserve(&mut self, count: usize) -> Result<bool, TryReserveError> {
+        let len = Self::layout_len(count);
+        if len <= self.data_len() {
+            return Ok(false);
+        }
+        if self.meta_enabled() {
+            self.meta.try_reserve_exact(len - self.meta.len())?;
+        }
+        self.release_pins();
+        self.keys.try_reserve_exact(len - self.keys.len())?;
+        self.values.try_reserve_exact(len - self.values.len())?;
+        Ok(self.reserve(count))
+    }
+
+    // Moves the key and value slots into mappings of the two files, where they stay through
+    // every later resize. Without a values file the value slots stay on the heap, which is
+    // meant for zero sized values that a heap vector stores without allocating.
+    #[cfg(all(unix, feature = "mmap"))]
+    pub(crate) fn map_slots(
+        &mut self,
+        keys_file: std::fs::File,
+        values_file: Option<std::fs::File>,
+    ) -> std::io::Result<()> {
+        self.release_pins();
+        self.keys = Slots::Mapped(Self::map_file(&mut self.keys, keys_file)?);
+        if let Some(values_file) = values_file {
+            self.values = Slots::Mapped(Self::map_file(&mut self.values, values_file)?);
+        }
+        Ok(())
+    }
+
+    // Moves the key and value slots into the arena, where they stay through every later
+    // resize.
+    #[cfg(feature = "arena")]
+    pub(crate) fn move_to_arena(&mut self, arena: &crate::Arena) {
+        self.release_pins();
+        self.keys = std::mem::replace(&mut self.keys, Slots::Heap(vec![])).into_arena(arena);
+        self.values = std::mem::replace(&mut self.values, Slots::Heap(vec![])).into_arena(arena);
+    }
+
+    #[cfg(all(unix, feature = "mmap"))]
+    fn map_file<T>(slots: &mut Slots<T>, file: std::fs::File) -> std::io::Result<MappedSlots<T>> {
+        let mut mapped = MappedSlots::new(file)?;
+        mapped.grow(slots.len())?;
+        for slot in std::mem::replace(slots, Slots::Heap(vec![])).into_vec() {
+            mapped.push(slot);
+        }
+        Ok(mapped)
+    }
+
+    // Releases the capacity kept around for reuse by earlier shrinks and clears.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        self.release_pins();
+        self.keys.shrink_to_fit();
+        self.values.shrink_to_fit();
+        self.meta.shrink_to_fit();
+        self.occupied.shrink_to_fit();
+        self.counts.shrink_to_fit();
+    }
+
+    // Takes over a layout saved from another PMA, as `height` and `segment_size_log2` of
+    // `slots.len()` slots. The caller checks the layout is one a PMA can be in.
+    pub(crate) fn from_layout(
+        slots: Vec<Option<(K, V)>>,
+        meta: Vec<u64>,
+        height: usize,
+        segment_size_log2: usize,
+    ) -> Self {
+        let mut pma = Self::new();
+        bitmap::resize(&mut pma.occupied, slots.len());
+        for (i, kv) in slots.iter().enumerate() {
+            if kv.is_some() {
+                bitmap::set(&mut pma.occupied, i);
+            }
+        }
+        let (keys, values) = slots
+            .into_iter()
+            .map(|kv| match kv {
+                Some((k, v)) => (MaybeUninit::new(k), MaybeUninit::new(v)),
+                None => (MaybeUninit::uninit(), MaybeUninit::uninit()),
+            })
+            .unzip();
+        pma.keys = Slots::Heap(keys);
+        pma.values = Slots::Heap(values);
+        pma.meta = meta;
+        pma.height = height;
+        pma.segment_size_log2 = segment_size_log2;
+        pma.segment_size = 1 << segment_size_log2;
+        pma.recount();
+        pma
+    }
+
+    // Whether the layout leaves updates room: the array is not full and no window holds more
+    // entries than the insert density bound of its depth, rounded up to whole entries as an
+    // even spread rounds them. Layouts taken over by `from_layout` are checked with it. The
+    // remove bound is left out: fresh layouts go down to 3 / 8 at the root, and a sparse
+    // window only makes the next remove rebalance.
+    pub(crate) fn densities_ok(&self) -> bool {
+        let first_segment_id = 1usize << (self.height - 1);
+        let height = self.height;
+        self.counts[1] < self.data_len()
+            && (1..first_segment_id << 1).all(|id| {
+                let depth = id.ilog2() as usize;
+                let size = self.segment_size << (height - 1 - depth);
+                self.counts[id] <= ((height * 3 + depth) * size).div_ceil(height << 2)
+            })
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.height
+    }
+
+    pub(crate) fn segment_size(&self) -> usize {
+        self.segment_size
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.keys.capacity()
+    }
+
+    // A segment over [from, to) carrying the metadata slots when they are enabled.
+    #[inline]
+    fn segment(&mut self, from: usize, to: usize, count: Option<usize>) -> Segment<'_, K, V> {
+        let meta = if self.meta.is_empty() {
+            None
+        } else {
+            Some(&mut self.meta[from..to])
+        };
+        Segment::new(
+            &mut self.keys[from..to],
+            &mut self.values[from..to],
+            &mut self.occupied,
+            from,
+            count,
+        )
+        .with_meta(meta)
+        .with_moves(self.stats.as_mut().map(|stats| &mut stats.moved))
+    }
+
+    pub(crate) fn stats(&self) -> Option<Stats> {
+        self.stats
+    }
+
+    // Starts counting from `stats`, or stops counting with `None`.
+    pub(crate) fn set_stats(&mut self, stats: Option<Stats>) {
+        self.stats = stats;
+    }
+
+    #[inline]
+    pub(crate) fn record_stats(&mut self, record: impl FnOnce(&mut Stats)) {
+        if let Some(stats) = &mut self.stats {
+            record(stats);
+        }
+    }
+
+    pub(crate) fn enable_meta(&mut self) {
+        if self.meta.is_empty() {
+            self.meta = vec![0; self.keys.len()];
+        }
+    }
+
+    #[inline]
+    pub(crate) fn meta_enabled(&self) -> bool {
+        !self.meta.is_empty()
+    }
+
+    // 0 <= index < data.len(), slots without metadata read as 0.
+    #[inline]
+    pub(crate) fn get_meta(&self, index: usize) -> u64 {
+        self.meta.get(index).copied().unwrap_or(0)
+    }
+
+    // 0 <= index < data.len(), metadata must be enabled.
+    #[inline]
+    pub(crate) fn set_meta(&mut self, index: usize, meta: u64) {
+        self.meta[index] = meta;
+    }
+
+    // Drops every key value and goes back to the initial layout, registered cursors stay
+    // registered and move to the front.
+    // The slot buffers keep their capacity for the next growth.
+    pub(crate) fn clear(&mut self) {
+        self.drop_key_values();
+        self.keys.clear();
+        self.values.clear();
+        if self.meta_enabled() {
+            self.meta.clear();
+            self.meta.push(0);
+        }
+        self.cursors
+            .iter_mut()
+            .flatten()
+            .for_each(|c| c.position = 0);
+        self.relayout(0);
+    }
+
+    // Drops the key values `keep` rejects in one pass and lays the survivors out again as
+    // `from_sorted` does, keeping every cursor behind the same surviving entries.
+    // Returns the dropped key values in key order.
+    pub(crate) fn retain<F>(&mut self, mut keep: F) -> Vec<(K, V)>
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        let mut positions: Vec<(usize, usize)> =
+            self.live_cursors().map(|(id, p)| (p, id)).collect();
+        positions.sort_unstable();
+        let mut positions = positions.into_iter().peekable();
+        let mut ranks = Vec::with_capacity(self.cursors.len());
+        let mut kept = 0;
+        let mut dropped = vec![];
+        for i in 0..self.keys.len() {
+            while let Some((_, id)) = positions.next_if(|&(p, _)| p <= i) {
+                ranks.push((id, kept));
+            }
+            if let Some((k, v)) = self.key_value_mut(i) {
+                if keep(k, v) {
+                    // Slots in front of `i` past the kept ones are free, the bits move along.
+                    if kept != i {
+                        let kv = self.take(i).unwrap();
+                        self.put(kept, kv);
+                    }
+                    if self.meta_enabled() {
+                        self.meta[kept] = self.meta[i];
+                    }
+                    kept += 1;
+                } else {
+                    dropped.push(self.take(i).unwrap());
+                }
+            }
+        }
+        ranks.extend(positions.map(|(_, id)| (id, kept)));
+        self.relayout(kept);
+        self.restore_cursors(0, self.data_len(), ranks);
+        dropped
+    }
+
+    // Merges key values sorted by unique keys, with their metadata, into the array in a single
+    // pass and lays the result out again. An incoming value replaces the stored value of an
+    // equal key, which keeps its metadata, and `replaced` is told about the key. Cursors keep
+    // the same stored entries behind them. Returns the number of incoming keys that were new.
+    pub(crate) fn merge_sorted<F>(
+        &mut self,
+        incoming: Vec<((K, V), u64)>,
+        incoming_meta: bool,
+        mut replaced: F,
+    ) -> usize
+    where
+        F: FnMut(&K),
+    {
+        if incoming_meta {
+            self.enable_meta();
+        }
+        let ranks = self.cursor_ranks(0, self.data_len(), true);
+        let stored = (0..self.data_len())
+            .filter_map(|i| Some((self.take(i)?, self.get_meta(i))))
+            .collect::<Vec<_>>();
+        let mut merged = Vec::with_capacity(stored.len() + incoming.len());
+        // Number of merged entries up to and including every stored entry.
+        let mut stored_ends = Vec::with_capacity(stored.len());
+        let mut added = 0;
+        let mut incoming = incoming.into_iter().peekable();
+        for (kv, meta) in stored {
+            while let Some(next) = incoming.next_if(|(next, _)| next.0 < kv.0) {
+                merged.push(next);
+                added += 1;
+            }
+            match incoming.next_if(|(next, _)| next.0 == kv.0) {
+                Some((next, _)) => {
+                    replaced(&next.0);
+                    merged.push((next, meta));
+                }
+                None => merged.push((kv, meta)),
+            }
+            stored_ends.push(merged.len());
+        }
+        for next in incoming {
+            merged.push(next);
+            added += 1;
+        }
+        let count = merged.len();
+        let meta_enabled = self.meta_enabled();
+        self.keys.clear();
+        self.values.clear();
+        self.meta.clear();
+        for ((k, v), meta) in merged {
+            self.keys.push(MaybeUninit::new(k));
+            self.values.push(MaybeUninit::new(v));
+            if meta_enabled {
+                self.meta.push(meta);
+            }
+        }
+        if meta_enabled && count == 0 {
+            self.meta.push(0);
+        }
+        self.relayout(count);
+        let ranks = ranks
+            .into_iter()
+            .map(|(id, rank)| (id, if rank == 0 { 0 } else { stored_ends[rank - 1] }))
+            .collect();
+        self.restore_cursors(0, self.data_len(), ranks);
+        added
+    }
+
+    // Merges key values sorted by unique keys, which `insert` would place between the slots
+    // `lo` and `hi`, into the smallest window around them that takes the new keys within its
+    // density bound, as `merge_sorted` does for the whole array. Returns the window and the
+    // number of new keys, or the key values back when even the whole array is too dense.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn merge_window<F>(
+        &mut self,
+        lo: usize,
+        hi: usize,
+        incoming: Vec<(K, V)>,
+        mut replaced: F,
+    ) -> Result<(usize, usize, usize), Vec<(K, V)>>
+    where
+        F: FnMut(&K),
+    {
+        // Keys equal to incoming ones can only sit in `lo..=hi`.
+        let mut added = incoming.len();
+        let mut stored = self
+            .range(lo, (hi + 1).min(self.data_len()))
+            .map(|(k, _)| k)
+            .peekable();
+        for (key, _) in &incoming {
+            while stored.next_if(|k| *k < key).is_some() {}
+            if stored.next_if(|k| *k == key).is_some() {
+                added -= 1;
+            }
+        }
+        let last = self.data_len() - 1;
+        let (lo_segment, hi_segment) = (
+            lo.min(last) >> self.segment_size_log2,
+            hi.min(last) >> self.segment_size_log2,
+        );
+        // The lowest window holding both segments, then its ancestors until one is sparse
+        // enough.
+        let mut depth = self.height - 1;
+        while lo_segment >> (self.height - 1 - depth) != hi_segment >> (self.height - 1 - depth) {
+            depth -= 1;
+        }
+        let window = loop {
+            let size = self.segment_size << (self.height - 1 - depth);
+            let from = lo.min(last) & !(size - 1);
+            let count = self.window_count(from, size) + added;
+            if self.insert_density_ok(depth, count, size) {
+                break (from, from + size);
+            }
+            if depth == 0 {
+                return Err(incoming);
+            }
+            depth -= 1;
+        };
+        let (from, to) = window;
+        let ranks = self.cursor_ranks(from, to, false);
+        let mut merged = Vec::with_capacity(self.window_count(from, to - from) + added);
+        let mut stored_ends = vec![];
+        let mut incoming = incoming.into_iter().peekable();
+        for i in from..to {
+            let Some(kv) = self.take(i) else {
+                continue;
+            };
+            let meta = self.get_meta(i);
+            while let Some(next) = incoming.next_if(|next| next.0 < kv.0) {
+                merged.push((next, 0));
+            }
+            match incoming.next_if(|next| next.0 == kv.0) {
+                Some(next) => {
+                    replaced(&next.0);
+                    merged.push((next, meta));
+                }
+                None => merged.push((kv, meta)),
+            }
+            stored_ends.push(merged.len());
+        }
+        merged.extend(incoming.map(|next| (next, 0)));
+        let count = merged.len();
+        let meta_enabled = self.meta_enabled();
+        for (i, (kv, meta)) in merged.into_iter().enumerate() {
+            self.put(from + i, kv);
+            if meta_enabled {
+                self.meta[from + i] = meta;
+            }
+        }
+        self.segment(from, to, Some(count))
+            .shuffle_key_values(false);
+        self.recount_window(from, to);
+        let ranks = ranks
+            .into_iter()
+            .map(|(id, rank)| (id, if rank == 0 { 0 } else { stored_ends[rank - 1] }))
+            .collect();
+        self.restore_cursors(from, to, ranks);
+        Ok((from, to, added))
+    }
+
+    // Takes out every key value in the slots [from, to) and rebalances once, the smallest
+    // window around them still dense enough, or the whole array laid out again as `retain`
+    // does when even the root is too sparse. Returns the key values taken, in key order, and
+    // the changed range as `remove` does.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn remove_span(
+        &mut self,
+        from: usize,
+        to: usize,
+    ) -> (Vec<(K, V)>, Option<(usize, usize)>) {
+        let taken = (from..to).filter_map(|i| self.take(i)).collect::<Vec<_>>();
+        if taken.is_empty() {
+            return (taken, Some((from, from)));
+        }
+        self.recount_window(from, to);
+        let (from_segment, to_segment) = (
+            from >> self.segment_size_log2,
+            (to - 1) >> self.segment_size_log2,
+        );
+        let mut depth = self.height - 1;
+        while from_segment >> (self.height - 1 - depth) != to_segment >> (self.height - 1 - depth) {
+            depth -= 1;
+        }
+        loop {
+            let size = self.segment_size << (self.height - 1 - depth);
+            let start = from & !(size - 1);
+            let count = self.window_count(start, size);
+            if self.remove_density_ok(depth, count, size) {
+                let ranks = self.cursor_ranks(start, start + size, false);
+                self.segment(start, start + size, Some(count))
+                    .shuffle_key_values(true);
+                self.recount_window(start, start + size);
+                self.restore_cursors(start, start + size, ranks);
+                return (taken, Some((start, start + size)));
+            }
+            if depth == 0 {
+                break;
+            }
+            depth -= 1;
+        }
+        self.retain(|_, _| true);
+        (taken, None)
+    }
+
+    // Registers a cursor living as long as `owner` and returns its id.
+    pub(crate) fn register_cursor(&mut self, position: usize, owner: &Arc<()>) -> usize {
+        let slot = CursorSlot {
+            position,
+            owner: Arc::downgrade(owner),
+        };
+        match self
+            .cursors
+            .iter()
+            .position(|c| !c.as_ref().is_some_and(CursorSlot::is_live))
+        {
+            Some(id) => {
+                self.cursors[id] = Some(slot);
+                id
+            }
+            None => {
+                self.cursors.push(Some(slot));
+                self.cursors.len() - 1
+            }
+        }
+    }
+
+    pub(crate) fn unregister_cursor(&mut self, id: usize) {
+        self.cursors[id] = None;
+        while let Some(None) = self
+            .cursors
+            .last()
+            .map(|c| c.as_ref().filter(|c| c.is_live()))
+        {
+            self.cursors.pop();
+        }
+    }
+
+    // Whether the cursor `id` is registered here for `owner`.
+    pub(crate) fn owns_cursor(&self, id: usize, owner: &Arc<()>) -> bool {
+        self.cursors
+            .get(id)
+            .and_then(Option::as_ref)
+            .is_some_and(|c| std::ptr::eq(c.owner.as_ptr(), Arc::as_ptr(owner)))
+    }
+
+    #[inline]
+    pub(crate) fn cursor_position(&self, id: usize) -> usize {
+        self.cursors[id]
+            .as_ref()
+            .expect("Cursor is not registered.")
+            .position
+    }
+
+    #[inline]
+    pub(crate) fn set_cursor_position(&mut self, id: usize, position: usize) {
+        if let Some(c) = &mut self.cursors[id] {
+            c.position = position;
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn live_cursor_count(&self) -> usize {
+        self.live_cursors().count()
+    }
+
+    // The ids and positions of the cursors whose handles are alive.
+    fn live_cursors(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.cursors
+            .iter()
+            .enumerate()
+            .filter_map(|(id, c)| c.as_ref().filter(|c| c.is_live()).map(|c| (id, c.position)))
+    }
+
+    // The move report of a rebalance over [from, to): for every cursor inside the window, the
+    // number of window entries in front of it. With `whole`, cursors past the window (at the
+    // end of the array) are included as well.
+    fn cursor_ranks(&self, from: usize, to: usize, whole: bool) -> Vec<(usize, usize)> {
+        self.live_cursors()
+            .filter(|&(_, p)| p > from && (whole || p <= to))
+            .map(|(id, p)| (id, bitmap::count(&self.occupied, from, p.min(to))))
+            .collect()
+    }
+
+    // Puts every cursor right behind the same number of window entries it had in front of it
+    // before the rebalance. Every rebalance ends here, so this is also where they are counted.
+    fn restore_cursors(&mut self, from: usize, to: usize, ranks: Vec<(usize, usize)>) {
+        self.record_stats(|stats| stats.rebalances += 1);
+        for (id, rank) in ranks {
+            let mut position = from;
+            for _ in 0..rank {
+                match bitmap::next(&self.occupied, position, to, true) {
+                    Some(i) => position = i + 1,
+                    None => break,
+                }
+            }
+            self.set_cursor_position(id, position);
         }
     }
 
     #[inline]
     pub(crate) fn data_len(&self) -> usize {
-        self.data.len()
+        self.keys.len()
+    }
+
+    #[inline]
+    pub(crate) fn is_occupied(&self, index: usize) -> bool {
+        bitmap::get(&self.occupied, index)
+    }
+
+    // The key in the slot, None for a gap or an index past the end.
+    #[inline]
+    pub(crate) fn key(&self, index: usize) -> Option<&K> {
+        if index >= self.data_len() || !self.is_occupied(index) {
+            return None;
+        }
+        // SAFETY: the slot is occupied.
+        Some(unsafe { self.keys[index].assume_init_ref() })
+    }
+
+    #[inline]
+    pub(crate) fn key_value(&self, index: usize) -> Option<(&K, &V)> {
+        let key = self.key(index)?;
+        // SAFETY: the key slot is occupied, and so is the value slot next to it.
+        Some((key, unsafe { self.values[index].assume_init_ref() }))
+    }
+
+    #[inline]
+    pub(crate) fn value_mut(&mut self, index: usize) -> Option<&mut V> {
+        self.key_value_mut(index).map(|(_, v)| v)
+    }
+
+    #[inline]
+    pub(crate) fn key_value_mut(&mut self, index: usize) -> Option<(&K, &mut V)> {
+        if index >= self.data_len() || !self.is_occupied(index) {
+            return None;
+        }
+        // SAFETY: the slots are occupied.
+        unsafe {
+            Some((
+                self.keys[index].assume_init_ref(),
+                self.values[index].assume_init_mut(),
+            ))
+        }
+    }
+
+    // The slot of the key, for the cache simulator and prefetches, which only take its address.
+    #[inline]
+    pub(crate) fn key_slot(&self, index: usize) -> &MaybeUninit<K> {
+        &self.keys[index]
+    }
+
+    // The first and last occupied slots in [from, to).
+    #[inline]
+    pub(crate) fn next_occupied(&self, from: usize, to: usize) -> Option<usize> {
+        bitmap::next(&self.occupied, from, to, true)
+    }
+
+    #[inline]
+    pub(crate) fn prev_occupied(&self, from: usize, to: usize) -> Option<usize> {
+        bitmap::prev(&self.occupied, from, to, true)
+    }
+
+    #[inline]
+    pub(crate) fn next_free(&self, from: usize, to: usize) -> Option<usize> {
+        bitmap::next(&self.occupied, from, to, false)
+    }
+
+    #[inline]
+    pub(crate) fn count_occupied(&self, from: usize, to: usize) -> usize {
+        bitmap::count(&self.occupied, from, to)
+    }
+
+    // The key values in the slots [from, to), in key order.
+    pub(crate) fn range(&self, from: usize, to: usize) -> PmaIter<'_, K, V> {
+        PmaIter {
+            keys: &self.keys[from..to],
+            values: &self.values[from..to],
+            occupied: &self.occupied,
+            from,
+            to,
+        }
+    }
+
+    pub(crate) fn range_mut(&mut self, from: usize, to: usize) -> PmaIterMut<'_, K, V> {
+        PmaIterMut {
+            keys: &self.keys[from..to],
+            values: &mut self.values[from..to],
+            occupied: &self.occupied,
+            from,
+            to,
+        }
+    }
+
+    // The keys and values of [from, to), every slot of which must be occupied.
+    pub(crate) fn run(&self, from: usize, to: usize) -> (&[K], &[V]) {
+        assert_eq!(self.count_occupied(from, to), to - from);
+        let (keys, values) = (&self.keys[from..to], &self.values[from..to]);
+        // SAFETY: every slot of the run is initialized, and `MaybeUninit<T>` has the layout of
+        // `T`.
+        unsafe {
+            (
+                &*(keys as *const [MaybeUninit<K>] as *const [K]),
+                &*(values as *const [MaybeUninit<V>] as *const [V]),
+            )
+        }
+    }
+
+    // The address and byte length of the key slots and of the value slots of [from, to).
+    pub(crate) fn buffers(&self, from: usize, to: usize) -> [(usize, usize); 2] {
+        let (keys, values) = (&self.keys[from..to], &self.values[from..to]);
+        [
+            (keys.as_ptr() as usize, std::mem::size_of_val(keys)),
+            (values.as_ptr() as usize, std::mem::size_of_val(values)),
+        ]
+    }
+
+    // Locks the key and value slots [from, to) in memory until `unpin_slots` or the next resize,
+    // which moves the slots.
+    #[cfg(all(unix, feature = "mlock"))]
+    pub(crate) fn pin_slots(&mut self, from: usize, to: usize) -> std::io::Result<()> {
+        for (address, len) in self.buffers(from, to) {
+            self.pinned.0.push(pinning::pin(address, len)?);
+        }
+        Ok(())
+    }
+
+    #[cfg(all(unix, feature = "mlock"))]
+    pub(crate) fn unpin_slots(&mut self) -> std::io::Result<()> {
+        for region in self.pinned.0.drain(..) {
+            pinning::unpin(region)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(all(test, unix, feature = "mlock"))]
+    pub(crate) fn are_slots_pinned(&self, from: usize, to: usize) -> bool {
+        self.buffers(from, to).iter().all(|&(address, len)| {
+            self.pinned
+                .0
+                .iter()
+                .any(|region| region.covers(address, len))
+        })
     }
 
+    #[cfg(all(unix, feature = "mlock"))]
+    pub(crate) fn pinned_slot_regions(&self) -> usize {
+        self.pinned.0.len()
+    }
+
+    // Unlocks the slots ahead of a resize, while the old buffers are still allocated. The map
+    // pins its ranges again once the resize is done.
+    #[cfg(all(unix, feature = "mlock"))]
+    fn release_pins(&mut self) {
+        let _ = self.unpin_slots();
+    }
+
+    #[cfg(not(all(unix, feature = "mlock")))]
+    #[inline(always)]
+    fn release_pins(&mut self) {}
+
+    pub(crate) fn into_key_values(mut self) -> IntoKeyValues<K, V> {
+        let to = self.data_len();
+        // The bitmap goes along with the slots, which leaves nothing for `drop` here.
+        IntoKeyValues {
+            keys: std::mem::replace(&mut self.keys, Slots::Heap(vec![])).into_vec(),
+            values: std::mem::replace(&mut self.values, Slots::Heap(vec![])).into_vec(),
+            occupied: std::mem::take(&mut self.occupied),
+            from: 0,
+            to,
+        }
+    }
+
+    // Takes the key value out of a slot, leaving a gap no rebalance accounts for: the caller
+    // lays the array out again, with `retain` or `clear`, before anything else reads it.
     #[inline]
-    pub(crate) fn get_key_values(&self) -> &[Option<(K, V)>] {
-        &self.v
+    pub(crate) fn take(&mut self, index: usize) -> Option<(K, V)> {
+        if !self.is_occupied(index) {
+            return None;
+        }
+        bitmap::unset(&mut self.occupied, index);
+        // SAFETY: the slots were occupied, and their bit is cleared before they are read out.
+        unsafe {
+            Some((
+                self.keys[index].assume_init_read(),
+                self.values[index].assume_init_read(),
+            ))
+        }
+    }
+
+    // Puts a key value in a free slot, the counterpart of `take`.
+    #[inline]
+    fn put(&mut self, index: usize, key_value: (K, V)) {
+        debug_assert!(!self.is_occupied(index));
+        self.keys[index] = MaybeUninit::new(key_value.0);
+        self.values[index] = MaybeUninit::new(key_value.1);
+        bitmap::set(&mut self.occupied, index);
+    }
+
+    // Drops the key value of every occupied slot, leaving all slots free.
+    fn drop_key_values(&mut self) {
+        let mut from = 0;
+        while let Some(i) = self.next_occupied(from, self.data_len()) {
+            drop(self.take(i));
+            from = i + 1;
+        }
     }
 
     #[inline]
     fn insert_density_ok(&self, depth: usize, count: usize, size: usize) -> bool {
-        // (1 / 4) + 3 * (d / height) * 4 = (height * 3 + d) / (height * 4)
-        Ratio::new_raw(count, size) <= Ratio::new_raw(self.height * 3 + depth, self.height << 2)
+        insert_density_ok(self.height, depth, count, size)
     }
 
     #[inline]
     fn remove_density_ok(&self, depth: usize, count: usize, size: usize) -> bool {
-        // (1 / 2) - (d / height) / 4 = (height * 2 - d) / (height * 4)
-        Ratio::new_raw(count, size) >= Ratio::new_raw((self.height << 1) - depth, self.height << 2)
+        remove_density_ok(self.height, depth, count, size)
     }
 
     // 0 <= index <= data.len(), Note: index == num is special.
-    // Returns (Option<Value>, Option(Changed_from, changed_to))
+    // Returns (Option<Value>, Option(Changed_from, changed_to)), see `Changed`.
     // The first Option value is for the old value (if any).
     // The 2nd Option is the range of leaf we need to update, None means the whole range.
-    pub(crate) fn insert(
+    pub(crate) fn insert_at(
         &mut self,
         index: usize,
         key_value: (K, V),
     ) -> (Option<V>, Option<(usize, usize)>) {
+        self.try_insert_at(index, key_value)
+            .unwrap_or_else(|error| panic!("{}", error))
+    }
+
+    // Same as `insert_at`, failing instead of panicking when the window counts are off and the
+    // rebalanced window has no room for the entry.
+    pub(crate) fn try_insert_at(
+        &mut self,
+        index: usize,
+        key_value: (K, V),
+    ) -> Result<Changed<V>, CoBTreeError> {
+        self.record_stats(|stats| stats.operations += 1);
         let mut segment_id = index >> self.segment_size_log2;
         let mut segment_pos = index & (self.segment_size - 1);
         if index == self.data_len() {
             segment_id -= 1;
             segment_pos = self.segment_size;
-        } else if let Some((key, _)) = &self.v[index] {
+        } else if let Some(key) = self.key(index) {
             if key == &key_value.0 {
-                return (
-                    self.v[index].replace(key_value).map(|x| x.1),
+                // SAFETY: the slots are occupied.
+                let (key, value) = unsafe {
+                    (
+                        self.keys[index].assume_init_mut(),
+                        self.values[index].assume_init_mut(),
+                    )
+                };
+                *key = key_value.0;
+                return Ok((
+                    Some(std::mem::replace(value, key_value.1)),
                     Some((index, index)),
-                );
+                ));
             }
         }
+        let run = self.track_insert(index);
         let mut from = segment_id << self.segment_size_log2;
         let mut to = from + self.segment_size;
         let mut size = self.segment_size;
-        let mut count = Segment::new(&self.data[from..to], None).get_count();
+        let mut count = self.window_count(from, size);
         let mut found_segment = false;
         let mut density_ok = false;
         if count < size {
@@ -90,12 +1252,12 @@ where
             for depth in (0..self.height - 1).rev() {
                 if ((from / size) & 1) > 0 {
                     // Previous is the right child, need to add the left child.
-                    count += Segment::new(&self.data[(from - size)..from], None).get_count();
+                    count += self.window_count(from - size, size);
                     segment_pos += size;
                     from -= size;
                 } else {
                     // Previous is the left child, need to add the right child.
-                    count += Segment::new(&self.data[to..(to + size)], None).get_count();
+                    count += self.window_count(to, size);
                     to += size;
                 }
                 size <<= 1;
@@ -109,123 +1271,340 @@ where
                 }
             }
         }
-        assert!(found_segment);
+        if !found_segment {
+            return Err(CoBTreeError::NoSpace);
+        }
+        let whole = !density_ok;
+        let mut ranks = self.cursor_ranks(from, to, whole);
+        // The rank of the new entry in the window, and so in the array when it grows.
+        let new_rank = if ranks.is_empty() && run.is_none() {
+            0
+        } else {
+            bitmap::count(&self.occupied, from, index)
+        };
+        // Rebalances in a run leave their gap right behind the new entry for ascending keys,
+        // right in front of it for descending ones.
+        let hot = run.map(|ascending| new_rank + usize::from(ascending));
+        if !ranks.is_empty() {
+            // The new entry lands in front of a cursor only if it sorts before an entry the
+            // cursor has already passed.
+            ranks
+                .iter_mut()
+                .filter(|(_, rank)| new_rank < *rank)
+                .for_each(|(_, rank)| *rank += 1);
+        }
         if density_ok {
-            let mut segment = Segment::new(&self.data[from..to], Some(count - 1));
-            segment.insert_key_value(segment_pos, key_value);
-            segment.shuffle_key_values(true);
-            return (None, Some((from, to)));
-        }
-        self.v.resize(size << 1, None);
-        self.data = self
-            .v
-            .iter_mut()
-            .map(|v| v as *mut Option<(K, V)>)
-            .collect();
+            let mut segment = self.segment(from, to, Some(count - 1));
+            segment.insert_key_value(segment_pos, key_value)?;
+            match hot {
+                Some(hot) => segment.shuffle_key_values_around(hot, true),
+                None => segment.shuffle_key_values(true),
+            }
+            self.recount_window(from, to);
+            self.restore_cursors(from, to, ranks);
+            return Ok((None, Some((from, to))));
+        }
+        self.release_pins();
+        self.keys.resize_with(size << 1, MaybeUninit::uninit);
+        self.values.resize_with(size << 1, MaybeUninit::uninit);
+        if self.meta_enabled() {
+            self.meta.resize(size << 1, 0);
+        }
+        bitmap::resize(&mut self.occupied, size << 1);
         if self.height - 1 == self.segment_size_log2 {
             self.height += 1;
         } else {
             self.segment_size_log2 += 1;
             self.segment_size <<= 1;
         }
-        let mut segment = Segment::new(&self.data, Some(count - 1));
-        segment.insert_key_value(segment_pos, key_value);
-        segment.shuffle_key_values(true);
-        (None, None)
+        let mut segment = self.segment(0, self.data_len(), Some(count - 1));
+        segment.insert_key_value(segment_pos, key_value)?;
+        match hot {
+            Some(hot) => segment.shuffle_key_values_around(hot, true),
+            None => segment.shuffle_key_values(true),
+        }
+        self.recount();
+        self.restore_cursors(0, self.data_len(), ranks);
+        Ok((None, None))
     }
 
     // 0 <= index < data.len().
-    pub(crate) fn remove(&mut self, index: usize) -> (Option<V>, Option<(usize, usize)>) {
-        if self.v[index].is_none() {
+    pub(crate) fn remove_at(&mut self, index: usize) -> (Option<V>, Option<(usize, usize)>) {
+        if !bitmap::get(&self.occupied, index) {
             return (None, None);
         }
+        self.record_stats(|stats| stats.operations += 1);
         let segment_id = index >> self.segment_size_log2;
         let segment_pos = index & (self.segment_size - 1);
         let mut from = self.segment_size * segment_id;
         let mut to = from + self.segment_size;
-        let mut segment = Segment::new(&self.data[from..to], None);
+        let leaf_count = self.window_count(from, self.segment_size);
+        let mut segment = Segment::new(
+            &mut self.keys[from..to],
+            &mut self.values[from..to],
+            &mut self.occupied,
+            from,
+            Some(leaf_count),
+        );
         let old_value = segment.remove_key_value(segment_pos);
         let mut count = segment.get_count();
         let mut size = self.segment_size;
         if self.remove_density_ok(self.height - 1, count, size) {
-            Segment::new(&self.data[from..to], Some(count)).shuffle_key_values(true);
+            let ranks = self.cursor_ranks(from, to, false);
+            self.segment(from, to, Some(count)).shuffle_key_values(true);
+            self.recount_window(from, to);
+            self.restore_cursors(from, to, ranks);
             return (old_value, Some((from, to)));
         }
         for depth in (0..self.height - 1).rev() {
             if ((from / size) & 1) > 0 {
                 // Current is the right child, need to add the left child.
-                count += Segment::new(&self.data[(from - size)..from], None).get_count();
+                count += self.window_count(from - size, size);
                 from -= size;
             } else {
                 // Current is the left child, need to add the right child.
-                count += Segment::new(&self.data[to..(to + size)], None).get_count();
+                count += self.window_count(to, size);
                 to += size;
             }
             size <<= 1;
             if self.remove_density_ok(depth, count, size) {
-                Segment::new(&self.data[from..to], Some(count)).shuffle_key_values(true);
+                let ranks = self.cursor_ranks(from, to, false);
+                self.segment(from, to, Some(count)).shuffle_key_values(true);
+                self.recount_window(from, to);
+                self.restore_cursors(from, to, ranks);
                 return (old_value, Some((from, to)));
             }
         }
-        assert!(self.data.len() == size);
+        assert!(self.data_len() == size);
         if count == 0 {
-            *self = Self::new();
+            self.clear();
             return (old_value, None);
         }
-        Segment::new(&self.data, Some(count)).move_all_key_values_to_front();
-        self.v.resize(size >> 1, None);
-        self.data = self
-            .v
-            .iter_mut()
-            .map(|v| v as *mut Option<(K, V)>)
-            .collect();
-        Segment::new(&self.data, Some(count)).shuffle_key_values(false);
+        let ranks = self.cursor_ranks(0, size, true);
+        self.segment(0, size, Some(count))
+            .move_all_key_values_to_front();
+        self.release_pins();
+        self.keys.resize_with(size >> 1, MaybeUninit::uninit);
+        self.values.resize_with(size >> 1, MaybeUninit::uninit);
+        if self.meta_enabled() {
+            self.meta.resize(size >> 1, 0);
+        }
+        bitmap::resize(&mut self.occupied, size >> 1);
+        self.segment(0, size >> 1, Some(count))
+            .shuffle_key_values(false);
+        self.restore_cursors(0, self.data_len(), ranks);
         if self.height - 1 == self.segment_size_log2 {
             self.segment_size_log2 -= 1;
             self.segment_size >>= 1;
         } else {
             self.height -= 1;
         }
+        self.recount();
         (old_value, None)
     }
 }
 
+impl<K: Ord, V> Drop for PackedMemoryArray<K, V> {
+    fn drop(&mut self) {
+        self.drop_key_values();
+    }
+}
+
+impl<K: Ord, V> Default for PackedMemoryArray<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord + fmt::Debug, V: fmt::Debug> fmt::Debug for PackedMemoryArray<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// The occupied slots of a span of the array, found through the occupancy bitmap.
+pub struct PmaIter<'a, K, V> {
+    // The slots of [from, to).
+    keys: &'a [MaybeUninit<K>],
+    values: &'a [MaybeUninit<V>],
+    occupied: &'a [u64],
+    from: usize,
+    to: usize,
+}
+
+impl<'a, K, V> PmaIter<'a, K, V> {
+    // `index` must be an occupied slot of [from, to).
+    fn get(&self, index: usize) -> (&'a K, &'a V) {
+        let (keys, values) = (self.keys, self.values);
+        let i = index - self.from;
+        // SAFETY: the slots are occupied.
+        unsafe { (keys[i].assume_init_ref(), values[i].assume_init_ref()) }
+    }
+}
+
+impl<'a, K, V> Iterator for PmaIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = bitmap::next(self.occupied, self.from, self.to, true)?;
+        let next = self.get(index);
+        self.keys = &self.keys[index + 1 - self.from..];
+        self.values = &self.values[index + 1 - self.from..];
+        self.from = index + 1;
+        Some(next)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.to - self.from))
+    }
+}
+
+impl<K, V> DoubleEndedIterator for PmaIter<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let index = bitmap::prev(self.occupied, self.from, self.to, true)?;
+        let next = self.get(index);
+        self.keys = &self.keys[..index - self.from];
+        self.values = &self.values[..index - self.from];
+        self.to = index;
+        Some(next)
+    }
+}
+
+// Same as `PmaIter`, with mutable values.
+pub(crate) struct PmaIterMut<'a, K, V> {
+    keys: &'a [MaybeUninit<K>],
+    values: &'a mut [MaybeUninit<V>],
+    occupied: &'a [u64],
+    from: usize,
+    to: usize,
+}
+
+impl<'a, K, V> Iterator for PmaIterMut<'a, K, V> {
+    type Item = (&'a K, &'a mut V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = bitmap::next(self.occupied, self.from, self.to, true)?;
+        let (key, keys) = self.keys[index - self.from..].split_first()?;
+        let values = std::mem::take(&mut self.values);
+        let (value, values) = values[index - self.from..].split_first_mut()?;
+        (self.keys, self.values, self.from) = (keys, values, index + 1);
+        // SAFETY: the slots are occupied.
+        Some(unsafe { (key.assume_init_ref(), value.assume_init_mut()) })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.to - self.from))
+    }
+}
+
+impl<K, V> DoubleEndedIterator for PmaIterMut<'_, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let index = bitmap::prev(self.occupied, self.from, self.to, true)?;
+        let (keys, key) = self.keys[..=index - self.from].split_at(index - self.from);
+        let values = std::mem::take(&mut self.values);
+        let (values, value) = values[..=index - self.from].split_at_mut(index - self.from);
+        (self.keys, self.values, self.to) = (keys, values, index);
+        // SAFETY: the slots are occupied.
+        Some(unsafe { (key[0].assume_init_ref(), value[0].assume_init_mut()) })
+    }
+}
+
+// The key values of an array taken apart, in key order. The occupied slots of [from, to) are
+// the ones not yielded yet, dropped along with the iterator.
+pub(crate) struct IntoKeyValues<K, V> {
+    keys: Vec<MaybeUninit<K>>,
+    values: Vec<MaybeUninit<V>>,
+    occupied: Vec<u64>,
+    from: usize,
+    to: usize,
+}
+
+impl<K, V> IntoKeyValues<K, V> {
+    // `index` must be an occupied slot of [from, to), which the caller moves past.
+    fn read(&self, index: usize) -> (K, V) {
+        // SAFETY: the slots are occupied, and leave [from, to) so they are read out once.
+        unsafe {
+            (
+                self.keys[index].assume_init_read(),
+                self.values[index].assume_init_read(),
+            )
+        }
+    }
+}
+
+impl<K, V> Iterator for IntoKeyValues<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let index = bitmap::next(&self.occupied, self.from, self.to, true)?;
+        self.from = index + 1;
+        Some(self.read(index))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.to - self.from))
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoKeyValues<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let index = bitmap::prev(&self.occupied, self.from, self.to, true)?;
+        self.to = index;
+        Some(self.read(index))
+    }
+}
+
+impl<K, V> Drop for IntoKeyValues<K, V> {
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod packed_memory_array {
-    use crate::packed_memory_array::PackedMemoryArray;
+    use crate::{bitmap, packed_memory_array::PackedMemoryArray};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::sync::Arc;
+
+    fn slots(pma: &PackedMemoryArray<usize, usize>) -> Vec<Option<(usize, usize)>> {
+        (0..pma.data_len())
+            .map(|i| pma.key_value(i).map(|(k, v)| (*k, *v)))
+            .collect()
+    }
 
     #[test]
     fn test_operations() {
         let mut pma = PackedMemoryArray::<usize, usize>::new();
-        assert_eq!(pma.v, [None]);
+        assert_eq!(slots(&pma), [None]);
         assert_eq!(pma.height, 1);
         assert_eq!(pma.segment_size, 1);
         assert_eq!(pma.segment_size_log2, 0);
 
-        assert_eq!(pma.insert(1, (100, 10)), (None, None));
-        assert_eq!(pma.v, [None, Some((100, 10))]);
+        assert_eq!(pma.insert_at(1, (100, 10)), (None, None));
+        assert_eq!(slots(&pma), [None, Some((100, 10))]);
         assert_eq!(pma.height, 2);
         assert_eq!(pma.segment_size, 1);
         assert_eq!(pma.segment_size_log2, 0);
 
-        assert_eq!(pma.insert(2, (200, 22)), (None, None));
-        assert_eq!(pma.v, [None, Some((100, 10)), None, Some((200, 22))]);
+        assert_eq!(pma.insert_at(2, (200, 22)), (None, None));
+        assert_eq!(slots(&pma), [None, Some((100, 10)), None, Some((200, 22))]);
         assert_eq!(pma.height, 2);
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.insert(3, (150, 11)), (None, Some((0, 4))));
+        assert_eq!(pma.insert_at(3, (150, 11)), (None, Some((0, 4))));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [None, Some((100, 10)), Some((150, 11)), Some((200, 22))]
         );
         assert_eq!(pma.height, 2);
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.insert(0, (88, 8)), (None, None));
+        assert_eq!(pma.insert_at(0, (88, 8)), (None, None));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 Some((88, 8)),
@@ -241,9 +1620,9 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.insert(2, (99, 9)), (None, Some((0, 4))));
+        assert_eq!(pma.insert_at(2, (99, 9)), (None, Some((0, 4))));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 Some((88, 8)),
@@ -259,9 +1638,9 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.insert(8, (250, 25)), (None, Some((4, 8))));
+        assert_eq!(pma.insert_at(8, (250, 25)), (None, Some((4, 8))));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 Some((88, 8)),
@@ -277,19 +1656,19 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.insert(6, (166, 66)), (None, None));
+        assert_eq!(pma.insert_at(6, (166, 66)), (None, None));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 None,
                 Some((88, 8)),
                 None,
-                None,
                 Some((99, 9)),
                 None,
                 Some((100, 10)),
                 None,
+                None,
                 Some((150, 11)),
                 None,
                 Some((166, 66)),
@@ -303,19 +1682,19 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 4);
         assert_eq!(pma.segment_size_log2, 2);
 
-        assert_eq!(pma.insert(13, (199, 19)), (None, Some((12, 16))));
+        assert_eq!(pma.insert_at(13, (199, 19)), (None, Some((12, 16))));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 None,
                 Some((88, 8)),
                 None,
-                None,
                 Some((99, 9)),
                 None,
                 Some((100, 10)),
                 None,
+                None,
                 Some((150, 11)),
                 None,
                 Some((166, 66)),
@@ -330,19 +1709,19 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size_log2, 2);
 
         // Update existing.
-        assert_eq!(pma.insert(13, (199, 99)), (Some(19), Some((13, 13))));
+        assert_eq!(pma.insert_at(13, (199, 99)), (Some(19), Some((13, 13))));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 None,
                 Some((88, 8)),
                 None,
-                None,
                 Some((99, 9)),
                 None,
                 Some((100, 10)),
                 None,
+                None,
                 Some((150, 11)),
                 None,
                 Some((166, 66)),
@@ -356,19 +1735,19 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 4);
         assert_eq!(pma.segment_size_log2, 2);
 
-        assert_eq!(pma.remove(13), (Some(99), Some((12, 16))));
+        assert_eq!(pma.remove_at(13), (Some(99), Some((12, 16))));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 None,
                 Some((88, 8)),
                 None,
-                None,
                 Some((99, 9)),
                 None,
                 Some((100, 10)),
                 None,
+                None,
                 Some((150, 11)),
                 None,
                 Some((166, 66)),
@@ -383,19 +1762,19 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size_log2, 2);
 
         // Remove non existed.
-        assert_eq!(pma.remove(14), (None, None));
+        assert_eq!(pma.remove_at(14), (None, None));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 None,
                 Some((88, 8)),
                 None,
-                None,
                 Some((99, 9)),
                 None,
                 Some((100, 10)),
                 None,
+                None,
                 Some((150, 11)),
                 None,
                 Some((166, 66)),
@@ -409,15 +1788,15 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 4);
         assert_eq!(pma.segment_size_log2, 2);
 
-        assert_eq!(pma.remove(11), (Some(66), None));
+        assert_eq!(pma.remove_at(11), (Some(66), None));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 Some((88, 8)),
-                None,
                 Some((99, 9)),
                 Some((100, 10)),
+                None,
                 Some((150, 11)),
                 Some((200, 22)),
                 Some((250, 25))
@@ -427,15 +1806,15 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.remove(7), (Some(25), Some((6, 8))));
+        assert_eq!(pma.remove_at(7), (Some(25), Some((6, 8))));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 Some((88, 8)),
-                None,
                 Some((99, 9)),
                 Some((100, 10)),
+                None,
                 Some((150, 11)),
                 None,
                 Some((200, 22))
@@ -445,9 +1824,9 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.remove(4), (Some(10), Some((4, 6))));
+        assert_eq!(pma.remove_at(3), (Some(10), Some((2, 4))));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [
                 None,
                 Some((88, 8)),
@@ -463,46 +1842,296 @@ mod packed_memory_array {
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.remove(1), (Some(8), None));
+        assert_eq!(pma.remove_at(1), (Some(8), None));
         assert_eq!(
-            pma.v,
+            slots(&pma),
             [None, Some((99, 9)), Some((150, 11)), Some((200, 22))]
         );
         assert_eq!(pma.height, 2);
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.remove(1), (Some(9), Some((0, 4))));
-        assert_eq!(pma.v, [None, Some((150, 11)), None, Some((200, 22))]);
+        assert_eq!(pma.remove_at(1), (Some(9), Some((0, 4))));
+        assert_eq!(slots(&pma), [None, Some((150, 11)), None, Some((200, 22))]);
         assert_eq!(pma.height, 2);
         assert_eq!(pma.segment_size, 2);
         assert_eq!(pma.segment_size_log2, 1);
 
-        assert_eq!(pma.remove(3), (Some(22), None));
-        assert_eq!(pma.v, [None, Some((150, 11))]);
+        assert_eq!(pma.remove_at(3), (Some(22), None));
+        assert_eq!(slots(&pma), [None, Some((150, 11))]);
         assert_eq!(pma.height, 2);
         assert_eq!(pma.segment_size, 1);
         assert_eq!(pma.segment_size_log2, 0);
 
-        assert_eq!(pma.remove(1), (Some(11), None));
-        assert_eq!(pma.v, [None]);
+        assert_eq!(pma.remove_at(1), (Some(11), None));
+        assert_eq!(slots(&pma), [None]);
         assert_eq!(pma.height, 1);
         assert_eq!(pma.segment_size, 1);
         assert_eq!(pma.segment_size_log2, 0);
     }
 
     #[test]
+    fn test_from_sorted() {
+        for n in 0usize..100usize {
+            let pma = PackedMemoryArray::from_sorted((0..n).map(|i| (i, i)).collect());
+            assert!(pma.height > pma.segment_size_log2);
+            assert!(pma.height - 1 - pma.segment_size_log2 <= 1);
+            assert!(pma.segment_size == (1 << pma.segment_size_log2));
+            assert!(pma.meta.is_empty() || pma.meta.len() == pma.data_len());
+            assert!(pma.data_len() == pma.segment_size * (1 << (pma.height - 1)));
+            assert!(n * 4 <= pma.data_len() * 3);
+            let v = slots(&pma)
+                .iter()
+                .filter_map(|&v| v)
+                .collect::<Vec<(usize, usize)>>();
+            assert_eq!(v, (0..n).map(|i| (i, i)).collect::<Vec<(usize, usize)>>());
+        }
+    }
+
+    #[test]
+    fn test_cursors() {
+        // Keeps a cursor behind each prefix of the entries and checks that it still sits right
+        // behind the same prefix after every rebalance.
+        let mut pma = PackedMemoryArray::<usize, usize>::new();
+        let owner = Arc::new(());
+        let check = |pma: &PackedMemoryArray<usize, usize>, ids: &[(usize, usize)]| {
+            for &(id, behind) in ids {
+                let position = pma.cursor_position(id);
+                let before = slots(pma)[..position].iter().filter_map(|&v| v).next_back();
+                assert_eq!(before.map(|kv| kv.0), behind.checked_sub(1).map(|k| k * 2));
+            }
+        };
+        for i in 0..64 {
+            pma.insert_at(pma.data_len(), (i * 2, i));
+        }
+        let mut ids = vec![];
+        for behind in [0usize, 1, 17, 40, 64] {
+            let position = match behind {
+                0 => 0,
+                _ => {
+                    slots(&pma)
+                        .iter()
+                        .position(|kv| kv.map(|kv| kv.0) == Some((behind - 1) * 2))
+                        .unwrap()
+                        + 1
+                }
+            };
+            ids.push((pma.register_cursor(position, &owner), behind));
+        }
+        check(&pma, &ids);
+        // Odd keys land strictly between existing ones, a cursor behind key `k` keeps seeing
+        // `k` as the last entry in front of it.
+        for i in 0..64 {
+            let key = i * 2 + 1;
+            let index = slots(&pma)
+                .iter()
+                .position(|kv| kv.is_some_and(|kv| kv.0 > key))
+                .unwrap_or(pma.data_len());
+            pma.insert_at(index, (key, key));
+            check(&pma, &ids);
+        }
+        for i in 0..64 {
+            let key = i * 2 + 1;
+            let index = slots(&pma)
+                .iter()
+                .position(|kv| kv.map(|kv| kv.0) == Some(key))
+                .unwrap();
+            pma.remove_at(index);
+            check(&pma, &ids);
+        }
+        pma.unregister_cursor(ids[4].0);
+        assert_eq!(pma.cursors.len(), 4);
+        while let Some(index) = slots(&pma).iter().position(|v| v.is_some()) {
+            pma.remove_at(index);
+        }
+        assert!(pma
+            .cursors
+            .iter()
+            .all(|c| c.as_ref().unwrap().position == 0));
+        // Dropping the owner frees the slots, the next cursor takes the first one.
+        drop(owner);
+        assert_eq!(pma.live_cursors().count(), 0);
+        let owner = Arc::new(());
+        assert_eq!(pma.register_cursor(0, &owner), 0);
+        pma.unregister_cursor(0);
+        assert!(pma.cursors.is_empty());
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut pma = PackedMemoryArray::<usize, usize>::new();
+        for i in 0..100 {
+            pma.insert_at(pma.data_len(), (i, i));
+        }
+        let position = slots(&pma)
+            .iter()
+            .position(|kv| kv.map(|kv| kv.0) == Some(51))
+            .unwrap();
+        let owner = Arc::new(());
+        let id = pma.register_cursor(position, &owner);
+        let dropped = pma.retain(|k, v| {
+            *v += 1;
+            k % 3 != 0
+        });
+        assert_eq!(
+            dropped,
+            (0..100).step_by(3).map(|i| (i, i + 1)).collect::<Vec<_>>()
+        );
+        let v = slots(&pma)
+            .iter()
+            .filter_map(|&v| v)
+            .collect::<Vec<(usize, usize)>>();
+        assert_eq!(
+            v,
+            (0..100)
+                .filter(|i| i % 3 != 0)
+                .map(|i| (i, i + 1))
+                .collect::<Vec<_>>()
+        );
+        assert!(pma.data_len() == pma.segment_size * (1 << (pma.height - 1)));
+        // The cursor was in front of 51, which got dropped, so 52 is the next entry.
+        let next = slots(&pma)[pma.cursor_position(id)..]
+            .iter()
+            .find_map(|&v| v);
+        assert_eq!(next, Some((52, 53)));
+        assert!(pma.retain(|_, _| false).len() == 66);
+        assert_eq!(slots(&pma), [None]);
+        assert_eq!(pma.cursor_position(id), 0);
+    }
+
+    #[test]
+    fn test_meta() {
+        // Metadata follows its key value through every rebalance, resize and compaction.
+        let mut pma = PackedMemoryArray::<usize, usize>::new();
+        pma.enable_meta();
+        let check = |pma: &PackedMemoryArray<usize, usize>| {
+            assert_eq!(pma.meta.len(), pma.data_len());
+            for (i, kv) in slots(pma).iter().enumerate() {
+                if let Some((k, _)) = kv {
+                    assert_eq!(pma.get_meta(i), *k as u64 + 1000);
+                }
+            }
+        };
+        for i in 0..200usize {
+            let key = (i * 7919) % 200;
+            let index = slots(&pma)
+                .iter()
+                .position(|kv| kv.is_some_and(|kv| kv.0 > key))
+                .unwrap_or(pma.data_len());
+            pma.insert_at(index, (key, key));
+            let index = slots(&pma)
+                .iter()
+                .position(|kv| kv.map(|kv| kv.0) == Some(key))
+                .unwrap();
+            pma.set_meta(index, key as u64 + 1000);
+            check(&pma);
+        }
+        pma.retain(|k, _| k % 2 == 0);
+        check(&pma);
+        for i in 0..100usize {
+            let index = slots(&pma)
+                .iter()
+                .position(|kv| kv.map(|kv| kv.0) == Some(i * 2))
+                .unwrap();
+            pma.remove_at(index);
+            check(&pma);
+        }
+        assert_eq!(slots(&pma), [None]);
+        assert!(pma.meta_enabled());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_counts() {
+        // Every window counter matches a scan of the slots, the key and value slots stay
+        // parallel and no bit is set past the last slot.
+        fn check(pma: &PackedMemoryArray<usize, usize>) {
+            assert_eq!(pma.occupied.len(), bitmap::words_for(pma.data_len()));
+            assert_eq!(pma.values.len(), pma.keys.len());
+            assert_eq!(
+                bitmap::count(&pma.occupied, pma.data_len(), pma.occupied.len() * 64),
+                0
+            );
+            let first_segment_id = 1 << (pma.height - 1);
+            assert_eq!(pma.counts.len(), first_segment_id << 1);
+            for id in 1..first_segment_id << 1 {
+                let level = (usize::BITS - 1 - id.leading_zeros()) as usize;
+                let size = pma.data_len() >> level;
+                let from = (id - (1 << level)) * size;
+                let count = slots(pma)[from..from + size].iter().flatten().count();
+                assert_eq!(pma.counts[id], count);
+            }
+        }
+        let mut pma = PackedMemoryArray::<usize, usize>::new();
+        check(&pma);
+        let mut rng = StdRng::seed_from_u64(9);
+        for i in 0..1000 {
+            let index = rng.gen_range(0..=pma.data_len());
+            pma.insert_at(index, (i, i));
+            check(&pma);
+        }
+        for _ in 0..900 {
+            let occupied = slots(&pma)
+                .iter()
+                .enumerate()
+                .filter(|(_, kv)| kv.is_some())
+                .map(|(i, _)| i)
+                .collect::<Vec<_>>();
+            pma.remove_at(occupied[rng.gen_range(0..occupied.len())]);
+            check(&pma);
+        }
+        pma.remove_span(0, pma.data_len() / 2);
+        check(&pma);
+        pma.merge_window(0, 0, vec![(0, 0)], |_| {}).ok();
+        check(&pma);
+        pma.retain(|k, _| k % 2 == 0);
+        check(&pma);
+        pma.clear();
+        check(&pma);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_buffer_reuse() {
+        let mut pma = PackedMemoryArray::<usize, usize>::new();
+        for i in 0..1000 {
+            pma.insert_at(pma.data_len(), (i, i));
+        }
+        let capacity = pma.capacity();
+        let buffer = pma.keys.as_ptr();
+        while let Some(index) = slots(&pma).iter().position(|v| v.is_some()) {
+            pma.remove_at(index);
+        }
+        pma.clear();
+        assert_eq!(slots(&pma), [None]);
+        assert_eq!(pma.capacity(), capacity);
+        for i in 0..1000 {
+            pma.insert_at(pma.data_len(), (i, i));
+        }
+        pma.retain(|k, _| k % 2 == 0);
+        assert_eq!(pma.capacity(), capacity);
+        assert_eq!(pma.keys.as_ptr(), buffer);
+        pma.shrink_to_fit();
+        assert_eq!(pma.capacity(), pma.data_len());
+        let v = slots(&pma)
+            .iter()
+            .filter_map(|&v| v)
+            .collect::<Vec<(usize, usize)>>();
+        assert_eq!(v, (0..1000).step_by(2).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
     fn test_restrictions() {
         let mut pma = PackedMemoryArray::<usize, usize>::new();
         for i in 0usize..10000usize {
-            pma.insert(pma.v.len(), (i, i));
-            assert!(pma.height - 1 >= pma.segment_size_log2);
+            pma.insert_at(pma.data_len(), (i, i));
+            assert!(pma.height > pma.segment_size_log2);
             assert!(pma.height - 1 - pma.segment_size_log2 <= 1);
             assert!(pma.segment_size == (1 << pma.segment_size_log2));
-            assert!(pma.v.len() == pma.data.len());
-            assert!(pma.v.len() == pma.segment_size * (1 << (pma.height - 1)));
-            let v = pma
-                .v
+            assert!(pma.meta.is_empty() || pma.meta.len() == pma.data_len());
+            assert!(pma.data_len() == pma.segment_size * (1 << (pma.height - 1)));
+            let v = slots(&pma)
                 .iter()
                 .filter_map(|&v| v)
                 .collect::<Vec<(usize, usize)>>();
@@ -511,16 +2140,15 @@ mod packed_memory_array {
                 .enumerate()
                 .for_each(|(i, &v)| assert_eq!(v, (i, i)));
         }
-        assert_eq!(pma.v.len(), 16384);
+        assert_eq!(pma.data_len(), 16384);
         for i in 0usize..10000usize {
-            pma.remove(pma.v.iter().position(|v| v.is_some()).unwrap());
-            assert!(pma.height - 1 >= pma.segment_size_log2);
+            pma.remove_at(slots(&pma).iter().position(|v| v.is_some()).unwrap());
+            assert!(pma.height > pma.segment_size_log2);
             assert!(pma.height - 1 - pma.segment_size_log2 <= 1);
             assert!(pma.segment_size == (1 << pma.segment_size_log2));
-            assert!(pma.v.len() == pma.data.len());
-            assert!(pma.v.len() == pma.segment_size * (1 << (pma.height - 1)));
-            let v = pma
-                .v
+            assert!(pma.meta.is_empty() || pma.meta.len() == pma.data_len());
+            assert!(pma.data_len() == pma.segment_size * (1 << (pma.height - 1)));
+            let v = slots(&pma)
                 .iter()
                 .filter_map(|&v| v)
                 .collect::<Vec<(usize, usize)>>();
@@ -529,6 +2157,120 @@ mod packed_memory_array {
                 assert!(v[j - 1].0 < v[j].0);
             }
         }
-        assert_eq!(pma.v.len(), 1);
+        assert_eq!(pma.data_len(), 1);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_sorted_map() {
+        let mut pma = PackedMemoryArray::<usize, usize>::default();
+        let mut expected = std::collections::BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(11);
+        for _ in 0..3000 {
+            let key = rng.gen_range(0..1000);
+            if rng.gen_bool(0.7) {
+                assert_eq!(
+                    pma.insert_sorted(key, key * 2),
+                    expected.insert(key, key * 2)
+                );
+            } else {
+                assert_eq!(pma.remove(&key), expected.remove(&key));
+            }
+            assert_eq!(pma.get(&key), expected.get(&key));
+            pma.check_invariants();
+        }
+        assert_eq!(pma.len(), expected.len());
+        assert!(pma.iter().eq(expected.iter()));
+        for (rank, kv) in expected.iter().enumerate() {
+            assert_eq!(pma.get_by_rank(rank), Some(kv));
+        }
+        assert_eq!(pma.get_by_rank(expected.len()), None);
+        assert_eq!(pma.get(&1000), None);
+        assert_eq!(
+            format!(
+                "{:?}",
+                PackedMemoryArray::from_sorted(vec![(1, 'a'), (2, 'b')])
+            ),
+            "{1: 'a', 2: 'b'}"
+        );
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_adaptive() {
+        // The free slots behind the last entry of ascending inserts and in front of the first
+        // entry of descending inserts, summed over the inserts.
+        let gaps = |adaptive: bool| {
+            let mut ascending = PackedMemoryArray::new();
+            let mut descending = PackedMemoryArray::new();
+            ascending.set_adaptive(adaptive);
+            descending.set_adaptive(adaptive);
+            let (mut after, mut before) = (0, 0);
+            for i in 0..3000 {
+                ascending.insert_sorted(i, i);
+                descending.insert_sorted(3000 - i, i);
+                let last = ascending.prev_occupied(0, ascending.data_len()).unwrap();
+                after += ascending.data_len() - 1 - last;
+                before += descending.next_occupied(0, descending.data_len()).unwrap();
+            }
+            assert!(ascending.iter().map(|(k, _)| *k).eq(0..3000));
+            assert!(descending.iter().map(|(k, _)| *k).eq(1..=3000));
+            (after, before)
+        };
+        let (even, adaptive) = (gaps(false), gaps(true));
+        assert!(adaptive.0 > even.0 * 10 && adaptive.1 > even.1 * 10);
+
+        let mut pma = PackedMemoryArray::new();
+        pma.set_adaptive(true);
+        let mut expected = std::collections::BTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(5);
+        for i in 0..3000 {
+            let key = match i % 1000 < 500 {
+                true => 10_000 + i,
+                false => rng.gen_range(0..20_000),
+            };
+            assert_eq!(pma.insert_sorted(key, i), expected.insert(key, i));
+            if i % 3 == 0 {
+                let key = rng.gen_range(0..20_000);
+                assert_eq!(pma.remove(&key), expected.remove(&key));
+            }
+        }
+        pma.check_invariants();
+        assert!(pma.iter().eq(expected.iter()));
+    }
+
+    #[test]
+    fn test_drops() {
+        // Every key value is dropped once, whether removed, replaced, cleared, left in a partly
+        // consumed iterator or dropped with the array.
+        let tracker = std::rc::Rc::new(());
+        let live = || std::rc::Rc::strong_count(&tracker) - 1;
+        let mut pma = PackedMemoryArray::<usize, std::rc::Rc<()>>::new();
+        for i in 0..100 {
+            pma.insert_at(pma.data_len(), (i, tracker.clone()));
+        }
+        assert_eq!(live(), 100);
+        for _ in 0..10 {
+            pma.remove_at(pma.next_occupied(0, pma.data_len()).unwrap());
+        }
+        let last = pma.prev_occupied(0, pma.data_len()).unwrap();
+        assert!(pma.insert_at(last, (99, tracker.clone())).0.is_some());
+        assert_eq!(live(), 90);
+        drop(pma.retain(|k, _| k % 2 == 0));
+        assert_eq!(live(), 45);
+        let mut iter = pma.into_key_values();
+        assert_eq!(iter.next().map(|(k, _)| k), Some(10));
+        assert_eq!(iter.next_back().map(|(k, _)| k), Some(98));
+        assert_eq!(live(), 43);
+        drop(iter);
+        assert_eq!(live(), 0);
+
+        let mut pma =
+            PackedMemoryArray::from_sorted((0..50).map(|i| (i, tracker.clone())).collect());
+        pma.clear();
+        assert_eq!(live(), 0);
+        pma.insert_at(0, (0, tracker.clone()));
+        drop(pma);
+        assert_eq!(live(), 0);
     }
 }
@@ -0,0 +1,584 @@
+use crate::{
+    comparable::Comparable,
+    cow::Page,
+    packed_memory_array::{insert_density_ok, layout_shape, remove_density_ok, PackedMemoryArray},
+    sorting,
+};
+use std::{cmp::Ordering, sync::Arc};
+
+// A window of the density tree: a leaf segment with its entries, gaps squeezed out like the
+// pages of a snapshot, or the two halves of a larger window.
+enum Window<K, V> {
+    Segment(Page<K, V>),
+    Split {
+        count: usize,
+        // The smallest key of the window, `None` when it is empty.
+        first: Option<K>,
+        left: Arc<Window<K, V>>,
+        right: Arc<Window<K, V>>,
+    },
+}
+
+// An immutable sorted map where `insert` and `remove` leave the map alone and return a new one.
+// It is a packed memory array whose segments are pages shared through `Arc`, under a tree of
+// windows that keeps their counts and first keys. An update copies the segment it lands in and
+// the windows above it. When the segment gets too dense or too sparse, the smallest window
+// around it within its density bound is spread again, copying only the segments of that
+// window, and the whole array doubles or halves when no window is. That is O(log² n) amortized
+// entries copied per update, as many as the array moves. Every other segment is shared with
+// the map the update started from, so old versions stay valid for as long as they are kept,
+// for undo stacks and functional code. Cloning is a reference count increment.
+pub struct PersistentMap<K, V> {
+    root: Arc<Window<K, V>>,
+    len: usize,
+    height: usize,
+    segment_size_log2: usize,
+}
+
+impl<K, V> Clone for PersistentMap<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            len: self.len,
+            height: self.height,
+            segment_size_log2: self.segment_size_log2,
+        }
+    }
+}
+
+impl<K, V> Default for PersistentMap<K, V> {
+    fn default() -> Self {
+        let (height, segment_size_log2) = layout_shape(1);
+        Self {
+            root: Arc::new(Window::Segment(Arc::new([]))),
+            len: 0,
+            height,
+            segment_size_log2,
+        }
+    }
+}
+
+impl<K, V> PersistentMap<K, V> {
+    fn slot_count(&self) -> usize {
+        1 << (self.segment_size_log2 + self.height - 1)
+    }
+
+    // The slots of a window `depth` levels below the root.
+    fn window_size(&self, depth: usize) -> usize {
+        self.slot_count() >> depth
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> PersistentMap<K, V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&V> {
+        self.get_key_value(key).map(|(_, v)| v)
+    }
+
+    pub fn get_key_value<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(&K, &V)> {
+        let mut window = &*self.root;
+        loop {
+            match window {
+                Window::Segment(page) => {
+                    let i = page
+                        .binary_search_by(|(k, _)| key.compare(k).reverse())
+                        .ok()?;
+                    let (k, v) = &page[i];
+                    return Some((k, v));
+                }
+                Window::Split { left, right, .. } => {
+                    window = if goes_right(right, key) { right } else { left };
+                }
+            }
+        }
+    }
+
+    pub fn contains_key<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.get(key).is_some()
+    }
+
+    // A map with `key` mapped to `value`, sharing all but the segments the insert touched
+    // with this one.
+    pub fn insert(&self, key: K, value: V) -> Self {
+        if self.contains_key(&key) {
+            return Self {
+                root: Arc::new(replace(&self.root, key, value)),
+                ..self.clone()
+            };
+        }
+        match self.insert_into(&self.root, 0, key, value) {
+            Ok(root) => Self {
+                root: Arc::new(root),
+                len: self.len + 1,
+                ..*self
+            },
+            // Not even the root window takes the entry, the array doubles.
+            Err(entry) => {
+                let entries = with_entry(Window::iter(&self.root, self.len), entry);
+                Self::from_sorted(entries, self.slot_count() << 1)
+            }
+        }
+    }
+
+    // A map without `key`, or a clone of this one if the key is not in it.
+    pub fn remove<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Self {
+        match self.extract(key) {
+            Some((_, map)) => map,
+            None => self.clone(),
+        }
+    }
+
+    // The value of `key` and a map without it.
+    pub fn extract<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(V, Self)> {
+        let value = self.get(key)?.clone();
+        let map = match self.remove_from(&self.root, 0, key) {
+            Some(root) => Self {
+                root: Arc::new(root),
+                len: self.len - 1,
+                ..*self
+            },
+            None if self.len == 1 => Self::default(),
+            // Not even the root window stays dense enough, the array halves.
+            None => {
+                let entries = without_key(Window::iter(&self.root, self.len), key);
+                Self::from_sorted(entries, self.slot_count() >> 1)
+            }
+        };
+        Some((value, map))
+    }
+
+    pub fn iter(&self) -> PersistentIter<'_, K, V> {
+        Window::iter(&self.root, self.len)
+    }
+
+    // Whether both maps are the same version, or versions sharing the whole array.
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.root, &other.root) || self.is_empty() && other.is_empty()
+    }
+
+    // The copy of `window`, `depth` levels below the root, holding the new entry, or the entry
+    // back when the window would get denser than its bound and the one above has to take it.
+    fn insert_into(
+        &self,
+        window: &Window<K, V>,
+        depth: usize,
+        key: K,
+        value: V,
+    ) -> Result<Window<K, V>, (K, V)> {
+        let count = window.count() + 1;
+        let child = match window {
+            Window::Segment(page) => {
+                if !insert_density_ok(self.height, depth, count, self.window_size(depth)) {
+                    return Err((key, value));
+                }
+                let i = page.partition_point(|(k, _)| *k < key);
+                let entries = page[..i].iter().cloned().chain([(key, value)]);
+                return Ok(Window::Segment(
+                    entries.chain(page[i..].iter().cloned()).collect(),
+                ));
+            }
+            Window::Split { left, right, .. } => {
+                if goes_right(right, &key) {
+                    let right = self.insert_into(right, depth + 1, key, value);
+                    right.map(|right| Window::split(left.clone(), Arc::new(right)))
+                } else {
+                    let left = self.insert_into(left, depth + 1, key, value);
+                    left.map(|left| Window::split(Arc::new(left), right.clone()))
+                }
+            }
+        };
+        match child {
+            Ok(window) => Ok(window),
+            Err(entry) if insert_density_ok(self.height, depth, count, self.window_size(depth)) => {
+                let entries = with_entry(Window::iter(window, count - 1), entry);
+                Ok(self.spread(entries, depth))
+            }
+            Err(entry) => Err(entry),
+        }
+    }
+
+    // The copy of `window`, `depth` levels below the root, without `key`, which it holds, or
+    // `None` when the window would get sparser than its bound and the one above has to spread
+    // its entries.
+    fn remove_from<Q: Comparable<K> + ?Sized>(
+        &self,
+        window: &Window<K, V>,
+        depth: usize,
+        key: &Q,
+    ) -> Option<Window<K, V>> {
+        let count = window.count() - 1;
+        let child = match window {
+            Window::Segment(page) => {
+                if !remove_density_ok(self.height, depth, count, self.window_size(depth)) {
+                    return None;
+                }
+                let entries = page
+                    .iter()
+                    .filter(|(k, _)| key.compare(k) != Ordering::Equal);
+                return Some(Window::Segment(entries.cloned().collect()));
+            }
+            Window::Split { left, right, .. } => {
+                if goes_right(right, key) {
+                    let right = self.remove_from(right, depth + 1, key);
+                    right.map(|right| Window::split(left.clone(), Arc::new(right)))
+                } else {
+                    let left = self.remove_from(left, depth + 1, key);
+                    left.map(|left| Window::split(Arc::new(left), right.clone()))
+                }
+            }
+        };
+        child.or_else(|| {
+            remove_density_ok(self.height, depth, count, self.window_size(depth)).then(|| {
+                let entries = without_key(Window::iter(window, count + 1), key);
+                self.spread(entries, depth)
+            })
+        })
+    }
+
+    // A window `depth` levels below the root holding `entries`, spread evenly over its
+    // segments.
+    fn spread(&self, entries: Vec<(K, V)>, depth: usize) -> Window<K, V> {
+        let count = entries.len();
+        build(
+            &mut entries.into_iter(),
+            count,
+            1 << (self.height - 1 - depth),
+        )
+    }
+
+    // A map of `entries`, sorted and distinct, over a layout of `len` slots.
+    fn from_sorted(entries: Vec<(K, V)>, len: usize) -> Self {
+        let (height, segment_size_log2) = layout_shape(len);
+        let count = entries.len();
+        Self {
+            root: Arc::new(build(&mut entries.into_iter(), count, 1 << (height - 1))),
+            len: count,
+            height,
+            segment_size_log2,
+        }
+    }
+}
+
+// Whether `key` belongs under `right`, the right half of a window. Keys below the first one
+// of the right half go left, so do all keys when the right half is empty.
+fn goes_right<K, V, Q: Comparable<K> + ?Sized>(right: &Window<K, V>, key: &Q) -> bool {
+    right
+        .first()
+        .is_some_and(|first| key.compare(first) != Ordering::Less)
+}
+
+// The copy of `window` with the value of `key`, which it holds, replaced.
+fn replace<K: Ord + Clone, V: Clone>(window: &Window<K, V>, key: K, value: V) -> Window<K, V> {
+    match window {
+        Window::Segment(page) => {
+            let mut entries = page.to_vec();
+            let i = entries.partition_point(|(k, _)| *k < key);
+            entries[i] = (key, value);
+            Window::Segment(entries.into())
+        }
+        Window::Split { left, right, .. } if goes_right(right, &key) => {
+            Window::split(left.clone(), Arc::new(replace(right, key, value)))
+        }
+        Window::Split { left, right, .. } => {
+            Window::split(Arc::new(replace(left, key, value)), right.clone())
+        }
+    }
+}
+
+// The window of `segments` segments, a power of two, holding the next `count` entries of
+// `entries`, the segments differing by one entry at most.
+fn build<K: Clone, V>(
+    entries: &mut std::vec::IntoIter<(K, V)>,
+    count: usize,
+    segments: usize,
+) -> Window<K, V> {
+    if segments == 1 {
+        return Window::Segment(entries.take(count).collect());
+    }
+    let left = build(entries, count / 2, segments / 2);
+    let right = build(entries, count - count / 2, segments / 2);
+    Window::split(Arc::new(left), Arc::new(right))
+}
+
+// The entries with `entry`, whose key is not among them, in key order.
+fn with_entry<K: Ord + Clone, V: Clone>(
+    entries: PersistentIter<'_, K, V>,
+    entry: (K, V),
+) -> Vec<(K, V)> {
+    let mut entries = entries
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect::<Vec<_>>();
+    let i = entries.partition_point(|(k, _)| *k < entry.0);
+    entries.insert(i, entry);
+    entries
+}
+
+fn without_key<K: Clone, V: Clone, Q: Comparable<K> + ?Sized>(
+    entries: PersistentIter<'_, K, V>,
+    key: &Q,
+) -> Vec<(K, V)> {
+    entries
+        .filter(|(k, _)| key.compare(k) != Ordering::Equal)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+impl<K: Clone, V> Window<K, V> {
+    fn split(left: Arc<Self>, right: Arc<Self>) -> Self {
+        Window::Split {
+            count: left.count() + right.count(),
+            first: left.first().or(right.first()).cloned(),
+            left,
+            right,
+        }
+    }
+}
+
+impl<K, V> Window<K, V> {
+    fn count(&self) -> usize {
+        match self {
+            Window::Segment(page) => page.len(),
+            Window::Split { count, .. } => *count,
+        }
+    }
+
+    fn first(&self) -> Option<&K> {
+        match self {
+            Window::Segment(page) => page.first().map(|(k, _)| k),
+            Window::Split { first, .. } => first.as_ref(),
+        }
+    }
+
+    // The `count` entries of the window in key order.
+    fn iter(&self, count: usize) -> PersistentIter<'_, K, V> {
+        PersistentIter {
+            stack: vec![self],
+            segment: [].iter(),
+            remaining: count,
+        }
+    }
+}
+
+// The entries of a `PersistentMap` in key order.
+pub struct PersistentIter<'a, K, V> {
+    // The windows not visited yet, the next one on top.
+    stack: Vec<&'a Window<K, V>>,
+    segment: std::slice::Iter<'a, (K, V)>,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iterator for PersistentIter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((k, v)) = self.segment.next() {
+                self.remaining -= 1;
+                return Some((k, v));
+            }
+            match self.stack.pop()? {
+                Window::Segment(page) => self.segment = page.iter(),
+                Window::Split { left, right, .. } => self.stack.extend([&**right, &**left]),
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V> ExactSizeIterator for PersistentIter<'_, K, V> {}
+
+// Sorts the entries and spreads them over the layout a `BTreeMap` of as many would get. When a
+// key shows up several times, the last value wins.
+impl<K: Ord + Clone, V: Clone> FromIterator<(K, V)> for PersistentMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut entries = iter.into_iter().collect::<Vec<_>>();
+        sorting::funnel_sort_by(&mut entries, |a, b| a.0.cmp(&b.0));
+        let mut deduped: Vec<(K, V)> = Vec::with_capacity(entries.len());
+        for kv in entries {
+            match deduped.last_mut() {
+                Some(last) if last.0 == kv.0 => *last = kv,
+                _ => deduped.push(kv),
+            }
+        }
+        let len = PackedMemoryArray::<K, V>::layout_len(deduped.len());
+        Self::from_sorted(deduped, len)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod persistent {
+    use super::{PersistentMap, Window};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    // The segments in key order.
+    fn segments<K, V>(map: &PersistentMap<K, V>) -> Vec<*const (K, V)> {
+        fn walk<K, V>(window: &Window<K, V>, out: &mut Vec<*const (K, V)>) {
+            match window {
+                Window::Segment(page) => out.push(page.as_ptr()),
+                Window::Split { left, right, .. } => {
+                    walk(left, out);
+                    walk(right, out);
+                }
+            }
+        }
+        let mut out = vec![];
+        walk(&map.root, &mut out);
+        out
+    }
+
+    // Checks the counts, first keys and order of every window, that the segments are all on
+    // the last level and none holds more entries than it has slots.
+    fn check<K: Ord + Clone, V>(map: &PersistentMap<K, V>) {
+        fn walk<K: Ord + Clone, V>(
+            map: &PersistentMap<K, V>,
+            window: &Window<K, V>,
+            depth: usize,
+        ) -> Vec<K> {
+            match window {
+                Window::Segment(page) => {
+                    assert_eq!(depth, map.height - 1);
+                    assert!(page.len() <= map.window_size(depth));
+                    page.iter().map(|(k, _)| k.clone()).collect()
+                }
+                Window::Split {
+                    count,
+                    first,
+                    left,
+                    right,
+                } => {
+                    let mut keys = walk(map, left, depth + 1);
+                    keys.extend(walk(map, right, depth + 1));
+                    assert_eq!(*count, keys.len());
+                    assert!(*first == keys.first().cloned());
+                    keys
+                }
+            }
+        }
+        let keys = walk(map, &map.root, 0);
+        assert_eq!(keys.len(), map.len);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_versions() {
+        let mut rng = StdRng::seed_from_u64(21);
+        let mut versions = vec![PersistentMap::new()];
+        let mut models = vec![BTreeMap::new()];
+        for i in 0..4000 {
+            let (map, mut model) = (versions.last().unwrap(), models.last().unwrap().clone());
+            let key = rng.gen_range(0..3000u32);
+            let next = if rng.gen_bool(0.25) {
+                let extracted = map.extract(&key);
+                assert_eq!(extracted.as_ref().map(|(v, _)| *v), model.remove(&key));
+                extracted.map_or_else(|| map.remove(&key), |(_, map)| map)
+            } else {
+                model.insert(key, i);
+                map.insert(key, i)
+            };
+            assert_eq!(next.len(), model.len());
+            if i % 200 == 0 {
+                check(&next);
+            }
+            versions.push(next);
+            models.push(model);
+        }
+        // Every version still reads as it did when it was made.
+        for (map, model) in versions.iter().zip(&models).step_by(97) {
+            assert!(map.iter().eq(model.iter()));
+            assert_eq!(map.iter().len(), model.len());
+            for key in (0..3000).step_by(13) {
+                assert_eq!(map.get(&key), model.get(&key));
+            }
+        }
+        let last = versions.last().unwrap();
+        let drained = models
+            .last()
+            .unwrap()
+            .keys()
+            .fold(last.clone(), |map, k| map.remove(k));
+        assert!(drained.is_empty() && drained.iter().next().is_none());
+        assert!(last.ptr_eq(&last.clone()) && !last.ptr_eq(&drained));
+        assert!(drained.ptr_eq(&PersistentMap::new()));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_sharing() {
+        let map = (0..100000u32)
+            .rev()
+            .map(|i| (i, i))
+            .collect::<PersistentMap<_, _>>();
+        check(&map);
+        assert!(map.iter().map(|(k, _)| *k).eq(0..100000));
+        let next = map.insert(500, 0).remove(&70000);
+        assert_eq!((next.get(&500), map.get(&500)), (Some(&0), Some(&500)));
+        assert_eq!(next.len(), map.len() - 1);
+        let (old, new) = (segments(&map), segments(&next));
+        let shared = new.iter().filter(|page| old.contains(page)).count();
+        assert_eq!(shared, old.len() - 2);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_rebalance() {
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut map = (0..20000u32)
+            .map(|i| (i * 4, i))
+            .collect::<PersistentMap<_, _>>();
+        let mut model = map
+            .iter()
+            .map(|(k, v)| (*k, *v))
+            .collect::<BTreeMap<_, _>>();
+        // Inserts crowding a few segments make the windows around them spread again, which
+        // copies those windows and shares the rest of the array.
+        let (mut copied, mut total) = (0, 0);
+        for i in 0..20000 {
+            let key = rng.gen_range(0..8000) + if i % 2 == 0 { 0 } else { 40000 };
+            let next = map.insert(key, i);
+            model.insert(key, i);
+            let (old, new) = (segments(&map), segments(&next));
+            if old.len() == new.len() {
+                // The copied segments are exactly those of one window.
+                let fresh = (0..new.len()).filter(|&j| old[j] != new[j]);
+                let fresh = fresh.collect::<Vec<_>>();
+                let size = fresh.len();
+                assert!(size.is_power_of_two() && fresh[0] % size == 0);
+                assert_eq!(fresh[size - 1], fresh[0] + size - 1);
+                copied += size;
+                total += new.len();
+            }
+            map = next;
+        }
+        check(&map);
+        assert!(map.iter().map(|(k, v)| (*k, *v)).eq(model.clone()));
+        assert!(copied * 20 < total);
+        // Removes shrink the array again, down to an empty map.
+        let keys = model.keys().copied().collect::<Vec<_>>();
+        for (i, key) in keys.iter().enumerate() {
+            let next = map.remove(key);
+            if i % 1000 == 0 {
+                check(&next);
+                assert!(next.slot_count() <= map.slot_count());
+            }
+            map = next;
+        }
+        assert!(map.is_empty() && map.slot_count() == 1);
+    }
+}
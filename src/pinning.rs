@@ -0,0 +1,146 @@
+use std::{io, ops::Bound};
+
+// A page aligned memory region locked with `mlock`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct PinnedRegion {
+    address: usize,
+    len: usize,
+}
+
+impl PinnedRegion {
+    #[cfg(test)]
+    pub(crate) fn covers(&self, address: usize, len: usize) -> bool {
+        self.address <= address && address + len <= self.address + self.len
+    }
+}
+
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+// Faults in and locks the pages covering `len` bytes from `address`.
+pub(crate) fn pin(address: usize, len: usize) -> io::Result<PinnedRegion> {
+    let page_size = page_size();
+    let start = address / page_size * page_size;
+    let end = (address + len.max(1)).div_ceil(page_size) * page_size;
+    let region = PinnedRegion {
+        address: start,
+        len: end - start,
+    };
+    unsafe {
+        // The hint only speeds up the fault-in done by `mlock`, its failure is not an error.
+        libc::madvise(
+            region.address as *mut libc::c_void,
+            region.len,
+            libc::MADV_WILLNEED,
+        );
+        if libc::mlock(region.address as *const libc::c_void, region.len) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(region)
+}
+
+// The regions pinned by a map, unlocked when the map goes away.
+#[derive(Default)]
+pub(crate) struct PinnedRegions(pub(crate) Vec<PinnedRegion>);
+
+impl Drop for PinnedRegions {
+    fn drop(&mut self) {
+        for region in self.0.drain(..) {
+            let _ = unpin(region);
+        }
+    }
+}
+
+// The key ranges a map pinned, kept to pin them again after a resize moves the buffers, and
+// the regions of the index locked for them. The slot regions are held by the PMA.
+pub(crate) struct PinnedRanges<K> {
+    pub(crate) ranges: Vec<(Bound<K>, Bound<K>)>,
+    pub(crate) index: PinnedRegions,
+}
+
+impl<K> Default for PinnedRanges<K> {
+    fn default() -> Self {
+        Self {
+            ranges: vec![],
+            index: PinnedRegions::default(),
+        }
+    }
+}
+
+pub(crate) fn unpin(region: PinnedRegion) -> io::Result<()> {
+    unsafe {
+        if libc::munlock(region.address as *const libc::c_void, region.len) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod pinning {
+    use crate::{
+        pinning::{page_size, pin, unpin},
+        BTreeMap,
+    };
+
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri does not support mlock")]
+    fn test_pin_region() {
+        let buffer = vec![0u8; 10000];
+        let region = pin(buffer.as_ptr() as usize + 10, 5000).unwrap();
+        assert_eq!(region.address % page_size(), 0);
+        assert_eq!(region.len % page_size(), 0);
+        assert!(region.address <= buffer.as_ptr() as usize + 10);
+        assert!(region.address + region.len >= buffer.as_ptr() as usize + 5010);
+        unpin(region).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri does not support mlock")]
+    fn test_pin_range() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..1000 {
+            map.insert(i, i);
+        }
+        assert_eq!(map.pinned_regions(), 0);
+        map.pin_range(100..200).unwrap();
+        map.pin_range(..).unwrap();
+        assert_eq!(map.pinned_regions(), 6);
+        map.unpin_all().unwrap();
+        assert_eq!(map.pinned_regions(), 0);
+        assert_eq!(map.get(&150), Some(&150));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "Miri does not support mlock")]
+    fn test_pinned_growth() {
+        let mut map = (0..1000usize).map(|i| (i, i)).collect::<BTreeMap<_, _>>();
+        map.pin_range(100..200).unwrap();
+        assert!(map.is_range_pinned(100..200));
+        // Every doubling moves the slots and the index, the range follows them.
+        let mut slots = map.pma().data_len();
+        for i in 1000..20000 {
+            map.insert(i, i);
+            if map.pma().data_len() != slots {
+                slots = map.pma().data_len();
+                assert!(map.is_range_pinned(100..200));
+            }
+        }
+        assert_eq!(slots, 32768);
+        assert_eq!(map.pinned_regions(), 3);
+        map.shrink_to_fit();
+        assert!(map.is_range_pinned(100..200));
+        map.try_reserve(100000).unwrap();
+        assert!(map.is_range_pinned(100..200));
+        map.remove_range(5000..);
+        assert!(map.pma().data_len() < slots);
+        assert!(map.is_range_pinned(100..200));
+        map.unpin_all().unwrap();
+        assert_eq!(map.pinned_regions(), 0);
+        map.insert(20000, 0);
+        assert_eq!(map.pinned_regions(), 0);
+    }
+}
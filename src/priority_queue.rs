@@ -0,0 +1,283 @@
+use crate::merger::{take_first, Merger};
+use std::collections::VecDeque;
+
+// The capacity of the insertion buffer, s_1 in the paper.
+const INSERT_CAP: usize = 8;
+
+// A cache oblivious min priority queue, the funnel heap of Brodal and Fagerberg.
+// https://www.cs.au.dk/~gerth/papers/swat02.pdf
+// Inserts collect in a small sorted buffer. When it fills, it is swept with everything in the
+// links in front of the first link that has an unused input into that input, a sorted run.
+// Link i merges its k_i runs of up to s_i elements through a k_i-merger, and a binary merger
+// joins its output with the output of the links behind it. `pop_min`
+// takes from the front of the first link, refilled by merging down the links on demand, so
+// n operations cost O(n/B log_{M/B} n/B) block transfers amortized. Buffers allocate as
+// they fill, so memory follows the number of elements rather than the link capacities.
+pub struct FunnelHeap<T> {
+    // The inserts not swept yet, largest first.
+    inserts: Vec<T>,
+    links: Vec<Link<T>>,
+    len: usize,
+}
+
+struct Link<T> {
+    // The capacity of each input of the merger.
+    s: usize,
+    // The output of this link merged with the output of the links behind it, A_i. Everything
+    // here sorts before everything still behind it.
+    front: VecDeque<T>,
+    // Merges the sorted runs S_i into B_i, its root buffer. The inputs before `next` were
+    // written by sweeps, the others are unused.
+    merger: Merger<T>,
+    next: usize,
+}
+
+impl<T: Ord> Link<T> {
+    fn new(k: usize, s: usize) -> Self {
+        Self {
+            s,
+            front: VecDeque::new(),
+            merger: Merger::new(k),
+            next: 0,
+        }
+    }
+
+    fn k(&self) -> usize {
+        self.merger.inputs()
+    }
+
+    // The number of elements in the link, its front included.
+    fn stored(&self) -> usize {
+        self.front.len() + self.merger.len()
+    }
+}
+
+impl<T: Ord> Default for FunnelHeap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Ord> FunnelHeap<T> {
+    pub fn new() -> Self {
+        Self {
+            inserts: Vec::with_capacity(INSERT_CAP),
+            links: Vec::new(),
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn push(&mut self, item: T) {
+        let at = self.inserts.partition_point(|x| *x > item);
+        self.inserts.insert(at, item);
+        self.len += 1;
+        if self.inserts.len() == INSERT_CAP {
+            let i = self
+                .links
+                .iter()
+                .position(|link| link.next < link.k())
+                .unwrap_or(self.links.len());
+            self.sweep(i);
+        }
+    }
+
+    // The smallest element. The front of the first link is kept filled, so this reads it and
+    // the insertion buffer only.
+    pub fn peek(&self) -> Option<&T> {
+        let front = self.links.first().and_then(|link| link.front.front());
+        match take_first(self.inserts.last(), front, &T::le)? {
+            true => self.inserts.last(),
+            false => front,
+        }
+    }
+
+    pub fn pop_min(&mut self) -> Option<T> {
+        let front = self.links.first().and_then(|link| link.front.front());
+        let item = match take_first(self.inserts.last(), front, &T::le)? {
+            true => self.inserts.pop(),
+            false => {
+                let item = self.links[0].front.pop_front();
+                self.refill();
+                item
+            }
+        };
+        self.len -= 1;
+        item
+    }
+
+    // Fills the front of the first link again once it is empty.
+    fn refill(&mut self) {
+        if self.links.first().is_some_and(|link| link.front.is_empty()) {
+            self.fill_front(0, self.links.len());
+        }
+    }
+
+    // Merges into the front of link `i` from its merger and from the front of link i + 1,
+    // with the links from `limit` on left out, until the front holds k_i^3 elements.
+    fn fill_front(&mut self, i: usize, limit: usize) {
+        let cap = self.links[i].k().pow(3);
+        let deeper = i + 1 < limit;
+        while self.links[i].front.len() < cap {
+            if deeper
+                && self.links[i + 1].front.is_empty()
+                && self.links[i + 1..limit]
+                    .iter()
+                    .any(|link| link.stored() > 0)
+            {
+                self.fill_front(i + 1, limit);
+            }
+            let (upper, lower) = self.links.split_at_mut(i + 1);
+            let link = &mut upper[i];
+            let behind = lower.first().filter(|_| deeper);
+            let merged = link.merger.front(&T::le);
+            let item = match take_first(behind.and_then(|l| l.front.front()), merged, &T::le) {
+                None => break,
+                Some(true) => lower[0].front.pop_front(),
+                Some(false) => link.merger.pop_front(),
+            };
+            link.front.extend(item);
+        }
+    }
+
+    // Writes the inserts and everything in the links before link `i` into its next input.
+    // The buffers on the path from the first front down to that input are emptied as well and
+    // the smallest of it all is put back into them, as many as each held, so every buffer
+    // still sorts before everything below it.
+    fn sweep(&mut self, i: usize) {
+        if i == self.links.len() {
+            let (k, s) = match self.links.last() {
+                Some(link) => {
+                    let s = link.s * (link.k() + 1);
+                    let k = (2..).map(|b| 1usize << b).find(|k| k.pow(3) >= s).unwrap();
+                    (k, s)
+                }
+                None => (2, INSERT_CAP),
+            };
+            self.links.push(Link::new(k, s));
+        }
+        let fronts = self.links[..=i]
+            .iter()
+            .map(|link| link.front.len())
+            .collect::<Vec<_>>();
+        // The links before `i`, drained in order through the first front, and the inserts.
+        let mut upper = Vec::new();
+        if i > 0 {
+            loop {
+                if self.links[0].front.is_empty() {
+                    self.fill_front(0, i);
+                }
+                match self.links[0].front.pop_front() {
+                    Some(item) => upper.push(item),
+                    None => break,
+                }
+            }
+        }
+        let upper = merge(upper, std::mem::take(&mut self.inserts).into_iter().rev());
+        let link = &mut self.links[i];
+        let path = link.merger.path(link.next);
+        let mut counts = Vec::with_capacity(path.len());
+        // The front and the path buffers, each sorting before the next.
+        let mut lower = link.front.drain(..).collect::<Vec<_>>();
+        for &v in &path {
+            let buffer = link.merger.buffer_mut(v);
+            counts.push(buffer.len());
+            lower.extend(buffer.drain(..));
+        }
+        let mut merged = merge(upper, lower).into_iter();
+        for (j, n) in fronts.into_iter().enumerate() {
+            self.links[j].front.extend(merged.by_ref().take(n));
+        }
+        let link = &mut self.links[i];
+        for (&v, n) in path.iter().zip(counts) {
+            link.merger.buffer_mut(v).extend(merged.by_ref().take(n));
+        }
+        let next = link.next;
+        let input = link.merger.input_mut(next);
+        input.extend(merged);
+        debug_assert!(input.len() <= link.s);
+        link.next += 1;
+        link.merger.recount(&path);
+        for link in &mut self.links[..i] {
+            link.next = 0;
+        }
+        self.inserts.reserve(INSERT_CAP);
+        self.refill();
+    }
+}
+
+// The elements of two sorted sequences in order.
+fn merge<T: Ord>(a: impl IntoIterator<Item = T>, b: impl IntoIterator<Item = T>) -> Vec<T> {
+    let (mut a, mut b) = (a.into_iter().peekable(), b.into_iter().peekable());
+    let mut merged = Vec::with_capacity(a.size_hint().0 + b.size_hint().0);
+    while let Some(first) = take_first(a.peek(), b.peek(), &T::le) {
+        merged.extend(if first { a.next() } else { b.next() });
+    }
+    merged
+}
+
+impl<T: Ord> Extend<T> for FunnelHeap<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|item| self.push(item));
+    }
+}
+
+impl<T: Ord> FromIterator<T> for FunnelHeap<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut heap = Self::new();
+        heap.extend(iter);
+        heap
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod priority_queue {
+    use super::FunnelHeap;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::{cmp::Reverse, collections::BinaryHeap};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_operations() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut heap = FunnelHeap::new();
+        let mut expected = BinaryHeap::new();
+        // Phases that grow and shrink the heap, so sweeps reach the later links and the
+        // fronts are drained and refilled across them.
+        for push_rate in [0.9, 0.3, 0.7, 0.1] {
+            for _ in 0..60000 {
+                if rng.gen_bool(push_rate) {
+                    let item = rng.gen_range(0..10000u32);
+                    heap.push(item);
+                    expected.push(Reverse(item));
+                } else {
+                    assert_eq!(heap.pop_min(), expected.pop().map(|Reverse(item)| item));
+                }
+                assert_eq!(heap.peek(), expected.peek().map(|Reverse(item)| item));
+                assert_eq!(heap.len(), expected.len());
+            }
+        }
+        assert!(heap.links.len() >= 4);
+        while let Some(Reverse(item)) = expected.pop() {
+            assert_eq!(heap.pop_min(), Some(item));
+        }
+        assert!(heap.is_empty() && heap.pop_min().is_none());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_sorted() {
+        let heap = (0..5000).rev().collect::<FunnelHeap<_>>();
+        let mut heap = heap;
+        assert_eq!(heap.peek(), Some(&0));
+        assert!(std::iter::from_fn(|| heap.pop_min()).eq(0..5000));
+    }
+}
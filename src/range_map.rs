@@ -0,0 +1,211 @@
+use crate::{cache_oblivious::ParallelBounds, BTreeMap};
+use std::ops::Range;
+
+// Disjoint half open key ranges mapped to values, such as address or IP ranges. Inserting a
+// range overwrites what it overlaps, splitting ranges it covers only in part, and merges with
+// the ranges right next to it that hold an equal value, so the stored ranges are always the
+// fewest describing the mapping. Stored as a map from the start of each range to its end and
+// value, so a point lookup is a predecessor search in the PMA.
+pub struct RangeMap<K: Ord, V> {
+    map: BTreeMap<K, (K, V)>,
+}
+
+impl<K, V> Default for RangeMap<K, V>
+where
+    K: Ord + Clone + ParallelBounds,
+    V: Eq + Clone + ParallelBounds,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> RangeMap<K, V>
+where
+    K: Ord + Clone + ParallelBounds,
+    V: Eq + Clone + ParallelBounds,
+{
+    pub fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+        }
+    }
+
+    // The number of stored ranges, after merging.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    // Maps every key in `range` to `value`. Empty ranges change nothing.
+    pub fn insert(&mut self, range: Range<K>, value: V) {
+        if range.start >= range.end {
+            return;
+        }
+        self.remove(range.clone());
+        let Range { mut start, mut end } = range;
+        let before = self
+            .map
+            .range(..start.clone())
+            .next_back()
+            .filter(|(_, (e, v))| *e == start && *v == value)
+            .map(|(s, _)| s.clone());
+        if let Some(s) = before {
+            self.map.remove(&s);
+            start = s;
+        }
+        let after = self.map.get(&end).filter(|(_, v)| *v == value).cloned();
+        if let Some((e, _)) = after {
+            self.map.remove(&end);
+            end = e;
+        }
+        self.map.insert(start, (end, value));
+    }
+
+    // Unmaps every key in `range`, cutting the ranges sticking out of it.
+    pub fn remove(&mut self, range: Range<K>) {
+        if range.start >= range.end {
+            return;
+        }
+        // A range starting before `range` and reaching into it keeps its part in front, and
+        // its part behind when it covers `range` whole.
+        let left = self
+            .map
+            .range(..range.start.clone())
+            .next_back()
+            .filter(|(_, (e, _))| *e > range.start)
+            .map(|(s, _)| s.clone());
+        if let Some(s) = left {
+            let (e, v) = self.map.get_mut(&s).unwrap();
+            let e = std::mem::replace(e, range.start.clone());
+            if e > range.end {
+                let v = v.clone();
+                self.map.insert(range.end.clone(), (e, v));
+                return;
+            }
+        }
+        // The last range starting inside `range` keeps its part behind it.
+        let right = self
+            .map
+            .range(range.clone())
+            .next_back()
+            .filter(|(_, (e, _))| *e > range.end)
+            .map(|(_, (e, v))| (e.clone(), v.clone()));
+        self.map.remove_range(range.clone());
+        if let Some(rest) = right {
+            self.map.insert(range.end, rest);
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.get_key_value(key).map(|(_, v)| v)
+    }
+
+    // The range holding `key` and its value.
+    pub fn get_key_value(&self, key: &K) -> Option<(Range<&K>, &V)> {
+        let (s, (e, v)) = self.map.range(..=key).next_back()?;
+        (key < e).then_some((s..e, v))
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.get_key_value(key).is_some()
+    }
+
+    // The stored ranges in key order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (Range<&K>, &V)> {
+        self.map.iter().map(|(s, (e, v))| (s..e, v))
+    }
+
+    // The stored ranges overlapping `range`, in key order.
+    pub fn overlapping(&self, range: Range<K>) -> impl Iterator<Item = (Range<&K>, &V)> {
+        let first = self
+            .map
+            .range(..range.start.clone())
+            .next_back()
+            .filter(|(_, (e, _))| *e > range.start);
+        first
+            .into_iter()
+            .chain(self.map.range(range))
+            .map(|(s, (e, v))| (s..e, v))
+    }
+}
+
+impl<K, V> FromIterator<(Range<K>, V)> for RangeMap<K, V>
+where
+    K: Ord + Clone + ParallelBounds,
+    V: Eq + Clone + ParallelBounds,
+{
+    // Later ranges overwrite earlier ones where they overlap.
+    fn from_iter<I: IntoIterator<Item = (Range<K>, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (range, value) in iter {
+            map.insert(range, value);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod range_map {
+    use super::RangeMap;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // Checks the map against a value per key, and that no two stored ranges could merge.
+    fn check(map: &RangeMap<u32, u8>, model: &[Option<u8>]) {
+        for (k, v) in model.iter().enumerate() {
+            assert_eq!(map.get(&(k as u32)), v.as_ref());
+        }
+        let ranges = map.iter().collect::<Vec<_>>();
+        for w in ranges.windows(2) {
+            assert!(w[0].0.end <= w[1].0.start);
+            assert!(w[0].0.end < w[1].0.start || w[0].1 != w[1].1);
+        }
+        let runs = model
+            .chunk_by(|a, b| a == b)
+            .filter(|run| run[0].is_some())
+            .count();
+        assert_eq!(map.len(), runs);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_operations() {
+        let mut rng = StdRng::seed_from_u64(17);
+        let mut map = RangeMap::new();
+        let mut model = vec![None; 200];
+        for _ in 0..3000 {
+            let (a, b) = (rng.gen_range(0..200), rng.gen_range(0..200));
+            let range = a.min(b)..a.max(b);
+            if rng.gen_bool(0.7) {
+                let value = rng.gen_range(0..3);
+                map.insert(range.clone(), value);
+                model[range.start as usize..range.end as usize].fill(Some(value));
+            } else {
+                map.remove(range.clone());
+                model[range.start as usize..range.end as usize].fill(None);
+            }
+            check(&map, &model);
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut map = [(0..10, 'a'), (20..30, 'a'), (10..20, 'a')]
+            .into_iter()
+            .collect::<RangeMap<_, _>>();
+        assert!(map.iter().eq([(&0..&30, &'a')]));
+        map.insert(5..8, 'b');
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get_key_value(&7), Some((&5..&8, &'b')));
+        assert!(map
+            .overlapping(7..21)
+            .map(|(range, _)| (*range.start, *range.end))
+            .eq([(5, 8), (8, 30)]));
+        map.remove(0..30);
+        assert!(map.is_empty());
+    }
+}
@@ -1,25 +1,72 @@
 #![allow(dead_code)]
 
-pub(crate) struct Segment<'a, K: Clone + Ord, V: Clone> {
-    data: &'a [*mut Option<(K, V)>],
+use crate::{bitmap, CoBTreeError};
+use std::mem::{self, MaybeUninit};
+
+// A window of PMA slots being rebalanced, its keys and values in parallel slots, with the
+// metadata slots parallel to them.
+pub(crate) struct Segment<'a, K: Ord, V> {
+    keys: &'a mut [MaybeUninit<K>],
+    values: &'a mut [MaybeUninit<V>],
     count: usize,
+    // The occupancy bitmap of the whole array, the window starting at bit `offset`. A slot is
+    // initialized exactly when its bit is set, so every move updates both together.
+    occupied: &'a mut [u64],
+    offset: usize,
+    // Metadata slots parallel to the keys, moved in lockstep with the key values.
+    meta: Option<&'a mut [u64]>,
+    // Counts the key values moved to another slot, when set.
+    moves: Option<&'a mut u64>,
+}
+
+// `x * len / count` without overflow: the product is up to len², past `usize` on 32-bit
+// targets once a window holds more than 65,536 slots.
+#[inline]
+fn scale(x: usize, len: usize, count: usize) -> usize {
+    (x as u128 * len as u128 / count as u128) as usize
 }
 
 impl<'a, K, V> Segment<'a, K, V>
 where
-    K: Clone + Ord,
-    V: Clone,
+    K: Ord,
 {
+    // `values` must hold as many slots as `keys`.
     #[inline]
-    pub(crate) fn new(data: &'a [*mut Option<(K, V)>], count: Option<usize>) -> Segment<'a, K, V> {
+    pub(crate) fn new(
+        keys: &'a mut [MaybeUninit<K>],
+        values: &'a mut [MaybeUninit<V>],
+        occupied: &'a mut [u64],
+        offset: usize,
+        count: Option<usize>,
+    ) -> Segment<'a, K, V> {
         Self {
-            data,
-            count: unsafe {
-                match count {
-                    Some(c) => c,
-                    None => data.iter().filter(|&&v| (*v).is_some()).count(),
-                }
-            },
+            count: count.unwrap_or_else(|| bitmap::count(occupied, offset, offset + keys.len())),
+            keys,
+            values,
+            occupied,
+            offset,
+            meta: None,
+            moves: None,
+        }
+    }
+
+    // `meta` must hold as many slots as `keys`.
+    #[inline]
+    pub(crate) fn with_meta(mut self, meta: Option<&'a mut [u64]>) -> Segment<'a, K, V> {
+        self.meta = meta;
+        self
+    }
+
+    #[inline]
+    pub(crate) fn with_moves(mut self, moves: Option<&'a mut u64>) -> Segment<'a, K, V> {
+        self.moves = moves;
+        self
+    }
+
+    #[inline]
+    fn move_meta(&mut self, src: usize, dst: usize) {
+        if let Some(meta) = &mut self.meta {
+            meta[dst] = meta[src];
         }
     }
 
@@ -28,72 +75,109 @@ where
         self.count
     }
 
+    // The first slot in [from, to) of the window that is occupied, or free.
+    #[inline]
+    fn next_slot(&self, from: usize, to: usize, occupied: bool) -> Option<usize> {
+        bitmap::next(
+            self.occupied,
+            self.offset + from,
+            self.offset + to,
+            occupied,
+        )
+        .map(|i| i - self.offset)
+    }
+
     #[inline]
-    fn move_key_value(&self, src: usize, dst: usize) {
+    fn prev_slot(&self, from: usize, to: usize, occupied: bool) -> Option<usize> {
+        bitmap::prev(
+            self.occupied,
+            self.offset + from,
+            self.offset + to,
+            occupied,
+        )
+        .map(|i| i - self.offset)
+    }
+
+    // `dst` must be free.
+    #[inline]
+    fn move_key_value(&mut self, src: usize, dst: usize) {
         if src == dst {
             return;
         }
-        unsafe {
-            *self.data[dst] = (*self.data[src]).take();
+        self.keys[dst] = mem::replace(&mut self.keys[src], MaybeUninit::uninit());
+        self.values[dst] = mem::replace(&mut self.values[src], MaybeUninit::uninit());
+        bitmap::unset(self.occupied, self.offset + src);
+        bitmap::set(self.occupied, self.offset + dst);
+        self.move_meta(src, dst);
+        if let Some(moves) = &mut self.moves {
+            **moves += 1;
         }
     }
 
-    #[inline]
-    fn move_key_value_if_src_not_none(&self, src: usize, dst: usize) -> bool {
-        unsafe {
-            if (*self.data[src]).is_none() {
-                return false;
-            }
-            if src != dst {
-                *self.data[dst] = (*self.data[src]).take();
-            }
+    pub(crate) fn move_all_key_values_to_front(&mut self) {
+        let mut num = 0;
+        while num < self.count {
+            let Some(src) = self.next_slot(num, self.keys.len(), true) else {
+                break;
+            };
+            self.move_key_value(src, num);
+            num += 1;
         }
-        true
     }
 
-    pub(crate) fn move_all_key_values_to_front(&self) {
+    // Evenly distribut the data.
+    pub(crate) fn shuffle_key_values(&mut self, need_to_move_to_front: bool) {
         if self.count == 0 {
             return;
         }
-        let mut num = 0;
-        for i in 0..self.data.len() {
-            if self.move_key_value_if_src_not_none(i, num) {
-                num += 1;
-                if num == self.count {
-                    break;
-                }
-            }
+        if need_to_move_to_front {
+            self.move_all_key_values_to_front();
+        }
+        // Entry i goes to the slot len - 1 - floor((count - 1 - i) * len / count), so the free
+        // slots spread evenly over the window with the last entry in its last slot. Steps of
+        // len / count with the remainder handed to the lowest ranks would pack the top entries
+        // together, leaving the end of the window, where appends go, with no room.
+        let (count, len) = (self.count, self.keys.len());
+        for i in (0..count).rev() {
+            self.move_key_value(i, len - 1 - scale(count - 1 - i, len, count));
         }
     }
 
-    // Evenly distribut the data.
-    pub(crate) fn shuffle_key_values(&self, need_to_move_to_front: bool) {
+    // Same as `shuffle_key_values`, but half the free slots of the window make one gap in
+    // front of the entry of rank `hot`, where the next inserts are expected, and the entries
+    // on either side spread evenly over the rest. `hot` may be the count, for a gap at the end.
+    pub(crate) fn shuffle_key_values_around(&mut self, hot: usize, need_to_move_to_front: bool) {
         if self.count == 0 {
             return;
         }
         if need_to_move_to_front {
             self.move_all_key_values_to_front();
         }
-        let sub_len = self.data.len() / self.count;
-        let remainer = self.data.len() % self.count;
-        let mut j = self.data.len() - 1;
-        for i in (0..self.count).rev() {
-            self.move_key_value(i, j);
-            if j < sub_len {
-                assert!(i == 0);
-                break;
-            }
-            j -= sub_len;
-            if i < remainer && j > 0 {
-                j -= 1;
-            }
+        let len = self.keys.len();
+        let spare = (len - self.count) - (len - self.count) / 2;
+        let left_spare = scale(spare, hot, self.count);
+        let right_slots = self.count - hot + spare - left_spare;
+        // The entries all move right, so the right side goes first.
+        self.spread(hot, self.count, len - right_slots, len);
+        self.spread(0, hot, 0, hot + left_spare);
+    }
+
+    // Spreads the entries of ranks [from, to), packed at the slots of the same numbers, evenly
+    // over the slots [start, end), which lie at or right of them.
+    fn spread(&mut self, from: usize, to: usize, start: usize, end: usize) {
+        let (count, len) = (to - from, end - start);
+        for i in (0..count).rev() {
+            self.move_key_value(from + i, start + scale(i + 1, len, count) - 1);
         }
     }
 
     fn set_key_value(&mut self, index: usize, key_value: (K, V)) {
-        unsafe {
-            assert!((*self.data[index]).is_none());
-            *self.data[index] = Some(key_value);
+        assert!(!bitmap::get(self.occupied, self.offset + index));
+        self.keys[index] = MaybeUninit::new(key_value.0);
+        self.values[index] = MaybeUninit::new(key_value.1);
+        bitmap::set(self.occupied, self.offset + index);
+        if let Some(meta) = &mut self.meta {
+            meta[index] = 0;
         }
         self.count += 1;
     }
@@ -101,75 +185,201 @@ where
     // Try inserting a value on index.
     // If values are sorted, the position should be the index that is
     // larger than the inserted value.
-    // Note: it's possible to have position == data.len() to insert
+    // Note: it's possible to have position == keys.len() to insert
     // a value after the right-most one, in this case, exisiting values
-    // may only be moved left.
-    pub(crate) fn insert_key_value(&mut self, position: usize, key_value: (K, V)) {
+    // may only be moved left. Fails when the segment is full.
+    pub(crate) fn insert_key_value(
+        &mut self,
+        position: usize,
+        key_value: (K, V),
+    ) -> Result<(), CoBTreeError> {
         // Insert on index, try moving right first (possible no moving).
-        for i in position..self.data.len() {
-            unsafe {
-                if (*self.data[i]).is_none() {
-                    for j in (position..i).rev() {
-                        self.move_key_value(j, j + 1);
-                    }
-                    self.set_key_value(position, key_value);
-                    return;
-                }
+        if let Some(i) = self.next_slot(position, self.keys.len(), false) {
+            for j in (position..i).rev() {
+                self.move_key_value(j, j + 1);
             }
+            self.set_key_value(position, key_value);
+            return Ok(());
         }
         // Try inserting on position - 1, move other values to left.
-        for i in (0..position).rev() {
-            unsafe {
-                if (*self.data[i]).is_none() {
-                    for j in i + 1..position {
-                        self.move_key_value(j, j - 1);
-                    }
-                    self.set_key_value(position - 1, key_value);
-                    return;
-                }
+        if let Some(i) = self.prev_slot(0, position, false) {
+            for j in i + 1..position {
+                self.move_key_value(j, j - 1);
             }
+            self.set_key_value(position - 1, key_value);
+            return Ok(());
         }
-        panic!("No space to insert");
+        Err(CoBTreeError::NoSpace)
     }
 
     #[inline]
     pub(crate) fn remove_key_value(&mut self, index: usize) -> Option<V> {
-        unsafe {
-            if (*self.data[index]).is_some() {
-                self.count -= 1;
-                (*self.data[index]).take().map(|kv| kv.1)
-            } else {
-                None
-            }
+        if !bitmap::get(self.occupied, self.offset + index) {
+            return None;
         }
+        bitmap::unset(self.occupied, self.offset + index);
+        self.count -= 1;
+        // SAFETY: the slot was occupied, and its bit is cleared before it is read out.
+        let (key, value) = unsafe {
+            (
+                self.keys[index].assume_init_read(),
+                self.values[index].assume_init_read(),
+            )
+        };
+        drop(key);
+        Some(value)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod segment {
-    use super::Segment;
+    use super::{scale, Segment};
+    use crate::bitmap;
+    use std::mem::MaybeUninit;
+
+    fn empty(len: usize) -> (Vec<MaybeUninit<usize>>, Vec<MaybeUninit<usize>>) {
+        (
+            vec![MaybeUninit::uninit(); len],
+            vec![MaybeUninit::uninit(); len],
+        )
+    }
+
+    fn read(
+        keys: &[MaybeUninit<usize>],
+        values: &[MaybeUninit<usize>],
+        occupied: &[u64],
+        offset: usize,
+    ) -> Vec<Option<(usize, usize)>> {
+        (0..keys.len())
+            .map(|i| {
+                bitmap::get(occupied, offset + i)
+                    .then(|| unsafe { (keys[i].assume_init_read(), values[i].assume_init_read()) })
+            })
+            .collect()
+    }
+
+    fn slots(s: &Segment<usize, usize>) -> Vec<Option<(usize, usize)>> {
+        read(s.keys, s.values, s.occupied, s.offset)
+    }
+
+    #[test]
+    fn test_meta() {
+        let (mut keys, mut values) = empty(6);
+        let mut meta = vec![0u64; 6];
+        let mut occupied = vec![0u64];
+        let mut s =
+            Segment::new(&mut keys, &mut values, &mut occupied, 0, None).with_meta(Some(&mut meta));
+        s.insert_key_value(0, (1, 1)).unwrap();
+        s.insert_key_value(1, (2, 2)).unwrap();
+        s.insert_key_value(2, (3, 3)).unwrap();
+        for (i, m) in [10, 20, 30].into_iter().enumerate() {
+            s.meta.as_mut().unwrap()[i] = m;
+        }
+        s.insert_key_value(0, (0, 0)).unwrap();
+        assert_eq!(s.meta.as_ref().unwrap()[..4], [0, 10, 20, 30]);
+        s.shuffle_key_values(true);
+        assert_eq!(
+            slots(&s),
+            [
+                None,
+                Some((0, 0)),
+                Some((1, 1)),
+                None,
+                Some((2, 2)),
+                Some((3, 3))
+            ]
+        );
+        assert_eq!(meta[1..3], [0, 10]);
+        assert_eq!(meta[4..], [20, 30]);
+        Segment::new(&mut keys, &mut values, &mut occupied, 0, Some(4))
+            .with_meta(Some(&mut meta))
+            .move_all_key_values_to_front();
+        assert_eq!(meta[..4], [0, 10, 20, 30]);
+    }
+
+    #[test]
+    fn test_shuffle_around() {
+        let (mut keys, mut values) = empty(10);
+        let mut occupied = vec![0u64];
+        let mut s = Segment::new(&mut keys, &mut values, &mut occupied, 0, None);
+        for i in 0..4 {
+            s.insert_key_value(i, (i, i)).unwrap();
+        }
+        s.shuffle_key_values_around(4, true);
+        assert_eq!(occupied, [0b1010101]);
+        let mut s = Segment::new(&mut keys, &mut values, &mut occupied, 0, None);
+        s.shuffle_key_values_around(2, true);
+        assert_eq!(occupied, [0b1010000101]);
+        assert!(read(&keys, &values, &occupied, 0)
+            .into_iter()
+            .flatten()
+            .eq((0..4).map(|i| (i, i))));
+    }
+
+    #[test]
+    fn test_scale() {
+        assert_eq!(scale(usize::MAX, usize::MAX, usize::MAX), usize::MAX);
+        assert_eq!(scale(usize::MAX / 2, 4, 8), usize::MAX / 4);
+        assert_eq!(scale(3, 10, 4), 7);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_shuffle_large() {
+        // Past 65,536 slots, where (count - 1 - i) * len overflows a 32-bit usize.
+        let len = 1 << 17;
+        let (mut keys, mut values) = empty(len);
+        let mut occupied = vec![0u64; len / 64];
+        for i in 0..len / 2 {
+            keys[i] = MaybeUninit::new(i);
+            values[i] = MaybeUninit::new(i);
+            bitmap::set(&mut occupied, i);
+        }
+        Segment::new(&mut keys, &mut values, &mut occupied, 0, None).shuffle_key_values(false);
+        assert!(occupied.iter().all(|&word| word == 0xaaaa_aaaa_aaaa_aaaa));
+        assert!(read(&keys, &values, &occupied, 0)
+            .into_iter()
+            .flatten()
+            .eq((0..len / 2).map(|i| (i, i))));
+    }
+
+    #[test]
+    fn test_full() {
+        let (mut keys, mut values) = empty(4);
+        let mut occupied = vec![0u64];
+        let mut s = Segment::new(&mut keys, &mut values, &mut occupied, 0, None);
+        for i in 0..4 {
+            s.insert_key_value(i, (i, i)).unwrap();
+        }
+        assert_eq!(
+            s.insert_key_value(2, (9, 9)),
+            Err(crate::CoBTreeError::NoSpace)
+        );
+        assert_eq!(s.get_count(), 4);
+    }
 
     #[test]
     fn test_operations() {
-        let mut v: Vec<Option<(usize, usize)>> = vec![None; 5];
-        let data = v
-            .iter_mut()
-            .map(|v| v as *mut Option<(usize, usize)>)
-            .collect::<Vec<*mut Option<(usize, usize)>>>();
-        let mut s = Segment::new(&data, None);
+        let (mut keys, mut values) = empty(5);
+        let mut occupied = vec![0u64];
+        let mut s = Segment::new(&mut keys, &mut values, &mut occupied, 0, None);
         assert_eq!(s.get_count(), 0);
 
-        s.insert_key_value(3, (11, 1111));
-        assert_eq!(v, [None, None, None, Some((11, 1111)), None]);
+        s.insert_key_value(3, (11, 1111)).unwrap();
+        assert_eq!(slots(&s), [None, None, None, Some((11, 1111)), None]);
         assert_eq!(s.get_count(), 1);
 
-        s.insert_key_value(2, (8, 888));
-        assert_eq!(v, [None, None, Some((8, 888)), Some((11, 1111)), None]);
+        s.insert_key_value(2, (8, 888)).unwrap();
+        assert_eq!(
+            slots(&s),
+            [None, None, Some((8, 888)), Some((11, 1111)), None]
+        );
         assert_eq!(s.get_count(), 2);
 
-        s.insert_key_value(3, (10, 1010));
+        s.insert_key_value(3, (10, 1010)).unwrap();
         assert_eq!(
-            v,
+            slots(&s),
             [
                 None,
                 None,
@@ -180,9 +390,9 @@ mod segment {
         );
         assert_eq!(s.get_count(), 3);
 
-        s.insert_key_value(3, (9, 999));
+        s.insert_key_value(3, (9, 999)).unwrap();
         assert_eq!(
-            v,
+            slots(&s),
             [
                 None,
                 Some((8, 888)),
@@ -193,9 +403,9 @@ mod segment {
         );
         assert_eq!(s.get_count(), 4);
 
-        s.insert_key_value(5, (12, 1212));
+        s.insert_key_value(5, (12, 1212)).unwrap();
         assert_eq!(
-            v,
+            slots(&s),
             [
                 Some((8, 888)),
                 Some((9, 999)),
@@ -207,7 +417,7 @@ mod segment {
 
         assert_eq!(s.remove_key_value(0), Some(888));
         assert_eq!(
-            v,
+            slots(&s),
             [
                 None,
                 Some((9, 999)),
@@ -220,7 +430,7 @@ mod segment {
 
         assert_eq!(s.remove_key_value(2), Some(1010));
         assert_eq!(
-            v,
+            slots(&s),
             [
                 None,
                 Some((9, 999)),
@@ -231,9 +441,9 @@ mod segment {
         );
         assert_eq!(s.get_count(), 3);
 
-        s.insert_key_value(5, (15, 1515));
+        s.insert_key_value(5, (15, 1515)).unwrap();
         assert_eq!(
-            v,
+            slots(&s),
             [
                 None,
                 Some((9, 999)),
@@ -246,7 +456,7 @@ mod segment {
 
         assert_eq!(s.remove_key_value(2), Some(1111));
         assert_eq!(
-            v,
+            slots(&s),
             [
                 None,
                 Some((9, 999)),
@@ -260,7 +470,7 @@ mod segment {
         // Remove non existing.
         assert_eq!(s.remove_key_value(2), None);
         assert_eq!(
-            v,
+            slots(&s),
             [
                 None,
                 Some((9, 999)),
@@ -273,7 +483,7 @@ mod segment {
 
         s.shuffle_key_values(true);
         assert_eq!(
-            v,
+            slots(&s),
             [
                 None,
                 Some((9, 999)),
@@ -285,15 +495,52 @@ mod segment {
         assert_eq!(s.get_count(), 3);
 
         assert_eq!(s.remove_key_value(4), Some(1515));
-        assert_eq!(v, [None, Some((9, 999)), None, Some((12, 1212)), None,]);
+        assert_eq!(
+            slots(&s),
+            [None, Some((9, 999)), None, Some((12, 1212)), None,]
+        );
         assert_eq!(s.get_count(), 2);
 
         s.shuffle_key_values(true);
-        assert_eq!(v, [None, None, Some((9, 999)), None, Some((12, 1212)),]);
+        assert_eq!(
+            slots(&s),
+            [None, None, Some((9, 999)), None, Some((12, 1212)),]
+        );
         assert_eq!(s.get_count(), 2);
 
         s.move_all_key_values_to_front();
-        assert_eq!(v, [Some((9, 999)), Some((12, 1212)), None, None, None,]);
+        assert_eq!(
+            slots(&s),
+            [Some((9, 999)), Some((12, 1212)), None, None, None,]
+        );
         assert_eq!(s.get_count(), 2);
+        assert_eq!(occupied, [0b11]);
+    }
+
+    #[test]
+    fn test_offset() {
+        // A window in the middle of a larger array, straddling a bitmap word.
+        let (mut keys, mut values) = empty(200);
+        let mut occupied = vec![0u64; 4];
+        let mut s = Segment::new(
+            &mut keys[60..140],
+            &mut values[60..140],
+            &mut occupied,
+            60,
+            None,
+        );
+        for i in 0..40 {
+            s.insert_key_value(s.get_count(), (i, i)).unwrap();
+        }
+        s.insert_key_value(80, (40, 40)).unwrap();
+        s.shuffle_key_values(true);
+        assert_eq!(s.get_count(), 41);
+        let slots = read(&keys, &values, &occupied, 0);
+        assert!(slots[60..140]
+            .iter()
+            .flatten()
+            .copied()
+            .eq((0..41).map(|i| (i, i))));
+        assert_eq!(slots[..60].iter().chain(&slots[140..]).flatten().count(), 0);
     }
 }
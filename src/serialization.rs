@@ -0,0 +1,83 @@
+use crate::{cache_oblivious::ParallelBounds, BTreeMap};
+use serde::{
+    de::{SeqAccess, Visitor},
+    ser::SerializeSeq,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{fmt, marker::PhantomData};
+
+// The visible entries as a sequence of `(key, value)` pairs in key order.
+impl<K, V> Serialize for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds + Serialize,
+    V: ParallelBounds + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.len()))?;
+        for entry in self.iter() {
+            seq.serialize_element(&entry)?;
+        }
+        seq.end()
+    }
+}
+
+// Reads the pairs back and builds the map in one pass when they come sorted by unique keys,
+// as `Serialize` writes them. Other sequences are sorted first, the last value of a key wins.
+impl<'de, K, V> Deserialize<'de> for BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds + Deserialize<'de>,
+    V: ParallelBounds + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_seq(EntriesVisitor(PhantomData))
+    }
+}
+
+struct EntriesVisitor<K, V>(PhantomData<(K, V)>);
+
+impl<'de, K, V> Visitor<'de> for EntriesVisitor<K, V>
+where
+    K: Ord + ParallelBounds + Deserialize<'de>,
+    V: ParallelBounds + Deserialize<'de>,
+{
+    type Value = BTreeMap<K, V>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a sequence of key value pairs")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        // The length comes from the input, do not trust it with a large allocation.
+        let mut key_values = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(key_value) = seq.next_element::<(K, V)>()? {
+            key_values.push(key_value);
+        }
+        Ok(key_values.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod serialization {
+    use crate::BTreeMap;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_round_trip() {
+        let mut map = BTreeMap::new();
+        for i in (0..500).rev() {
+            map.insert(format!("{:03}", i), i);
+        }
+        map.remove("100");
+        map.mark_removed("200");
+        let json = serde_json::to_string(&map).unwrap();
+        assert!(json.starts_with(r#"[["000",0],["001",1],"#));
+        let back: BTreeMap<String, usize> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, map);
+        assert_eq!(back.len(), 498);
+
+        let unsorted: BTreeMap<usize, usize> = serde_json::from_str("[[3,1],[1,2],[3,3]]").unwrap();
+        assert!(unsorted.iter().eq([(&1, &2), (&3, &3)]));
+        assert!(serde_json::from_str::<BTreeMap<usize, usize>>("[[1]]").is_err());
+    }
+}
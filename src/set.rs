@@ -0,0 +1,265 @@
+use crate::{cache_oblivious::ParallelBounds, comparable::Comparable, BTreeMap, Keys};
+use std::{cmp::Ordering, fmt, iter::Peekable};
+
+// An ordered set on top of the cache oblivious map, with unit values.
+pub struct CacheObliviousSet<K: Ord> {
+    map: BTreeMap<K, ()>,
+}
+
+impl<K> Default for CacheObliviousSet<K>
+where
+    K: Ord + ParallelBounds,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K> fmt::Debug for CacheObliviousSet<K>
+where
+    K: Ord + ParallelBounds + fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl<K> CacheObliviousSet<K>
+where
+    K: Ord + ParallelBounds,
+{
+    pub fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+        }
+    }
+
+    // Returns whether the key was not in the set yet.
+    pub fn insert(&mut self, key: K) -> bool {
+        self.map.try_insert(key, ()).is_ok()
+    }
+
+    // Returns whether the key was in the set.
+    pub fn remove<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> bool {
+        self.map.remove(key).is_some()
+    }
+
+    pub fn contains<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.map.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn first(&self) -> Option<&K> {
+        self.map.first_key_value().map(|(k, _)| k)
+    }
+
+    pub fn last(&self) -> Option<&K> {
+        self.map.last_key_value().map(|(k, _)| k)
+    }
+
+    pub fn iter(&self) -> Keys<'_, K, ()> {
+        self.map.keys()
+    }
+
+    // The keys in either set, in order. Like the other set operations, a merge of the two
+    // sorted key sequences: O(n + m), no lookups.
+    pub fn union<'a>(&'a self, other: &'a Self) -> Union<'a, K> {
+        Union {
+            merge: Merge::new(self, other),
+        }
+    }
+
+    // The keys in both sets, in order.
+    pub fn intersection<'a>(&'a self, other: &'a Self) -> Intersection<'a, K> {
+        Intersection {
+            merge: Merge::new(self, other),
+        }
+    }
+
+    // The keys in `self` but not in `other`, in order.
+    pub fn difference<'a>(&'a self, other: &'a Self) -> Difference<'a, K> {
+        Difference {
+            merge: Merge::new(self, other),
+        }
+    }
+
+    // The keys in exactly one of the sets, in order.
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Self) -> SymmetricDifference<'a, K> {
+        SymmetricDifference {
+            merge: Merge::new(self, other),
+        }
+    }
+}
+
+impl<K> FromIterator<K> for CacheObliviousSet<K>
+where
+    K: Ord + ParallelBounds,
+{
+    fn from_iter<I: IntoIterator<Item = K>>(iter: I) -> Self {
+        Self {
+            map: iter.into_iter().map(|k| (k, ())).collect(),
+        }
+    }
+}
+
+impl<K> Extend<K> for CacheObliviousSet<K>
+where
+    K: Ord + ParallelBounds,
+{
+    fn extend<I: IntoIterator<Item = K>>(&mut self, iter: I) {
+        self.map.extend(iter.into_iter().map(|k| (k, ())));
+    }
+}
+
+impl<'a, K> IntoIterator for &'a CacheObliviousSet<K>
+where
+    K: Ord + ParallelBounds,
+{
+    type Item = &'a K;
+    type IntoIter = Keys<'a, K, ()>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Walks two sets in lockstep, each step reports the smallest key left and which sides hold it.
+struct Merge<'a, K: Ord> {
+    left: Peekable<Keys<'a, K, ()>>,
+    right: Peekable<Keys<'a, K, ()>>,
+}
+
+impl<'a, K> Merge<'a, K>
+where
+    K: Ord + ParallelBounds,
+{
+    fn new(left: &'a CacheObliviousSet<K>, right: &'a CacheObliviousSet<K>) -> Self {
+        Self {
+            left: left.iter().peekable(),
+            right: right.iter().peekable(),
+        }
+    }
+}
+
+impl<'a, K: Ord> Iterator for Merge<'a, K> {
+    // The key, whether the left set holds it, whether the right set holds it.
+    type Item = (&'a K, bool, bool);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let order = match (self.left.peek(), self.right.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(l), Some(r)) => l.cmp(r),
+        };
+        Some(match order {
+            Ordering::Less => (self.left.next()?, true, false),
+            Ordering::Greater => (self.right.next()?, false, true),
+            Ordering::Equal => {
+                self.right.next();
+                (self.left.next()?, true, true)
+            }
+        })
+    }
+}
+
+pub struct Union<'a, K: Ord> {
+    merge: Merge<'a, K>,
+}
+
+impl<'a, K: Ord> Iterator for Union<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.merge.next().map(|(k, _, _)| k)
+    }
+}
+
+pub struct Intersection<'a, K: Ord> {
+    merge: Merge<'a, K>,
+}
+
+impl<'a, K: Ord> Iterator for Intersection<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.merge.find(|&(_, l, r)| l && r).map(|(k, _, _)| k)
+    }
+}
+
+pub struct Difference<'a, K: Ord> {
+    merge: Merge<'a, K>,
+}
+
+impl<'a, K: Ord> Iterator for Difference<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.merge.find(|&(_, l, r)| l && !r).map(|(k, _, _)| k)
+    }
+}
+
+pub struct SymmetricDifference<'a, K: Ord> {
+    merge: Merge<'a, K>,
+}
+
+impl<'a, K: Ord> Iterator for SymmetricDifference<'a, K> {
+    type Item = &'a K;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.merge.find(|&(_, l, r)| l != r).map(|(k, _, _)| k)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod set {
+    use crate::set::CacheObliviousSet;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_operations() {
+        let mut set = CacheObliviousSet::new();
+        assert!(set.is_empty());
+        assert!(set.insert(String::from("b")));
+        assert!(set.insert(String::from("a")));
+        assert!(!set.insert(String::from("a")));
+        assert!(set.contains("a"));
+        assert!(!set.contains("c"));
+        assert_eq!(set.len(), 2);
+        assert_eq!(set.first().map(String::as_str), Some("a"));
+        assert_eq!(set.last().map(String::as_str), Some("b"));
+        assert_eq!(format!("{:?}", set), r#"{"a", "b"}"#);
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec!["b"]);
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a = (0..300)
+            .map(|i| i * 2)
+            .collect::<CacheObliviousSet<usize>>();
+        let mut b = CacheObliviousSet::new();
+        b.extend((0..200).map(|i| i * 3));
+        let std_a = (0..300).map(|i| i * 2).collect::<BTreeSet<usize>>();
+        let std_b = (0..200).map(|i| i * 3).collect::<BTreeSet<usize>>();
+        assert!(a.union(&b).eq(std_a.union(&std_b)));
+        assert!(a.intersection(&b).eq(std_a.intersection(&std_b)));
+        assert!(a.difference(&b).eq(std_a.difference(&std_b)));
+        assert!(b.difference(&a).eq(std_b.difference(&std_a)));
+        assert!(a
+            .symmetric_difference(&b)
+            .eq(std_a.symmetric_difference(&std_b)));
+        let empty = CacheObliviousSet::new();
+        assert!(a.union(&empty).eq(a.iter()));
+        assert_eq!(a.intersection(&empty).next(), None);
+    }
+}
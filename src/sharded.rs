@@ -0,0 +1,193 @@
+use crate::{cache_oblivious::ParallelBounds, BTreeMap, Range};
+use std::{
+    borrow::Borrow,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash},
+    iter::Peekable,
+    ops::RangeBounds,
+    sync::{Mutex, MutexGuard},
+};
+
+// Cache oblivious maps over a key space partitioned by hash, each behind its own lock, so
+// writers to different shards do not contend. Point operations lock a single shard, ordered
+// reads lock every shard and merge their ranges.
+pub struct ShardedBTreeMap<K: Ord, V> {
+    shards: Vec<Mutex<BTreeMap<K, V>>>,
+    hasher: RandomState,
+}
+
+impl<K, V> ShardedBTreeMap<K, V>
+where
+    K: Ord + Hash + ParallelBounds,
+    V: ParallelBounds,
+{
+    pub fn new(shard_count: usize) -> Self {
+        assert!(shard_count > 0, "A sharded map needs at least one shard.");
+        Self {
+            shards: (0..shard_count)
+                .map(|_| Mutex::new(BTreeMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard<Q: Hash + ?Sized>(&self, key: &Q) -> MutexGuard<'_, BTreeMap<K, V>> {
+        let index = self.hasher.hash_one(key) as usize % self.shards.len();
+        lock(&self.shards[index])
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).insert(key, value)
+    }
+
+    pub fn remove<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + ?Sized,
+    {
+        self.shard(key).remove(key)
+    }
+
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + ?Sized,
+        V: Clone,
+    {
+        self.shard(key).get(key).cloned()
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + ?Sized,
+    {
+        self.shard(key).contains_key(key)
+    }
+
+    // Sums the shard sizes one lock at a time, so it is only exact without concurrent writers.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| lock(shard).len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| lock(shard).is_empty())
+    }
+
+    // Runs `f` over the entries in the range across all shards, in key order. Every shard stays
+    // locked while `f` runs, so it sees one consistent state of the whole map.
+    pub fn range_with<R, T, F>(&self, range: R, f: F) -> T
+    where
+        R: RangeBounds<K>,
+        F: FnOnce(ShardedRange<'_, K, V>) -> T,
+    {
+        // Locking in shard order keeps concurrent ordered reads from deadlocking.
+        let guards = self.shards.iter().map(lock).collect::<Vec<_>>();
+        let ranges = guards
+            .iter()
+            .map(|shard| {
+                shard
+                    .range((range.start_bound(), range.end_bound()))
+                    .peekable()
+            })
+            .collect();
+        f(ShardedRange { ranges })
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// The entries of a range across the shards of a `ShardedBTreeMap`, merged in key order. A key
+// lives in one shard only, so the merge never sees duplicates.
+pub struct ShardedRange<'a, K: Ord, V> {
+    ranges: Vec<Peekable<Range<'a, K, V>>>,
+}
+
+impl<'a, K: Ord, V> Iterator for ShardedRange<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Shard counts are small, a linear scan for the smallest head beats a heap.
+        let mut smallest: Option<(usize, &'a K)> = None;
+        for (i, range) in self.ranges.iter_mut().enumerate() {
+            if let Some(&(key, _)) = range.peek() {
+                if smallest.is_none_or(|(_, k)| key < k) {
+                    smallest = Some((i, key));
+                }
+            }
+        }
+        self.ranges[smallest?.0].next()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod sharded {
+    use crate::ShardedBTreeMap;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap as StdBTreeMap;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_operations() {
+        let map = ShardedBTreeMap::new(8);
+        let mut expected = StdBTreeMap::new();
+        let mut rng = StdRng::seed_from_u64(11);
+        for _ in 0..2000 {
+            let key = rng.gen_range(0..500u32);
+            if rng.gen_range(0..3) == 0 {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key, key * 3), expected.insert(key, key * 3));
+            }
+        }
+        assert_eq!(map.len(), expected.len());
+        for key in 0..500 {
+            assert_eq!(map.get(&key), expected.get(&key).copied());
+        }
+        map.range_with(.., |range| {
+            assert!(range.map(|(k, v)| (*k, *v)).eq(expected.clone()));
+        });
+        let count = map.range_with(100..=200, |range| range.count());
+        assert_eq!(count, expected.range(100..=200).count());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_concurrent_writers() {
+        let map = ShardedBTreeMap::new(4);
+        std::thread::scope(|scope| {
+            for t in 0..4u32 {
+                let map = &map;
+                scope.spawn(move || {
+                    for i in 0..500 {
+                        map.insert(i * 4 + t, t);
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len(), 2000);
+        map.range_with(.., |range| {
+            assert!(range
+                .map(|(k, v)| (*k, *v))
+                .eq((0..2000).map(|i| (i, i % 4))));
+        });
+    }
+
+    #[test]
+    fn test_string_keys() {
+        let map = ShardedBTreeMap::new(3);
+        map.insert(String::from("b"), 2);
+        map.insert(String::from("a"), 1);
+        assert_eq!(map.get("a"), Some(1));
+        assert!(map.contains_key("b"));
+        assert_eq!(map.remove("b"), Some(2));
+        assert!(!map.is_empty());
+    }
+}
@@ -0,0 +1,142 @@
+#[cfg(all(unix, feature = "mmap"))]
+use crate::mmap::MappedSlots;
+#[cfg(feature = "arena")]
+use crate::Arena;
+use std::{
+    collections::TryReserveError,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+// The slot storage of a PMA and of the index: a heap vector, with the `mmap` feature a
+// memory-mapped file that leaves the block transfers of datasets larger than RAM to the OS
+// paging, or with the `arena` feature a vector in a bump arena.
+pub(crate) enum Slots<T> {
+    Heap(Vec<T>),
+    #[cfg(feature = "arena")]
+    Arena(allocator_api2::vec::Vec<T, Arena>),
+    #[cfg(all(unix, feature = "mmap"))]
+    Mapped(MappedSlots<T>),
+}
+
+impl<T> Slots<T> {
+    pub(crate) fn clear(&mut self) {
+        match self {
+            Slots::Heap(v) => v.clear(),
+            #[cfg(feature = "arena")]
+            Slots::Arena(v) => v.clear(),
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m.truncate(0),
+        }
+    }
+
+    pub(crate) fn push(&mut self, item: T) {
+        match self {
+            Slots::Heap(v) => v.push(item),
+            #[cfg(feature = "arena")]
+            Slots::Arena(v) => v.push(item),
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m.push(item),
+        }
+    }
+
+    pub(crate) fn resize_with<F: FnMut() -> T>(&mut self, len: usize, f: F) {
+        match self {
+            Slots::Heap(v) => v.resize_with(len, f),
+            #[cfg(feature = "arena")]
+            Slots::Arena(v) => v.resize_with(len, f),
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m.resize_with(len, f),
+        }
+    }
+
+    pub(crate) fn try_reserve_exact(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        match self {
+            Slots::Heap(v) => v.try_reserve_exact(additional),
+            // An exhausted arena reports like an allocation beyond the address space.
+            #[cfg(feature = "arena")]
+            Slots::Arena(v) => v
+                .try_reserve_exact(additional)
+                .map_err(|_| Vec::<T>::new().try_reserve(usize::MAX).unwrap_err()),
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m.try_reserve_exact(additional),
+        }
+    }
+
+    pub(crate) fn shrink_to_fit(&mut self) {
+        match self {
+            Slots::Heap(v) => v.shrink_to_fit(),
+            // The arena takes nothing back, a smaller copy would only add to it.
+            #[cfg(feature = "arena")]
+            Slots::Arena(_) => {}
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m.shrink_to_fit(),
+        }
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        match self {
+            Slots::Heap(v) => v.capacity(),
+            #[cfg(feature = "arena")]
+            Slots::Arena(v) => v.capacity(),
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m.capacity(),
+        }
+    }
+
+    // Moves the items into a vector allocated in the arena.
+    #[cfg(feature = "arena")]
+    pub(crate) fn into_arena(self, arena: &Arena) -> Slots<T> {
+        let mut items = allocator_api2::vec::Vec::with_capacity_in(self.len(), arena.clone());
+        items.extend(self.into_vec());
+        Slots::Arena(items)
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<T> {
+        match self {
+            Slots::Heap(v) => v,
+            #[cfg(feature = "arena")]
+            Slots::Arena(v) => v.into_iter().collect(),
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m.into_vec(),
+        }
+    }
+}
+
+impl<T> Deref for Slots<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            Slots::Heap(v) => v,
+            #[cfg(feature = "arena")]
+            Slots::Arena(v) => v,
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m,
+        }
+    }
+}
+
+impl<T> DerefMut for Slots<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self {
+            Slots::Heap(v) => v,
+            #[cfg(feature = "arena")]
+            Slots::Arena(v) => v,
+            #[cfg(all(unix, feature = "mmap"))]
+            Slots::Mapped(m) => m,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Slots<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: PartialEq, const N: usize> PartialEq<[T; N]> for Slots<T> {
+    fn eq(&self, other: &[T; N]) -> bool {
+        **self == other[..]
+    }
+}
@@ -0,0 +1,339 @@
+use crate::{cache_oblivious::ParallelBounds, packed_memory_array::PackedMemoryArray, BTreeMap};
+use std::io::{self, Read, Write};
+
+// The byte encoding of keys and values in snapshots: little endian integers, and strings and
+// byte vectors prefixed with their length.
+pub trait Persist: Sized {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()>;
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self>;
+}
+
+macro_rules! persist_int {
+    ($($t:ty),*) => {$(
+        impl Persist for $t {
+            fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+
+            fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+                let mut bytes = [0; std::mem::size_of::<$t>()];
+                reader.read_exact(&mut bytes)?;
+                Ok(<$t>::from_le_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+persist_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+// Pointer sized integers are stored as 64 bits, so snapshots move between platforms.
+impl Persist for usize {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u64).write_to(writer)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        usize::try_from(u64::read_from(reader)?).map_err(|_| invalid("Integer out of range."))
+    }
+}
+
+impl Persist for isize {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as i64).write_to(writer)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        isize::try_from(i64::read_from(reader)?).map_err(|_| invalid("Integer out of range."))
+    }
+}
+
+impl Persist for bool {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        (*self as u8).write_to(writer)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        match u8::read_from(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid("Invalid boolean.")),
+        }
+    }
+}
+
+impl Persist for Vec<u8> {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().write_to(writer)?;
+        writer.write_all(self)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len = u64::read_from(reader)?;
+        // Read through `take` so a corrupt length cannot reserve a huge buffer up front.
+        let mut bytes = vec![];
+        reader.take(len).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+}
+
+impl Persist for String {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.len().write_to(writer)?;
+        writer.write_all(self.as_bytes())
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        String::from_utf8(Vec::read_from(reader)?).map_err(|_| invalid("Invalid UTF-8."))
+    }
+}
+
+impl<A: Persist, B: Persist> Persist for (A, B) {
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.0.write_to(writer)?;
+        self.1.write_to(writer)
+    }
+
+    fn read_from<R: Read>(reader: &mut R) -> io::Result<Self> {
+        Ok((A::read_from(reader)?, B::read_from(reader)?))
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+const MAGIC: &[u8; 4] = b"COBT";
+const FORMAT_VERSION: u32 = 1;
+
+const EMPTY_SLOT: u8 = 0;
+const ENTRY_SLOT: u8 = 1;
+
+impl<K, V> BTreeMap<K, V>
+where
+    K: Ord + ParallelBounds + Persist,
+    V: ParallelBounds + Persist,
+{
+    // Writes the map in a stable format: a versioned header with the PMA height, segment size,
+    // slot count and entry count, then every slot in order, so `read_snapshot` can restore the
+    // exact layout without a rebalance. Hidden entries are written as gaps. Pass a buffered
+    // writer, slots are written field by field.
+    pub fn write_snapshot<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let slot_count = self.pma().data_len();
+        let meta = self.pma().meta_enabled();
+        writer.write_all(MAGIC)?;
+        FORMAT_VERSION.write_to(&mut writer)?;
+        self.pma().height().write_to(&mut writer)?;
+        self.pma().segment_size().write_to(&mut writer)?;
+        slot_count.write_to(&mut writer)?;
+        self.len().write_to(&mut writer)?;
+        meta.write_to(&mut writer)?;
+        for index in 0..slot_count {
+            match self.pma().key_value(index) {
+                Some((k, v)) if !self.is_marked(k) => {
+                    ENTRY_SLOT.write_to(&mut writer)?;
+                    k.write_to(&mut writer)?;
+                    v.write_to(&mut writer)?;
+                }
+                _ => EMPTY_SLOT.write_to(&mut writer)?,
+            }
+            if meta {
+                self.pma().get_meta(index).write_to(&mut writer)?;
+            }
+        }
+        writer.flush()
+    }
+
+    // Restores a map written by `write_snapshot`. A snapshot of another format version, an
+    // impossible layout or keys out of order fail with `InvalidData`. The saved layout is
+    // taken over when every window is within the density bounds, otherwise the entries are
+    // laid out again, so whatever the input, later updates find room.
+    pub fn read_snapshot<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("Not a map snapshot."));
+        }
+        if u32::read_from(&mut reader)? != FORMAT_VERSION {
+            return Err(invalid("Unsupported snapshot version."));
+        }
+        let height = usize::read_from(&mut reader)?;
+        let segment_size = usize::read_from(&mut reader)?;
+        let slot_count = usize::read_from(&mut reader)?;
+        let len = usize::read_from(&mut reader)?;
+        let meta_enabled = bool::read_from(&mut reader)?;
+        if !segment_size.is_power_of_two()
+            || height == 0
+            || height > usize::BITS as usize
+            || (segment_size as u128) << (height - 1) != slot_count as u128
+            // Resizes keep the height one or two above the segment size log.
+            || !(1..=2).contains(&(height as u32).wrapping_sub(segment_size.trailing_zeros()))
+        {
+            return Err(invalid("Invalid layout."));
+        }
+        // The counts come from the input, do not trust them with a large allocation.
+        let mut slots = Vec::with_capacity(slot_count.min(1 << 16));
+        let mut meta = vec![];
+        let mut entries = 0;
+        let mut last_entry = None;
+        for _ in 0..slot_count {
+            let slot = match u8::read_from(&mut reader)? {
+                EMPTY_SLOT => None,
+                ENTRY_SLOT => {
+                    let (k, v) = (K::read_from(&mut reader)?, V::read_from(&mut reader)?);
+                    if let Some(Some((last, _))) = last_entry.map(|i: usize| &slots[i]) {
+                        if *last >= k {
+                            return Err(invalid("Keys out of order."));
+                        }
+                    }
+                    last_entry = Some(slots.len());
+                    entries += 1;
+                    Some((k, v))
+                }
+                _ => return Err(invalid("Invalid slot.")),
+            };
+            slots.push(slot);
+            if meta_enabled {
+                meta.push(u64::read_from(&mut reader)?);
+            }
+        }
+        if entries != len {
+            return Err(invalid("Entry count mismatch."));
+        }
+        let segment_size_log2 = segment_size.trailing_zeros() as usize;
+        let mut pma = PackedMemoryArray::from_layout(slots, meta, height, segment_size_log2);
+        if !pma.densities_ok() {
+            let meta = match meta_enabled {
+                true => (0..slot_count)
+                    .filter(|&i| pma.is_occupied(i))
+                    .map(|i| pma.get_meta(i))
+                    .collect(),
+                false => vec![],
+            };
+            pma = PackedMemoryArray::from_sorted_with_meta(pma.into_key_values().collect(), meta);
+        }
+        Ok(Self::from_layout(pma, len))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod snapshot {
+    use crate::BTreeMap;
+    use std::io::ErrorKind;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_round_trip() {
+        let mut map = BTreeMap::new();
+        for i in (0..2000u32).rev() {
+            map.insert(format!("{:04}", i), (i, i % 2 == 0));
+        }
+        for i in 0..500 {
+            map.remove(&format!("{:04}", i * 3));
+        }
+        map.mark_removed("1000");
+        map.set_meta("1001", 7);
+        let mut bytes = vec![];
+        map.write_snapshot(&mut bytes).unwrap();
+        let restored = BTreeMap::<String, (u32, bool)>::read_snapshot(&bytes[..]).unwrap();
+        assert_eq!(restored, map);
+        assert_eq!(restored.len(), 1499);
+        assert_eq!(restored.get_meta("1001"), Some(7));
+        // The hidden entry is left out, its slot becomes a gap.
+        let (stats, restored_stats) = (map.range_stats(..), restored.range_stats(..));
+        assert_eq!(restored_stats.slots, stats.slots);
+        assert_eq!(restored_stats.occupied, stats.occupied - 1);
+
+        let mut restored = restored;
+        restored.insert(String::from("0000"), (1, true));
+        assert_eq!(restored.get("0000"), Some(&(1, true)));
+        assert_eq!(restored.len(), 1500);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut map = BTreeMap::new();
+        map.insert(1u64, 2u64);
+        map.insert(3, 4);
+        let mut bytes = vec![];
+        map.write_snapshot(&mut bytes).unwrap();
+        let read = |bytes: &[u8]| BTreeMap::<u64, u64>::read_snapshot(bytes).map(|_| ());
+        assert_eq!(
+            read(&bytes[..bytes.len() - 1]).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert_eq!(read(&bad).unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut bad = bytes.clone();
+        bad[4] = 2;
+        assert_eq!(read(&bad).unwrap_err().kind(), ErrorKind::InvalidData);
+        // Swaps the two keys, found by walking the slots after the 41 byte header.
+        let mut keys = vec![];
+        let mut offset = 41;
+        while offset < bytes.len() {
+            if bytes[offset] == 1 {
+                keys.push(offset + 1);
+                offset += 17;
+            } else {
+                offset += 1;
+            }
+        }
+        let mut bad = bytes.clone();
+        bad[keys[0]] = 3;
+        bad[keys[1]] = 1;
+        assert_eq!(read(&bad).unwrap_err().kind(), ErrorKind::InvalidData);
+        // A height the segment size cannot have, the 4 slots as 1 slot segments under 3 levels.
+        let mut bad = bytes.clone();
+        bad[8..16].copy_from_slice(&3u64.to_le_bytes());
+        bad[16..24].copy_from_slice(&1u64.to_le_bytes());
+        assert_eq!(read(&bad).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    // A header and the slots of a snapshot of `u64` keys and values, `None` for a gap.
+    fn snapshot_bytes(height: u64, segment_size: u64, slots: &[Option<u64>]) -> Vec<u8> {
+        let mut bytes = b"COBT".to_vec();
+        bytes.extend(1u32.to_le_bytes());
+        for field in [height, segment_size, slots.len() as u64] {
+            bytes.extend(field.to_le_bytes());
+        }
+        bytes.extend((slots.iter().flatten().count() as u64).to_le_bytes());
+        bytes.push(0);
+        for slot in slots {
+            match slot {
+                Some(k) => {
+                    bytes.push(1);
+                    bytes.extend(k.to_le_bytes());
+                    bytes.extend(k.to_le_bytes());
+                }
+                None => bytes.push(0),
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_over_dense() {
+        // A single full slot leaves an insert no room anywhere.
+        let full = snapshot_bytes(1, 1, &[Some(3)]);
+        let mut map = BTreeMap::<u64, u64>::read_snapshot(&full[..]).unwrap();
+        assert!(map.pma().densities_ok());
+        map.insert(7, 7);
+        map.check_invariants();
+        assert!(map.iter().eq([(&3, &3), (&7, &7)]));
+
+        // Three full segments put the first half over its bound, it is laid out again too.
+        let slots = (0..16).map(|k| (k < 12).then_some(k)).collect::<Vec<_>>();
+        let dense = snapshot_bytes(3, 4, &slots);
+        let mut map = BTreeMap::<u64, u64>::read_snapshot(&dense[..]).unwrap();
+        assert!(map.pma().densities_ok());
+        for k in 100..200 {
+            map.insert(k, k);
+        }
+        map.check_invariants();
+        assert_eq!(map.len(), 112);
+    }
+}
@@ -0,0 +1,83 @@
+// Funnelsort, the cache oblivious merge sort of Frigo, Leiserson, Prokop and Ramachandran, with
+// the lazy funnels of Brodal and Fagerberg: n elements are cut into n^(1/3) runs of n^(2/3),
+// each sorted the same way, and the runs are merged through a k-merger that fills its buffers
+// on demand. It sorts in O(n/B log_{M/B} n/B) block transfers for any block and cache size,
+// like the map it feeds in bulk loads. The sorts are stable and take the vector by mutable
+// reference because the runs are moved through the merger rather than swapped in place.
+
+use crate::merger::Merger;
+use std::{cmp::Ordering, collections::VecDeque};
+
+// Runs this short are sorted with `slice::sort_by`, they fit in any cache.
+const BASE_LEN: usize = 1 << 8;
+
+pub fn funnel_sort<T: Ord>(items: &mut Vec<T>) {
+    funnel_sort_by(items, T::cmp);
+}
+
+pub fn funnel_sort_by<T, F: Fn(&T, &T) -> Ordering>(items: &mut Vec<T>, compare: F) {
+    *items = sort(std::mem::take(items), &compare);
+}
+
+pub fn funnel_sort_by_key<T, K: Ord, F: Fn(&T) -> K>(items: &mut Vec<T>, key: F) {
+    funnel_sort_by(items, |a, b| key(a).cmp(&key(b)));
+}
+
+fn sort<T>(mut items: Vec<T>, compare: &impl Fn(&T, &T) -> Ordering) -> Vec<T> {
+    let n = items.len();
+    if n <= BASE_LEN {
+        items.sort_by(compare);
+        return items;
+    }
+    // The number of runs, rounded up to a power of two for the merger.
+    let k = (1..).map(|b| 1usize << b).find(|k| k.pow(3) >= n).unwrap();
+    let size = n.div_ceil(k);
+    let mut runs = Vec::with_capacity(k);
+    for i in (0..k).rev() {
+        let run = items.split_off((i * size).min(items.len()));
+        runs.push(VecDeque::from(sort(run, compare)));
+    }
+    runs.reverse();
+    let le = |a: &T, b: &T| compare(a, b) != Ordering::Greater;
+    let mut merger = Merger::with_inputs(runs);
+    while let Some(item) = merger.pop(&le) {
+        items.push(item);
+    }
+    items
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod sorting {
+    use super::{funnel_sort, funnel_sort_by_key};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_sort() {
+        let mut rng = StdRng::seed_from_u64(5);
+        for n in [0, 1, 2, 255, 256, 257, 1000, 4097, 100000] {
+            let mut items = (0..n)
+                .map(|_| rng.gen_range(0..n / 3 + 1))
+                .collect::<Vec<_>>();
+            let mut expected = items.clone();
+            expected.sort();
+            funnel_sort(&mut items);
+            assert_eq!(items, expected);
+        }
+        let mut descending = (0..30000).rev().collect::<Vec<_>>();
+        funnel_sort(&mut descending);
+        assert!(descending.into_iter().eq(0..30000));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_stable() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let mut items = (0..50000)
+            .map(|i| (rng.gen_range(0..100), i))
+            .collect::<Vec<_>>();
+        funnel_sort_by_key(&mut items, |&(key, _)| key);
+        assert!(items.windows(2).all(|w| w[0] < w[1]));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{comparable::Comparable, layout::veb_index};
+use std::{cmp::Ordering, fmt, mem::MaybeUninit};
+
+// A read-only map built once from sorted key values, for repeated lookups over a frozen
+// dataset. The keys form a complete binary search tree laid out in vEB order, with no gaps
+// between entries and no index nodes on top, and the values sit apart in key order so a
+// descent only reads keys.
+pub struct CoStaticMap<K, V> {
+    // The 2^height - 1 nodes of the tree, the node of in-order rank r holding the key of rank
+    // r. Nodes of rank `len` and above only pad the tree to a complete one and hold nothing.
+    keys: Vec<MaybeUninit<K>>,
+    values: Vec<V>,
+    height: usize,
+}
+
+// The in-order rank of the node with BFS index `i`, from 0, in a tree of `height` levels.
+#[inline]
+fn rank_of(i: usize, height: usize) -> usize {
+    let depth = (usize::BITS - 1 - (i + 1).leading_zeros()) as usize;
+    ((((i + 1 - (1 << depth)) << 1) | 1) << (height - 1 - depth)) - 1
+}
+
+// The inverse of `rank_of`.
+#[inline]
+fn node_of(rank: usize, height: usize) -> usize {
+    let zeros = (rank + 1).trailing_zeros() as usize;
+    (1 << (height - 1 - zeros)) + ((rank + 1) >> (zeros + 1)) - 1
+}
+
+impl<K: Ord, V> CoStaticMap<K, V> {
+    // `key_values` must be sorted by unique keys.
+    pub fn from_sorted(key_values: Vec<(K, V)>) -> Self {
+        assert!(
+            key_values.windows(2).all(|w| w[0].0 < w[1].0),
+            "Key values are not sorted by unique keys."
+        );
+        let height = (usize::BITS - key_values.len().leading_zeros()) as usize;
+        let mut map = Self {
+            keys: Vec::with_capacity((1 << height) - 1),
+            values: Vec::with_capacity(key_values.len()),
+            height,
+        };
+        map.keys.resize_with((1 << height) - 1, MaybeUninit::uninit);
+        for (rank, (key, value)) in key_values.into_iter().enumerate() {
+            map.keys[veb_index(node_of(rank, height), height)] = MaybeUninit::new(key);
+            map.values.push(value);
+        }
+        map
+    }
+
+    pub fn from_sorted_slice(key_values: &[(K, V)]) -> Self
+    where
+        K: Clone,
+        V: Clone,
+    {
+        Self::from_sorted(key_values.to_vec())
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    // The key of rank `rank`, which must be below `len`.
+    #[inline]
+    fn key(&self, rank: usize) -> &K {
+        debug_assert!(rank < self.len());
+        // SAFETY: the nodes of the ranks below `len` are initialized.
+        unsafe { self.keys[veb_index(node_of(rank, self.height), self.height)].assume_init_ref() }
+    }
+
+    // The rank of the key, descending from the root. Padding nodes compare greater than every
+    // key.
+    fn find<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<usize> {
+        let mut node = 0;
+        for _ in 0..self.height {
+            let rank = rank_of(node, self.height);
+            let ordering = if rank < self.len() {
+                // SAFETY: the node is below `len`.
+                key.compare(unsafe { self.keys[veb_index(node, self.height)].assume_init_ref() })
+            } else {
+                Ordering::Less
+            };
+            node = match ordering {
+                Ordering::Equal => return Some(rank),
+                Ordering::Less => (node << 1) + 1,
+                Ordering::Greater => (node << 1) + 2,
+            };
+        }
+        None
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&V> {
+        self.find(key).map(|rank| &self.values[rank])
+    }
+
+    pub fn get_key_value<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<(&K, &V)> {
+        self.find(key)
+            .map(|rank| (self.key(rank), &self.values[rank]))
+    }
+
+    pub fn contains_key<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.find(key).is_some()
+    }
+
+    // The key values in key order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&K, &V)> + ExactSizeIterator + '_ {
+        (0..self.len()).map(|rank| (self.key(rank), &self.values[rank]))
+    }
+}
+
+impl<K, V> Drop for CoStaticMap<K, V> {
+    fn drop(&mut self) {
+        for rank in 0..self.values.len() {
+            // SAFETY: the nodes of the ranks below `len` are initialized, and dropped once.
+            unsafe {
+                self.keys[veb_index(node_of(rank, self.height), self.height)].assume_init_drop()
+            };
+        }
+    }
+}
+
+// Sorts the key values, a later value of a key replacing the earlier ones as inserts would.
+impl<K: Ord, V> FromIterator<(K, V)> for CoStaticMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut key_values = iter.into_iter().collect::<Vec<_>>();
+        key_values.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut unique: Vec<(K, V)> = Vec::with_capacity(key_values.len());
+        for kv in key_values {
+            match unique.last_mut() {
+                Some(last) if last.0 == kv.0 => *last = kv,
+                _ => unique.push(kv),
+            }
+        }
+        Self::from_sorted(unique)
+    }
+}
+
+impl<K: Ord + fmt::Debug, V: fmt::Debug> fmt::Debug for CoStaticMap<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod static_map {
+    use super::{node_of, rank_of, CoStaticMap};
+    use std::rc::Rc;
+
+    #[test]
+    fn test_ranks() {
+        for height in 1..10 {
+            for rank in 0..(1 << height) - 1 {
+                assert_eq!(rank_of(node_of(rank, height), height), rank);
+            }
+        }
+    }
+
+    #[test]
+    fn test_lookups() {
+        for len in [0, 1, 2, 7, 8, 100, 1000] {
+            let map = CoStaticMap::from_sorted((0..len).map(|i| (i * 2, i)).collect());
+            assert_eq!(map.len(), len);
+            for i in 0..len {
+                assert_eq!(map.get(&(i * 2)), Some(&i));
+                assert_eq!(map.get(&(i * 2 + 1)), None);
+            }
+            assert!(!map.contains_key(&usize::MAX));
+            assert!(map
+                .iter()
+                .map(|(k, v)| (*k, *v))
+                .eq((0..len).map(|i| (i * 2, i))));
+        }
+        let map = [(3, "c"), (1, "a"), (3, "d")]
+            .into_iter()
+            .collect::<CoStaticMap<_, _>>();
+        assert_eq!(format!("{:?}", map), r#"{1: "a", 3: "d"}"#);
+        assert_eq!(map.get_key_value(&3), Some((&3, &"d")));
+    }
+
+    #[test]
+    fn test_drops() {
+        let key = Rc::new(());
+        let map = CoStaticMap::from_sorted((0..10).map(|i| ((i, key.clone()), ())).collect());
+        assert_eq!(Rc::strong_count(&key), 11);
+        drop(map);
+        assert_eq!(Rc::strong_count(&key), 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unsorted() {
+        CoStaticMap::from_sorted(vec![(2, 0), (1, 0)]);
+    }
+}
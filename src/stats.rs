@@ -0,0 +1,17 @@
+// Counters of the work behind the updates of a map, kept once `BTreeMap::enable_stats` is
+// called, to check the amortized bounds on a real workload: O(log^2 n) entries moved and
+// O(log n) index nodes written per update, and a full rebuild of the index only when the PMA
+// resizes or a bulk operation lays it out again.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    // Single entry inserts and removes that reached the PMA, value replacements included.
+    pub operations: u64,
+    // Windows of the PMA laid out again, from one segment to the whole array.
+    pub rebalances: u64,
+    // Entries moved to another slot by rebalances.
+    pub moved: u64,
+    // Rebuilds of the whole index.
+    pub rebuilds: u64,
+    // Index nodes written, leaves and branches, rebuilds included.
+    pub nodes_touched: u64,
+}
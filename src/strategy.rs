@@ -0,0 +1,56 @@
+// Proptest strategies for the maps and sets of this crate, for tests of code that stores them.
+// They mirror `proptest::collection::{btree_map, btree_set}`: the size range counts distinct
+// keys, and shrinking drops entries and shrinks the remaining keys and values.
+
+use crate::{cache_oblivious::ParallelBounds, BTreeMap, CacheObliviousSet};
+use proptest::{collection::SizeRange, strategy::Strategy};
+
+// Maps of `size` entries, the keys drawn from `key` and the values from `value`.
+pub fn btree_map<K, V>(
+    key: K,
+    value: V,
+    size: impl Into<SizeRange>,
+) -> impl Strategy<Value = BTreeMap<K::Value, V::Value>>
+where
+    K: Strategy,
+    V: Strategy,
+    K::Value: Ord + ParallelBounds,
+    V::Value: ParallelBounds,
+{
+    proptest::collection::btree_map(key, value, size).prop_map(|map| map.into_iter().collect())
+}
+
+// Sets of `size` elements drawn from `element`.
+pub fn set<T>(
+    element: T,
+    size: impl Into<SizeRange>,
+) -> impl Strategy<Value = CacheObliviousSet<T::Value>>
+where
+    T: Strategy,
+    T::Value: Ord + ParallelBounds,
+{
+    proptest::collection::btree_set(element, size).prop_map(|set| set.into_iter().collect())
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod strategy {
+    use super::{btree_map, set};
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        #[cfg_attr(miri, ignore = "slow under Miri")]
+        fn test_btree_map(map in btree_map(any::<u16>(), any::<u8>(), 0..300)) {
+            map.check_invariants();
+            prop_assert!(map.len() < 300);
+            prop_assert!(map.keys().zip(map.keys().skip(1)).all(|(a, b)| a < b));
+        }
+
+        #[test]
+        #[cfg_attr(miri, ignore = "slow under Miri")]
+        fn test_set(set in set("[a-z]{1,4}", 5..20)) {
+            prop_assert!((5..20).contains(&set.len()));
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use futures_core::Stream;
+use std::{
+    future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// Asynchronous iteration over an ordered iterator of the map, handing control back to the
+// executor every `yield_every` items so a long scan streamed to a client does not monopolize
+// its worker thread. Executor agnostic: it is a `Stream`, so the stream combinators apply,
+// and `next` serves callers without them.
+pub struct AsyncIter<I> {
+    iter: I,
+    yield_every: usize,
+    since_yield: usize,
+}
+
+impl<I: Iterator> AsyncIter<I> {
+    pub fn new(iter: I, yield_every: usize) -> Self {
+        assert!(yield_every > 0, "Yield interval must be positive.");
+        Self {
+            iter,
+            yield_every,
+            since_yield: 0,
+        }
+    }
+
+    pub async fn next(&mut self) -> Option<I::Item> {
+        future::poll_fn(|cx| self.poll_item(cx)).await
+    }
+
+    // Pending once every `yield_every` items, with the task scheduled again right away.
+    fn poll_item(&mut self, cx: &mut Context<'_>) -> Poll<Option<I::Item>> {
+        if self.since_yield == self.yield_every {
+            self.since_yield = 0;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        self.since_yield += 1;
+        Poll::Ready(self.iter.next())
+    }
+}
+
+impl<I: Iterator + Unpin> Stream for AsyncIter<I> {
+    type Item = I::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<I::Item>> {
+        self.get_mut().poll_item(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod stream {
+    use crate::BTreeMap;
+    use futures_util::StreamExt;
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::Arc,
+        task::{Context, Poll, Wake},
+    };
+
+    struct Noop;
+
+    impl Wake for Noop {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    // Polls the future to completion, returning its output and the number of times it yielded.
+    fn block_on<F: Future>(future: F) -> (F::Output, usize) {
+        let waker = Arc::new(Noop).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = pin!(future);
+        let mut pending = 0;
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return (output, pending),
+                Poll::Pending => pending += 1,
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_async_iter() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..1000 {
+            map.insert(i, i * 2);
+        }
+        let (sum, yields) = block_on(async {
+            let mut entries = map.async_iter(100);
+            let mut sum = 0;
+            while let Some((_, &v)) = entries.next().await {
+                sum += v;
+            }
+            sum
+        });
+        assert_eq!(sum, 999 * 1000);
+        assert_eq!(yields, 10);
+    }
+
+    #[test]
+    fn test_stream() {
+        let map = (0..1000usize).map(|i| (i, i)).collect::<BTreeMap<_, _>>();
+        let (odd, yields) = block_on(
+            map.async_iter(64)
+                .filter(|(k, _)| std::future::ready(*k % 2 == 1))
+                .map(|(_, &v)| v)
+                .fold(0, |sum, v| std::future::ready(sum + v)),
+        );
+        assert_eq!(odd, 500 * 500);
+        // 1000 items and the final `None` are polled, so 1001 / 64 yields.
+        assert_eq!(yields, 15);
+        let (keys, _) = block_on(
+            map.async_iter(3)
+                .skip(10)
+                .take(5)
+                .map(|(&k, _)| k)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(keys, (10..15).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,822 @@
+use std::{iter::Zip, slice};
+
+// Keys per leaf and children per branch before a node splits. Nodes other than the root hold
+// at least half as many, removes merge or refill the ones falling below.
+const LEAF_CAP: usize = 64;
+const BRANCH_CAP: usize = 64;
+
+// The blind trie of a sorted run of distinct byte strings, stored as what it is made of: for
+// every two neighbours, the length of their common prefix and the byte the larger one goes on
+// with there. Walking it reads these alone, never the strings.
+type Branches = Vec<(usize, u8)>;
+
+enum Node<V> {
+    // Keys sorted, their values, and the branches between neighbouring keys.
+    Leaf {
+        keys: Vec<Box<[u8]>>,
+        values: Vec<V>,
+        branches: Branches,
+    },
+    // `keys[i]` is the smallest key that may be stored under `children[i + 1]`. The branches
+    // run over the keys with the bounds of the node around them, the separators of the parent
+    // in front of and behind it where there are such, so the trie of a node holds both keys a
+    // search came between on its way down.
+    Branch {
+        keys: Vec<Box<[u8]>>,
+        children: Vec<Node<V>>,
+        branches: Branches,
+    },
+}
+
+// The right half a node split off and the separator in front of it.
+type Split<V> = Option<(Box<[u8]>, Node<V>)>;
+
+// A map over byte string keys, laid out as a string B-tree: a B-tree whose nodes each keep a
+// blind trie over their keys, as the branching positions and bytes between neighbours. A search
+// walks the trie of a node without reading any key, which leads it to the one key of the node
+// sharing the longest prefix with the query. One comparison with that key, started past the
+// prefix the query is known to share with it, places the query among the node's keys from the
+// branches again. The prefix known going into a node is the longer of the ones the query
+// shares with the two separators it fell between, which the trie of the child holds, so the
+// comparisons of a search pick up where the one above stopped and read every byte of a query
+// of P bytes about once. With nodes of B keys that is O(P/B + log_B n) block transfers, where
+// full key comparisons at every level of a binary index cost up to P/B each for long keys with
+// shared prefixes such as paths or URLs. Only the leaves restart at the prefix shared with
+// both of their bounds, which adds one more pass over the query at most.
+pub struct StringBTreeMap<V> {
+    root: Node<V>,
+    len: usize,
+}
+
+impl<V> Default for StringBTreeMap<V> {
+    fn default() -> Self {
+        Self {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<V> StringBTreeMap<V> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // Takes `String`, `&str`, `Vec<u8>` or `&[u8]` keys, which all sort by their bytes.
+    pub fn insert(&mut self, key: impl Into<Vec<u8>>, value: V) -> Option<V> {
+        let (replaced, split) = insert(&mut self.root, Bounds::default(), key.into(), value);
+        if let Some((key, right)) = split {
+            let left = std::mem::take(&mut self.root);
+            // The root has no bounds, so the separator is the only string of its trie.
+            self.root = Node::Branch {
+                keys: vec![key],
+                children: vec![left, right],
+                branches: vec![],
+            };
+        }
+        self.len += usize::from(replaced.is_none());
+        replaced
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&V> {
+        let key = key.as_ref();
+        let (mut node, mut bounds) = (&self.root, Bounds::default());
+        loop {
+            match node {
+                Node::Leaf { values, .. } => {
+                    let found = node.search(bounds, key);
+                    return found.equal.then(|| &values[found.le - 1]);
+                }
+                Node::Branch { keys, children, .. } => {
+                    let found = node.search(bounds, key);
+                    let child = found.le - usize::from(bounds.lower.is_some());
+                    bounds = bounds.child(keys, &found);
+                    node = &children[child];
+                }
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self, key: impl AsRef<[u8]>) -> Option<&mut V> {
+        get_mut(&mut self.root, Bounds::default(), key.as_ref())
+    }
+
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Option<V> {
+        let value = remove(&mut self.root, Bounds::default(), key.as_ref())?;
+        self.len -= 1;
+        // A branch left with a single child is replaced by it.
+        while let Node::Branch { children, .. } = &mut self.root {
+            if children.len() > 1 {
+                break;
+            }
+            let child = children.pop().unwrap();
+            self.root = child;
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> StringIter<'_, V> {
+        self.seek(&[], vec![])
+    }
+
+    // The entries whose keys start with `prefix`, in key order. A search finds the first one,
+    // the iteration stops at the first key past them.
+    pub fn prefix(&self, prefix: impl AsRef<[u8]>) -> StringIter<'_, V> {
+        let prefix = prefix.as_ref();
+        self.seek(prefix, prefix.to_vec())
+    }
+
+    // Iterates from the first key not less than `key`.
+    fn seek(&self, key: &[u8], prefix: Vec<u8>) -> StringIter<'_, V> {
+        let mut stack = vec![];
+        let (mut node, mut bounds) = (&self.root, Bounds::default());
+        loop {
+            let found = node.search(bounds, key);
+            match node {
+                Node::Leaf { keys, values, .. } => {
+                    let from = found.le - usize::from(found.equal);
+                    let leaf = keys[from..].iter().zip(&values[from..]);
+                    return StringIter {
+                        stack,
+                        leaf,
+                        prefix,
+                    };
+                }
+                Node::Branch { keys, children, .. } => {
+                    let child = found.le - usize::from(bounds.lower.is_some());
+                    stack.push(children[child + 1..].iter());
+                    bounds = bounds.child(keys, &found);
+                    node = &children[child];
+                }
+            }
+        }
+    }
+}
+
+// The bounds of a node on the way down, the separators the search came between, with the
+// common prefix lengths of the query with each.
+#[derive(Clone, Copy, Default)]
+struct Bounds<'a> {
+    lower: Option<&'a [u8]>,
+    upper: Option<&'a [u8]>,
+    lower_common: usize,
+    upper_common: usize,
+}
+
+impl<'a> Bounds<'a> {
+    // The bounds of the child a search of a branch with `keys` under these bounds went to.
+    fn child(self, keys: &'a [Box<[u8]>], found: &Found) -> Bounds<'a> {
+        Bounds {
+            lower: (found.le > 0).then(|| self.string(keys, found.le - 1)),
+            upper: (found.le < self.count(keys)).then(|| self.string(keys, found.le)),
+            lower_common: found.lower_common,
+            upper_common: found.upper_common,
+        }
+    }
+
+    // The number of strings in the trie of a branch with `keys` under these bounds.
+    fn count(self, keys: &[Box<[u8]>]) -> usize {
+        keys.len() + usize::from(self.lower.is_some()) + usize::from(self.upper.is_some())
+    }
+
+    // The `i`th string of the trie of a branch with `keys` under these bounds.
+    fn string(self, keys: &'a [Box<[u8]>], i: usize) -> &'a [u8] {
+        let i = match self.lower {
+            Some(lower) if i == 0 => return lower,
+            Some(_) => i - 1,
+            None => i,
+        };
+        match keys.get(i) {
+            Some(key) => key,
+            None => self.upper.unwrap(),
+        }
+    }
+
+    // The prefix the query shares with the key of a branch under these bounds that shares the
+    // longest prefix with it: the bounds are among its strings.
+    fn branch_common(&self) -> usize {
+        let lower = self.lower.map_or(0, |_| self.lower_common);
+        let upper = self.upper.map_or(0, |_| self.upper_common);
+        lower.max(upper)
+    }
+
+    // The prefix the query shares with every key of a leaf under these bounds, which all lie
+    // between them.
+    fn leaf_common(&self) -> usize {
+        match (self.lower, self.upper) {
+            (Some(_), Some(_)) => self.lower_common.min(self.upper_common),
+            _ => 0,
+        }
+    }
+}
+
+// Where a query falls in the sorted strings of a trie.
+struct Found {
+    // The number of strings not greater than the query.
+    le: usize,
+    // Whether the last of them is the query.
+    equal: bool,
+    // The common prefix lengths of the query with the string in front of it, the last one not
+    // greater, and with the one behind it, where there are such.
+    lower_common: usize,
+    upper_common: usize,
+}
+
+impl<V> Node<V> {
+    // Places `key` among the keys of the node, the bounds around them for a branch.
+    fn search(&self, bounds: Bounds<'_>, key: &[u8]) -> Found {
+        match self {
+            Node::Leaf { keys, branches, .. } => search(
+                branches,
+                keys.len(),
+                |i| &keys[i],
+                key,
+                bounds.leaf_common(),
+            ),
+            Node::Branch { keys, branches, .. } => search(
+                branches,
+                bounds.count(keys),
+                |i| bounds.string(keys, i),
+                key,
+                bounds.branch_common(),
+            ),
+        }
+    }
+
+    fn is_underfull(&self) -> bool {
+        match self {
+            Node::Leaf { keys, .. } => keys.len() < LEAF_CAP / 2,
+            Node::Branch { children, .. } => children.len() < BRANCH_CAP / 2,
+        }
+    }
+}
+
+impl<V> Default for Node<V> {
+    fn default() -> Self {
+        Node::Leaf {
+            keys: vec![],
+            values: vec![],
+            branches: vec![],
+        }
+    }
+}
+
+// The length of the common prefix of two strings, counted from `from`, which they share.
+fn common_prefix(a: &[u8], b: &[u8], from: usize) -> usize {
+    debug_assert_eq!(a[..from], b[..from]);
+    from + a[from..]
+        .iter()
+        .zip(&b[from..])
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+// The branch from a string to a greater one.
+fn branch(a: &[u8], b: &[u8]) -> (usize, u8) {
+    let common = common_prefix(a, b, 0);
+    (common, b[common])
+}
+
+// The trie of sorted distinct strings.
+fn trie<'a>(strings: impl IntoIterator<Item = &'a [u8]>) -> Branches {
+    let mut strings = strings.into_iter().peekable();
+    let mut branches = vec![];
+    while let (Some(a), Some(b)) = (strings.next(), strings.peek()) {
+        branches.push(branch(a, b));
+    }
+    branches
+}
+
+// Walks the trie of `count` strings down to a leaf, the string sharing the longest prefix
+// with `key` of all. A node of the trie is a run of strings whose branches are all at least
+// its depth, split into children by the ones at exactly that depth, and the child taken is the
+// one going on with the byte of `key` there. Only the first child of a node has no branch
+// recording its byte, so when none matches the walk goes there: either it is the match, or
+// `key` leaves every string of the node at that depth or earlier, and any leaf will do.
+fn blind_walk(branches: &[(usize, u8)], key: &[u8]) -> usize {
+    let (mut from, mut to) = (0, branches.len());
+    while from < to {
+        let depth = branches[from..to].iter().map(|b| b.0).min().unwrap();
+        let splits = move || (from..to).filter(move |&i| branches[i].0 == depth);
+        let first = splits().next().unwrap();
+        (from, to) = match splits().find(|&i| key.get(depth) == Some(&branches[i].1)) {
+            Some(i) => (i + 1, splits().find(|&j| j > i).unwrap_or(to)),
+            None => (from, first),
+        };
+    }
+    from
+}
+
+// Places `key` among `count` sorted strings with the trie `branches`, reading one string:
+// the one the blind walk leads to, compared from `known` on, which `key` shares with it. Every
+// string shares with `key` at most the prefix found, those sharing all of it are a run around
+// the one compared, and `key` goes in front of the run when it ends there or goes on with a
+// lower byte, and otherwise behind the strings of the run going on with lower bytes, which the
+// branches at that depth tell apart.
+fn search<'a>(
+    branches: &[(usize, u8)],
+    count: usize,
+    string: impl Fn(usize) -> &'a [u8],
+    key: &[u8],
+    known: usize,
+) -> Found {
+    if count == 0 {
+        return Found {
+            le: 0,
+            equal: false,
+            lower_common: 0,
+            upper_common: 0,
+        };
+    }
+    let candidate = blind_walk(branches, key);
+    let found = string(candidate);
+    let common = common_prefix(key, found, known);
+    // The common prefix of the strings `i` and `j`, `common` for the candidate.
+    let common_with = |i: usize| {
+        let (a, b) = (i.min(candidate), i.max(candidate));
+        branches[a..b].iter().map(|b| b.0).fold(common, usize::min)
+    };
+    let (le, equal) = if common == key.len() && common == found.len() {
+        (candidate + 1, true)
+    } else {
+        let mut from = candidate;
+        while from > 0 && branches[from - 1].0 >= common {
+            from -= 1;
+        }
+        let mut to = candidate;
+        while to + 1 < count && branches[to].0 >= common {
+            to += 1;
+        }
+        match key.get(common) {
+            // `key` is a prefix of every string of the run, and equal to the first one if it
+            // ends there.
+            None => {
+                let equal = string(from).len() == common;
+                (from + usize::from(equal), equal)
+            }
+            // The candidate is in the first group of the run, which it shows the byte of.
+            Some(&byte) if found.get(common).is_some_and(|&first| byte < first) => (from, false),
+            Some(&byte) => {
+                let behind = (from..to).find(|&i| branches[i].0 == common && branches[i].1 > byte);
+                (behind.map_or(to + 1, |i| i + 1), false)
+            }
+        }
+    };
+    Found {
+        le,
+        equal,
+        lower_common: if le > 0 { common_with(le - 1) } else { 0 },
+        upper_common: if le < count { common_with(le) } else { 0 },
+    }
+}
+
+// Inserts into the subtree, returning the replaced value and the right half split off when
+// the node outgrew its capacity.
+fn insert<V>(
+    node: &mut Node<V>,
+    bounds: Bounds<'_>,
+    key: Vec<u8>,
+    value: V,
+) -> (Option<V>, Split<V>) {
+    let found = node.search(bounds, &key);
+    match node {
+        Node::Leaf {
+            keys,
+            values,
+            branches,
+        } => {
+            if found.equal {
+                return (
+                    Some(std::mem::replace(&mut values[found.le - 1], value)),
+                    None,
+                );
+            }
+            let at = found.le;
+            // The branches from the key in front and to the key behind, replacing the one
+            // between those two.
+            if at < keys.len() {
+                branches.insert(at, (found.upper_common, keys[at][found.upper_common]));
+            }
+            if at > 0 {
+                let lower = (found.lower_common, key[found.lower_common]);
+                if at < keys.len() {
+                    branches[at - 1] = lower;
+                } else {
+                    branches.push(lower);
+                }
+            }
+            keys.insert(at, key.into_boxed_slice());
+            values.insert(at, value);
+            if keys.len() <= LEAF_CAP {
+                return (None, None);
+            }
+            let mid = keys.len() / 2;
+            let right = Node::Leaf {
+                keys: keys.split_off(mid),
+                values: values.split_off(mid),
+                branches: branches.split_off(mid),
+            };
+            branches.pop();
+            let Node::Leaf {
+                keys: right_keys, ..
+            } = &right
+            else {
+                unreachable!()
+            };
+            (None, Some((right_keys[0].clone(), right)))
+        }
+        Node::Branch {
+            keys,
+            children,
+            branches,
+        } => {
+            let child = found.le - usize::from(bounds.lower.is_some());
+            let (replaced, split) =
+                insert(&mut children[child], bounds.child(keys, &found), key, value);
+            let Some((separator, right)) = split else {
+                return (replaced, None);
+            };
+            keys.insert(child, separator);
+            children.insert(child + 1, right);
+            *branches = branch_trie(keys, bounds);
+            if children.len() <= BRANCH_CAP {
+                return (replaced, None);
+            }
+            let has_lower = bounds.lower.is_some();
+            (
+                replaced,
+                Some(split_branch(keys, children, branches, has_lower)),
+            )
+        }
+    }
+}
+
+// The trie of a branch with `keys` under `bounds`.
+fn branch_trie(keys: &[Box<[u8]>], bounds: Bounds<'_>) -> Branches {
+    let keys = keys.iter().map(|key| &key[..]);
+    trie(bounds.lower.into_iter().chain(keys).chain(bounds.upper))
+}
+
+// Moves the upper half of an overfull branch, with a lower bound or not, into a new node. The
+// middle separator goes up as the bound both halves share, so their tries are the two parts of
+// the trie around it.
+fn split_branch<V>(
+    keys: &mut Vec<Box<[u8]>>,
+    children: &mut Vec<Node<V>>,
+    branches: &mut Branches,
+    has_lower: bool,
+) -> (Box<[u8]>, Node<V>) {
+    let mid = children.len() / 2;
+    let right_children = children.split_off(mid);
+    let right_keys = keys.split_off(mid);
+    let separator = keys.pop().unwrap();
+    let right_branches = branches.split_off(mid - 1 + usize::from(has_lower));
+    let right = Node::Branch {
+        keys: right_keys,
+        children: right_children,
+        branches: right_branches,
+    };
+    (separator, right)
+}
+
+fn get_mut<'a, V>(node: &'a mut Node<V>, bounds: Bounds<'_>, key: &[u8]) -> Option<&'a mut V> {
+    let found = node.search(bounds, key);
+    match node {
+        Node::Leaf { values, .. } => found.equal.then(|| &mut values[found.le - 1]),
+        Node::Branch { keys, children, .. } => {
+            let child = found.le - usize::from(bounds.lower.is_some());
+            get_mut(&mut children[child], bounds.child(keys, &found), key)
+        }
+    }
+}
+
+// Removes from the subtree, which may be left less than half full.
+fn remove<V>(node: &mut Node<V>, bounds: Bounds<'_>, key: &[u8]) -> Option<V> {
+    let found = node.search(bounds, key);
+    match node {
+        Node::Leaf {
+            keys,
+            values,
+            branches,
+        } => {
+            if !found.equal {
+                return None;
+            }
+            let at = found.le - 1;
+            keys.remove(at);
+            // The branch between the neighbours of the key is the shallower of the two around
+            // it, the deeper one only going on inside the prefix they all share.
+            if at == 0 {
+                if !branches.is_empty() {
+                    branches.remove(0);
+                }
+            } else if at == branches.len() {
+                branches.pop();
+            } else {
+                let (lower, upper) = (branches[at - 1], branches.remove(at));
+                branches[at - 1] = if lower.0 < upper.0 { lower } else { upper };
+            }
+            Some(values.remove(at))
+        }
+        Node::Branch {
+            keys,
+            children,
+            branches,
+        } => {
+            let child = found.le - usize::from(bounds.lower.is_some());
+            let value = remove(&mut children[child], bounds.child(keys, &found), key)?;
+            if children[child].is_underfull() && children.len() > 1 {
+                // Joins the child with its left sibling, or with the right one for the first.
+                let left = child.saturating_sub(1).min(children.len() - 2);
+                let right = children.remove(left + 1);
+                let separator = keys.remove(left);
+                let has_lower = left > 0 || bounds.lower.is_some();
+                if let Some((separator, right)) =
+                    join(&mut children[left], separator, right, has_lower)
+                {
+                    keys.insert(left, separator);
+                    children.insert(left + 1, right);
+                }
+                *branches = branch_trie(keys, bounds);
+            }
+            Some(value)
+        }
+    }
+}
+
+// Appends `right`, the sibling behind `left` with `separator` between them, to `left`, and
+// splits the result evenly again if it is overfull, returning the right half. `has_lower`
+// tells whether `left` has a lower bound.
+fn join<V>(left: &mut Node<V>, separator: Box<[u8]>, right: Node<V>, has_lower: bool) -> Split<V> {
+    match (left, right) {
+        (
+            Node::Leaf {
+                keys,
+                values,
+                branches,
+            },
+            Node::Leaf {
+                keys: right_keys,
+                values: right_values,
+                ..
+            },
+        ) => {
+            keys.extend(right_keys);
+            values.extend(right_values);
+            *branches = trie(keys.iter().map(|key| &key[..]));
+            if keys.len() <= LEAF_CAP {
+                return None;
+            }
+            let mid = keys.len() / 2;
+            let right = Node::Leaf {
+                keys: keys.split_off(mid),
+                values: values.split_off(mid),
+                branches: branches.split_off(mid),
+            };
+            branches.pop();
+            let Node::Leaf {
+                keys: right_keys, ..
+            } = &right
+            else {
+                unreachable!()
+            };
+            Some((right_keys[0].clone(), right))
+        }
+        (
+            Node::Branch {
+                keys: left_keys,
+                children,
+                branches,
+            },
+            Node::Branch {
+                keys: right_keys,
+                children: right_children,
+                branches: right_branches,
+            },
+        ) => {
+            // The separator is the upper bound of the left node and the lower one of the right
+            // node, the last string of the one trie and the first of the other.
+            left_keys.push(separator);
+            left_keys.extend(right_keys);
+            children.extend(right_children);
+            branches.extend(right_branches);
+            if children.len() <= BRANCH_CAP {
+                return None;
+            }
+            Some(split_branch(left_keys, children, branches, has_lower))
+        }
+        _ => unreachable!("Siblings are on the same level."),
+    }
+}
+
+// The entries of a `StringBTreeMap` in key order, from a given key on, and only as long as
+// the keys start with a given prefix for `prefix`.
+pub struct StringIter<'a, V> {
+    // The children of the branches on the way down not visited yet.
+    stack: Vec<slice::Iter<'a, Node<V>>>,
+    leaf: Zip<slice::Iter<'a, Box<[u8]>>, slice::Iter<'a, V>>,
+    prefix: Vec<u8>,
+}
+
+impl<'a, V> Iterator for StringIter<'a, V> {
+    type Item = (&'a [u8], &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.leaf.next() {
+                if !key.starts_with(&self.prefix) {
+                    self.stack.clear();
+                    self.leaf = [].iter().zip([].iter());
+                    return None;
+                }
+                return Some((key, value));
+            }
+            match self.stack.last_mut()?.next() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(Node::Leaf { keys, values, .. }) => self.leaf = keys.iter().zip(values.iter()),
+                Some(Node::Branch { children, .. }) => self.stack.push(children.iter()),
+            }
+        }
+    }
+}
+
+impl<K: Into<Vec<u8>>, V> FromIterator<(K, V)> for StringBTreeMap<V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Self::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod string_map {
+    use super::{trie, Node, StringBTreeMap, BRANCH_CAP, LEAF_CAP};
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap;
+
+    // Long keys sharing most of their bytes, the case the blind tries are for.
+    fn key(rng: &mut StdRng) -> Vec<u8> {
+        let mut key = b"/var/lib/data/shard-".to_vec();
+        for _ in 0..rng.gen_range(0..4) {
+            key.extend_from_slice(&[b'a' + rng.gen_range(0..3), b'/']);
+        }
+        key.extend((0..rng.gen_range(0..3)).map(|_| rng.gen_range(0xfd..=0xff)));
+        key
+    }
+
+    // Short keys over two bytes, many of them prefixes of others, the empty one included.
+    fn short_key(rng: &mut StdRng) -> Vec<u8> {
+        (0..rng.gen_range(0..12))
+            .map(|_| b'a' + rng.gen_range(0..2))
+            .collect()
+    }
+
+    // Checks that the keys are sorted between the bounds, every trie matches the strings it is
+    // over, the leaves are all on one level and every node but the root is at least half full,
+    // returning the height.
+    fn check<V>(node: &Node<V>, lower: Option<&[u8]>, upper: Option<&[u8]>, root: bool) -> usize {
+        let keys = match node {
+            Node::Leaf {
+                keys,
+                values,
+                branches,
+            } => {
+                assert_eq!(keys.len(), values.len());
+                assert!(keys.len() <= LEAF_CAP && (root || keys.len() >= LEAF_CAP / 2));
+                assert_eq!(*branches, trie(keys.iter().map(|key| &key[..])));
+                keys
+            }
+            Node::Branch {
+                keys,
+                children,
+                branches,
+            } => {
+                assert_eq!(keys.len() + 1, children.len());
+                assert!(children.len() <= BRANCH_CAP);
+                assert!(children.len() >= if root { 2 } else { BRANCH_CAP / 2 });
+                let strings = lower.into_iter().chain(keys.iter().map(|key| &key[..]));
+                assert_eq!(*branches, trie(strings.chain(upper)));
+                keys
+            }
+        };
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(keys
+            .iter()
+            .all(|key| lower.is_none_or(|lower| lower <= &key[..])));
+        assert!(keys
+            .iter()
+            .all(|key| upper.is_none_or(|upper| &key[..] < upper)));
+        let Node::Branch { children, .. } = node else {
+            return 1;
+        };
+        let heights = children.iter().enumerate().map(|(i, child)| {
+            let lower = if i == 0 {
+                lower
+            } else {
+                Some(&keys[i - 1][..])
+            };
+            let upper = keys.get(i).map_or(upper, |key| Some(&key[..]));
+            check(child, lower, upper, false)
+        });
+        let heights = heights.collect::<Vec<_>>();
+        assert!(heights.iter().all(|&height| height == heights[0]));
+        heights[0] + 1
+    }
+
+    fn run(seed: u64, mut key: impl FnMut(&mut StdRng) -> Vec<u8>) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut map = StringBTreeMap::new();
+        let mut expected = BTreeMap::new();
+        for i in 0..5000 {
+            let key = key(&mut rng);
+            if rng.gen_bool(0.3) {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key.clone(), i), expected.insert(key, i));
+            }
+            if i % 500 == 0 {
+                check(&map.root, None, None, true);
+            }
+        }
+        assert_eq!(map.len(), expected.len());
+        assert!(map.iter().eq(expected.iter().map(|(k, v)| (&k[..], v))));
+        for _ in 0..1000 {
+            let key = key(&mut rng);
+            assert_eq!(map.get(&key), expected.get(&key));
+            assert_eq!(map.contains_key(&key), expected.contains_key(&key));
+        }
+        check(&map.root, None, None, true);
+        // Emptying the map joins the nodes back into a single leaf.
+        for (i, key) in expected.keys().enumerate() {
+            assert_eq!(map.remove(key), Some(&expected[key]).copied());
+            if i % 200 == 0 {
+                check(&map.root, None, None, true);
+            }
+        }
+        assert!(map.is_empty() && map.iter().next().is_none());
+        assert!(matches!(map.root, Node::Leaf { .. }));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_operations() {
+        run(7, key);
+        run(3, short_key);
+        let mut map = StringBTreeMap::new();
+        map.insert("/var/lib/data/shard-", 0);
+        *map.get_mut(b"/var/lib/data/shard-").unwrap() += 1;
+        assert_eq!(map.get("/var/lib/data/shard-"), Some(&1));
+        assert_eq!(map.get("missing"), None);
+        assert_eq!(map.get_mut("missing"), None);
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_prefix() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let map = (0..3000)
+            .map(|i| (key(&mut rng), i))
+            .collect::<StringBTreeMap<_>>();
+        check(&map.root, None, None, true);
+        for prefix in [
+            &b""[..],
+            b"/var/lib/data/shard-",
+            b"/var/lib/data/shard-a/",
+            b"/var/lib/data/shard-b/c/",
+            b"/var/lib/data/shard-\xff",
+            b"/var/lib/data/shard-c/\xfe\xff",
+            b"/var/lib/data/shard-d",
+            b"/zzz",
+            b"\xff\xff",
+        ] {
+            assert!(map
+                .prefix(prefix)
+                .eq(map.iter().filter(|(k, _)| k.starts_with(prefix))));
+        }
+        let names = ["beta", "alpha", "alphabet", "al"]
+            .into_iter()
+            .map(|name| (name, name.len()))
+            .collect::<StringBTreeMap<_>>();
+        assert!(names
+            .prefix("alp")
+            .map(|(k, _)| k)
+            .eq([&b"alpha"[..], b"alphabet"]));
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{cache_oblivious::ParallelBounds, comparable::Comparable, BTreeMap};
+
+// Writes staged by `BTreeMap::transaction`. Reads through a transaction see its own staged
+// writes on top of the map, while the map itself is left untouched until the closure
+// succeeds, so an error or a panic simply drops the staged writes.
+pub struct Transaction<'a, K: Ord, V> {
+    map: &'a BTreeMap<K, V>,
+    // `None` stages a removal.
+    staged: BTreeMap<K, Option<V>>,
+}
+
+impl<'a, K, V> Transaction<'a, K, V>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+{
+    pub(crate) fn new(map: &'a BTreeMap<K, V>) -> Self {
+        Self {
+            map,
+            staged: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        self.staged.insert(key, Some(value));
+    }
+
+    pub fn remove(&mut self, key: &K)
+    where
+        K: Clone,
+    {
+        self.staged.insert(key.clone(), None);
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&V> {
+        match self.staged.get(key) {
+            Some(staged) => staged.as_ref(),
+            None => self.map.get(key),
+        }
+    }
+
+    pub fn contains_key<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.get(key).is_some()
+    }
+
+    // Number of keys with a staged write.
+    pub fn staged_len(&self) -> usize {
+        self.staged.len()
+    }
+
+    // Drops every staged write, the transaction can still be used afterwards.
+    pub fn rollback(&mut self) {
+        self.staged.clear();
+    }
+
+    pub(crate) fn into_staged(self) -> BTreeMap<K, Option<V>> {
+        self.staged
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod transaction {
+    use crate::BTreeMap;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[test]
+    fn test_commit_and_rollback() {
+        let mut map = BTreeMap::<usize, usize>::new();
+        for i in 0..10 {
+            map.insert(i, i);
+        }
+
+        let result: Result<usize, ()> = map.transaction(|txn| {
+            txn.insert(3, 33);
+            txn.insert(20, 20);
+            txn.remove(&4);
+            txn.remove(&100);
+            assert_eq!(txn.get(&3), Some(&33));
+            assert_eq!(txn.get(&4), None);
+            assert_eq!(txn.get(&5), Some(&5));
+            assert!(txn.contains_key(&20));
+            Ok(txn.staged_len())
+        });
+        assert_eq!(result, Ok(4));
+        assert_eq!(map.len(), 10);
+        assert_eq!(map.get(&3), Some(&33));
+        assert_eq!(map.get(&4), None);
+        assert_eq!(map.get(&20), Some(&20));
+
+        let before = map
+            .get_all_key_values()
+            .into_iter()
+            .map(|(&k, &v)| (k, v))
+            .collect::<Vec<(usize, usize)>>();
+        let result: Result<(), &str> = map.transaction(|txn| {
+            txn.remove(&3);
+            txn.insert(4, 4);
+            Err("abort")
+        });
+        assert_eq!(result, Err("abort"));
+        let panicked = catch_unwind(AssertUnwindSafe(|| {
+            let _: Result<(), ()> = map.transaction(|txn| {
+                txn.remove(&3);
+                panic!("abort");
+            });
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(
+            map.get_all_key_values(),
+            before.iter().map(|(k, v)| (k, v)).collect::<Vec<_>>()
+        );
+
+        let result: Result<(), ()> = map.transaction(|txn| {
+            txn.insert(50, 50);
+            txn.rollback();
+            txn.insert(51, 51);
+            Ok(())
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(map.get(&50), None);
+        assert_eq!(map.get(&51), Some(&51));
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_batched_commit() {
+        let mut map = (0..1000)
+            .map(|i| (format!("{i:04}"), i))
+            .collect::<BTreeMap<_, _>>();
+        let result: Result<(), ()> = map.transaction(|txn| {
+            for i in (0..2000).step_by(2) {
+                txn.insert(format!("{i:04}"), i * 10);
+            }
+            for i in (1..1000).step_by(2) {
+                txn.remove(&format!("{i:04}"));
+            }
+            // Lookups take anything comparable to the keys.
+            assert_eq!(txn.get("0002"), Some(&20));
+            assert_eq!(txn.get("0003"), None);
+            assert!(txn.contains_key("1998") && !txn.contains_key("1999"));
+            Ok(())
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(map.len(), 1000);
+        assert!(map
+            .iter()
+            .map(|(k, &v)| (k.clone(), v))
+            .eq((0..2000).step_by(2).map(|i| (format!("{i:04}"), i * 10))));
+        map.check_invariants();
+    }
+}
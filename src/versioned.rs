@@ -0,0 +1,260 @@
+use crate::{cache_oblivious::ParallelBounds, BTreeMap, Comparable, Equivalent};
+use std::cmp::{Ordering, Reverse};
+
+// A multi-version map for storage engines: every write is stamped with the next version
+// number and kept next to the older versions of its key, so reads can ask for the map as it
+// was at any version not compacted yet. The versions of a key are stored together, newest
+// first, under composite keys, so a read at a version is a single successor search in the
+// PMA. Removals store a tombstone. `compact` drops the history no read needs any more.
+pub struct VersionedMap<K: Ord, V> {
+    // `None` is a removal.
+    map: BTreeMap<(K, Reverse<u64>), Option<V>>,
+    // The version of the last write, 0 for none.
+    version: u64,
+    // The oldest version reads may ask for.
+    oldest: u64,
+    // The number of keys present at the latest version.
+    len: usize,
+}
+
+// Finds the newest version of `key` not after `version`.
+struct AtVersion<'a, Q: ?Sized> {
+    key: &'a Q,
+    version: u64,
+}
+
+impl<K, Q: Equivalent<K> + ?Sized> Equivalent<(K, Reverse<u64>)> for AtVersion<'_, Q> {
+    fn equivalent(&self, (key, Reverse(version)): &(K, Reverse<u64>)) -> bool {
+        self.key.equivalent(key) && self.version == *version
+    }
+}
+
+impl<K, Q: Comparable<K> + ?Sized> Comparable<(K, Reverse<u64>)> for AtVersion<'_, Q> {
+    fn compare(&self, (key, version): &(K, Reverse<u64>)) -> Ordering {
+        self.key
+            .compare(key)
+            .then_with(|| Reverse(self.version).cmp(version))
+    }
+}
+
+impl<K, V> Default for VersionedMap<K, V>
+where
+    K: Ord + Clone + ParallelBounds,
+    V: ParallelBounds,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V> VersionedMap<K, V>
+where
+    K: Ord + Clone + ParallelBounds,
+    V: ParallelBounds,
+{
+    pub fn new() -> Self {
+        Self {
+            map: BTreeMap::new(),
+            version: 0,
+            oldest: 0,
+            len: 0,
+        }
+    }
+
+    // The version of the last write, the one reads without a version see.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // The oldest version reads can still ask for, moved up by `compact`.
+    pub fn oldest_version(&self) -> u64 {
+        self.oldest
+    }
+
+    // The number of keys at the latest version.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The number of versions stored over all keys, tombstones included.
+    pub fn stored_versions(&self) -> usize {
+        self.map.len()
+    }
+
+    // Writes `value` under `key` and returns the version of the write.
+    pub fn insert(&mut self, key: K, value: V) -> u64 {
+        if !self.contains_key(&key) {
+            self.len += 1;
+        }
+        self.version += 1;
+        self.map.insert((key, Reverse(self.version)), Some(value));
+        self.version
+    }
+
+    // Removes `key` and returns the version of the removal, or `None` when the key is not
+    // present, which writes nothing.
+    pub fn remove<Q: Comparable<K> + ?Sized>(&mut self, key: &Q) -> Option<u64> {
+        let (key, _) = self.latest(key, self.version)?;
+        let key = key.clone();
+        self.len -= 1;
+        self.version += 1;
+        self.map.insert((key, Reverse(self.version)), None);
+        Some(self.version)
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&V> {
+        self.latest(key, self.version).map(|(_, v)| v)
+    }
+
+    pub fn contains_key<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.get(key).is_some()
+    }
+
+    // The value of `key` as of `version`, that is written by the newest write not after it.
+    // Panics when `version` is older than `oldest_version`.
+    pub fn get_at<Q: Comparable<K> + ?Sized>(&self, key: &Q, version: u64) -> Option<&V> {
+        self.check_version(version);
+        self.latest(key, version).map(|(_, v)| v)
+    }
+
+    fn latest<Q: Comparable<K> + ?Sized>(&self, key: &Q, version: u64) -> Option<(&K, &V)> {
+        let ((k, _), value) = self.map.lower_bound(&AtVersion { key, version })?;
+        if !key.equivalent(k) {
+            return None;
+        }
+        value.as_ref().map(|value| (k, value))
+    }
+
+    // Iterates over the latest version of the map in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.iter_versions(self.version)
+    }
+
+    // Iterates over the map as of `version` in key order. It walks every stored version of
+    // the keys, so `compact` keeps it fast. Panics when `version` is older than
+    // `oldest_version`.
+    pub fn iter_at(&self, version: u64) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.check_version(version);
+        self.iter_versions(version)
+    }
+
+    fn iter_versions(&self, version: u64) -> impl Iterator<Item = (&K, &V)> + '_ {
+        // The versions of a key are adjacent, newest first, so the first one not after
+        // `version` is the one to see and the older ones behind it are skipped.
+        self.map
+            .iter()
+            .filter(move |((_, Reverse(v)), _)| *v <= version)
+            .scan(None, |last: &mut Option<&K>, ((k, _), value)| {
+                let seen = *last == Some(k);
+                *last = Some(k);
+                Some((!seen).then_some((k, value)))
+            })
+            .flatten()
+            .filter_map(|(k, value)| value.as_ref().map(|value| (k, value)))
+    }
+
+    fn check_version(&self, version: u64) {
+        assert!(
+            version >= self.oldest,
+            "version {version} is older than the oldest version {} kept",
+            self.oldest
+        );
+    }
+
+    // Drops the history reads at `up_to` and later do not need: of the versions of a key not
+    // after `up_to` only the newest is kept, and not even that one when it is a removal.
+    // Reads before `up_to` are no longer allowed afterwards. Returns the number of versions
+    // dropped.
+    pub fn compact(&mut self, up_to: u64) -> usize {
+        let up_to = up_to.min(self.version);
+        if up_to <= self.oldest {
+            return 0;
+        }
+        self.oldest = up_to;
+        let mut last: Option<K> = None;
+        // Whether the newest version of `last` not after `up_to` was seen.
+        let mut kept = false;
+        self.map
+            .extract_if(|(k, Reverse(v)), value| {
+                if last.as_ref() != Some(k) {
+                    last = Some(k.clone());
+                    kept = false;
+                }
+                if *v > up_to {
+                    return false;
+                }
+                let drop = kept || value.is_none();
+                kept = true;
+                drop
+            })
+            .count()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod versioned {
+    use super::VersionedMap;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::collections::BTreeMap as StdBTreeMap;
+
+    #[test]
+    #[cfg_attr(miri, ignore = "slow under Miri")]
+    fn test_versions() {
+        let mut rng = StdRng::seed_from_u64(23);
+        let mut map = VersionedMap::new();
+        // The expected map at every version.
+        let mut history = vec![StdBTreeMap::new()];
+        for _ in 0..3000 {
+            let key = rng.gen_range(0..300u32);
+            let mut model = history.last().unwrap().clone();
+            if rng.gen_bool(0.7) {
+                let value = rng.gen::<u8>();
+                assert_eq!(map.insert(key, value), history.len() as u64);
+                model.insert(key, value);
+            } else {
+                let removed = map.remove(&key);
+                assert_eq!(removed.is_some(), model.remove(&key).is_some());
+                if removed.is_none() {
+                    continue;
+                }
+            }
+            history.push(model);
+        }
+        assert_eq!(map.version() as usize, history.len() - 1);
+        let check = |map: &VersionedMap<u32, u8>, from: usize| {
+            for (version, model) in history.iter().enumerate().skip(from).step_by(97) {
+                assert!(map.iter_at(version as u64).eq(model.iter()));
+                for key in (0..300).step_by(7) {
+                    assert_eq!(map.get_at(&key, version as u64), model.get(&key));
+                }
+            }
+            assert_eq!(map.len(), history.last().unwrap().len());
+        };
+        check(&map, 0);
+        let stored = map.stored_versions();
+        let dropped = map.compact(1500);
+        assert_eq!(map.stored_versions(), stored - dropped);
+        assert!(map.stored_versions() < stored);
+        assert_eq!(map.oldest_version(), 1500);
+        check(&map, 1500);
+        map.compact(u64::MAX);
+        assert_eq!(map.stored_versions(), map.len());
+        check(&map, history.len() - 1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_compacted_read() {
+        let mut map = VersionedMap::new();
+        map.insert("a", 1);
+        map.insert("a", 2);
+        assert_eq!(map.get_at("a", 1), Some(&1));
+        map.compact(2);
+        map.get_at("a", 1);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::{cache_oblivious::ParallelBounds, comparable::Comparable, BTreeMap};
+use std::ops::{Bound, RangeBounds};
+
+// A read-only view of the entries of a map accepted by a predicate, see
+// `BTreeMap::view_filter`. Nothing is copied: every read goes to the map and skips the
+// entries the predicate rejects.
+pub struct FilterView<'a, K: Ord, V, P> {
+    map: &'a BTreeMap<K, V>,
+    pred: P,
+}
+
+impl<'a, K, V, P> FilterView<'a, K, V, P>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+    P: Fn(&K, &V) -> bool,
+{
+    pub(crate) fn new(map: &'a BTreeMap<K, V>, pred: P) -> Self {
+        Self { map, pred }
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<&'a V> {
+        self.map
+            .range_entries(Bound::Included(key), Bound::Included(key))
+            .find(|(k, v)| (self.pred)(k, v))
+            .map(|(_, v)| v)
+    }
+
+    pub fn contains_key<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (&'a K, &'a V)> + '_ {
+        self.map.range(range).filter(|(k, v)| (self.pred)(k, v))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a K, &'a V)> + '_ {
+        self.range(..)
+    }
+}
+
+// A read-only view presenting every value of a map through a function, see
+// `BTreeMap::view_map`. Values are computed on each read, never stored.
+pub struct MapView<'a, K: Ord, V, F> {
+    map: &'a BTreeMap<K, V>,
+    f: F,
+}
+
+impl<'a, K, V, U, F> MapView<'a, K, V, F>
+where
+    K: Ord + ParallelBounds,
+    V: ParallelBounds,
+    F: Fn(&K, &V) -> U,
+{
+    pub(crate) fn new(map: &'a BTreeMap<K, V>, f: F) -> Self {
+        Self { map, f }
+    }
+
+    pub fn get<Q: Comparable<K> + ?Sized>(&self, key: &Q) -> Option<U> {
+        self.map
+            .range_entries(Bound::Included(key), Bound::Included(key))
+            .next()
+            .map(|(k, v)| (self.f)(k, v))
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (&'a K, U)> + '_ {
+        self.map.range(range).map(|(k, v)| (k, (self.f)(k, v)))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&'a K, U)> + '_ {
+        self.range(..)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod view {
+    use crate::BTreeMap;
+
+    #[test]
+    fn test_filter_view() {
+        // Keys are (tenant, id).
+        let mut map = BTreeMap::new();
+        for i in 0..300usize {
+            map.insert((i % 3, i), i * 10);
+        }
+        let tenant = map.view_filter(|&(tenant, _), _| tenant == 1);
+        assert_eq!(tenant.get(&(1, 4)), Some(&40));
+        assert_eq!(tenant.get(&(2, 5)), None);
+        assert!(!tenant.contains_key(&(0, 0)));
+        assert_eq!(tenant.iter().count(), 100);
+        assert!(tenant.iter().all(|(&(t, i), &v)| t == 1 && v == i * 10));
+        let ids = tenant
+            .range((0, 0)..(1, 10))
+            .map(|(&(_, i), _)| i)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 4, 7]);
+
+        let large = map.view_filter(|_, &v| v >= 2980);
+        assert_eq!(large.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_map_view() {
+        let mut map = BTreeMap::new();
+        for i in 0..100usize {
+            map.insert(i, i);
+        }
+        let view = map.view_map(|&k, &v| format!("{}:{}", k, v * 2));
+        assert_eq!(view.get(&7), Some(String::from("7:14")));
+        assert_eq!(view.get(&100), None);
+        assert_eq!(
+            view.range(98..).map(|(_, s)| s).collect::<Vec<_>>(),
+            vec![String::from("98:196"), String::from("99:198")]
+        );
+        assert_eq!(view.iter().count(), 100);
+    }
+}